@@ -1,10 +1,18 @@
-use std::net::TcpStream;
 use std::{io, fmt};
 use std::io::Write;
 use std::error::Error;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::process::Command as Process;
 
-use kvsys::kvstorage::{Key, Value};
+use clap::{Arg, App};
+use kvsys::kvstorage::{Key, Value, VALUE_SIZE};
+use kvsys::kvstorage::scan::ScanOptions;
 use kvsys::kvclient::KVClient;
+use kvsys::kvserver::auth::Credentials;
+use kvsys::chunktps::Transport;
+use kvsys::chunktps::tls::TlsConnector;
 
 #[derive(Debug)]
 struct ClientError {
@@ -29,35 +37,171 @@ impl ClientError {
 fn main() {
     env_logger::init();
 
+    let matches = App::new("Project-KV Client Program")
+        .version("0.1")
+        .author("ICEY <icey@icey.tech>")
+        .about("The official interactive client of Project-KV server")
+        .arg(Arg::with_name("variable")
+            .long("variable-length")
+            .help("Send keys and values as typed, for servers running with --variable-length"))
+        .arg(Arg::with_name("tls_ca")
+            .long("tls-ca")
+            .value_name("FILE")
+            .help("Speak TLS, trusting servers with a certificate signed by one of the CAs in the PEM FILE")
+            .takes_value(true))
+        .arg(Arg::with_name("tls_server_name")
+            .long("tls-server-name")
+            .value_name("NAME")
+            .help("Name the certificate of the server must be valid for, the host connected to by default")
+            .takes_value(true))
+        .arg(Arg::with_name("tls_cert")
+            .long("tls-cert")
+            .value_name("FILE")
+            .help("Show the certificate chain in the PEM FILE to servers requiring one")
+            .takes_value(true))
+        .arg(Arg::with_name("tls_key")
+            .long("tls-key")
+            .value_name("FILE")
+            .help("Private key of the client certificate, in the PEM FILE")
+            .takes_value(true))
+        .arg(Arg::with_name("user")
+            .short("u")
+            .long("user")
+            .value_name("NAME")
+            .help("Authenticate as the user NAME, whose password is prompted for")
+            .takes_value(true))
+        .arg(Arg::with_name("token")
+            .long("token")
+            .conflicts_with("user")
+            .help("Authenticate with a token, which is prompted for"))
+        .get_matches();
+    let variable_length = matches.is_present("variable");
+
     println!("KV storage client -- v0.1");
-    print!("server IP:PORT to connect: ");
+    print!("server IP:PORT (or Unix socket path) to connect: ");
     io::stdout().flush().unwrap();
 
     let mut ip_addr = String::new();
     io::stdin().read_line(&mut ip_addr).unwrap();
-    match TcpStream::connect(ip_addr.trim()) {
-        Ok(tcp_stream) => {
-            if let Err(e) = mainloop(tcp_stream) {
+    let ip_addr = ip_addr.trim();
+    let credentials = read_credentials(matches.value_of("user"), matches.is_present("token"));
+    if let Some(ca_file) = matches.value_of("tls_ca") {
+        let server_name = matches.value_of("tls_server_name").unwrap_or_else(|| host_of(ip_addr));
+        let identity = matches.value_of("tls_cert").zip(matches.value_of("tls_key"));
+        match TlsConnector::from_pem_files(ca_file, server_name, identity) {
+            Ok(connector) => {
+                #[cfg(unix)]
+                {
+                    if ip_addr.contains('/') {
+                        let client = KVClient::connect_with(|| connector.connect(UnixStream::connect(ip_addr)?));
+                        return run(client, credentials, variable_length);
+                    }
+                }
+                run(KVClient::connect_tls(ip_addr, &connector), credentials, variable_length);
+            },
+            Err(e) => {
+                eprintln!("critical error occurred while setting TLS up, client shutting down");
+                eprintln!("detailed error info: {}", e);
+            }
+        }
+        return;
+    }
+    #[cfg(unix)]
+    {
+        if ip_addr.contains('/') {
+            return run(KVClient::connect_unix(ip_addr), credentials, variable_length);
+        }
+    }
+    run(KVClient::connect(ip_addr), credentials, variable_length);
+}
+
+/// Prompts for the credentials to authenticate with: the password of `user`, or a `token`. Without
+/// either of them, prompts for a user name first, `None` if none is given
+fn read_credentials(user: Option<&str>, token: bool) -> Option<Credentials> {
+    if token {
+        return Some(Credentials::Token(read_secret("token: ")));
+    }
+    let user = match user {
+        Some(user) => user.to_owned(),
+        None => {
+            print!("user name (empty for none): ");
+            io::stdout().flush().unwrap();
+            let mut user = String::new();
+            io::stdin().read_line(&mut user).unwrap();
+            user.trim().to_owned()
+        }
+    };
+    if user.is_empty() {
+        return None;
+    }
+    Some(Credentials::Password(user, read_secret("password: ")))
+}
+
+/// Reads a line without echoing it, on terminals where echo can be turned off
+fn read_secret(prompt: &str) -> String {
+    print!("{}", prompt);
+    io::stdout().flush().unwrap();
+    let echo_off = set_echo(false);
+    let mut ret = String::new();
+    io::stdin().read_line(&mut ret).unwrap();
+    if echo_off {
+        set_echo(true);
+        println!();
+    }
+    ret.trim_end_matches(&['\r', '\n'][..]).to_owned()
+}
+
+/// Turns echo of the terminal on or off, returns whether it could
+#[cfg(unix)]
+fn set_echo(on: bool) -> bool {
+    let status = Process::new("stty").arg(if on { "echo" } else { "-echo" }).status();
+    status.is_ok_and(|status| status.success())
+}
+
+#[cfg(not(unix))]
+fn set_echo(_on: bool) -> bool {
+    false
+}
+
+/// The host of `addr`, an IP:PORT (or HOST:PORT) address, or `localhost` for Unix socket paths
+fn host_of(addr: &str) -> &str {
+    if addr.contains('/') {
+        return "localhost";
+    }
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+fn run<S: Transport>(client: Result<KVClient<S>, Box<dyn Error>>, credentials: Option<Credentials>, variable_length: bool) {
+    match client {
+        Ok(mut client) => {
+            if let Some(credentials) = credentials {
+                if let Err(e) = client.do_authenticate(&credentials) {
+                    eprintln!("critical error occurred while authenticating, client shutting down");
+                    eprintln!("detailed error info: {}", e);
+                    return;
+                }
+            }
+            if let Err(e) = mainloop(client, variable_length) {
                 eprintln!("critical error occurred in client mainloop, client shutting down");
                 eprintln!("detailed error info: {}", e);
             }
         }
         Err(e) => {
-            eprintln!("critical error occurred while opening TCP connection, client shutting down");
+            eprintln!("critical error occurred while opening connection, client shutting down");
             eprintln!("detailed error info: {}", e);
         }
     }
 }
 
-fn mainloop(tcp_stream: TcpStream) -> Result<(), Box<dyn Error>> {
-    let mut client = KVClient::new(tcp_stream);
+fn mainloop<S: Transport>(mut client: KVClient<S>, variable_length: bool) -> Result<(), Box<dyn Error>> {
     loop {
         print!("kv-client> ");
         io::stdout().flush().unwrap();
 
         let mut command = String::new();
         io::stdin().read_line(&mut command).unwrap();
-        match parse_command(command) {
+        match parse_command(command, variable_length) {
             Ok(command) => {
                 exec_command(&mut client, &command)?;
                 if let Command::Close = command {
@@ -72,14 +216,15 @@ fn mainloop(tcp_stream: TcpStream) -> Result<(), Box<dyn Error>> {
 enum Command {
     Get(Key),
     Put(Key, Value),
-    Scan(Key, Key),
+    Scan(Key, Key, ScanOptions),
+    ScanPrefix(Vec<u8>),
     Delete(Key),
     Close
 }
 
-fn parse_command(command: String) -> Result<Command, ClientError> {
-    let parts = command.trim().split_whitespace().collect::<Vec<_>>();
-    if parts.len() == 0 {
+fn parse_command(command: String, variable_length: bool) -> Result<Command, ClientError> {
+    let parts = command.split_whitespace().collect::<Vec<_>>();
+    if parts.is_empty() {
         return Err(ClientError::new("no command given!"));
     }
     match parts[0] {
@@ -87,7 +232,7 @@ fn parse_command(command: String) -> Result<Command, ClientError> {
             if parts.len() != 2 {
                 return Err(ClientError::new("`get` requires exactly 1 argument"))
             }
-            let key = check_key_size(&parts[1].as_bytes())?;
+            let key = check_key_size(parts[1].as_bytes(), variable_length)?;
             Ok(Command::Get(key))
         },
         "put" => {
@@ -95,24 +240,32 @@ fn parse_command(command: String) -> Result<Command, ClientError> {
                 return Err(ClientError::new("put requires exactly 2 arguments"))
             }
 
-            let key = check_key_size(parts[1].as_bytes())?;
-            let value = check_value_size(parts[2].as_bytes())?;
+            let key = check_key_size(parts[1].as_bytes(), variable_length)?;
+            let value = check_value_size(parts[2].as_bytes(), variable_length)?;
             Ok(Command::Put(key, value))
         },
         "scan" => {
-            if parts.len() != 3 {
-                return Err(ClientError::new("scan requires exactly 2 arguments"))
+            if parts.len() < 3 {
+                return Err(ClientError::new("scan requires at least 2 arguments"))
             }
 
-            let key1 = check_key_size(parts[1].as_bytes())?;
-            let key2 = check_key_size(parts[2].as_bytes())?;
-            Ok(Command::Scan(key1, key2))
+            let key1 = check_key_size(parts[1].as_bytes(), variable_length)?;
+            let key2 = check_key_size(parts[2].as_bytes(), variable_length)?;
+            let options = parse_scan_options(&parts[3..], variable_length)?;
+            Ok(Command::Scan(key1, key2, options))
+        },
+        "prefix" => {
+            if parts.len() != 2 {
+                return Err(ClientError::new("prefix requires exactly 1 argument"))
+            }
+            // a prefix is shorter than a key, its size is never checked
+            Ok(Command::ScanPrefix(parts[1].as_bytes().to_vec()))
         },
         "del" | "delete" => {
             if parts.len() != 2 {
                 return Err(ClientError::new("delete requires exactly 1 argument"))
             }
-            let key = check_key_size(parts[1].as_bytes())?;
+            let key = check_key_size(parts[1].as_bytes(), variable_length)?;
             Ok(Command::Delete(key))
         },
         "close" => {
@@ -124,7 +277,32 @@ fn parse_command(command: String) -> Result<Command, ClientError> {
     }
 }
 
-fn exec_command(client: &mut KVClient, command: &Command) -> Result<(), Box<dyn Error>> {
+/// Parses the options following the keys of `scan`: `desc`, `limit <n>`, `exclusive` (key1 is
+/// excluded), `inclusive` (key2 is included) and `after <token>`
+fn parse_scan_options(parts: &[&str], variable_length: bool) -> Result<ScanOptions, ClientError> {
+    let mut options = ScanOptions::from_default();
+    let mut parts = parts.iter();
+    while let Some(&part) = parts.next() {
+        match part {
+            "desc" => options.descending = true,
+            "exclusive" => options.include_start = false,
+            "inclusive" => options.include_end = true,
+            "limit" => {
+                let limit = parts.next().and_then(|limit| limit.parse::<usize>().ok())
+                    .ok_or_else(|| ClientError::new("`limit` requires a number"))?;
+                options.limit = Some(limit);
+            },
+            "after" => {
+                let token = parts.next().ok_or_else(|| ClientError::new("`after` requires a key"))?;
+                options.continuation = Some(check_key_size(token.as_bytes(), variable_length)?);
+            },
+            _ => return Err(ClientError::new("unknown scan option"))
+        }
+    }
+    Ok(options)
+}
+
+fn exec_command<S: Transport>(client: &mut KVClient<S>, command: &Command) -> Result<(), Box<dyn Error>> {
     match command {
         Command::Get(key) => {
             client.do_get(key, handle_get_result)
@@ -134,20 +312,28 @@ fn exec_command(client: &mut KVClient, command: &Command) -> Result<(), Box<dyn
             println!("  Done");
             Ok(())
         },
-        Command::Scan(key1, key2) => {
-            client.do_scan(key1, key2, handle_scan_result)?;
+        Command::Scan(key1, key2, options) => {
+            let (_, continuation) = client.do_scan_with(key1, key2, options, handle_scan_result)?;
+            if let Some(token) = continuation {
+                println!("  More pairs follow, scan again with `after {}`", String::from_utf8_lossy(&token.data));
+            }
+            Ok(())
+        },
+        Command::ScanPrefix(prefix) => {
+            client.do_scan_prefix(prefix, handle_scan_result)?;
             Ok(())
         },
         Command::Delete(key) => {
             client.do_delete(key, handle_delete_result)
         },
         Command::Close => {
-            Ok(client.do_close())
+            client.do_close();
+            Ok(())
         }
     }
 }
 
-fn handle_get_result(value: Option<Value>) -> () {
+fn handle_get_result(value: Option<Value>) {
     if let Some(value) = value {
         println!("  {}", value);
     } else {
@@ -155,28 +341,32 @@ fn handle_get_result(value: Option<Value>) -> () {
     }
 }
 
-fn handle_delete_result(rows_affected: usize) -> () {
+fn handle_delete_result(rows_affected: usize) {
     println!("  Ok, {} rows affected", rows_affected)
 }
 
-fn handle_scan_result(kv_pairs: Vec<(Key, Value)>) -> () {
+fn handle_scan_result(kv_pairs: Vec<(Key, Value)>) {
     for (key, value) in kv_pairs.iter() {
         println!("  {} => {}", key, value)
     }
 }
 
-fn check_key_size(slice: &[u8]) -> Result<Key, ClientError> {
-    Key::from_slice_checked(slice).ok_or(ClientError::new("incorrect key size"))
+fn check_key_size(slice: &[u8], variable_length: bool) -> Result<Key, ClientError> {
+    if variable_length {
+        Ok(Key::from_bytes(slice))
+    } else {
+        Key::from_slice_checked(slice).ok_or_else(|| ClientError::new("incorrect key size"))
+    }
 }
 
-fn check_value_size(slice: &[u8]) -> Result<Value, ClientError> {
-    if slice.len() < 256 {
-        let mut ret = [0; 256];
-        for i in 0..slice.len() {
-            ret[i] = slice[i];
-        }
+fn check_value_size(slice: &[u8], variable_length: bool) -> Result<Value, ClientError> {
+    if variable_length {
+        Ok(Value::from_bytes(slice))
+    } else if slice.len() < VALUE_SIZE {
+        let mut ret = [0; VALUE_SIZE];
+        ret[..slice.len()].copy_from_slice(slice);
         Ok(Value::from_slice(&ret))
     } else {
-        Value::from_slice_checked(slice).ok_or(ClientError::new("incorrect value size"))
+        Value::from_slice_checked(slice).ok_or_else(|| ClientError::new("incorrect value size"))
     }
 }
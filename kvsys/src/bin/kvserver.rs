@@ -1,6 +1,5 @@
 use clap::{Arg, App};
 use kvsys::kvserver::{KVServerConfig, run_server};
-use env_logger;
 
 fn main() {
     env_logger::init();
@@ -15,6 +14,31 @@ fn main() {
             .value_name("PORT")
             .help("Choose the port the server should listen to")
             .takes_value(true))
+        .arg(Arg::with_name("unix_socket")
+            .long("unix-socket")
+            .value_name("PATH")
+            .help("Listen to the Unix domain socket at PATH instead of the TCP port")
+            .takes_value(true))
+        .arg(Arg::with_name("tls_cert")
+            .long("tls-cert")
+            .value_name("FILE")
+            .help("Speak TLS only, with the certificate chain in the PEM FILE")
+            .takes_value(true))
+        .arg(Arg::with_name("tls_key")
+            .long("tls-key")
+            .value_name("FILE")
+            .help("Private key of the TLS certificate, in the PEM FILE")
+            .takes_value(true))
+        .arg(Arg::with_name("tls_client_ca")
+            .long("tls-client-ca")
+            .value_name("FILE")
+            .help("Require clients to show a certificate signed by one of the CAs in the PEM FILE")
+            .takes_value(true))
+        .arg(Arg::with_name("users")
+            .long("users")
+            .value_name("FILE")
+            .help("Require clients to authenticate as one of the users in FILE, and only allow them what it grants")
+            .takes_value(true))
         .arg(Arg::with_name("dbfile")
             .short("f")
             .long("filename")
@@ -26,6 +50,51 @@ fn main() {
             .long("threads")
             .value_name("THREADS")
             .takes_value(true))
+        .arg(Arg::with_name("compaction")
+            .short("c")
+            .long("compaction-ratio")
+            .value_name("RATIO")
+            .help("Compact the log once dead records exceed RATIO times live keys, 0 disables")
+            .takes_value(true))
+        .arg(Arg::with_name("strict")
+            .long("strict-recovery")
+            .help("Refuse to start if the log ends with an incomplete record, instead of truncating it"))
+        .arg(Arg::with_name("sync")
+            .short("s")
+            .long("sync")
+            .value_name("POLICY")
+            .help("When to sync the log to disk: always, never, or an interval like 100ms")
+            .takes_value(true))
+        .arg(Arg::with_name("snapshot")
+            .long("snapshot-interval")
+            .value_name("SECONDS")
+            .help("Take a snapshot and cut the log short every SECONDS seconds, 0 disables")
+            .takes_value(true))
+        .arg(Arg::with_name("reap")
+            .long("reap-interval")
+            .value_name("SECONDS")
+            .help("Delete keys that have expired every SECONDS seconds, 0 disables")
+            .takes_value(true))
+        .arg(Arg::with_name("variable")
+            .long("variable-length")
+            .help("Accept keys and values of any size, instead of fixed 8 bytes keys and 256 bytes values"))
+        .arg(Arg::with_name("max_value")
+            .long("max-value-size")
+            .value_name("BYTES")
+            .help("Largest value accepted with --variable-length")
+            .takes_value(true))
+        .arg(Arg::with_name("memory")
+            .short("m")
+            .long("memory-budget")
+            .value_name("BYTES")
+            .help("Hold at most about BYTES of values in memory, reading older ones back from disk, 0 for unlimited")
+            .takes_value(true))
+        .arg(Arg::with_name("engine")
+            .short("e")
+            .long("engine")
+            .value_name("ENGINE")
+            .help("Storage engine to serve: log (persistent, default), lsm (persistent, FILE is a directory) or memory (nothing persisted)")
+            .takes_value(true))
         .get_matches();
 
     let config = KVServerConfig::from_arg_matches(matches);
@@ -5,7 +5,15 @@
 //! this document gets written, the only user of chunktp, Project-KV Protocol, uses empty chunk as
 //! termination.
 //!
-//! Chunktps is based on TCP now. It is possible to port it to KCP or UDP, however.
+//! Chunktps runs over any reliable byte stream (see `Transport`): TCP, Unix domain sockets, or
+//! in-process pipes for tests. It is possible to port it to KCP or UDP, however.
+//! Connections may be encrypted with TLS, see the `tls` module.
+//!
+//! Two versions of chunktp exist. Version 1 has every chunk acknowledged by the receiver before the
+//! next one is sent, and chunks of up to 65535 bytes. Version 2 lifts both limits: chunks carry a
+//! 4 bytes length, and flow control is done by credits the receiver grants as it reads chunks.
+//! Clients choose the version (`ChunktpConnection::new` for v1, `ChunktpConnection::connect` for
+//! v2), servers speak whichever the client does (`ChunktpConnection::accept`).
 //!
 //! A typical echo-server, based on chunktp:
 //! ```no_run
@@ -30,11 +38,18 @@
 //!     }
 //! ```
 
+pub mod tls;
+pub mod transport;
+mod v2;
+
+pub use transport::{Transport, Listener};
+pub use v2::{CHUNK_MAX_SIZE_V2, DEFAULT_WINDOW};
+
 use std::net::TcpStream;
 use std::error::Error;
 use std::fmt;
 use std::fmt::{Display, Formatter};
-use std::io::{Read, Write};
+use std::sync::Arc;
 
 // The chunktp chunk format
 //   - 4 bytes magic (0xdeadbeef)
@@ -44,6 +59,30 @@ use std::io::{Read, Write};
 // After reading a chunk from sender, the receiver must send back a 5 bytes message
 //   - OK means the message is successfully received by the client
 //   - TE means a critical error occurred during transport, and the transport must shutdown
+//
+// Both ends may agree (through the protocol on top of chunktp) to stop acknowledging chunks for a
+// while, chunks are then sent one after another without waiting for anything, see
+// `ChunktpConnection::set_acknowledged`.
+//
+// A v2 connection starts with the client sending a 6 bytes hello
+//   - 4 bytes magic (0xdeadbee2)
+//   - 2 bytes window in big endian, the count of chunks it accepts before having read them, at
+//     least 1
+// which the server answers with a hello of its own. A v1 server answers TE instead, since the
+// hello does not start with the magic of a chunk, and the client has to connect again using v1.
+//
+// The v2 frame format
+//   - 4 bytes size, in big endian, up to CHUNK_MAX_SIZE_V2
+//   - 4 bytes stream id, in big endian
+//   - 1 byte flags
+//     - 0 for a chunk
+//     - 1 (credit) for credits granted to the other end, the data being the 4 bytes count of chunks
+//     - 2 (terminate) if a critical error occurred, and the transport must shutdown
+//   - (size) bytes data
+//
+// Each end may send as many chunks as the window of the other end, and then one more chunk per
+// credit granted. The receiver grants credits back for the chunks it has read, by halves of its
+// window. Nothing is acknowledged.
 const CHUNKTPS_MAGIC: [u8; 4] = [0xde, 0xad, 0xbe, 0xef];
 const CHUNKTPS_READER_OK: [u8; 5] = [0xde, 0xad, 0xbe, 0xef, 0xac];
 const CHUNKTPS_READER_TE: [u8; 5] = [0xca, 0xfe, 0xba, 0xbe, 0xff];
@@ -72,53 +111,183 @@ impl Display for ChunktpError {
 impl Error for ChunktpError {
 }
 
-/// A chunktp connection, now chunktps connection supports TCP only
-pub struct ChunktpConnection {
-    tcp_stream: TcpStream
+/// A chunktp connection over a `Transport`, TCP by default
+pub struct ChunktpConnection<S: Transport = TcpStream> {
+    stream: S,
+    acknowledged: bool,
+    // the magic of the next chunk has already been read, by `accept`
+    magic_read: bool,
+    // the state shared by the handles to a v2 connection, `None` for v1
+    link: Option<Arc<v2::Link<S>>>,
+    // max size of the chunks read on v1, the link keeps its own on v2
+    read_limit: usize
 }
 
-impl ChunktpConnection {
-    /// Creates a chunktp connection over a TCP stream. It does not make any assumption, check or
+impl<S: Transport> ChunktpConnection<S> {
+    /// Creates a chunktp v1 connection over a stream. It does not make any assumption, check or
     /// operation on the stream
-    pub fn new(tcp_stream: TcpStream) -> Self {
-        ChunktpConnection { tcp_stream }
+    pub fn new(stream: S) -> Self {
+        ChunktpConnection { stream, acknowledged: true, magic_read: false, link: None, read_limit: CHUNK_MAX_SIZE }
+    }
+
+    /// Creates a chunktp v2 connection over a stream to a server, by exchanging hellos with
+    /// it. Returns `Err` if the stream fails or the server does not speak v2, in which case
+    /// the stream is of no use any more: the server has to be connected again, using `new`
+    pub fn connect(mut stream: S) -> Result<Self, Box<dyn Error>> {
+        stream.write_all(&v2::hello(DEFAULT_WINDOW))?;
+        let mut reply = [0u8; 6];
+        stream.read_exact(&mut reply[..5])?;
+        if reply[..5] == CHUNKTPS_READER_TE {
+            return Err(Box::new(ChunktpError::new("server does not speak chunktp v2")));
+        }
+        stream.read_exact(&mut reply[5..])?;
+        let window = u16::from_be_bytes([reply[4], reply[5]]);
+        if reply[..4] != v2::HELLO_MAGIC || window == 0 {
+            return Err(Box::new(ChunktpError::new("server hello not understood")));
+        }
+        let link = v2::Link::new(stream.try_clone()?, DEFAULT_WINDOW, window)?;
+        Ok(ChunktpConnection { stream, acknowledged: false, magic_read: false, link: Some(Arc::new(link)), read_limit: CHUNK_MAX_SIZE })
+    }
+
+    /// Creates a chunktp connection over a stream from a client, of the version the client
+    /// speaks: v2 if it starts with a hello, v1 otherwise. Returns `Err` if the stream fails or
+    /// the client speaks neither
+    pub fn accept(mut stream: S) -> Result<Self, Box<dyn Error>> {
+        let mut magic = [0u8; 4];
+        stream.read_exact(&mut magic)?;
+        if magic == CHUNKTPS_MAGIC {
+            return Ok(ChunktpConnection { stream, acknowledged: true, magic_read: true, link: None, read_limit: CHUNK_MAX_SIZE });
+        }
+        if magic != v2::HELLO_MAGIC {
+            let _ = stream.write(&CHUNKTPS_READER_TE);
+            return Err(Box::new(ChunktpError::new("incorrect chunktps magic!")));
+        }
+        let mut window = [0u8; 2];
+        stream.read_exact(&mut window)?;
+        let window = u16::from_be_bytes(window);
+        // nothing could ever be sent to the client
+        if window == 0 {
+            return Err(Box::new(ChunktpError::new("client hello tells an empty window!")));
+        }
+        stream.write_all(&v2::hello(DEFAULT_WINDOW))?;
+        let link = v2::Link::new(stream.try_clone()?, DEFAULT_WINDOW, window)?;
+        Ok(ChunktpConnection { stream, acknowledged: false, magic_read: false, link: Some(Arc::new(link)), read_limit: CHUNK_MAX_SIZE })
+    }
+
+    /// Version of chunktp the connection speaks, 1 or 2
+    pub fn version(&self) -> u8 {
+        if self.link.is_some() { 2 } else { 1 }
+    }
+
+    /// Max size of a chunk on this connection, `CHUNK_MAX_SIZE` or `CHUNK_MAX_SIZE_V2`
+    pub fn max_chunk_size(&self) -> usize {
+        if self.link.is_some() { CHUNK_MAX_SIZE_V2 } else { CHUNK_MAX_SIZE }
+    }
+
+    /// Creates another handle to the same connection, e.g. for reading chunks in one thread while
+    /// writing chunks in another. Only meaningful while chunks are not acknowledged, reads and
+    /// writes depend on each other otherwise
+    pub fn try_clone(&self) -> Result<Self, Box<dyn Error>> {
+        Ok(ChunktpConnection {
+            stream: self.stream.try_clone()?,
+            acknowledged: self.acknowledged,
+            magic_read: false,
+            link: self.link.clone(),
+            read_limit: self.read_limit
+        })
+    }
+
+    /// Limits the size of the chunks read to `limit`, at most `max_chunk_size`, e.g. while the
+    /// other end is not trusted yet. A larger chunk fails the read, and the other end is told to
+    /// terminate. The limit is shared by all the handles to a v2 connection
+    pub fn set_read_limit(&mut self, limit: usize) {
+        self.read_limit = limit.min(CHUNK_MAX_SIZE);
+        if let Some(link) = &self.link {
+            link.set_read_limit(limit);
+        }
     }
 
-    /// Try reading a chunk from the chunktp connection, returns Err type if the TCP stream fails
+    /// Turns acknowledgements of chunks on or off. Both ends must turn them off (or back on) right
+    /// after the same chunk, it is up to the protocol on top of chunktp to agree on when. Without
+    /// acknowledgements, writing a chunk does not wait for the other end to read it, which saves
+    /// a round trip per chunk. Chunks are never acknowledged on v2 connections, whatever is set
+    pub fn set_acknowledged(&mut self, acknowledged: bool) {
+        self.acknowledged = acknowledged && self.link.is_none();
+    }
+
+    /// Whether chunks are acknowledged, see `set_acknowledged`
+    pub fn is_acknowledged(&self) -> bool {
+        self.acknowledged
+    }
+
+    /// Try reading a chunk from the chunktp connection, returns Err type if the stream fails
     /// or the received buffer is ill-formed
     pub fn read_chunk(&mut self) -> Result<Vec<u8>, Box<dyn Error>> {
-        let mut magic = [0u8; 4];
+        Ok(self.read_stream_chunk()?.1)
+    }
+
+    /// Same as `read_chunk`, but also returns the stream the chunk belongs to, always 0 on v1
+    /// connections
+    pub fn read_stream_chunk(&mut self) -> Result<(u32, Vec<u8>), Box<dyn Error>> {
+        if let Some(link) = &self.link {
+            return link.read_chunk();
+        }
+        let mut magic = CHUNKTPS_MAGIC;
         let mut size = [0u8; 2];
 
-        self.tcp_stream.read_exact(&mut magic)?;
-        self.tcp_stream.read_exact(&mut size)?;
+        if !self.magic_read {
+            self.stream.read_exact(&mut magic)?;
+        }
+        self.magic_read = false;
+        self.stream.read_exact(&mut size)?;
         if magic != CHUNKTPS_MAGIC {
-            let _ = self.tcp_stream.write(&CHUNKTPS_READER_TE);
+            let _ = self.stream.write(&CHUNKTPS_READER_TE);
             return Err(Box::new(ChunktpError::new("incorrect chunktps magic!")));
         }
         let size = size[0] as usize * 256 + size[1] as usize;
+        if size > self.read_limit {
+            let _ = self.stream.write(&CHUNKTPS_READER_TE);
+            return Err(Box::new(ChunktpError::new("chunk too large!")));
+        }
 
         let mut recv_buffer = Vec::with_capacity(size);
         recv_buffer.resize_with(size, Default::default);
-        self.tcp_stream.read_exact(recv_buffer.as_mut_slice())?;
+        self.stream.read_exact(recv_buffer.as_mut_slice())?;
 
-        self.tcp_stream.write(&CHUNKTPS_READER_OK)?;
-        Ok(recv_buffer)
+        if self.acknowledged {
+            self.stream.write_all(&CHUNKTPS_READER_OK)?;
+        }
+        Ok((0, recv_buffer))
     }
 
-    /// Try writing a chunk into the chunktp connection, returns Err type if the TCP stream fails
+    /// Try writing a chunk into the chunktp connection, returns Err type if the stream fails
     /// or the received buffer is ill-formed
     pub fn write_chunk(&mut self, data: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        self.write_stream_chunk(0, data)
+    }
+
+    /// Same as `write_chunk`, but the chunk belongs to the given `stream`, which the other end
+    /// gets together with it. Returns `Err` for streams other than 0 on v1 connections
+    pub fn write_stream_chunk(&mut self, stream: u32, data: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        if let Some(link) = &self.link {
+            return link.write_chunk(stream, &data);
+        }
+        if stream != 0 {
+            return Err(Box::new(ChunktpError::new("streams require chunktp v2")));
+        }
         let size = data.len();
         assert!(size <= CHUNK_MAX_SIZE);
         let size = [(size / 256) as u8, (size % 256) as u8];
 
-        self.tcp_stream.write(&CHUNKTPS_MAGIC)?;
-        self.tcp_stream.write(&size)?;
-        self.tcp_stream.write(data.as_slice())?;
+        self.stream.write_all(&CHUNKTPS_MAGIC)?;
+        self.stream.write_all(&size)?;
+        self.stream.write_all(data.as_slice())?;
+        if !self.acknowledged {
+            return Ok(());
+        }
 
         let mut client_reply = [0u8; 5];
-        self.tcp_stream.read_exact(&mut client_reply)?;
+        self.stream.read_exact(&mut client_reply)?;
 
         match client_reply {
             CHUNKTPS_READER_OK => Ok(()),
@@ -130,8 +299,10 @@ impl ChunktpConnection {
 
 #[cfg(test)]
 mod test {
-    use crate::chunktps::ChunktpConnection;
-    use std::net::{TcpListener, TcpStream};
+    use crate::chunktps::{ChunktpConnection, DEFAULT_WINDOW};
+    use crate::chunktps::v2;
+    use crate::chunktps::transport::duplex;
+    use std::io::{Read, Write};
     use std::thread;
     use std::time::Duration;
 
@@ -149,25 +320,164 @@ mod test {
                 b"this is the last message to send"
             ];
 
+            let (stream, server_stream) = duplex();
             let t = thread::spawn(
                 move || {
-                    let listener = TcpListener::bind("127.0.0.1:8964").unwrap();
-                    let (stream, _) = listener.accept().unwrap();
-                    let mut chunktps = ChunktpConnection::new(stream);
+                    let mut chunktps = ChunktpConnection::new(server_stream);
                     for &piece in data.iter() {
                         chunktps.write_chunk(piece.to_vec()).unwrap();
                     }
                 }
             );
 
-            thread::sleep(Duration::from_secs(1));
-            let stream = TcpStream::connect("127.0.0.1:8964").unwrap();
             let mut chunktps = ChunktpConnection::new(stream);
-            for i in 0..data.len() {
-                assert_eq!(chunktps.read_chunk().unwrap(), data[i].to_vec());
+            for piece in data.iter() {
+                assert_eq!(chunktps.read_chunk().unwrap(), piece.to_vec());
             }
 
             t.join().unwrap();
         }
     }
+
+    #[test]
+    fn test_unacknowledged_rw() {
+        let (stream, server_stream) = duplex();
+        let t = thread::spawn(
+            move || {
+                let mut chunktps = ChunktpConnection::new(server_stream);
+                chunktps.write_chunk(b"acknowledged".to_vec()).unwrap();
+                chunktps.set_acknowledged(false);
+                // nothing is read on the other end until everything has been written
+                for i in 0..1000u32 {
+                    chunktps.write_chunk(i.to_be_bytes().to_vec()).unwrap();
+                }
+                assert_eq!(chunktps.read_chunk().unwrap(), b"done".to_vec());
+                chunktps.set_acknowledged(true);
+                chunktps.write_chunk(b"acknowledged again".to_vec()).unwrap();
+            }
+        );
+
+        let mut chunktps = ChunktpConnection::new(stream);
+        assert_eq!(chunktps.read_chunk().unwrap(), b"acknowledged".to_vec());
+        chunktps.set_acknowledged(false);
+        thread::sleep(Duration::from_millis(100));
+        let mut reader = chunktps.try_clone().unwrap();
+        for i in 0..1000u32 {
+            assert_eq!(reader.read_chunk().unwrap(), i.to_be_bytes().to_vec());
+        }
+        chunktps.write_chunk(b"done".to_vec()).unwrap();
+        chunktps.set_acknowledged(true);
+        assert_eq!(chunktps.read_chunk().unwrap(), b"acknowledged again".to_vec());
+
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn test_v2_rw() {
+        let ((stream, server_stream), (v1_stream, v1_server_stream)) = (duplex(), duplex());
+        let t = thread::spawn(
+            move || {
+                let mut chunktps = ChunktpConnection::accept(server_stream).unwrap();
+                assert_eq!(chunktps.version(), 2);
+                // far more chunks than the window, and chunks larger than v1 allows
+                for i in 0..1000usize {
+                    assert_eq!(chunktps.read_chunk().unwrap(), vec![(i % 256) as u8; i * 97]);
+                }
+                for i in 0..1000u32 {
+                    chunktps.write_stream_chunk(i % 7, i.to_be_bytes().to_vec()).unwrap();
+                }
+
+                // v1 clients are still served
+                let mut chunktps = ChunktpConnection::accept(v1_server_stream).unwrap();
+                assert_eq!(chunktps.version(), 1);
+                assert_eq!(chunktps.read_chunk().unwrap(), b"v1".to_vec());
+                chunktps.write_chunk(b"still v1".to_vec()).unwrap();
+            }
+        );
+
+        let mut chunktps = ChunktpConnection::connect(stream).unwrap();
+        assert_eq!(chunktps.version(), 2);
+        assert!(!chunktps.is_acknowledged());
+        for i in 0..1000usize {
+            chunktps.write_chunk(vec![(i % 256) as u8; i * 97]).unwrap();
+        }
+        for i in 0..1000u32 {
+            assert_eq!(chunktps.read_stream_chunk().unwrap(), (i % 7, i.to_be_bytes().to_vec()));
+        }
+
+        let mut chunktps = ChunktpConnection::new(v1_stream);
+        assert!(chunktps.write_stream_chunk(1, b"v1".to_vec()).is_err());
+        chunktps.write_chunk(b"v1".to_vec()).unwrap();
+        assert_eq!(chunktps.read_chunk().unwrap(), b"still v1".to_vec());
+
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn test_v2_flow_control() {
+        // the client ignores the window of the server, or grants more credits than there can be
+        let credit = [0u8, 0, 0, 4, 0, 0, 0, 0, v2::FLAG_CREDIT, 0xff, 0xff, 0xff, 0xff];
+        for frames in [[0u8, 0, 0, 1, 0, 0, 0, 0, 0, 7].repeat(DEFAULT_WINDOW as usize + 1), credit.repeat(2)] {
+            let (mut stream, server_stream) = duplex();
+            let t = thread::spawn(
+                move || {
+                    let mut chunktps = ChunktpConnection::accept(server_stream).unwrap();
+                    chunktps.write_chunk(b"first".to_vec()).unwrap();
+                    // waits for credits, reading what the client sends meanwhile
+                    assert!(chunktps.write_chunk(b"second".to_vec()).is_err() || chunktps.read_chunk().is_err());
+                }
+            );
+
+            stream.write_all(&v2::hello(1)).unwrap();
+            let mut hello = [0u8; 6];
+            stream.read_exact(&mut hello).unwrap();
+            stream.write_all(&frames).unwrap();
+            // the server tells the client to terminate after its chunks
+            loop {
+                let mut header = [0u8; 9];
+                stream.read_exact(&mut header).unwrap();
+                if header[8] == v2::FLAG_TERMINATE {
+                    break;
+                }
+                let mut data = vec![0u8; u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize];
+                stream.read_exact(&mut data).unwrap();
+            }
+
+            t.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_v2_empty_window() {
+        let (mut stream, server_stream) = duplex();
+        let t = thread::spawn(move || assert!(ChunktpConnection::accept(server_stream).is_err()));
+        stream.write_all(&v2::hello(0)).unwrap();
+        t.join().unwrap();
+
+        let (stream, mut server_stream) = duplex();
+        let t = thread::spawn(
+            move || {
+                let mut hello = [0u8; 6];
+                server_stream.read_exact(&mut hello).unwrap();
+                server_stream.write_all(&v2::hello(0)).unwrap();
+            }
+        );
+        assert!(ChunktpConnection::connect(stream).is_err());
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn test_v2_to_v1_server() {
+        let (stream, server_stream) = duplex();
+        let t = thread::spawn(
+            move || {
+                let mut chunktps = ChunktpConnection::new(server_stream);
+                assert!(chunktps.read_chunk().is_err());
+            }
+        );
+
+        assert!(ChunktpConnection::connect(stream).is_err());
+
+        t.join().unwrap();
+    }
 }
@@ -0,0 +1,428 @@
+//! TLS for chunktp connections
+//!
+//! `TlsStream` encrypts a `Transport` with TLS, and is a `Transport` itself, so that chunktp runs
+//! over it as over any other stream. Servers encrypt the streams of their clients with a
+//! `TlsAcceptor` (see `TlsListener`), holding their certificate and key, and clients the streams
+//! they open with a `TlsConnector`, which checks the certificate of the server against a CA bundle.
+//! Servers may require clients to show a certificate too, signed by a CA they trust (mutual TLS).
+//!
+//! The handshake goes on along with the first reads and writes, which its errors show up through.
+//!
+//! ```no_run
+//!     use std::net::TcpStream;
+//!     use kvsys::chunktps::ChunktpConnection;
+//!     use kvsys::chunktps::tls::TlsConnector;
+//!     // ...
+//!     let connector = TlsConnector::from_pem_files("ca.pem", "kv.example.com", None).unwrap();
+//!     let tcp_stream = TcpStream::connect("kv.example.com:1926").unwrap();
+//!     let mut chunktps = ChunktpConnection::connect(connector.connect(tcp_stream).unwrap()).unwrap();
+//!     // ...
+//! ```
+
+use crate::chunktps::{Transport, Listener};
+
+use rustls::{ClientConfig, ClientConnection, Connection, RootCertStore, ServerConfig, ServerConnection};
+use rustls::crypto::ring;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::pki_types::pem::PemObject;
+use rustls::server::WebPkiClientVerifier;
+
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Size of the buffer encrypted data is read into
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// The error type used when setting TLS up
+#[derive(Debug)]
+pub struct TlsError {
+    description: String
+}
+
+impl TlsError {
+    pub fn new(description: &str) -> Self {
+        TlsError { description: description.to_owned() }
+    }
+}
+
+impl Display for TlsError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(f, "tls error: {}", self.description)
+    }
+}
+
+impl Error for TlsError {
+}
+
+/// A `Transport` encrypted with TLS, see `TlsAcceptor::accept` and `TlsConnector::connect`
+pub struct TlsStream<S: Transport> {
+    shared: Arc<TlsShared<S>>
+}
+
+/// The state shared by all the handles to a `TlsStream`. Reads and writes may go on at once from
+/// different handles: the state is only held while encrypting or decrypting, not while waiting
+/// on the underlying stream
+struct TlsShared<S: Transport> {
+    state: Mutex<TlsState>,
+    // the reading half of the stream, with the buffer encrypted data is read into. It is taken
+    // before the state, if together
+    reader: Mutex<(S, Vec<u8>)>,
+    // the writing half of the stream. It is taken after the state, if together
+    writer: Mutex<S>
+}
+
+struct TlsState {
+    connection: Connection,
+    // encrypted data read, not handed over to the connection yet
+    pending: Vec<u8>
+}
+
+impl<S: Transport> TlsStream<S> {
+    fn new(stream: S, mut connection: Connection) -> io::Result<Self> {
+        // what is written is bounded by the chunktp window, rather than by the connection
+        connection.set_buffer_limit(None);
+        let shared = TlsShared {
+            state: Mutex::new(TlsState { connection, pending: Vec::new() }),
+            reader: Mutex::new((stream.try_clone()?, vec![0u8; READ_BUFFER_SIZE])),
+            writer: Mutex::new(stream)
+        };
+        Ok(TlsStream { shared: Arc::new(shared) })
+    }
+}
+
+impl<S: Transport> TlsShared<S> {
+    /// Writes out whatever the connection has to send. The state is released before writing,
+    /// the writing half being taken first, so that data goes out in the order it has been made
+    fn send(&self, mut state: MutexGuard<TlsState>) -> io::Result<()> {
+        let mut data = Vec::new();
+        while state.connection.wants_write() {
+            state.connection.write_tls(&mut data)?;
+        }
+        if data.is_empty() {
+            return Ok(());
+        }
+        let mut writer = self.writer.lock().unwrap();
+        drop(state);
+        writer.write_all(&data)
+    }
+
+    /// Hands the pending encrypted data over to the connection
+    fn process(&self, mut state: MutexGuard<TlsState>) -> io::Result<()> {
+        let handshaking = state.connection.is_handshaking();
+        let TlsState { connection, pending } = &mut *state;
+        let read = connection.read_tls(&mut &pending[..])?;
+        pending.drain(..read);
+        if read == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "tls data not accepted by the connection"));
+        }
+        match connection.process_new_packets() {
+            // the handshake goes on through reads, as clients may read before writing anything
+            Ok(_) if handshaking => self.send(state),
+            Ok(_) => Ok(()),
+            Err(e) => {
+                // the other end is told why, if it can be
+                let _ = self.send(state);
+                Err(io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+        }
+    }
+
+    /// Reads encrypted data from the stream, or tells the connection it has ended
+    fn receive(&self) -> io::Result<()> {
+        let mut reader = self.reader.lock().unwrap();
+        let (stream, buffer) = &mut *reader;
+        let size = stream.read(buffer)?;
+        let mut state = self.state.lock().unwrap();
+        if size == 0 {
+            state.connection.read_tls(&mut &[][..])?;
+        }
+        state.pending.extend_from_slice(&buffer[..size]);
+        Ok(())
+    }
+}
+
+impl<S: Transport> Drop for TlsShared<S> {
+    fn drop(&mut self) {
+        // lets the other end know the stream has ended on purpose
+        let mut state = self.state.lock().unwrap();
+        state.connection.send_close_notify();
+        let _ = self.send(state);
+    }
+}
+
+impl<S: Transport> Read for TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut state = self.shared.state.lock().unwrap();
+            match state.connection.reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                ret => return ret
+            }
+            if !state.pending.is_empty() {
+                self.shared.process(state)?;
+                continue;
+            }
+            drop(state);
+            self.shared.receive()?;
+        }
+    }
+}
+
+impl<S: Transport> Write for TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.shared.state.lock().unwrap();
+        let size = state.connection.writer().write(buf)?;
+        self.shared.send(state)?;
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.shared.send(self.shared.state.lock().unwrap())
+    }
+}
+
+impl<S: Transport> Transport for TlsStream<S> {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(TlsStream { shared: self.shared.clone() })
+    }
+
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.shared.writer.lock().unwrap().set_nodelay(nodelay)
+    }
+}
+
+/// The TLS settings of a server, which the streams of its clients are encrypted with
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    config: Arc<ServerConfig>
+}
+
+impl TlsAcceptor {
+    /// Settings of a server with the certificate chain `cert` and the private key `key`, both PEM.
+    /// If `client_ca` (a PEM bundle) is given, clients must show a certificate signed by one of
+    /// its CAs (mutual TLS)
+    pub fn from_pem(cert: &[u8], key: &[u8], client_ca: Option<&[u8]>) -> Result<Self, Box<dyn Error>> {
+        let provider = Arc::new(ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone()).with_safe_default_protocol_versions()?;
+        let builder = match client_ca {
+            Some(client_ca) => {
+                let roots = Arc::new(root_store(client_ca)?);
+                builder.with_client_cert_verifier(WebPkiClientVerifier::builder_with_provider(roots, provider).build()?)
+            },
+            None => builder.with_no_client_auth()
+        };
+        let config = builder.with_single_cert(certificates(cert)?, private_key(key)?)?;
+        Ok(TlsAcceptor { config: Arc::new(config) })
+    }
+
+    /// Same as `from_pem`, with the PEM read from the given files
+    pub fn from_pem_files(cert_file: &str, key_file: &str, client_ca_file: Option<&str>) -> Result<Self, Box<dyn Error>> {
+        let client_ca = client_ca_file.map(read_pem).transpose()?;
+        TlsAcceptor::from_pem(&read_pem(cert_file)?, &read_pem(key_file)?, client_ca.as_deref())
+    }
+
+    /// Encrypts `stream` from a client
+    pub fn accept<S: Transport>(&self, stream: S) -> io::Result<TlsStream<S>> {
+        let connection = ServerConnection::new(self.config.clone())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        TlsStream::new(stream, connection.into())
+    }
+}
+
+/// The TLS settings of a client of a server, which the streams to it are encrypted with
+#[derive(Clone)]
+pub struct TlsConnector {
+    config: Arc<ClientConfig>,
+    server_name: ServerName<'static>
+}
+
+impl TlsConnector {
+    /// Settings of a client of the server named `server_name` (a DNS name or an IP address), whose
+    /// certificate must be valid for that name and signed by one of the CAs of `ca` (a PEM bundle).
+    /// `identity` is the certificate chain and private key (PEM) of the client, for servers
+    /// requiring mutual TLS
+    pub fn from_pem(ca: &[u8], server_name: &str, identity: Option<(&[u8], &[u8])>) -> Result<Self, Box<dyn Error>> {
+        let provider = Arc::new(ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider).with_safe_default_protocol_versions()?
+            .with_root_certificates(root_store(ca)?);
+        let config = match identity {
+            Some((cert, key)) => builder.with_client_auth_cert(certificates(cert)?, private_key(key)?)?,
+            None => builder.with_no_client_auth()
+        };
+        let server_name = ServerName::try_from(server_name.to_owned())?;
+        Ok(TlsConnector { config: Arc::new(config), server_name })
+    }
+
+    /// Same as `from_pem`, with the PEM read from the given files
+    pub fn from_pem_files(ca_file: &str, server_name: &str, identity_files: Option<(&str, &str)>) -> Result<Self, Box<dyn Error>> {
+        let identity = match identity_files {
+            Some((cert_file, key_file)) => Some((read_pem(cert_file)?, read_pem(key_file)?)),
+            None => None
+        };
+        let identity = identity.as_ref().map(|(cert, key)| (&cert[..], &key[..]));
+        TlsConnector::from_pem(&read_pem(ca_file)?, server_name, identity)
+    }
+
+    /// Encrypts `stream` to the server
+    pub fn connect<S: Transport>(&self, stream: S) -> io::Result<TlsStream<S>> {
+        let connection = ClientConnection::new(self.config.clone(), self.server_name.clone())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        TlsStream::new(stream, connection.into())
+    }
+}
+
+/// Accepts clients from a `Listener`, with their streams encrypted
+pub struct TlsListener<L: Listener> {
+    listener: L,
+    acceptor: TlsAcceptor
+}
+
+impl<L: Listener> TlsListener<L> {
+    pub fn new(listener: L, acceptor: TlsAcceptor) -> Self {
+        TlsListener { listener, acceptor }
+    }
+}
+
+impl<L: Listener> Listener for TlsListener<L> {
+    type Stream = TlsStream<L::Stream>;
+
+    fn accept(&self) -> io::Result<Self::Stream> {
+        self.acceptor.accept(self.listener.accept()?)
+    }
+}
+
+fn read_pem(file: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    fs::read(file).map_err(|e| Box::new(TlsError::new(&format!("cannot read '{}': {}", file, e))) as Box<dyn Error>)
+}
+
+fn certificates(pem: &[u8]) -> Result<Vec<CertificateDer<'static>>, Box<dyn Error>> {
+    let ret = CertificateDer::pem_slice_iter(pem).collect::<Result<Vec<_>, _>>()?;
+    if ret.is_empty() {
+        return Err(Box::new(TlsError::new("no certificate found in PEM")));
+    }
+    Ok(ret)
+}
+
+fn private_key(pem: &[u8]) -> Result<PrivateKeyDer<'static>, Box<dyn Error>> {
+    PrivateKeyDer::from_pem_slice(pem).map_err(|e| Box::new(TlsError::new(&format!("no private key found in PEM: {}", e))) as Box<dyn Error>)
+}
+
+fn root_store(pem: &[u8]) -> Result<RootCertStore, Box<dyn Error>> {
+    let mut ret = RootCertStore::empty();
+    for cert in certificates(pem)? {
+        ret.add(cert)?;
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use crate::chunktps::ChunktpConnection;
+    use crate::chunktps::tls::{TlsAcceptor, TlsConnector};
+    use crate::chunktps::transport::duplex;
+    use rcgen::{BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+    use std::thread;
+
+    /// Certificates and keys (PEM) of a CA, and of a server and a client it has signed
+    pub(crate) struct TestPki {
+        pub ca: String,
+        pub server: (String, String),
+        pub client: (String, String)
+    }
+
+    /// Generates a self-signed CA, and certificates signed by it for a server named `localhost`
+    /// and for a client
+    pub(crate) fn gen_pki() -> TestPki {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.distinguished_name.push(DnType::CommonName, "kvsys test CA");
+        let ca = params.self_signed(&ca_key).unwrap();
+        let issue = |names: &[&str], usage: ExtendedKeyUsagePurpose| {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(names.iter().map(|&name| name.to_owned()).collect::<Vec<_>>()).unwrap();
+            params.extended_key_usages = vec![usage];
+            (params.signed_by(&key, &ca, &ca_key).unwrap().pem(), key.serialize_pem())
+        };
+        TestPki {
+            server: issue(&["localhost"], ExtendedKeyUsagePurpose::ServerAuth),
+            client: issue(&[], ExtendedKeyUsagePurpose::ClientAuth),
+            ca: ca.pem()
+        }
+    }
+
+    #[test]
+    fn test_tls_rw() {
+        let pki = gen_pki();
+        let acceptor = TlsAcceptor::from_pem(pki.server.0.as_bytes(), pki.server.1.as_bytes(), Some(pki.ca.as_bytes())).unwrap();
+        let identity = Some((pki.client.0.as_bytes(), pki.client.1.as_bytes()));
+        let connector = TlsConnector::from_pem(pki.ca.as_bytes(), "localhost", identity).unwrap();
+
+        let (stream, server_stream) = duplex();
+        let server_stream = acceptor.accept(server_stream).unwrap();
+        let t = thread::spawn(move || {
+            let mut chunktps = ChunktpConnection::accept(server_stream).unwrap();
+            assert_eq!(chunktps.version(), 2);
+            for i in 0..200usize {
+                assert_eq!(chunktps.read_chunk().unwrap(), vec![i as u8; i * 997]);
+            }
+            for i in 0..200usize {
+                chunktps.write_chunk(vec![i as u8; i * 997]).unwrap();
+            }
+        });
+        let mut chunktps = ChunktpConnection::connect(connector.connect(stream).unwrap()).unwrap();
+        // chunks go both ways at once, from different handles
+        let mut reader = chunktps.try_clone().unwrap();
+        let r = thread::spawn(move || {
+            for i in 0..200usize {
+                assert_eq!(reader.read_chunk().unwrap(), vec![i as u8; i * 997]);
+            }
+        });
+        for i in 0..200usize {
+            chunktps.write_chunk(vec![i as u8; i * 997]).unwrap();
+        }
+        r.join().unwrap();
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn test_tls_refused() {
+        let (pki, other) = (gen_pki(), gen_pki());
+        let attempt = |acceptor: TlsAcceptor, connector: TlsConnector| {
+            let (stream, server_stream) = duplex();
+            let server_stream = acceptor.accept(server_stream).unwrap();
+            let t = thread::spawn(move || ChunktpConnection::accept(server_stream).is_ok());
+            let client = ChunktpConnection::connect(connector.connect(stream).unwrap()).is_ok();
+            (client, t.join().unwrap())
+        };
+        let server = (pki.server.0.as_bytes(), pki.server.1.as_bytes());
+        let identity = Some((pki.client.0.as_bytes(), pki.client.1.as_bytes()));
+        let mutual = TlsAcceptor::from_pem(server.0, server.1, Some(pki.ca.as_bytes())).unwrap();
+
+        // a server signed by another CA, or for another name
+        let connector = TlsConnector::from_pem(other.ca.as_bytes(), "localhost", identity).unwrap();
+        assert_eq!(attempt(mutual.clone(), connector), (false, false));
+        let connector = TlsConnector::from_pem(pki.ca.as_bytes(), "kv.example.com", identity).unwrap();
+        assert_eq!(attempt(mutual.clone(), connector), (false, false));
+        // a client without a certificate, or with one signed by another CA
+        let connector = TlsConnector::from_pem(pki.ca.as_bytes(), "localhost", None).unwrap();
+        assert!(!attempt(mutual.clone(), connector).1);
+        let identity = Some((other.client.0.as_bytes(), other.client.1.as_bytes()));
+        let connector = TlsConnector::from_pem(pki.ca.as_bytes(), "localhost", identity).unwrap();
+        assert!(!attempt(mutual, connector).1);
+        // which is fine if the server does not require one
+        let acceptor = TlsAcceptor::from_pem(server.0, server.1, None).unwrap();
+        let connector = TlsConnector::from_pem(pki.ca.as_bytes(), "localhost", None).unwrap();
+        assert_eq!(attempt(acceptor, connector), (true, true));
+
+        assert!(TlsAcceptor::from_pem(b"", server.1, None).is_err());
+        assert!(TlsAcceptor::from_pem(server.0, b"", None).is_err());
+        assert!(TlsAcceptor::from_pem_files("no-such-cert.pem", "no-such-key.pem", None).is_err());
+    }
+}
@@ -0,0 +1,252 @@
+//! Byte streams chunktp runs over, and listeners servers accept them from
+//!
+//! Chunktp only needs a reliable, ordered stream of bytes in both directions, which it can get
+//! another handle to (see `ChunktpConnection::try_clone`). TCP and Unix domain sockets are such
+//! streams, and so is `DuplexStream`, an in-process pair of pipes meant for tests, which do not
+//! have to bind any port with it:
+//! ```
+//!     use std::thread;
+//!     use kvsys::chunktps::ChunktpConnection;
+//!     use kvsys::chunktps::transport::duplex;
+//!     // ...
+//!     let (client, server) = duplex();
+//!     let t = thread::spawn(move || {
+//!         let mut chunktps = ChunktpConnection::accept(server).unwrap();
+//!         let chunk = chunktps.read_chunk().unwrap();
+//!         chunktps.write_chunk(chunk).unwrap();
+//!     });
+//!     let mut chunktps = ChunktpConnection::connect(client).unwrap();
+//!     chunktps.write_chunk(b"echo".to_vec()).unwrap();
+//!     assert_eq!(chunktps.read_chunk().unwrap(), b"echo".to_vec());
+//!     t.join().unwrap();
+//! ```
+
+use std::collections::VecDeque;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{Arc, Condvar, Mutex, mpsc};
+
+/// A byte stream chunktp can run over
+pub trait Transport: Read + Write + Send + Sized + 'static {
+    /// Creates another handle to the same stream: what is written through either of them goes
+    /// out in order, and what comes in is read by whichever reads first
+    fn try_clone(&self) -> io::Result<Self>;
+
+    /// Sends small writes right away rather than holding them back to gather larger ones, for
+    /// streams that do so (e.g. TCP with the Nagle algorithm)
+    fn set_nodelay(&self, _nodelay: bool) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Where a server accepts the streams of its clients from
+pub trait Listener: Send {
+    type Stream: Transport;
+
+    /// Waits for the next client, and returns the stream to it
+    fn accept(&self) -> io::Result<Self::Stream>;
+}
+
+impl Transport for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        TcpStream::set_nodelay(self, nodelay)
+    }
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept(&self) -> io::Result<TcpStream> {
+        Ok(TcpListener::accept(self)?.0)
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+}
+
+#[cfg(unix)]
+impl Listener for UnixListener {
+    type Stream = UnixStream;
+
+    fn accept(&self) -> io::Result<UnixStream> {
+        Ok(UnixListener::accept(self)?.0)
+    }
+}
+
+/// One end of an in-process stream, see `duplex`
+pub struct DuplexStream {
+    end: Arc<DuplexEnd>
+}
+
+struct DuplexEnd {
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>
+}
+
+/// Bytes going one way, from one end to the other
+struct Pipe {
+    state: Mutex<PipeState>,
+    readable: Condvar
+}
+
+struct PipeState {
+    buffer: VecDeque<u8>,
+    // one of the ends has gone, so that nothing will be written any more, or read
+    closed: bool
+}
+
+/// Creates both ends of an in-process stream. Each end reads what the other writes, until all
+/// the handles to the other end have been dropped
+pub fn duplex() -> (DuplexStream, DuplexStream) {
+    let (a, b) = (Arc::new(Pipe::new()), Arc::new(Pipe::new()));
+    let first = DuplexEnd { incoming: a.clone(), outgoing: b.clone() };
+    let second = DuplexEnd { incoming: b, outgoing: a };
+    (DuplexStream { end: Arc::new(first) }, DuplexStream { end: Arc::new(second) })
+}
+
+impl Pipe {
+    fn new() -> Self {
+        Pipe { state: Mutex::new(PipeState { buffer: VecDeque::new(), closed: false }), readable: Condvar::new() }
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.readable.notify_all();
+    }
+}
+
+impl Drop for DuplexEnd {
+    fn drop(&mut self) {
+        self.incoming.close();
+        self.outgoing.close();
+    }
+}
+
+impl Read for DuplexStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let pipe = &self.end.incoming;
+        let mut state = pipe.state.lock().unwrap();
+        while state.buffer.is_empty() && !state.closed && !buf.is_empty() {
+            state = pipe.readable.wait(state).unwrap();
+        }
+        // once closed, what is left is still read before the end of the stream
+        let size = buf.len().min(state.buffer.len());
+        for (dst, src) in buf.iter_mut().zip(state.buffer.drain(..size)) {
+            *dst = src;
+        }
+        Ok(size)
+    }
+}
+
+impl Write for DuplexStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let pipe = &self.end.outgoing;
+        let mut state = pipe.state.lock().unwrap();
+        if state.closed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "the other end of the duplex stream is gone"));
+        }
+        state.buffer.extend(buf);
+        pipe.readable.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for DuplexStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(DuplexStream { end: self.end.clone() })
+    }
+}
+
+/// Accepts in-process streams, made by the `DuplexConnector`s going with it, see `duplex_listener`
+pub struct DuplexListener {
+    incoming: Mutex<mpsc::Receiver<DuplexStream>>
+}
+
+/// Makes in-process streams to a `DuplexListener`
+#[derive(Clone)]
+pub struct DuplexConnector {
+    sender: mpsc::Sender<DuplexStream>
+}
+
+/// Creates a listener of in-process streams, together with what connects to it. Accepting fails
+/// once all the connectors have been dropped
+pub fn duplex_listener() -> (DuplexListener, DuplexConnector) {
+    let (sender, receiver) = mpsc::channel();
+    (DuplexListener { incoming: Mutex::new(receiver) }, DuplexConnector { sender })
+}
+
+impl DuplexConnector {
+    /// Makes a stream to the listener, which it returns the end of the client of. Returns `Err` if
+    /// the listener is gone
+    pub fn connect(&self) -> io::Result<DuplexStream> {
+        let (client, server) = duplex();
+        self.sender.send(server).map_err(|_| io::Error::new(io::ErrorKind::ConnectionRefused, "the duplex listener is gone"))?;
+        Ok(client)
+    }
+}
+
+impl Listener for DuplexListener {
+    type Stream = DuplexStream;
+
+    fn accept(&self) -> io::Result<DuplexStream> {
+        self.incoming.lock().unwrap().recv()
+            .map_err(|_| io::Error::new(io::ErrorKind::ConnectionAborted, "all the duplex connectors are gone"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::chunktps::transport::{duplex, duplex_listener, Listener, Transport};
+    use std::io::{Read, Write};
+    use std::thread;
+
+    #[test]
+    fn test_duplex() {
+        let (mut a, mut b) = duplex();
+        a.write_all(b"ping").unwrap();
+        let mut buffer = [0u8; 4];
+        b.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"ping");
+
+        // reads wait for writes from the other thread, through any handle
+        let mut clone = b.try_clone().unwrap();
+        let t = thread::spawn(move || clone.write_all(&[7; 100000]).unwrap());
+        let mut received = vec![0u8; 100000];
+        a.read_exact(&mut received).unwrap();
+        assert!(received.iter().all(|&byte| byte == 7));
+        t.join().unwrap();
+
+        // the end of the stream comes once all the handles to the other end are gone
+        b.write_all(b"last").unwrap();
+        drop(b);
+        let mut rest = Vec::new();
+        a.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"last".to_vec());
+        assert!(a.write_all(b"nobody").is_err());
+
+        let (listener, connector) = duplex_listener();
+        let mut client = connector.connect().unwrap();
+        client.write_all(b"hello").unwrap();
+        let mut server = listener.accept().unwrap();
+        let mut buffer = [0u8; 5];
+        server.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"hello");
+        drop(connector);
+        assert!(listener.accept().is_err());
+    }
+}
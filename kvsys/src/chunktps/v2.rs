@@ -0,0 +1,205 @@
+//! Chunktp v2 framing and flow control, see the format in the `chunktps` module
+//!
+//! All the handles to a v2 connection (see `ChunktpConnection::try_clone`) share a `Link`. Frames
+//! are read by whichever handle needs something from the other end, be it a chunk or credits to
+//! send one: it takes the reading half of the stream, reads a frame and hands it over to the
+//! others, which wait meanwhile.
+
+use crate::chunktps::{ChunktpError, Transport};
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::sync::{Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Magic starting the hello of both ends of a v2 connection
+pub(crate) const HELLO_MAGIC: [u8; 4] = [0xde, 0xad, 0xbe, 0xe2];
+
+/// The frame grants credits to the other end, its data is the count of chunks granted
+pub(crate) const FLAG_CREDIT: u8 = 1;
+/// The other end has run into a critical error, and the transport must shutdown
+pub(crate) const FLAG_TERMINATE: u8 = 2;
+
+/// Max size of a chunk on a v2 connection
+pub const CHUNK_MAX_SIZE_V2: usize = 16 * 1024 * 1024;
+
+/// Count of chunks an end accepts before having read them, which it tells in its hello
+pub const DEFAULT_WINDOW: u16 = 64;
+
+/// One end of a v2 connection, shared by all its handles
+pub(crate) struct Link<S: Transport> {
+    reader: Mutex<S>,
+    writer: Mutex<S>,
+    state: Mutex<LinkState>,
+    changed: Condvar,
+    window: u32,
+    // max size of the chunks read, see `ChunktpConnection::set_read_limit`
+    read_limit: AtomicUsize
+}
+
+struct LinkState {
+    // chunks that may still be sent before the other end grants more
+    credits: u32,
+    // chunks read from the other end, not handed over yet
+    received: VecDeque<(u32, Vec<u8>)>,
+    // chunks handed over since credits have last been granted for them
+    consumed: u32,
+    // why the connection cannot be used any more, if it cannot
+    failure: Option<String>
+}
+
+impl<S: Transport> Link<S> {
+    /// Creates the link over `stream` once hellos have been exchanged, `window` being the
+    /// window of this end and `credits` the one of the other end
+    pub fn new(stream: S, window: u16, credits: u16) -> Result<Self, Box<dyn Error>> {
+        // credits are small frames the other end waits for, they must not be held back
+        stream.set_nodelay(true)?;
+        let state = LinkState { credits: credits as u32, received: VecDeque::new(), consumed: 0, failure: None };
+        Ok(Link {
+            reader: Mutex::new(stream.try_clone()?),
+            writer: Mutex::new(stream),
+            state: Mutex::new(state),
+            changed: Condvar::new(),
+            window: window as u32,
+            read_limit: AtomicUsize::new(CHUNK_MAX_SIZE_V2)
+        })
+    }
+
+    /// Writes `data` as a chunk of `stream`, once the other end has granted credits for it
+    pub fn write_chunk(&self, stream: u32, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        assert!(data.len() <= CHUNK_MAX_SIZE_V2);
+        self.wait_for(|state| {
+            if state.credits == 0 {
+                return None;
+            }
+            state.credits -= 1;
+            Some(())
+        })?;
+        self.write_frame(stream, 0, data)
+    }
+
+    /// Sets the max size of the chunks read, by any handle
+    pub fn set_read_limit(&self, limit: usize) {
+        self.read_limit.store(limit.min(CHUNK_MAX_SIZE_V2), Ordering::Relaxed);
+    }
+
+    /// Reads the next chunk of any stream, together with the stream
+    pub fn read_chunk(&self) -> Result<(u32, Vec<u8>), Box<dyn Error>> {
+        let mut granted = 0;
+        let ret = self.wait_for(|state| {
+            let chunk = state.received.pop_front()?;
+            state.consumed += 1;
+            // credits are granted back by halves of the window, rather than chunk by chunk
+            if state.consumed * 2 >= self.window {
+                granted = state.consumed;
+                state.consumed = 0;
+            }
+            Some(chunk)
+        })?;
+        if granted > 0 {
+            // the chunk has been read anyway: the other end may be gone after its last chunk, and
+            // anything else going wrong shows up with the next read or write
+            let _ = self.write_frame(0, FLAG_CREDIT, &granted.to_be_bytes());
+        }
+        Ok(ret)
+    }
+
+    /// Waits until `ready` gives something out of the state, reading frames meanwhile if no other
+    /// handle is reading them
+    fn wait_for<T, F>(&self, mut ready: F) -> Result<T, Box<dyn Error>>
+        where F: FnMut(&mut LinkState) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(failure) = &state.failure {
+                return Err(Box::new(ChunktpError::new(failure)));
+            }
+            if let Some(ret) = ready(&mut state) {
+                return Ok(ret);
+            }
+            // the reading half is only released together with the state, so that waiting for
+            // another handle to read cannot miss what it has read
+            state = match self.reader.try_lock() {
+                Ok(mut reader) => {
+                    drop(state);
+                    let frame = self.read_frame(&mut reader);
+                    let mut state = self.state.lock().unwrap();
+                    if let Err(e) = frame.and_then(|frame| self.handle_frame(&mut state, frame)) {
+                        state.failure = Some(e.to_string());
+                    }
+                    drop(reader);
+                    self.changed.notify_all();
+                    state
+                },
+                Err(_) => self.changed.wait(state).unwrap()
+            };
+        }
+    }
+
+    /// Takes a frame read into the state, returns `Err` if the other end has broken the flow
+    /// control, in which case it is told to terminate
+    fn handle_frame(&self, state: &mut LinkState, (stream, flags, data): (u32, u8, Vec<u8>)) -> Result<(), Box<dyn Error>> {
+        let valid = match flags {
+            // chunks not granted back yet are all within the window of this end
+            0 => {
+                state.received.push_back((stream, data));
+                state.received.len() as u32 + state.consumed <= self.window
+            },
+            FLAG_CREDIT => {
+                let mut granted = [0u8; 4];
+                granted.copy_from_slice(&data);
+                match state.credits.checked_add(u32::from_be_bytes(granted)) {
+                    Some(credits) => {
+                        state.credits = credits;
+                        true
+                    },
+                    None => false
+                }
+            },
+            _ => return Err(Box::new(ChunktpError::new("other end requested terminate")))
+        };
+        if !valid {
+            let _ = self.write_frame(0, FLAG_TERMINATE, &[]);
+            return Err(Box::new(ChunktpError::new("other end broke chunktps flow control!")));
+        }
+        Ok(())
+    }
+
+    /// Reads a frame, as its stream, flags and data
+    fn read_frame(&self, reader: &mut S) -> Result<(u32, u8, Vec<u8>), Box<dyn Error>> {
+        let mut header = [0u8; 9];
+        reader.read_exact(&mut header)?;
+        let size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let stream = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+        let flags = header[8];
+        let valid = match flags {
+            0 => size <= self.read_limit.load(Ordering::Relaxed),
+            FLAG_CREDIT => size == 4,
+            FLAG_TERMINATE => size == 0,
+            _ => false
+        };
+        if !valid {
+            let _ = self.write_frame(0, FLAG_TERMINATE, &[]);
+            return Err(Box::new(ChunktpError::new("ill-formed chunktps frame!")));
+        }
+        let mut data = vec![0u8; size];
+        reader.read_exact(&mut data)?;
+        Ok((stream, flags, data))
+    }
+
+    fn write_frame(&self, stream: u32, flags: u8, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        // the header and the data go out at once, in as few packets as possible
+        let mut frame = Vec::with_capacity(9 + data.len());
+        frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&stream.to_be_bytes());
+        frame.push(flags);
+        frame.extend_from_slice(data);
+        self.writer.lock().unwrap().write_all(&frame)?;
+        Ok(())
+    }
+}
+
+/// Hello of an end of a v2 connection, telling its window
+pub(crate) fn hello(window: u16) -> [u8; 6] {
+    let window = window.to_be_bytes();
+    [HELLO_MAGIC[0], HELLO_MAGIC[1], HELLO_MAGIC[2], HELLO_MAGIC[3], window[0], window[1]]
+}
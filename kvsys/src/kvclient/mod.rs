@@ -2,11 +2,21 @@
 
 use std::fmt;
 use std::error::Error;
+use std::thread;
+use std::time::Duration;
 
-use crate::chunktps::ChunktpConnection;
-use crate::kvstorage::{Key, Value};
-use crate::kvserver::protocol::{Request, ReplyChunk};
-use std::net::TcpStream;
+use crate::chunktps::{ChunktpConnection, ChunktpError, Transport};
+use crate::chunktps::tls::{TlsConnector, TlsStream};
+use crate::kvstorage::{Key, Value, WriteBatch, Ttl};
+use crate::kvstorage::scan::ScanOptions;
+use crate::kvserver::auth::Credentials;
+use crate::kvserver::protocol::{Request, ReplyChunk, read_message, write_message};
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::Path;
 
 /// Error occurred on server, and received by client
 #[derive(Debug)]
@@ -29,20 +39,97 @@ impl ServerError {
     }
 }
 
-/// A key-value storage client, basically a wrapper for `ChunktpConnection`
+/// A key-value storage client, basically a wrapper for `ChunktpConnection`, over TCP by default
 ///
 /// `KVClient` relies on callback functions to handle server returned results since server can
 /// send reply in multi-chunk form, while caching all these chunks is somewhat expensive. If
 /// there's an error when reading and parsing server reply, the callback function will not be
 /// called. Read documentation of `do_xx` functions for further information
-pub struct KVClient {
-    chunktps: ChunktpConnection
+///
+/// Keys and values of any size can be sent and received, messages larger than a chunk are sent in
+/// fragments. Servers only accept keys and values other than `KEY_SIZE` and `VALUE_SIZE` bytes if
+/// they run with `DataLayout::Variable`
+///
+/// Servers with users (see `kvserver::auth`) require `do_authenticate` before anything else, and
+/// then deny the requests the user is not allowed, which return `Err`
+pub struct KVClient<S: Transport = TcpStream> {
+    chunktps: ChunktpConnection<S>
+}
+
+/// Result of a conditional write, `Err` with the current value (`None` if there is none) if it did
+/// not match the expected one, see `KVClient::do_compare_and_swap`
+pub type SwapReply = Result<(), Option<Value>>;
+
+/// Reply to a request of a pipeline, see `KVClient::do_pipeline`: its reply chunks, in the order
+/// received, without the empty chunk ending replies of several chunks
+pub type PipelineReply = Vec<ReplyChunk>;
+
+/// A transaction going on through a `KVClient`, see `KVClient::do_transaction`
+pub struct TransactionHandle<'a, S: Transport = TcpStream> {
+    client: &'a mut KVClient<S>
+}
+
+impl KVClient<TcpStream> {
+    /// Connects to the server at `addr`, see `connect_with`
+    ///
+    /// Returns `Err` if TCP connection fails
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self, Box<dyn Error>> {
+        let addrs = addr.to_socket_addrs()?.collect::<Vec<_>>();
+        KVClient::connect_with(|| TcpStream::connect(&addrs[..]))
+    }
+}
+
+impl KVClient<TlsStream<TcpStream>> {
+    /// Connects to the server at `addr` over TLS set up by `connector`, see `connect_with`
+    ///
+    /// Returns `Err` if TCP connection or the TLS handshake fails
+    pub fn connect_tls<A: ToSocketAddrs>(addr: A, connector: &TlsConnector) -> Result<Self, Box<dyn Error>> {
+        let addrs = addr.to_socket_addrs()?.collect::<Vec<_>>();
+        KVClient::connect_with(|| connector.connect(TcpStream::connect(&addrs[..])?))
+    }
+}
+
+#[cfg(unix)]
+impl KVClient<UnixStream> {
+    /// Connects to the server listening to the Unix domain socket at `path`, see `connect_with`
+    ///
+    /// Returns `Err` if connecting to the socket fails
+    pub fn connect_unix<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        KVClient::connect_with(|| UnixStream::connect(path.as_ref()))
+    }
 }
 
-impl KVClient {
-    /// Creates a `KVClient` using the given `TcpStream`
-    pub fn new(tcp_stream: TcpStream) -> Self {
-        KVClient { chunktps: ChunktpConnection::new(tcp_stream) }
+impl<S: Transport> KVClient<S> {
+    /// Creates a `KVClient` using the given stream, over chunktp v1
+    pub fn new(stream: S) -> Self {
+        KVClient { chunktps: ChunktpConnection::new(stream) }
+    }
+
+    /// Connects to a server over chunktp v2, which streams replies without waiting for each chunk
+    /// to be acknowledged, through a stream `open` gives. Servers that only speak v1 are connected
+    /// to again, through another stream, over v1
+    ///
+    /// Returns `Err` if `open` or the stream fails
+    pub fn connect_with<F: FnMut() -> io::Result<S>>(mut open: F) -> Result<Self, Box<dyn Error>> {
+        match ChunktpConnection::connect(open()?) {
+            Ok(chunktps) => Ok(KVClient { chunktps }),
+            Err(e) if e.is::<ChunktpError>() => Ok(KVClient::new(open()?)),
+            Err(e) => Err(e)
+        }
+    }
+
+    /// Version of chunktp spoken with the server, see `connect`
+    pub fn chunktp_version(&self) -> u8 {
+        self.chunktps.version()
+    }
+
+    /// Trying authenticate the connection as the user `credentials` belong to, which servers with
+    /// users require before any other request. Servers without users accept any credentials
+    ///
+    /// Returns `Err` if TCP connection fails, Chunktp fails or server denies the credentials, in
+    /// which case it closes the connection
+    pub fn do_authenticate(&mut self, credentials: &Credentials) -> Result<(), Box<dyn Error>> {
+        self.do_simple(Request::Authenticate(credentials.clone()), "error authenticating")
     }
 
     /// Trying get a value corresponding to the given `Key`
@@ -62,15 +149,18 @@ impl KVClient {
     ///}
     /// ```
     ///
-    /// Returns `Err` if TCP connection fails or Chunktp fails
+    /// Returns `Err` if TCP connection fails, Chunktp fails or server fails
     pub fn do_get<F, T>(&mut self, key: &Key, result_handler: F) -> Result<T, Box<dyn Error>>
         where F: Fn(Option<Value>) -> T {
-        self.chunktps.write_chunk(Request::Get(*key).serialize())?;
-        let reply = ReplyChunk::deserialize(self.chunktps.read_chunk()?)?;
+        write_message(&mut self.chunktps, Request::Get(key.clone()).serialize())?;
+        let reply = deserialize_reply(self.read_reply()?)?;
         match reply {
             ReplyChunk::SingleValue(value ) => {
                 Ok(result_handler(value))
-            }
+            },
+            ReplyChunk::Error => {
+                Err(Box::new(ServerError::new("error getting value")))
+            },
             _ => Err(Box::new(ServerError::new("unexpected reply chunk kind")))
         }
     }
@@ -82,8 +172,8 @@ impl KVClient {
     ///
     /// Returns `Err` if TCP connection fails, Chunktp fails or server fails.
     pub fn do_put(&mut self, key: &Key, value: &Value) -> Result<(), Box<dyn Error>> {
-        self.chunktps.write_chunk(Request::Put(*key, *value).serialize())?;
-        let reply = ReplyChunk::deserialize(self.chunktps.read_chunk()?)?;
+        write_message(&mut self.chunktps, Request::Put(key.clone(), value.clone()).serialize())?;
+        let reply = deserialize_reply(self.read_reply()?)?;
         match reply {
             ReplyChunk::Success => {
                 Ok(())
@@ -116,26 +206,111 @@ impl KVClient {
     /// an `Err`, without rollback or further processing. Please avoid write codes with strong
     /// side effects, for example, interacting with anther database.
     ///
-    /// Returns `Err` if TCP connection fails or Chunktp fails
+    /// The server reads the interval in batches and sends each of them as soon as read, without
+    /// blocking writers for the whole scan. Writes made meanwhile may thus show up: each key is
+    /// received at most once and in order, but a key written during the scan may come with its old
+    /// value, its new one, or not at all. Use `do_scan_at` for a consistent view.
+    ///
+    /// Returns `Err` if TCP connection fails, Chunktp fails or server fails
     pub fn do_scan<F, T>(&mut self, key1: &Key, key2: &Key, chunk_handler: F) -> Result<Vec<T>, Box<dyn Error>>
         where F: Fn(Vec<(Key, Value)>) -> T {
-        self.chunktps.write_chunk(Request::Scan(*key1, *key2).serialize())?;
+        let request = Request::Scan(key1.clone(), key2.clone(), ScanOptions::from_default());
+        Ok(self.do_scan_request(request, chunk_handler)?.0)
+    }
+
+    /// Same as `do_scan`, but scans the interval from `key1` to `key2` as told by `options`: in
+    /// descending order, with either bound included or excluded, and up to a limit of pairs. Also
+    /// returns the continuation token of a scan cut short by its limit, which is to be set in
+    /// `options` to fetch the next page
+    ///
+    /// Returns `Err` if TCP connection fails, Chunktp fails or server fails
+    pub fn do_scan_with<F, T>(&mut self, key1: &Key, key2: &Key, options: &ScanOptions, chunk_handler: F)
+        -> Result<(Vec<T>, Option<Key>), Box<dyn Error>>
+        where F: Fn(Vec<(Key, Value)>) -> T {
+        self.do_scan_request(Request::Scan(key1.clone(), key2.clone(), options.clone()), chunk_handler)
+    }
+
+    /// Same as `do_scan`, but only scans the keys holding a value within interval [`key1`,
+    /// `key2`), which saves shipping values. `chunk_handler` is called once per chunk of keys
+    /// received
+    ///
+    /// Returns `Err` if TCP connection fails, Chunktp fails or server fails
+    pub fn do_scan_keys<F, T>(&mut self, key1: &Key, key2: &Key, chunk_handler: F) -> Result<Vec<T>, Box<dyn Error>>
+        where F: Fn(Vec<Key>) -> T {
+        write_message(&mut self.chunktps, Request::ScanKeys(key1.clone(), key2.clone()).serialize())?;
         let mut ret = Vec::new();
         loop {
-            let chunk = self.chunktps.read_chunk()?;
-            if chunk.len() == 0 {
+            let chunk = read_message(&mut self.chunktps)?;
+            if chunk.is_empty() {
                 return Ok(ret)
             }
-            let reply = ReplyChunk::deserialize(chunk)?;
-            match reply {
-                ReplyChunk::KVPairs(kv_pairs) => {
-                    ret.push(chunk_handler(kv_pairs));
-                },
+            match deserialize_reply(chunk)? {
+                ReplyChunk::Keys(keys) => ret.push(chunk_handler(keys)),
+                ReplyChunk::Error => return Err(Box::new(ServerError::new("error scanning keys"))),
                 _ => return Err(Box::new(ServerError::new("unexpected reply chunk kind")))
             }
         }
     }
 
+    /// Same as `do_scan`, but scans all the keys starting with `prefix` instead of an interval.
+    /// Prefixes made of 0xff bytes only (or empty) are fine, they go on up to the greatest key
+    ///
+    /// Returns `Err` if TCP connection fails, Chunktp fails or server fails
+    pub fn do_scan_prefix<F, T>(&mut self, prefix: &[u8], chunk_handler: F) -> Result<Vec<T>, Box<dyn Error>>
+        where F: Fn(Vec<(Key, Value)>) -> T {
+        Ok(self.do_scan_request(Request::ScanPrefix(prefix.to_vec()), chunk_handler)?.0)
+    }
+
+    /// Trying count the keys holding a value within interval [`key1`, `key2`)
+    ///
+    /// Returns `Err` if TCP connection fails, Chunktp fails or server fails
+    pub fn do_count(&mut self, key1: &Key, key2: &Key) -> Result<usize, Box<dyn Error>> {
+        self.do_number(Request::Count(key1.clone(), key2.clone()), "error counting keys")
+    }
+
+    /// Trying open a read snapshot on the server, returns its id. Reads through the snapshot
+    /// (`do_get_at`, `do_scan_at`) see the content of the moment it has been opened, whatever gets
+    /// written afterwards, so that several reads are consistent with each other
+    ///
+    /// The snapshot should be released by `do_release_snapshot` once done with, the server keeps
+    /// old values for it until then (or until the connection is closed).
+    ///
+    /// Returns `Err` if TCP connection fails, Chunktp fails or server fails
+    pub fn do_open_snapshot(&mut self) -> Result<u64, Box<dyn Error>> {
+        write_message(&mut self.chunktps, Request::OpenSnapshot.serialize())?;
+        match deserialize_reply(self.read_reply()?)? {
+            ReplyChunk::Number(snapshot) => Ok(snapshot as u64),
+            ReplyChunk::Error => Err(Box::new(ServerError::new("error opening snapshot"))),
+            _ => Err(Box::new(ServerError::new("unexpected reply chunk kind")))
+        }
+    }
+
+    /// Same as `do_get`, but reads through the snapshot `snapshot` opened by `do_open_snapshot`
+    pub fn do_get_at<F, T>(&mut self, snapshot: u64, key: &Key, result_handler: F) -> Result<T, Box<dyn Error>>
+        where F: Fn(Option<Value>) -> T {
+        write_message(&mut self.chunktps, Request::GetAt(snapshot, key.clone()).serialize())?;
+        match deserialize_reply(self.read_reply()?)? {
+            ReplyChunk::SingleValue(value) => Ok(result_handler(value)),
+            ReplyChunk::Error => Err(Box::new(ServerError::new("error getting value"))),
+            _ => Err(Box::new(ServerError::new("unexpected reply chunk kind")))
+        }
+    }
+
+    /// Same as `do_scan`, but reads through the snapshot `snapshot` opened by `do_open_snapshot`
+    pub fn do_scan_at<F, T>(&mut self, snapshot: u64, key1: &Key, key2: &Key, chunk_handler: F)
+        -> Result<Vec<T>, Box<dyn Error>>
+        where F: Fn(Vec<(Key, Value)>) -> T {
+        Ok(self.do_scan_request(Request::ScanAt(snapshot, key1.clone(), key2.clone()), chunk_handler)?.0)
+    }
+
+    /// Trying release the snapshot `snapshot` opened by `do_open_snapshot`
+    ///
+    /// Returns `Err` if TCP connection fails, Chunktp fails or server fails (e.g. the snapshot is
+    /// not open)
+    pub fn do_release_snapshot(&mut self, snapshot: u64) -> Result<(), Box<dyn Error>> {
+        self.do_simple(Request::ReleaseSnapshot(snapshot), "error releasing snapshot")
+    }
+
     /// Trying delete the `key` from storage
     ///
     /// The result handler function should accept a `usize`, rows affected by the delete operation
@@ -150,8 +325,8 @@ impl KVClient {
     /// Returns `Err` if TCP connection fails, Chunktp fails or server fails
     pub fn do_delete<F, T>(&mut self, key: &Key, result_handler: F) -> Result<T, Box<dyn Error>>
         where F: Fn(usize) -> T {
-        self.chunktps.write_chunk(Request::Del(*key).serialize())?;
-        let reply = ReplyChunk::deserialize(self.chunktps.read_chunk()?)?;
+        write_message(&mut self.chunktps, Request::Del(key.clone()).serialize())?;
+        let reply = deserialize_reply(self.read_reply()?)?;
         match reply {
             ReplyChunk::Number(number ) => {
                 Ok(result_handler(number))
@@ -163,7 +338,312 @@ impl KVClient {
         }
     }
 
+    /// Trying apply all the puts and deletes of `batch` atomically, the server applies either all
+    /// of them or none of them
+    ///
+    /// Like `do_put`, this function returns `()` silently if everything goes on well.
+    ///
+    /// Returns `Err` if TCP connection fails, Chunktp fails or server fails
+    pub fn do_batch(&mut self, batch: &WriteBatch) -> Result<(), Box<dyn Error>> {
+        write_message(&mut self.chunktps, Request::Batch(batch.clone()).serialize())?;
+        let reply = deserialize_reply(self.read_reply()?)?;
+        match reply {
+            ReplyChunk::Success => {
+                Ok(())
+            },
+            ReplyChunk::Error => {
+                Err(Box::new(ServerError::new("error applying write batch")))
+            }
+            _ => Err(Box::new(ServerError::new("unexpected reply chunk kind")))
+        }
+    }
+
+    /// Trying put `new` as the value of `key`, provided that `key` currently holds `expected`
+    /// (`None` meaning no value at all). The server compares and writes atomically, so that
+    /// concurrent clients cannot both succeed in swapping the same value
+    ///
+    /// Returns `Ok(Ok(()))` if the value is swapped, or `Ok(Err(current))` with the current value
+    /// if it is not `expected`, in which case nothing is written. Returns `Err` if TCP connection
+    /// fails, Chunktp fails or server fails
+    pub fn do_compare_and_swap(&mut self, key: &Key, expected: Option<&Value>, new: &Value)
+        -> Result<SwapReply, Box<dyn Error>> {
+        self.do_conditional(Request::CompareAndSwap(key.clone(), expected.cloned(), new.clone()))
+    }
+
+    /// Trying put the `key` - `value` pair, provided that `key` holds no value yet, replied like
+    /// `do_compare_and_swap`
+    pub fn do_put_if_absent(&mut self, key: &Key, value: &Value) -> Result<SwapReply, Box<dyn Error>> {
+        self.do_conditional(Request::PutIfAbsent(key.clone(), value.clone()))
+    }
+
+    /// Trying delete `key`, provided that it currently holds `expected`, replied like
+    /// `do_compare_and_swap`
+    pub fn do_delete_if_equals(&mut self, key: &Key, expected: &Value) -> Result<SwapReply, Box<dyn Error>> {
+        self.do_conditional(Request::DeleteIfEquals(key.clone(), expected.clone()))
+    }
+
+    /// Trying put the `key` - `value` pair, which expires after `ttl` (with a precision of
+    /// milliseconds): the key then holds no value, as if it had been deleted
+    ///
+    /// Returns `Err` if TCP connection fails, Chunktp fails or server fails (e.g. its storage
+    /// engine does not support expiry, or a transaction is going on)
+    pub fn do_put_with_ttl(&mut self, key: &Key, value: &Value, ttl: Duration) -> Result<(), Box<dyn Error>> {
+        let request = Request::PutWithTtl(key.clone(), value.clone(), ttl.as_millis() as u64);
+        self.do_simple(request, "error inserting kv pair")
+    }
+
+    /// Trying make the value of `key` expire after `ttl`, replacing any expiry time it had.
+    /// Returns the rows affected (1 if `key` holds a value, 0 otherwise)
+    ///
+    /// Returns `Err` if TCP connection fails, Chunktp fails or server fails
+    pub fn do_expire(&mut self, key: &Key, ttl: Duration) -> Result<usize, Box<dyn Error>> {
+        self.do_number(Request::Expire(key.clone(), ttl.as_millis() as u64), "error setting expiry")
+    }
+
+    /// Trying make the value of `key` never expire. Returns the rows affected (1 if `key` holds a
+    /// value that had an expiry time, 0 otherwise)
+    ///
+    /// Returns `Err` if TCP connection fails, Chunktp fails or server fails
+    pub fn do_persist(&mut self, key: &Key) -> Result<usize, Box<dyn Error>> {
+        self.do_number(Request::Persist(key.clone()), "error removing expiry")
+    }
+
+    /// Trying get the time left before the value of `key` expires
+    ///
+    /// Returns `Err` if TCP connection fails, Chunktp fails or server fails
+    pub fn do_ttl(&mut self, key: &Key) -> Result<Ttl, Box<dyn Error>> {
+        write_message(&mut self.chunktps, Request::Ttl(key.clone()).serialize())?;
+        match deserialize_reply(self.read_reply()?)? {
+            ReplyChunk::Ttl(ttl) => Ok(ttl),
+            ReplyChunk::Error => Err(Box::new(ServerError::new("error getting ttl"))),
+            _ => Err(Box::new(ServerError::new("unexpected reply chunk kind")))
+        }
+    }
+
+    /// Runs `body` in a transaction, which is committed once `body` returns `Ok`. Gets, puts and
+    /// deletes done through the `TransactionHandle` given to `body` are part of the transaction:
+    /// puts and deletes are only written on commit, and the commit fails if any key the
+    /// transaction has read has changed meanwhile. In that case, `body` is run again in a new
+    /// transaction, up to `max_attempts` times in total.
+    ///
+    /// An example body, which increases a counter:
+    /// ```no_run
+    /// use std::error::Error;
+    /// use kvsys::kvstorage::{Key, Value};
+    /// use kvsys::kvclient::TransactionHandle;
+    /// fn increase(txn: &mut TransactionHandle) -> Result<u8, Box<dyn Error>> {
+    ///     let key = Key::from_bytes(b"counter");
+    ///     let count = txn.get(&key)?.map_or(0, |value| value.data[0]) + 1;
+    ///     txn.put(&key, &Value::from_bytes(&[count]))?;
+    ///     Ok(count)
+    /// }
+    /// ```
+    ///
+    /// If `body` returns `Err`, the transaction is aborted and the error returned as is. Returns
+    /// `Err` if TCP connection fails, Chunktp fails, server fails or the transaction still
+    /// conflicts after `max_attempts` attempts
+    pub fn do_transaction<F, T>(&mut self, max_attempts: usize, mut body: F) -> Result<T, Box<dyn Error>>
+        where F: FnMut(&mut TransactionHandle<S>) -> Result<T, Box<dyn Error>> {
+        for _ in 0..max_attempts {
+            self.do_simple(Request::Begin, "error beginning transaction")?;
+            let ret = match body(&mut TransactionHandle { client: self }) {
+                Ok(ret) => ret,
+                Err(e) => {
+                    let _ = self.do_simple(Request::Abort, "error aborting transaction");
+                    return Err(e);
+                }
+            };
+            write_message(&mut self.chunktps, Request::Commit.serialize())?;
+            match deserialize_reply(self.read_reply()?)? {
+                ReplyChunk::Success => return Ok(ret),
+                ReplyChunk::Conflict => continue,
+                ReplyChunk::Error => return Err(Box::new(ServerError::new("error committing transaction"))),
+                _ => return Err(Box::new(ServerError::new("unexpected reply chunk kind")))
+            }
+        }
+        Err(Box::new(ServerError::new(&format!("transaction still conflicts after {} attempts", max_attempts))))
+    }
+
+    /// Trying send all the `requests` at once, and then receive all their replies, returned in the
+    /// order of the requests. This saves waiting for each reply before sending the next request
+    /// (and for each chunk to be acknowledged), which is most of the time taken by bulk loads
+    ///
+    /// The server handles the requests concurrently, in no particular order: a request depending
+    /// on the outcome of another one should be sent once the other one has been replied. Requests
+    /// cannot be part of a transaction, and transactions (and `Close`) cannot be pipelined, these
+    /// are replied with `Error`. Replies are kept in memory until all of them have been received,
+    /// very large loads should rather be sent in several pipelines.
+    ///
+    /// ```no_run
+    /// use std::net::TcpStream;
+    /// use kvsys::kvclient::KVClient;
+    /// use kvsys::kvstorage::{Key, Value};
+    /// use kvsys::kvserver::protocol::{Request, ReplyChunk};
+    /// // ...
+    /// let mut client = KVClient::new(TcpStream::connect("127.0.0.1:1926").unwrap());
+    /// let requests = (0..1000u64).map(|i| Request::Put(Key::decode(i), Value::from_bytes(b"v"))).collect();
+    /// let replies = client.do_pipeline(requests).unwrap();
+    /// assert!(replies.iter().all(|reply| matches!(reply[..], [ReplyChunk::Success])));
+    /// ```
+    ///
+    /// Returns `Err` if TCP connection fails, Chunktp fails or server fails, failures of single
+    /// requests are `Error` replies instead
+    pub fn do_pipeline(&mut self, requests: Vec<Request>) -> Result<Vec<PipelineReply>, Box<dyn Error>> {
+        if requests.len() > u32::MAX as usize {
+            return Err(Box::new(ServerError::new("too many requests for a pipeline")));
+        }
+        self.do_simple(Request::Pipeline, "error starting pipeline")?;
+        self.chunktps.set_acknowledged(false);
+        let result = self.exchange_pipeline(requests);
+        self.chunktps.set_acknowledged(true);
+        result
+    }
+
+    /// Sends the `requests` of a pipeline tagged with their index from another thread, and receives
+    /// their replies meanwhile: the server only reads so many requests ahead of its replies
+    fn exchange_pipeline(&mut self, requests: Vec<Request>) -> Result<Vec<PipelineReply>, Box<dyn Error>> {
+        let count = requests.len();
+        let mut sender = self.chunktps.try_clone()?;
+        thread::scope(|scope| {
+            let sending = scope.spawn(move || -> Result<(), String> {
+                for (id, request) in requests.into_iter().enumerate() {
+                    write_message(&mut sender, Request::Pipelined(id as u32, Box::new(request)).serialize())
+                        .map_err(|e| e.to_string())?;
+                }
+                sender.write_chunk(vec![]).map_err(|e| e.to_string())
+            });
+            let received = self.receive_pipeline(count);
+            if let Err(e) = sending.join().unwrap() {
+                return Err(Box::new(ServerError::new(&format!("sending pipeline failed: {}", e))) as Box<dyn Error>);
+            }
+            received
+        })
+    }
+
+    /// Receives the replies of the `count` requests of a pipeline, until its end
+    fn receive_pipeline(&mut self, count: usize) -> Result<Vec<PipelineReply>, Box<dyn Error>> {
+        let mut ret = (0..count).map(|_| Vec::new()).collect::<Vec<_>>();
+        loop {
+            let chunk = read_message(&mut self.chunktps)?;
+            if chunk.is_empty() {
+                return Ok(ret)
+            }
+            match deserialize_reply(chunk)? {
+                ReplyChunk::Pipelined(id, chunk) => {
+                    let reply = ret.get_mut(id as usize)
+                        .ok_or_else(|| ServerError::new("reply to an unknown pipelined request"))?;
+                    reply.extend(chunk.map(|chunk| *chunk));
+                },
+                _ => return Err(Box::new(ServerError::new("unexpected reply chunk kind")))
+            }
+        }
+    }
+
+    /// Sends a scan `request`, returns what `chunk_handler` gives for each chunk of pairs, together
+    /// with the continuation token if any
+    fn do_scan_request<F, T>(&mut self, request: Request, chunk_handler: F) -> Result<(Vec<T>, Option<Key>), Box<dyn Error>>
+        where F: Fn(Vec<(Key, Value)>) -> T {
+        write_message(&mut self.chunktps, request.serialize())?;
+        let mut ret = Vec::new();
+        let mut continuation = None;
+        loop {
+            let chunk = read_message(&mut self.chunktps)?;
+            if chunk.is_empty() {
+                return Ok((ret, continuation))
+            }
+            let reply = deserialize_reply(chunk)?;
+            match reply {
+                ReplyChunk::KVPairs(kv_pairs) => {
+                    ret.push(chunk_handler(kv_pairs));
+                },
+                ReplyChunk::Continuation(token) => {
+                    continuation = Some(token);
+                },
+                ReplyChunk::Error => return Err(Box::new(ServerError::new("error scanning kv pairs"))),
+                _ => return Err(Box::new(ServerError::new("unexpected reply chunk kind")))
+            }
+        }
+    }
+
+    /// Sends `request` which is replied with `Success` or `Error`, the latter turned into an
+    /// `Err` described by `error`
+    fn do_simple(&mut self, request: Request, error: &str) -> Result<(), Box<dyn Error>> {
+        write_message(&mut self.chunktps, request.serialize())?;
+        match deserialize_reply(self.read_reply()?)? {
+            ReplyChunk::Success => Ok(()),
+            ReplyChunk::Error => Err(Box::new(ServerError::new(error))),
+            _ => Err(Box::new(ServerError::new("unexpected reply chunk kind")))
+        }
+    }
+
+    /// Same as `do_simple`, for a `request` replied with a `Number`
+    fn do_number(&mut self, request: Request, error: &str) -> Result<usize, Box<dyn Error>> {
+        write_message(&mut self.chunktps, request.serialize())?;
+        match deserialize_reply(self.read_reply()?)? {
+            ReplyChunk::Number(number) => Ok(number),
+            ReplyChunk::Error => Err(Box::new(ServerError::new(error))),
+            _ => Err(Box::new(ServerError::new("unexpected reply chunk kind")))
+        }
+    }
+
+    fn do_conditional(&mut self, request: Request) -> Result<SwapReply, Box<dyn Error>> {
+        write_message(&mut self.chunktps, request.serialize())?;
+        match deserialize_reply(self.read_reply()?)? {
+            ReplyChunk::Success => Ok(Ok(())),
+            ReplyChunk::Mismatch(current) => Ok(Err(current)),
+            ReplyChunk::Error => Err(Box::new(ServerError::new("error swapping value"))),
+            _ => Err(Box::new(ServerError::new("unexpected reply chunk kind")))
+        }
+    }
+
+    fn read_reply(&mut self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let reply = read_message(&mut self.chunktps)?;
+        if reply.is_empty() {
+            return Err(Box::new(ServerError::new("unexpected empty reply")));
+        }
+        Ok(reply)
+    }
+
     pub fn do_close(&mut self) {
         let _ = self.chunktps.write_chunk(Request::Close.serialize());
     }
 }
+
+/// Deserializes a reply chunk, turning `Denied` into an `Err`: the user the client has authenticated
+/// as (if any) is not allowed the request
+fn deserialize_reply(raw: Vec<u8>) -> Result<ReplyChunk, Box<dyn Error>> {
+    match ReplyChunk::deserialize(raw)? {
+        ReplyChunk::Denied => Err(Box::new(ServerError::new("access denied"))),
+        reply => Ok(reply)
+    }
+}
+
+impl<S: Transport> TransactionHandle<'_, S> {
+    /// Gets the value of `key` as seen by the transaction, see `KVClient::do_get`
+    pub fn get(&mut self, key: &Key) -> Result<Option<Value>, Box<dyn Error>> {
+        self.client.do_get(key, |value| value)
+    }
+
+    /// Puts the `key` - `value` pair on commit, see `KVClient::do_put`
+    pub fn put(&mut self, key: &Key, value: &Value) -> Result<(), Box<dyn Error>> {
+        self.client.do_put(key, value)
+    }
+
+    /// Deletes `key` on commit, returns the rows affected as seen by the transaction, see
+    /// `KVClient::do_delete`
+    pub fn delete(&mut self, key: &Key) -> Result<usize, Box<dyn Error>> {
+        self.client.do_delete(key, |rows_affected| rows_affected)
+    }
+
+    /// Swaps the value of `key` on commit if it is `expected` as seen by the transaction, see
+    /// `KVClient::do_compare_and_swap`
+    pub fn compare_and_swap(&mut self, key: &Key, expected: Option<&Value>, new: &Value)
+        -> Result<SwapReply, Box<dyn Error>> {
+        self.client.do_compare_and_swap(key, expected, new)
+    }
+
+    /// Applies all the puts and deletes of `batch` on commit, see `KVClient::do_batch`
+    pub fn batch(&mut self, batch: &WriteBatch) -> Result<(), Box<dyn Error>> {
+        self.client.do_batch(batch)
+    }
+}
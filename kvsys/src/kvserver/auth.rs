@@ -0,0 +1,435 @@
+//! Authentication of clients, and access control of their requests
+//!
+//! A server given a users file (see `KVServerConfig::users_file`) only handles the requests of
+//! clients that have authenticated first (see `Request::Authenticate`), and only the ones the user
+//! they have authenticated as is allowed. The users file lists the users one after another, each
+//! of them starting with its name, followed by its credentials and what it is allowed:
+//! ```text
+//! # lines starting with '#' are comments
+//! user alice
+//! password pbkdf2-sha256 100000 6a1f0c93b8d2e457a0c4f1e2d3b5a697 d68cf52eb2a5db6984015160f40ceb8e6b58a2d7283751a3d97a13240c2eccfe
+//! allow read-write prefix app/
+//! allow read-only all
+//!
+//! user backup
+//! token 4c5dc9b7708905f77f5e5d16316b5dfb425e68cb326dcd55a860e90a7707031e
+//! allow read-only range 0x00 0x7f
+//! ```
+//! Passwords and tokens are never given in clear. A password is given as its PBKDF2-HMAC-SHA256
+//! digest: the count of iterations, then the salt and the 32 bytes digest in hex, e.g.
+//! ```text
+//! python3 -c 'import hashlib, os; s = os.urandom(16); print("pbkdf2-sha256 100000", s.hex(),
+//!     hashlib.pbkdf2_hmac("sha256", b"secret", s, 100000).hex())'
+//! ```
+//! A token is given as its SHA-256 digest in hex (e.g. `printf %s token | sha256sum`), which is
+//! enough for tokens drawn at random, and lets the token alone tell the user it belongs to. A user
+//! may have a password, a token, or both. Each of its `allow`
+//! lines grants either reads (`read-only`) or reads and writes (`read-write`) of `all` the keys, of
+//! the keys starting with a `prefix`, or of the keys of a `range` from a key (included) to another
+//! one (excluded). Keys are taken as written, or as hex if they start with `0x`.
+//!
+//! A request is allowed if a single grant covers all the keys it may read or write. Requests that
+//! touch no key (transactions, snapshots, pipelines) are always allowed, the requests within them
+//! are checked one by one.
+
+use crate::kvstorage::{Key, BatchOp};
+use crate::kvstorage::scan::{ScanOptions, prefix_range};
+use crate::kvserver::protocol::Request;
+
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::error::Error;
+use std::fs;
+use std::ops::Bound;
+use std::ops::Bound::{Included, Excluded, Unbounded};
+use std::path::Path;
+use std::sync::Arc;
+
+use std::num::NonZeroU32;
+
+use ring::digest::{digest, SHA256, SHA256_OUTPUT_LEN};
+use ring::pbkdf2;
+
+/// PBKDF2 iterations of the password checked against when there is no password to check, unless
+/// users have passwords of their own
+const DUMMY_ITERATIONS: NonZeroU32 = NonZeroU32::new(100_000).unwrap();
+
+/// The error type used by auth module
+#[derive(Debug)]
+pub struct AuthError {
+    description: String
+}
+
+impl AuthError {
+    pub fn new(description: &str) -> Self {
+        AuthError { description: description.to_owned() }
+    }
+}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(f, "auth error: {}", self.description)
+    }
+}
+
+impl Error for AuthError {
+}
+
+/// What a client authenticates with, see `Request::Authenticate`
+#[derive(Clone, PartialEq, Eq)]
+pub enum Credentials {
+    /// Name of a user, and its password
+    Password(String, String),
+    /// A token, which tells the user it belongs to by itself
+    Token(String)
+}
+
+/// What a grant allows on its keys, `ReadWrite` allowing everything `ReadOnly` does
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Access {
+    ReadOnly,
+    ReadWrite
+}
+
+/// Access to the keys within bounds, as given by an `allow` line
+struct Grant {
+    access: Access,
+    start: Bound<Key>,
+    end: Bound<Key>
+}
+
+/// A salted digest of a password, as given by a `password` line
+struct PasswordDigest {
+    iterations: NonZeroU32,
+    salt: Vec<u8>,
+    digest: [u8; SHA256_OUTPUT_LEN]
+}
+
+/// A user of the users file, see the module documentation
+pub struct User {
+    name: String,
+    password: Option<PasswordDigest>,
+    token: Option<[u8; SHA256_OUTPUT_LEN]>,
+    grants: Vec<Grant>
+}
+
+/// The users a server authenticates clients as, loaded from a users file
+pub struct Users {
+    users: Vec<Arc<User>>,
+    // checked against when there is no password to check, so that failing takes as long either way
+    dummy_password: PasswordDigest
+}
+
+impl Users {
+    /// Loads the users file at `path`, see the module documentation for its format
+    ///
+    /// Returns `Err` if the file cannot be read or is ill-formed
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        Ok(Users::parse(&fs::read_to_string(path)?)?)
+    }
+
+    /// Parses the `content` of a users file
+    pub fn parse(content: &str) -> Result<Self, AuthError> {
+        let mut users: Vec<User> = Vec::new();
+        for (number, line) in content.lines().enumerate() {
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            if words.is_empty() || words[0].starts_with('#') {
+                continue;
+            }
+            let at_line = |description: &str| AuthError::new(&format!("line {}: {}", number + 1, description));
+            if words[0] == "user" {
+                if words.len() != 2 {
+                    return Err(at_line("`user` requires exactly 1 argument"));
+                }
+                if users.iter().any(|user| user.name == words[1]) {
+                    return Err(at_line("user defined twice"));
+                }
+                users.push(User { name: words[1].to_owned(), password: None, token: None, grants: Vec::new() });
+                continue;
+            }
+            let user = users.last_mut().ok_or_else(|| at_line("no `user` given before"))?;
+            match words[0] {
+                "password" => {
+                    let password = parse_password(&words[1..]).map_err(|e| at_line(&e.description))?;
+                    user.password = Some(password);
+                },
+                "token" => {
+                    let hash = match words[1..] {
+                        [hash] => parse_digest(hash).ok_or_else(|| at_line("incorrect SHA-256 digest"))?,
+                        _ => return Err(at_line("`token` requires exactly 1 argument"))
+                    };
+                    user.token = Some(hash);
+                },
+                "allow" => {
+                    let grant = parse_grant(&words[1..]).map_err(|e| at_line(&e.description))?;
+                    user.grants.push(grant);
+                },
+                _ => return Err(at_line("unknown directive"))
+            }
+        }
+        let mut digests = users.iter().filter_map(|user| user.token).collect::<Vec<_>>();
+        digests.sort_unstable();
+        if digests.windows(2).any(|pair| pair[0] == pair[1]) {
+            return Err(AuthError::new("the same token is given to several users"));
+        }
+        let iterations = users.iter().filter_map(|user| user.password.as_ref()).map(|digest| digest.iterations).max();
+        let dummy_password = PasswordDigest {
+            iterations: iterations.unwrap_or(DUMMY_ITERATIONS),
+            salt: vec![0u8; 16],
+            digest: [0u8; SHA256_OUTPUT_LEN]
+        };
+        Ok(Users { users: users.into_iter().map(Arc::new).collect(), dummy_password })
+    }
+
+    /// The user the `credentials` belong to, `None` if they do not belong to any
+    pub fn authenticate(&self, credentials: &Credentials) -> Option<Arc<User>> {
+        let found = match credentials {
+            Credentials::Password(name, password) => {
+                // unknown users cost the same as wrong passwords, which tells nothing about who exists
+                let user = self.users.iter().find(|user| &user.name == name);
+                match user.and_then(|user| user.password.as_ref()) {
+                    Some(digest) if digest.verify(password) => user,
+                    Some(_) => None,
+                    None => {
+                        self.dummy_password.verify(password);
+                        None
+                    }
+                }
+            },
+            Credentials::Token(token) => {
+                let hash = sha256(token);
+                self.users.iter().find(|user| user.token.is_some_and(|digest| digests_equal(&digest, &hash)))
+            }
+        };
+        found.cloned()
+    }
+}
+
+impl User {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether the user is allowed to make `request`, see the module documentation
+    pub fn allows(&self, request: &Request) -> bool {
+        use Access::{ReadOnly, ReadWrite};
+        match request {
+            Request::Get(key) | Request::GetAt(_, key) | Request::Ttl(key) => self.allows_key(ReadOnly, key),
+            Request::Scan(key1, key2, options) => self.allows_range(ReadOnly, options.range(key1, key2)),
+            Request::ScanAt(_, key1, key2) | Request::ScanKeys(key1, key2) | Request::Count(key1, key2) => {
+                self.allows_range(ReadOnly, ScanOptions::from_default().range(key1, key2))
+            },
+            Request::ScanPrefix(prefix) => {
+                let (start, end) = prefix_range(prefix);
+                self.allows_range(ReadOnly, Some((start.as_ref(), end.as_ref())))
+            },
+            Request::Put(key, _) | Request::Del(key) | Request::CompareAndSwap(key, ..) | Request::PutIfAbsent(key, _) |
+            Request::DeleteIfEquals(key, _) | Request::PutWithTtl(key, ..) | Request::Expire(key, _) |
+            Request::Persist(key) => self.allows_key(ReadWrite, key),
+            Request::Batch(batch) => batch.ops().iter().all(|op: &BatchOp| self.allows_key(ReadWrite, op.key())),
+            Request::Pipelined(_, request) => self.allows(request),
+            Request::Begin | Request::Commit | Request::Abort | Request::OpenSnapshot | Request::ReleaseSnapshot(_) |
+            Request::Pipeline | Request::Authenticate(_) | Request::Close => true
+        }
+    }
+
+    fn allows_key(&self, access: Access, key: &Key) -> bool {
+        self.allows_range(access, Some((Included(key), Included(key))))
+    }
+
+    /// Whether a single grant gives `access` to all the keys of `range`, `None` for no key at all
+    fn allows_range(&self, access: Access, range: Option<(Bound<&Key>, Bound<&Key>)>) -> bool {
+        let (start, end) = match range {
+            Some(range) => range,
+            None => return true
+        };
+        self.grants.iter().any(|grant| {
+            grant.access >= access && starts_within(&grant.start, start) && ends_within(&grant.end, end)
+        })
+    }
+}
+
+impl PasswordDigest {
+    /// Whether `password` has this digest, taking the same time wherever they differ
+    fn verify(&self, password: &str) -> bool {
+        pbkdf2::verify(pbkdf2::PBKDF2_HMAC_SHA256, self.iterations, &self.salt, password.as_bytes(), &self.digest).is_ok()
+    }
+}
+
+/// Whether a range starting at `start` starts within a range starting at `scope`
+fn starts_within(scope: &Bound<Key>, start: Bound<&Key>) -> bool {
+    match (scope, start) {
+        (Unbounded, _) => true,
+        (_, Unbounded) => false,
+        (Excluded(first), Included(key)) => first < key,
+        (Included(first), Included(key)) | (Included(first), Excluded(key)) | (Excluded(first), Excluded(key)) => {
+            first <= key
+        }
+    }
+}
+
+/// Whether a range ending at `end` ends within a range ending at `scope`
+fn ends_within(scope: &Bound<Key>, end: Bound<&Key>) -> bool {
+    match (scope, end) {
+        (Unbounded, _) => true,
+        (_, Unbounded) => false,
+        (Excluded(last), Included(key)) => last > key,
+        (Included(last), Included(key)) | (Included(last), Excluded(key)) | (Excluded(last), Excluded(key)) => {
+            last >= key
+        }
+    }
+}
+
+/// Parses the arguments of an `allow` line
+fn parse_grant(words: &[&str]) -> Result<Grant, AuthError> {
+    let access = match words.first() {
+        Some(&"read-only") => Access::ReadOnly,
+        Some(&"read-write") => Access::ReadWrite,
+        _ => return Err(AuthError::new("`allow` requires `read-only` or `read-write` first"))
+    };
+    let (start, end) = match words[1..] {
+        ["all"] => (Unbounded, Unbounded),
+        ["prefix", prefix] => prefix_range(&parse_key(prefix)?.data),
+        ["range", key1, key2] => (Included(parse_key(key1)?), Excluded(parse_key(key2)?)),
+        _ => return Err(AuthError::new("`allow` requires `all`, `prefix <prefix>` or `range <key1> <key2>`"))
+    };
+    Ok(Grant { access, start, end })
+}
+
+/// Parses the arguments of a `password` line
+fn parse_password(words: &[&str]) -> Result<PasswordDigest, AuthError> {
+    let (iterations, salt, digest) = match words {
+        ["pbkdf2-sha256", iterations, salt, digest] => (iterations, salt, digest),
+        _ => return Err(AuthError::new("`password` requires `pbkdf2-sha256 <iterations> <salt> <digest>`"))
+    };
+    let iterations = iterations.parse::<NonZeroU32>().map_err(|_| AuthError::new("incorrect count of iterations"))?;
+    let salt = parse_hex(salt).ok_or_else(|| AuthError::new("incorrect hex salt"))?;
+    let digest = parse_digest(digest).ok_or_else(|| AuthError::new("incorrect PBKDF2-HMAC-SHA256 digest"))?;
+    Ok(PasswordDigest { iterations, salt, digest })
+}
+
+/// Parses a key of the users file, as written or as hex if it starts with `0x`
+fn parse_key(word: &str) -> Result<Key, AuthError> {
+    match word.strip_prefix("0x") {
+        Some(hex) => parse_hex(hex).map(|data| Key::from_bytes(&data)).ok_or_else(|| AuthError::new("incorrect hex key")),
+        None => Ok(Key::from_bytes(word.as_bytes()))
+    }
+}
+
+fn parse_digest(word: &str) -> Option<[u8; SHA256_OUTPUT_LEN]> {
+    let data = parse_hex(word)?;
+    let mut ret = [0u8; SHA256_OUTPUT_LEN];
+    if data.len() != ret.len() {
+        return None;
+    }
+    ret.copy_from_slice(&data);
+    Some(ret)
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i+2], 16).ok()).collect()
+}
+
+/// Compares digests in a time that does not depend on where they differ
+fn digests_equal(a: &[u8; SHA256_OUTPUT_LEN], b: &[u8; SHA256_OUTPUT_LEN]) -> bool {
+    a.iter().zip(b.iter()).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn sha256(secret: &str) -> [u8; SHA256_OUTPUT_LEN] {
+    let mut ret = [0u8; SHA256_OUTPUT_LEN];
+    ret.copy_from_slice(digest(&SHA256, secret.as_bytes()).as_ref());
+    ret
+}
+
+#[cfg(test)]
+mod test {
+    use crate::kvserver::auth::{Users, Credentials};
+    use crate::kvserver::protocol::Request;
+    use crate::kvstorage::{Key, Value, WriteBatch};
+    use crate::kvstorage::scan::ScanOptions;
+
+    // password digests of "secret" and "letmein", and token digest of "letmein"
+    const SECRET: &str = "pbkdf2-sha256 1000 73616c7473616c74 86047d1ecaad2aea56c699eff32f7d4eb3c36a34d3ffd3dc49394d69fa5d2d74";
+    const LETMEIN: &str = "pbkdf2-sha256 1000 0123456789abcdef 57b917423312e6d355c1f1b85237d9a1e440886fc09e9201743d00a4da510577";
+    const LETMEIN_TOKEN: &str = "1c8bfe8f801d79745c4631d09fff36c82aa37fc4cce4fc946683d7b336b63032";
+
+    fn key(data: &str) -> Key {
+        Key::from_bytes(data.as_bytes())
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let content = format!("# users\nuser alice\npassword {}\n\nuser bob\ntoken {}\n\nuser carol\npassword {}\n",
+                              SECRET, LETMEIN_TOKEN, LETMEIN);
+        let users = Users::parse(&content).unwrap();
+        let alice = Credentials::Password("alice".to_owned(), "secret".to_owned());
+        assert_eq!(users.authenticate(&alice).unwrap().name(), "alice");
+        assert!(users.authenticate(&Credentials::Password("alice".to_owned(), "letmein".to_owned())).is_none());
+        assert!(users.authenticate(&Credentials::Password("bob".to_owned(), "letmein".to_owned())).is_none());
+        assert_eq!(users.authenticate(&Credentials::Token("letmein".to_owned())).unwrap().name(), "bob");
+        assert!(users.authenticate(&Credentials::Token("secret".to_owned())).is_none());
+        let carol = Credentials::Password("carol".to_owned(), "letmein".to_owned());
+        assert_eq!(users.authenticate(&carol).unwrap().name(), "carol");
+        assert!(users.authenticate(&Credentials::Password("carol".to_owned(), "secret".to_owned())).is_none());
+
+        for content in &["password 00\n", "user alice\npassword 00\n", "user alice\ntoken 00\n",
+                         "user alice\npassword pbkdf2-sha256 0 00 00\n",
+                         &format!("user alice\npassword {}\n", LETMEIN_TOKEN), "user alice\nallow read-only\n",
+                         "user alice\nallow everything all\n", "user alice\nallow read-only prefix 0xz\n",
+                         "user alice\nuser alice\n", "user alice\nfoo\n"] {
+            assert!(Users::parse(content).is_err());
+        }
+        let twice = format!("user alice\ntoken {}\nuser bob\ntoken {}\n", LETMEIN_TOKEN, LETMEIN_TOKEN);
+        assert!(Users::parse(&twice).is_err());
+    }
+
+    #[test]
+    fn test_allows() {
+        let content = format!("user alice\npassword {}\nallow read-write prefix app/\nallow read-only range b d\n\
+                               allow read-only range 0x00ff 0x01\nuser bob\npassword {}\n", SECRET, SECRET);
+        let users = Users::parse(&content).unwrap();
+        let alice = users.authenticate(&Credentials::Password("alice".to_owned(), "secret".to_owned())).unwrap();
+        let value = Value::from_bytes(b"value");
+
+        assert!(alice.allows(&Request::Put(key("app/x"), value.clone())));
+        assert!(alice.allows(&Request::Get(key("app/x"))));
+        assert!(!alice.allows(&Request::Put(key("apq"), value.clone())));
+        assert!(alice.allows(&Request::Get(key("b"))));
+        assert!(alice.allows(&Request::Get(Key::from_bytes(&[0, 0xff, 7]))));
+        assert!(!alice.allows(&Request::Put(key("b"), value.clone())));
+        assert!(!alice.allows(&Request::Del(key("c"))));
+        assert!(!alice.allows(&Request::Get(key("d"))));
+
+        assert!(alice.allows(&Request::Scan(key("b"), key("d"), ScanOptions::from_default())));
+        assert!(alice.allows(&Request::Count(key("b"), key("c"))));
+        assert!(!alice.allows(&Request::ScanKeys(key("a"), key("c"))));
+        let mut options = ScanOptions::from_default();
+        options.include_end = true;
+        assert!(!alice.allows(&Request::Scan(key("b"), key("d"), options.clone())));
+        // the continuation token is where the scan resumes, it narrows the range
+        options.include_start = false;
+        options.continuation = Some(key("b"));
+        assert!(alice.allows(&Request::Scan(key("a"), key("c"), options)));
+        // whatever the bounds, an empty range reads nothing
+        assert!(alice.allows(&Request::Scan(key("z"), key("a"), ScanOptions::from_default())));
+        assert!(alice.allows(&Request::ScanPrefix(b"app/".to_vec())));
+        assert!(alice.allows(&Request::ScanPrefix(b"app/x".to_vec())));
+        assert!(!alice.allows(&Request::ScanPrefix(b"app".to_vec())));
+
+        let mut batch = WriteBatch::new();
+        batch.put(key("app/1"), value.clone()).delete(key("app/2"));
+        assert!(alice.allows(&Request::Batch(batch.clone())));
+        batch.delete(key("b"));
+        assert!(!alice.allows(&Request::Batch(batch)));
+        assert!(!alice.allows(&Request::Pipelined(0, Box::new(Request::Del(key("b"))))));
+        assert!(alice.allows(&Request::Begin));
+
+        // users without grants do not read anything
+        let bob = users.authenticate(&Credentials::Password("bob".to_owned(), "secret".to_owned())).unwrap();
+        assert!(!bob.allows(&Request::Get(key("app/x"))));
+        assert!(bob.allows(&Request::OpenSnapshot));
+    }
+}
@@ -5,16 +5,57 @@
 
 use clap::{ArgMatches, value_t};
 use log::info;
+use std::error::Error;
+use std::time::Duration;
+
+use crate::chunktps::tls::{TlsAcceptor, TlsError};
+use crate::kvserver::auth::Users;
+use crate::kvstorage::{StorageOptions, RecoveryMode, SyncPolicy, DataLayout, DEFAULT_COMPACTION_RATIO};
+use crate::kvstorage::options::{DEFAULT_SYNC_INTERVAL, DEFAULT_MAX_KEY_SIZE, DEFAULT_MAX_VALUE_SIZE};
+use crate::kvstorage::engine::EngineKind;
+use crate::kvstorage::lsm::LsmOptions;
 
 const DEFAULT_FILENAME: &str = "data.kv";
 const DEFAULT_LISTEN_PORT: u16 = 1926;
 const DEFAULT_THREADS: u16 = 4;
+const DEFAULT_SNAPSHOT_INTERVAL_SECS: u64 = 300;
+const DEFAULT_REAP_INTERVAL_SECS: u64 = 1;
 
 /// Configuration info needed for running a KV server, see its field for futher information
 pub struct KVServerConfig {
     pub db_file: String,
     pub listen_port: u16,
-    pub threads: u16
+    /// path of a Unix domain socket to listen to instead of `listen_port`, `None` for TCP
+    pub unix_socket: Option<String>,
+    /// PEM files of the certificate chain and the private key of the server, which then only
+    /// speaks TLS. Both or none must be given, `None` for plaintext
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    /// PEM bundle of the CAs which clients must show a certificate signed by (mutual TLS), `None`
+    /// for not asking clients for certificates
+    pub tls_client_ca: Option<String>,
+    /// users file clients must authenticate against, which also tells what they are allowed (see
+    /// the `auth` module), `None` for letting any client do anything
+    pub users_file: Option<String>,
+    pub threads: u16,
+    /// dead records / live keys ratio that triggers log compaction, `None` for never
+    pub compaction_ratio: Option<f64>,
+    /// refuses to start if the log ends with an incomplete record, instead of truncating it
+    pub strict_recovery: bool,
+    /// when the log gets synced to the disk, replies to writes are sent after the policy is met
+    pub sync_policy: SyncPolicy,
+    /// how often a snapshot is taken (and the log cut short), `None` for never
+    pub snapshot_interval: Option<Duration>,
+    /// how often expired keys are deleted, `None` for never (they stay invisible, but take room)
+    pub reap_interval: Option<Duration>,
+    /// sizes of keys and values accepted by the server
+    pub layout: DataLayout,
+    /// bytes of values the log engine holds in memory, `None` for all of them, see
+    /// `StorageOptions::memory_budget`
+    pub memory_budget: Option<usize>,
+    /// the storage engine to serve. `EngineKind::Log` uses `db_file` and the options above,
+    /// `EngineKind::Lsm` uses `db_file` as its directory, and all but compaction and snapshot options
+    pub engine: EngineKind
 }
 
 impl KVServerConfig {
@@ -23,14 +64,38 @@ impl KVServerConfig {
         KVServerConfig {
             db_file: DEFAULT_FILENAME.to_owned(),
             listen_port: DEFAULT_LISTEN_PORT,
-            threads: DEFAULT_THREADS }
+            unix_socket: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            users_file: None,
+            threads: DEFAULT_THREADS,
+            compaction_ratio: Some(DEFAULT_COMPACTION_RATIO),
+            strict_recovery: false,
+            sync_policy: SyncPolicy::Every(DEFAULT_SYNC_INTERVAL),
+            snapshot_interval: Some(Duration::from_secs(DEFAULT_SNAPSHOT_INTERVAL_SECS)),
+            reap_interval: Some(Duration::from_secs(DEFAULT_REAP_INTERVAL_SECS)),
+            layout: DataLayout::Fixed,
+            memory_budget: None,
+            engine: EngineKind::Log }
     }
 
     /// Creates a `KVServerConfig` from command line arguments (`clap::ArgMatches`).
     ///
-    /// This function requires three formal parameters from commandline: `dbfile` of type `String`
-    /// for database file name, `port` of type `u16` for listening port and `threads` of type `u16`
-    /// for thread pool size. If there are some formal parameters missing from the command line
+    /// This function requires four formal parameters from commandline: `dbfile` of type `String`
+    /// for database file name, `port` of type `u16` for listening port, `unix_socket` of type `String`
+    /// for the path of a Unix domain socket to listen to instead, `tls_cert`, `tls_key` and
+    /// `tls_client_ca` of type `String` for the PEM files TLS is set up with, `users` of type `String`
+    /// for the users file clients authenticate against, `threads` of type `u16` for thread pool size
+    /// and `compaction` of type `f64` for log compaction ratio (a ratio of zero or below disables
+    /// automatic compaction). The `strict` flag enables strict recovery, and
+    /// `sync` of type `SyncPolicy` (`always`, `never` or an interval like `100ms`) sets the
+    /// durability of the log, `snapshot` of type `u64` for seconds between snapshots (zero disables
+    /// snapshots), `reap` of type `u64` for seconds between deletes of expired keys (zero disables
+    /// them). The `variable` flag accepts keys and values of any size, up to `max_value` of
+    /// type `usize` bytes for values. `memory` of type `usize` sets the memory budget in bytes
+    /// (zero or missing for unlimited). `engine` of type `EngineKind` (`log`, `memory` or `lsm`) chooses
+    /// the storage engine. If there are some formal parameters missing from the command line
     /// argument, or the arguments provided from command line does not satisfy the type
     /// requirements, this function will generate some `Info` level log, and use default values to
     /// fill in these parameters.
@@ -43,10 +108,102 @@ impl KVServerConfig {
                 info!("no valid listen port provided from commandline, using default port {}", DEFAULT_LISTEN_PORT);
                 DEFAULT_LISTEN_PORT
             });
+        let unix_socket = matches.value_of("unix_socket").map(|path| path.to_owned());
+        let tls_cert = matches.value_of("tls_cert").map(|path| path.to_owned());
+        let tls_key = matches.value_of("tls_key").map(|path| path.to_owned());
+        let tls_client_ca = matches.value_of("tls_client_ca").map(|path| path.to_owned());
+        let users_file = matches.value_of("users").map(|path| path.to_owned());
         let threads = value_t!(matches, "threads", u16).unwrap_or_else(|_| {
                 info!("no valid thread pool size provided from commandline, using default size {}", DEFAULT_THREADS);
                 DEFAULT_THREADS
             });
-        KVServerConfig { db_file, listen_port, threads }
+        let compaction_ratio = value_t!(matches, "compaction", f64).unwrap_or_else(|_| {
+                info!("no valid compaction ratio provided from commandline, using default ratio {}", DEFAULT_COMPACTION_RATIO);
+                DEFAULT_COMPACTION_RATIO
+            });
+        let compaction_ratio = if compaction_ratio > 0.0 { Some(compaction_ratio) } else { None };
+        let strict_recovery = matches.is_present("strict");
+        let sync_policy = value_t!(matches, "sync", SyncPolicy).unwrap_or_else(|_| {
+                let default = SyncPolicy::Every(DEFAULT_SYNC_INTERVAL);
+                info!("no valid sync policy provided from commandline, using default policy '{}'", default);
+                default
+            });
+        let snapshot_interval = value_t!(matches, "snapshot", u64).unwrap_or_else(|_| {
+                info!("no valid snapshot interval provided from commandline, using default interval {}s", DEFAULT_SNAPSHOT_INTERVAL_SECS);
+                DEFAULT_SNAPSHOT_INTERVAL_SECS
+            });
+        let snapshot_interval = if snapshot_interval > 0 { Some(Duration::from_secs(snapshot_interval)) } else { None };
+        let reap_interval = value_t!(matches, "reap", u64).unwrap_or_else(|_| {
+                info!("no valid reap interval provided from commandline, using default interval {}s", DEFAULT_REAP_INTERVAL_SECS);
+                DEFAULT_REAP_INTERVAL_SECS
+            });
+        let reap_interval = if reap_interval > 0 { Some(Duration::from_secs(reap_interval)) } else { None };
+        let layout = if matches.is_present("variable") {
+            let max_value_size = value_t!(matches, "max_value", usize).unwrap_or_else(|_| {
+                    info!("no valid max value size provided from commandline, using default size {}", DEFAULT_MAX_VALUE_SIZE);
+                    DEFAULT_MAX_VALUE_SIZE
+                });
+            DataLayout::Variable { max_key_size: DEFAULT_MAX_KEY_SIZE, max_value_size }
+        } else {
+            DataLayout::Fixed
+        };
+        let memory_budget = match value_t!(matches, "memory", usize) {
+            Ok(budget) if budget > 0 => Some(budget),
+            _ => {
+                info!("no valid memory budget provided from commandline, holding every value in memory");
+                None
+            }
+        };
+        let engine = value_t!(matches, "engine", EngineKind).unwrap_or_else(|_| {
+                info!("no valid storage engine provided from commandline, using default engine '{}'", EngineKind::Log);
+                EngineKind::Log
+            });
+        KVServerConfig {
+            db_file, listen_port, unix_socket, tls_cert, tls_key, tls_client_ca, users_file, threads, compaction_ratio, strict_recovery, sync_policy, snapshot_interval,
+            reap_interval, layout, memory_budget, engine
+        }
+    }
+
+    /// Options for opening the storage engine described by this configuration
+    pub fn storage_options(&self) -> StorageOptions {
+        let mut ret = StorageOptions::from_default();
+        ret.compaction_ratio = self.compaction_ratio;
+        ret.sync_policy = self.sync_policy;
+        ret.layout = self.layout;
+        ret.memory_budget = self.memory_budget;
+        if self.strict_recovery {
+            ret.recovery = RecoveryMode::Strict;
+        }
+        ret
+    }
+
+    /// TLS settings of the server described by this configuration, `None` if it speaks plaintext.
+    /// Returns `Err` if the PEM files cannot be read or used, or only some of them are given
+    pub fn tls_acceptor(&self) -> Result<Option<TlsAcceptor>, Box<dyn Error>> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Ok(Some(TlsAcceptor::from_pem_files(cert, key, self.tls_client_ca.as_deref())?)),
+            (None, None) if self.tls_client_ca.is_none() => Ok(None),
+            _ => Err(Box::new(TlsError::new("both a certificate and a private key are needed")))
+        }
+    }
+
+    /// Users of the server described by this configuration, `None` if clients do not authenticate.
+    /// Returns `Err` if the users file cannot be read or is ill-formed
+    pub fn users(&self) -> Result<Option<Users>, Box<dyn Error>> {
+        match &self.users_file {
+            Some(path) => Ok(Some(Users::load(path)?)),
+            None => Ok(None)
+        }
+    }
+
+    /// Options for opening the `EngineKind::Lsm` storage engine described by this configuration
+    pub fn lsm_options(&self) -> LsmOptions {
+        let mut ret = LsmOptions::from_default();
+        ret.sync_policy = self.sync_policy;
+        ret.layout = self.layout;
+        if self.strict_recovery {
+            ret.recovery = RecoveryMode::Strict;
+        }
+        ret
     }
 }
@@ -1,21 +1,44 @@
 //! Server API of Project-KV
 
+pub mod auth;
 pub mod config;
 pub mod protocol;
 pub use config::KVServerConfig;
 
-use std::{fs, path, process};
-use std::net::{TcpListener, SocketAddr, TcpStream};
-use std::sync::{Arc, RwLock};
+use std::{fs, process, thread};
+use std::time::Duration;
+use std::net::{TcpListener, SocketAddr};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::sync::{Arc, Mutex, RwLock, mpsc};
 use std::error::Error;
 
-use crate::kvstorage::{KVStorage};
+use crate::kvstorage::{KVStorage, Key, SyncPolicy, ReadSnapshot};
+use crate::kvstorage::disklog::DiskLogWriter;
+use crate::kvstorage::engine::{StorageEngine, EngineKind, LogEngine, MemoryEngine, KVPairs};
+use crate::kvstorage::lsm::LsmEngine;
+use crate::kvstorage::scan::ScanOptions;
+use crate::kvstorage::transaction::{Transaction, ConflictError};
 use crate::threadpool::ThreadPool;
-use crate::kvserver::protocol::{Request, ServerReplyChunk, KV_PAIR_SERIALIZED_SIZE};
-use crate::chunktps::{ChunktpConnection, CHUNK_MAX_SIZE};
+use crate::kvserver::auth::{Users, User};
+use crate::kvserver::protocol::{Request, ServerReplyChunk, ProtocolError, read_message, read_message_limited, write_message,
+                                kv_pair_serialized_size, key_serialized_size, AUTH_MESSAGE_MAX_SIZE};
+use crate::chunktps::{ChunktpConnection, Transport, Listener};
+use crate::chunktps::tls::{TlsAcceptor, TlsListener};
 
 use log::{error, warn, info};
 
+/// Max count of pairs a scan reads from the storage at once, see `stream_scan`
+pub const SCAN_BATCH_SIZE: usize = 1024;
+
+/// Count of threads handling the requests of a pipeline of a connection, see `Request::Pipeline`
+pub const PIPELINE_WORKERS: usize = 4;
+
+/// Count of requests of a pipeline read ahead of its workers, reading waits for them beyond
+pub const PIPELINE_QUEUE_SIZE: usize = 64;
+
 /// Starts a KV server with given configuration. This function also blocks the current thread, and
 /// currently there is no way to recover.
 pub fn run_server(config: KVServerConfig) {
@@ -24,28 +47,76 @@ pub fn run_server(config: KVServerConfig) {
             error!("error occurred when creating storage engine: {}", e);
             process::exit(1);
         });
-    info!("done creating storage engine");
+    info!("done creating {} storage engine", config.engine);
+    let tls_acceptor = config.tls_acceptor().unwrap_or_else(
+        | e | {
+            error!("error occurred when setting TLS up: {}", e);
+            process::exit(1);
+        });
+    if tls_acceptor.is_some() {
+        info!("done setting TLS up");
+    }
+    let users = config.users().unwrap_or_else(
+        | e | {
+            error!("error occurred when loading users: {}", e);
+            process::exit(1);
+        }).map(Arc::new);
+    if users.is_some() {
+        info!("done loading users, clients must authenticate");
+    }
+    #[cfg(unix)]
+    {
+        if let Some(path) = &config.unix_socket {
+            let unix_listener = bind_unix_listener(path).unwrap_or_else(
+                | e | {
+                    error!("error occurred when creating Unix socket listener: {}", e);
+                    process::exit(1);
+                });
+            info!("successfully bounded Unix socket listener");
+            serve_with_tls(unix_listener, tls_acceptor, storage, users, config.threads as usize);
+            return;
+        }
+    }
     let tcp_listener = bind_tcp_listener(&config).unwrap_or_else(
         | e | {
             error!("error occurred when creating TCP listener: {}", e);
             process::exit(1);
         });
     info!("successfully bounded TCP listener");
-    let pool = ThreadPool::new(config.threads as usize);
+    serve_with_tls(tcp_listener, tls_acceptor, storage, users, config.threads as usize);
+}
+
+/// Same as `serve`, over TLS if there is a `tls_acceptor`
+fn serve_with_tls<L: Listener>(listener: L, tls_acceptor: Option<TlsAcceptor>, storage_engine: Arc<dyn StorageEngine>,
+                               users: Option<Arc<Users>>, threads: usize) {
+    match tls_acceptor {
+        Some(tls_acceptor) => serve(TlsListener::new(listener, tls_acceptor), storage_engine, users, threads),
+        None => serve(listener, storage_engine, users, threads)
+    }
+}
+
+/// Serves the clients accepted from `listener` with `storage_engine`, handling `threads`
+/// connections at once. If there are `users`, clients must authenticate as one of them first, and
+/// are then only allowed what the user is (see the `auth` module). Blocks the current thread until
+/// accepting a client fails, and then until the connections being handled end
+pub fn serve<L: Listener>(listener: L, storage_engine: Arc<dyn StorageEngine>, users: Option<Arc<Users>>, threads: usize) {
+    let pool = ThreadPool::new(threads);
     info!("successfully created thread pool");
 
     info!("done initialization, started listening requests.");
-    for stream in tcp_listener.incoming() {
-        if let Err(e) = stream {
-            warn!("an TCP error occurred, extra info: {}", e);
-            info!("automatically gave up and moved to next iteration");
-            break;
-        }
-        let stream = stream.unwrap();
+    loop {
+        let stream = match listener.accept() {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("an error occurred when accepting connection, extra info: {}", e);
+                info!("automatically gave up and moved to next iteration");
+                break;
+            }
+        };
 
-        let storage = storage.clone();
+        let (storage, users) = (storage_engine.clone(), users.clone());
         pool.execute(move || {
-            if let Err(e) = handle_connection(stream, storage) {
+            if let Err(e) = handle_connection(stream, storage, users) {
                 warn!("an error occurred when processing request");
                 info!("detailed error info: {}", e);
             }
@@ -53,140 +124,754 @@ pub fn run_server(config: KVServerConfig) {
     }
 }
 
-fn handle_connection(stream: TcpStream, storage_engine: Arc<RwLock<KVStorage>>) -> Result<(), Box<dyn Error>> {
-    let mut chunktps = ChunktpConnection::new(stream);
+fn handle_connection<S: Transport>(stream: S, storage_engine: Arc<dyn StorageEngine>,
+                                   users: Option<Arc<Users>>) -> Result<(), Box<dyn Error>> {
+    let mut chunktps = ChunktpConnection::accept(stream)?;
+    let user = match users {
+        Some(users) => match authenticate(&mut chunktps, &users)? {
+            Some(user) => Some(user),
+            None => return Ok(())
+        },
+        None => None
+    };
+    // read snapshots opened by the client, released when the connection ends, however it ends
+    let snapshots = Arc::new(Mutex::new(Vec::new()));
+    let result = handle_requests(&mut chunktps, &storage_engine, &snapshots, &user);
+    for snapshot in snapshots.lock().unwrap().drain(..) {
+        if let Err(e) = storage_engine.release_read_snapshot(snapshot) {
+            warn!("releasing read snapshot failed");
+            info!("detailed info: {}", e);
+        }
+    }
+    result
+}
+
+/// Reads the first request of a connection, which must authenticate it as one of `users`. Replies
+/// `Success` and returns the user, or replies `Denied` and returns `None`, in which case the
+/// connection is to be closed. A request larger than `AUTH_MESSAGE_MAX_SIZE` fails, so that clients
+/// not authenticated yet cannot make the server buffer much
+fn authenticate<S: Transport>(chunktps: &mut ChunktpConnection<S>, users: &Users) -> Result<Option<Arc<User>>, Box<dyn Error>> {
+    let request = read_message_limited(chunktps, AUTH_MESSAGE_MAX_SIZE)?;
+    if request.is_empty() {
+        return Err(Box::new(ProtocolError::new("empty request")));
+    }
+    let user = match Request::deserialize_from(request)? {
+        Request::Authenticate(credentials) => users.authenticate(&credentials),
+        Request::Close => return Ok(None),
+        _ => None
+    };
+    match &user {
+        Some(user) => {
+            info!("client authenticated as user {}", user.name());
+            chunktps.write_chunk(ServerReplyChunk::Success.serialize())?;
+        },
+        None => {
+            warn!("authentication failed");
+            chunktps.write_chunk(ServerReplyChunk::Denied.serialize())?;
+        }
+    }
+    Ok(user)
+}
+
+fn handle_requests<S: Transport>(chunktps: &mut ChunktpConnection<S>, storage_engine: &Arc<dyn StorageEngine>,
+                   snapshots: &Arc<Mutex<Vec<ReadSnapshot>>>, user: &Option<Arc<User>>) -> Result<(), Box<dyn Error>> {
+    // the transaction going on, gets, puts and deletes go through it
+    let mut transaction: Option<Transaction> = None;
     loop {
-        match Request::deserialize_from(chunktps.read_chunk()?)? {
-            Request::Get(key) => {
-                let maybe_value = storage_engine.read().unwrap().get(&key);
-                chunktps.write_chunk(ServerReplyChunk::SingleValue(maybe_value).serialize())?;
+        let request = read_message(chunktps)?;
+        if request.is_empty() {
+            return Err(Box::new(ProtocolError::new("empty request")));
+        }
+        let request = Request::deserialize_from(request)?;
+        match request {
+            Request::Close => {
+                return Ok(())
             },
-            Request::Put(key, value) => {
-                match storage_engine.write().unwrap().put(&key, &value) {
-                    Ok(_) => {
-                        chunktps.write_chunk(ServerReplyChunk::Success.serialize())?;
-                    },
-                    Err(e) => {
-                        warn!("put operation failed");
-                        info!("detailed info: {}", e);
-                        chunktps.write_chunk(ServerReplyChunk::Error.serialize())?;
-                    }
-                }
+            Request::Pipeline if transaction.is_some() => {
+                warn!("pipeline operation failed, a transaction is going on");
+                chunktps.write_chunk(ServerReplyChunk::Error.serialize())?;
+            },
+            Request::Pipeline => {
+                chunktps.write_chunk(ServerReplyChunk::Success.serialize())?;
+                chunktps.set_acknowledged(false);
+                handle_pipeline(chunktps, storage_engine, snapshots, user)?;
+                chunktps.set_acknowledged(true);
             },
-            Request::Del(key) => {
-                match storage_engine.write().unwrap().delete(&key) {
-                    Ok(rows_effected) => {
-                        chunktps.write_chunk(ServerReplyChunk::Number(rows_effected).serialize())?;
-                    },
-                    Err(e) => {
-                        warn!("delete operation failed");
-                        info!("detailed info: {}", e);
-                        chunktps.write_chunk(ServerReplyChunk::Error.serialize())?;
+            request => handle_request(chunktps, storage_engine.as_ref(), snapshots, &mut transaction, user.as_deref(), request)?
+        }
+    }
+}
+
+/// Handles the requests of a pipeline (see `Request::Pipeline`) until its end: they are read one
+/// after another, and handed over to `PIPELINE_WORKERS` threads, which reply each of them as soon
+/// as done with it. At most `PIPELINE_QUEUE_SIZE` requests wait for the workers, so that a client
+/// not reading its replies cannot make the server buffer its requests
+fn handle_pipeline<S: Transport>(chunktps: &mut ChunktpConnection<S>, storage_engine: &Arc<dyn StorageEngine>,
+                   snapshots: &Arc<Mutex<Vec<ReadSnapshot>>>, user: &Option<Arc<User>>) -> Result<(), Box<dyn Error>> {
+    let connection = Arc::new(Mutex::new(chunktps.try_clone()?));
+    // requests that cannot be pipelined come as `None`, the workers reply them with `Error`
+    let (sender, receiver) = mpsc::sync_channel::<(u32, Option<Request>)>(PIPELINE_QUEUE_SIZE);
+    let receiver = Arc::new(Mutex::new(receiver));
+    let workers = (0..PIPELINE_WORKERS).map(|_| {
+        let (connection, receiver) = (connection.clone(), receiver.clone());
+        let (storage_engine, snapshots, user) = (storage_engine.clone(), snapshots.clone(), user.clone());
+        thread::spawn(move || -> Result<(), String> {
+            loop {
+                let message = receiver.lock().unwrap().recv();
+                let (id, request) = match message {
+                    Ok(message) => message,
+                    Err(_) => return Ok(())
+                };
+                let mut replies = PipelinedReplies { connection: connection.clone(), id };
+                let result = match request {
+                    Some(request) => handle_request(&mut replies, storage_engine.as_ref(), &snapshots, &mut None, user.as_deref(), request),
+                    None => {
+                        warn!("pipelined operation failed, the request cannot be pipelined");
+                        replies.send(ServerReplyChunk::Error.serialize())
                     }
+                };
+                result.map_err(|e| e.to_string())?;
+            }
+        })
+    }).collect::<Vec<_>>();
+
+    let result = read_pipeline(chunktps, &sender);
+    drop(sender);
+    for worker in workers {
+        if let Err(e) = worker.join().unwrap() {
+            return Err(Box::new(ProtocolError::new(&format!("pipelined request failed: {}", e))));
+        }
+    }
+    result?;
+    // every request has been replied
+    chunktps.write_chunk(vec![])
+}
+
+/// Reads the requests of a pipeline until its end, sending them to the workers through `sender`.
+/// Requests that cannot be pipelined are sent as `None`, for the workers to reply them with `Error`:
+/// replying them here could wait for the client, which may itself wait for its requests to be read
+fn read_pipeline<S: Transport>(chunktps: &mut ChunktpConnection<S>,
+                 sender: &mpsc::SyncSender<(u32, Option<Request>)>) -> Result<(), Box<dyn Error>> {
+    loop {
+        let request = read_message(chunktps)?;
+        if request.is_empty() {
+            return Ok(());
+        }
+        let (id, request) = match Request::deserialize_from(request)? {
+            Request::Pipelined(id, request) => (id, *request),
+            _ => return Err(Box::new(ProtocolError::new("request of a pipeline without id")))
+        };
+        let request = match request {
+            Request::Begin | Request::Commit | Request::Abort | Request::Pipeline | Request::Close => None,
+            request => Some(request)
+        };
+        sender.send((id, request)).map_err(|_| ProtocolError::new("pipeline workers are gone"))?;
+    }
+}
+
+/// Handles a single `request` and replies it through `replies`, whatever it is but `Close` and
+/// pipelines. Gets, puts and deletes go through the `transaction` going on, if any. Requests the
+/// authenticated `user` is not allowed are replied with `Denied`
+fn handle_request<R: ReplySink + ?Sized>(replies: &mut R, storage_engine: &dyn StorageEngine,
+                                         snapshots: &Mutex<Vec<ReadSnapshot>>, transaction: &mut Option<Transaction>,
+                                         user: Option<&User>, request: Request) -> Result<(), Box<dyn Error>> {
+    if let Some(user) = user {
+        if !user.allows(&request) {
+            warn!("operation denied to user {}", user.name());
+            return replies.send(ServerReplyChunk::Denied.serialize());
+        }
+    }
+    match request {
+        Request::Get(key) => {
+            let result = match transaction {
+                Some(transaction) => transaction.get(storage_engine, &key),
+                None => storage_engine.get(&key)
+            };
+            match result {
+                Ok(maybe_value) => {
+                    replies.send(ServerReplyChunk::SingleValue(maybe_value).serialize())?;
+                },
+                Err(e) => {
+                    warn!("get operation failed");
+                    info!("detailed info: {}", e);
+                    replies.send(ServerReplyChunk::Error.serialize())?;
+                }
+            }
+        },
+        Request::Put(key, value) => {
+            let result = match transaction {
+                Some(transaction) => {
+                    transaction.put(&key, &value);
+                    Ok(())
+                },
+                None => storage_engine.put(&key, &value)
+            };
+            match result {
+                Ok(_) => {
+                    replies.send(ServerReplyChunk::Success.serialize())?;
+                },
+                Err(e) => {
+                    warn!("put operation failed");
+                    info!("detailed info: {}", e);
+                    replies.send(ServerReplyChunk::Error.serialize())?;
+                }
+            }
+        },
+        Request::Del(key) => {
+            let result = match transaction {
+                Some(transaction) => transaction.delete(storage_engine, &key),
+                None => storage_engine.delete(&key)
+            };
+            match result {
+                Ok(rows_effected) => {
+                    replies.send(ServerReplyChunk::Number(rows_effected).serialize())?;
+                },
+                Err(e) => {
+                    warn!("delete operation failed");
+                    info!("detailed info: {}", e);
+                    replies.send(ServerReplyChunk::Error.serialize())?;
+                }
+            }
+        },
+        Request::Scan(key1, key2, options) => {
+            stream_scan(replies, storage_engine, &key1, &key2, options, None)?;
+        },
+        Request::Batch(batch) => {
+            let result = match transaction {
+                Some(transaction) => {
+                    transaction.write_batch(&batch);
+                    Ok(())
+                },
+                None => storage_engine.write_batch(&batch)
+            };
+            match result {
+                Ok(_) => {
+                    replies.send(ServerReplyChunk::Success.serialize())?;
+                },
+                Err(e) => {
+                    warn!("batch operation failed");
+                    info!("detailed info: {}", e);
+                    replies.send(ServerReplyChunk::Error.serialize())?;
+                }
+            }
+        },
+        Request::Begin => {
+            if transaction.is_some() {
+                warn!("begin operation failed, a transaction is already going on");
+                replies.send(ServerReplyChunk::Error.serialize())?;
+            } else {
+                *transaction = Some(Transaction::new());
+                replies.send(ServerReplyChunk::Success.serialize())?;
+            }
+        },
+        Request::Commit => {
+            let result = match transaction.take() {
+                Some(transaction) => transaction.commit(storage_engine),
+                None => Err(Box::new(ProtocolError::new("no transaction to commit")) as Box<dyn Error>)
+            };
+            match result {
+                Ok(_) => {
+                    replies.send(ServerReplyChunk::Success.serialize())?;
+                },
+                Err(e) if e.is::<ConflictError>() => {
+                    info!("transaction not committed: {}", e);
+                    replies.send(ServerReplyChunk::Conflict.serialize())?;
+                },
+                Err(e) => {
+                    warn!("commit operation failed");
+                    info!("detailed info: {}", e);
+                    replies.send(ServerReplyChunk::Error.serialize())?;
+                }
+            }
+        },
+        Request::Abort => {
+            if transaction.take().is_some() {
+                replies.send(ServerReplyChunk::Success.serialize())?;
+            } else {
+                warn!("abort operation failed, no transaction is going on");
+                replies.send(ServerReplyChunk::Error.serialize())?;
+            }
+        },
+        Request::CompareAndSwap(..) | Request::PutIfAbsent(..) | Request::DeleteIfEquals(..) => {
+            let (key, expected, new) = match request {
+                Request::CompareAndSwap(key, expected, new) => (key, expected, Some(new)),
+                Request::PutIfAbsent(key, value) => (key, None, Some(value)),
+                Request::DeleteIfEquals(key, expected) => (key, Some(expected), None),
+                _ => unreachable!()
+            };
+            let result = match transaction {
+                Some(transaction) =>
+                    transaction.compare_and_swap(storage_engine, &key, expected.as_ref(), new.as_ref()),
+                None => storage_engine.compare_and_swap(&key, expected.as_ref(), new.as_ref())
+            };
+            match result {
+                Ok(Ok(())) => {
+                    replies.send(ServerReplyChunk::Success.serialize())?;
+                },
+                Ok(Err(current)) => {
+                    replies.send(ServerReplyChunk::Mismatch(current).serialize())?;
+                },
+                Err(e) => {
+                    warn!("compare and swap operation failed");
+                    info!("detailed info: {}", e);
+                    replies.send(ServerReplyChunk::Error.serialize())?;
+                }
+            }
+        },
+        Request::OpenSnapshot => {
+            match storage_engine.create_read_snapshot() {
+                Ok(snapshot) => {
+                    snapshots.lock().unwrap().push(snapshot);
+                    replies.send(ServerReplyChunk::Number(snapshot.id() as usize).serialize())?;
+                },
+                Err(e) => {
+                    warn!("open snapshot operation failed");
+                    info!("detailed info: {}", e);
+                    replies.send(ServerReplyChunk::Error.serialize())?;
                 }
-            },
-            Request::Scan(key1, key2) => {
-                const ROW_PER_CHUNK: usize = (CHUNK_MAX_SIZE - 1) / KV_PAIR_SERIALIZED_SIZE;
-                let scan_result = storage_engine.read().unwrap().scan(&key1, &key2);
-                for i in (0..scan_result.len()).step_by(ROW_PER_CHUNK) {
-                    let slice = if i + ROW_PER_CHUNK < scan_result.len() {
-                        &scan_result[i..i+ROW_PER_CHUNK]
-                    } else {
-                        &scan_result[i..scan_result.len()]
-                    };
-                    chunktps.write_chunk(ServerReplyChunk::KVPairs(slice).serialize())?;
-                }
-                chunktps.write_chunk(vec![])?;
-            },
-            Request::Close => {
-                return Ok(())
             }
+        },
+        Request::GetAt(id, key) => {
+            // the snapshots are not to be locked while reading, let alone replying
+            let snapshot = find_snapshot(&snapshots.lock().unwrap(), id);
+            let result = match snapshot {
+                Ok(snapshot) => storage_engine.get_at(&key, Some(snapshot)),
+                Err(e) => Err(e)
+            };
+            match result {
+                Ok(maybe_value) => {
+                    replies.send(ServerReplyChunk::SingleValue(maybe_value).serialize())?;
+                },
+                Err(e) => {
+                    warn!("get at snapshot operation failed");
+                    info!("detailed info: {}", e);
+                    replies.send(ServerReplyChunk::Error.serialize())?;
+                }
+            }
+        },
+        Request::ScanAt(id, key1, key2) => {
+            let snapshot = find_snapshot(&snapshots.lock().unwrap(), id);
+            match snapshot {
+                Ok(snapshot) => {
+                    let options = ScanOptions::from_default();
+                    stream_scan(replies, storage_engine, &key1, &key2, options, Some(snapshot))?;
+                },
+                Err(e) => {
+                    warn!("scan at snapshot operation failed");
+                    info!("detailed info: {}", e);
+                    replies.send(ServerReplyChunk::Error.serialize())?;
+                }
+            }
+        },
+        Request::ReleaseSnapshot(id) => {
+            let released = {
+                let mut snapshots = snapshots.lock().unwrap();
+                snapshots.iter().position(|snapshot| snapshot.id() == id).map(|i| snapshots.swap_remove(i))
+            };
+            let result = match released {
+                Some(snapshot) => storage_engine.release_read_snapshot(snapshot),
+                None => Err(Box::new(ProtocolError::new("no such snapshot open")) as Box<dyn Error>)
+            };
+            match result {
+                Ok(_) => {
+                    replies.send(ServerReplyChunk::Success.serialize())?;
+                },
+                Err(e) => {
+                    warn!("release snapshot operation failed");
+                    info!("detailed info: {}", e);
+                    replies.send(ServerReplyChunk::Error.serialize())?;
+                }
+            }
+        },
+        Request::PutWithTtl(key, value, ttl) => {
+            let result = match &*transaction {
+                Some(_) => Err(Box::new(ProtocolError::new("expiry cannot be part of a transaction")) as Box<dyn Error>),
+                None => storage_engine.put_with_ttl(&key, &value, Duration::from_millis(ttl))
+            };
+            match result {
+                Ok(_) => {
+                    replies.send(ServerReplyChunk::Success.serialize())?;
+                },
+                Err(e) => {
+                    warn!("put with ttl operation failed");
+                    info!("detailed info: {}", e);
+                    replies.send(ServerReplyChunk::Error.serialize())?;
+                }
+            }
+        },
+        Request::Expire(..) | Request::Persist(..) => {
+            let result = match (&*transaction, request) {
+                (Some(_), _) => Err(Box::new(ProtocolError::new("expiry cannot be part of a transaction")) as Box<dyn Error>),
+                (None, Request::Expire(key, ttl)) => storage_engine.expire(&key, Duration::from_millis(ttl)),
+                (None, Request::Persist(key)) => storage_engine.persist(&key),
+                _ => unreachable!()
+            };
+            match result {
+                Ok(rows_effected) => {
+                    replies.send(ServerReplyChunk::Number(rows_effected).serialize())?;
+                },
+                Err(e) => {
+                    warn!("expire operation failed");
+                    info!("detailed info: {}", e);
+                    replies.send(ServerReplyChunk::Error.serialize())?;
+                }
+            }
+        },
+        Request::Ttl(key) => {
+            match storage_engine.ttl(&key) {
+                Ok(ttl) => {
+                    replies.send(ServerReplyChunk::Ttl(ttl).serialize())?;
+                },
+                Err(e) => {
+                    warn!("ttl operation failed");
+                    info!("detailed info: {}", e);
+                    replies.send(ServerReplyChunk::Error.serialize())?;
+                }
+            }
+        },
+        Request::ScanKeys(key1, key2) => {
+            match storage_engine.scan_keys(&key1, &key2) {
+                Ok(keys) => write_keys(replies, &keys)?,
+                Err(e) => {
+                    warn!("scan keys operation failed");
+                    info!("detailed info: {}", e);
+                    replies.send(ServerReplyChunk::Error.serialize())?;
+                }
+            }
+        },
+        Request::Count(key1, key2) => {
+            match storage_engine.count(&key1, &key2) {
+                Ok(count) => {
+                    replies.send(ServerReplyChunk::Number(count).serialize())?;
+                },
+                Err(e) => {
+                    warn!("count operation failed");
+                    info!("detailed info: {}", e);
+                    replies.send(ServerReplyChunk::Error.serialize())?;
+                }
+            }
+        },
+        Request::ScanPrefix(prefix) => {
+            match storage_engine.scan_prefix(&prefix) {
+                Ok(scan_result) => write_kv_pairs(replies, &scan_result)?,
+                Err(e) => {
+                    warn!("scan prefix operation failed");
+                    info!("detailed info: {}", e);
+                    replies.send(ServerReplyChunk::Error.serialize())?;
+                }
+            }
+        },
+        Request::Authenticate(_) => {
+            // connections are authenticated by their first request, if ever
+            if user.is_some() {
+                warn!("authenticate operation failed, the connection is already authenticated");
+                replies.send(ServerReplyChunk::Error.serialize())?;
+            } else {
+                replies.send(ServerReplyChunk::Success.serialize())?;
+            }
+        },
+        Request::Close | Request::Pipeline | Request::Pipelined(..) => {
+            warn!("operation failed, the request cannot be handled here");
+            replies.send(ServerReplyChunk::Error.serialize())?;
+        }
+    }
+    Ok(())
+}
+
+/// Where the replies to requests go, one message (see `write_message`) at a time
+trait ReplySink {
+    fn send(&mut self, message: Vec<u8>) -> Result<(), Box<dyn Error>>;
+
+    /// Max size of a message sent without being fragmented
+    fn max_chunk_size(&self) -> usize;
+}
+
+impl<S: Transport> ReplySink for ChunktpConnection<S> {
+    fn send(&mut self, message: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        write_message(self, message)
+    }
+
+    fn max_chunk_size(&self) -> usize {
+        ChunktpConnection::max_chunk_size(self)
+    }
+}
+
+/// Replies to the pipelined request of the given `id`, tagged with it. The connection is shared by
+/// the whole pipeline, a message is written at once, without being mixed with others
+struct PipelinedReplies<S: Transport> {
+    connection: Arc<Mutex<ChunktpConnection<S>>>,
+    id: u32
+}
+
+impl<S: Transport> ReplySink for PipelinedReplies<S> {
+    fn send(&mut self, message: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        let message = ServerReplyChunk::Pipelined(self.id, &message).serialize();
+        write_message(&mut self.connection.lock().unwrap(), message)
+    }
+
+    fn max_chunk_size(&self) -> usize {
+        // leaves room for the tag
+        self.connection.lock().unwrap().max_chunk_size() - 5
+    }
+}
+
+/// The snapshot of the given `id` among the ones opened by the connection
+fn find_snapshot(snapshots: &[ReadSnapshot], id: u64) -> Result<ReadSnapshot, Box<dyn Error>> {
+    match snapshots.iter().find(|snapshot| snapshot.id() == id) {
+        Some(snapshot) => Ok(*snapshot),
+        None => Err(Box::new(ProtocolError::new("no such snapshot open")))
+    }
+}
+
+/// Replies a scan from `key1` to `key2` as told by `options`, streamed in batches of at most
+/// `SCAN_BATCH_SIZE` pairs: each batch is read from the storage and sent before the next one is
+/// read, the storage is only locked while a batch is read.
+///
+/// Scans through a `snapshot` see the content of the moment it has been opened. Other scans are
+/// consistent within a batch only, writes between batches may show up: every key is still sent at
+/// most once, in the order of the scan, and keys not written during the scan are sent if they hold
+/// a value. A key written during the scan is sent with its old or new value, or not at all.
+///
+/// Errors of the storage are replied with an `Error` chunk, possibly after some pairs
+fn stream_scan<R: ReplySink + ?Sized>(replies: &mut R, storage_engine: &dyn StorageEngine, key1: &Key, key2: &Key,
+               mut options: ScanOptions, snapshot: Option<ReadSnapshot>) -> Result<(), Box<dyn Error>> {
+    let mut left = options.limit.filter(|&limit| limit > 0);
+    loop {
+        options.limit = Some(left.map_or(SCAN_BATCH_SIZE, |left| left.min(SCAN_BATCH_SIZE)));
+        let page = match storage_engine.scan_with(key1, key2, &options, snapshot) {
+            Ok(page) => page,
+            Err(e) => {
+                warn!("scan operation failed");
+                info!("detailed info: {}", e);
+                return replies.send(ServerReplyChunk::Error.serialize());
+            }
+        };
+        write_packed(replies, &page.pairs, |(key, value)| kv_pair_serialized_size(key, value),
+                     |pairs| ServerReplyChunk::KVPairs(pairs).serialize())?;
+        left = left.map(|left| left - page.pairs.len());
+        match page.continuation {
+            // cut short by the limit of the client, which resumes from there if it wants more
+            Some(token) if left == Some(0) => {
+                replies.send(ServerReplyChunk::Continuation(&token).serialize())?;
+                break;
+            },
+            Some(token) => options.continuation = Some(token),
+            None => break
         }
     }
+    replies.send(vec![])
 }
 
-fn create_storage_engine(config: &KVServerConfig) -> Result<Arc<RwLock<KVStorage>>, Box<dyn Error>> {
-    let path = path::Path::new(&config.db_file);
-    if path.exists() {
-        let content;
-        {
-            let file = fs::File::open(path)?;
-            content = KVStorage::read_log_file(file)?;
+/// Replies scanned `pairs`, packed into chunks by their size and followed by an empty chunk. A pair
+/// too large for a chunk is sent alone, in fragments
+fn write_kv_pairs<R: ReplySink + ?Sized>(replies: &mut R, pairs: &KVPairs) -> Result<(), Box<dyn Error>> {
+    write_packed(replies, pairs, |(key, value)| kv_pair_serialized_size(key, value),
+                 |pairs| ServerReplyChunk::KVPairs(pairs).serialize())?;
+    replies.send(vec![])
+}
+
+/// Replies scanned `keys` like `write_kv_pairs` does with pairs
+fn write_keys<R: ReplySink + ?Sized>(replies: &mut R, keys: &[Key]) -> Result<(), Box<dyn Error>> {
+    write_packed(replies, keys, key_serialized_size, |keys| ServerReplyChunk::Keys(keys).serialize())?;
+    replies.send(vec![])
+}
+
+/// Writes `items` packed into chunks, given the `size` each of them takes in a chunk and how to
+/// `serialize` a chunk of them
+fn write_packed<R, T, S, F>(replies: &mut R, items: &[T], size: S, serialize: F) -> Result<(), Box<dyn Error>>
+    where R: ReplySink + ?Sized, S: Fn(&T) -> usize, F: Fn(&[T]) -> Vec<u8> {
+    let max_chunk_size = replies.max_chunk_size();
+    let mut begin = 0;
+    while begin < items.len() {
+        let mut end = begin;
+        let mut chunk_size = 1;
+        while end < items.len() {
+            chunk_size += size(&items[end]);
+            if chunk_size > max_chunk_size && end > begin {
+                break;
+            }
+            end += 1;
         }
-        {
-            let file = fs::OpenOptions::new().write(true).append(true).open(path)?;
-            Ok(Arc::new(RwLock::new(KVStorage::with_content(content, file))))
+        replies.send(serialize(&items[begin..end]))?;
+        begin = end;
+    }
+    Ok(())
+}
+
+/// Creates the storage engine chosen by `config`, together with the background threads it needs
+fn create_storage_engine(config: &KVServerConfig) -> Result<Arc<dyn StorageEngine>, Box<dyn Error>> {
+    let engine = open_storage_engine(config)?;
+    if let Some(interval) = config.reap_interval {
+        spawn_reaper(engine.clone(), interval);
+    }
+    Ok(engine)
+}
+
+fn open_storage_engine(config: &KVServerConfig) -> Result<Arc<dyn StorageEngine>, Box<dyn Error>> {
+    match config.engine {
+        EngineKind::Log => {
+            let storage = KVStorage::open_with_options(&config.db_file, &config.storage_options())?;
+            let engine = LogEngine::new(storage);
+            if let SyncPolicy::Every(interval) = config.sync_policy {
+                spawn_log_syncer(engine.storage().read().unwrap().log_writer(), interval);
+            }
+            if let Some(interval) = config.snapshot_interval {
+                spawn_snapshotter(engine.storage(), interval);
+            }
+            Ok(Arc::new(engine))
+        },
+        EngineKind::Memory => {
+            warn!("running on the memory storage engine, nothing will be persisted");
+            Ok(Arc::new(MemoryEngine::new(config.layout)))
+        },
+        EngineKind::Lsm => {
+            let engine = Arc::new(LsmEngine::open(&config.db_file, config.lsm_options())?);
+            if let SyncPolicy::Every(interval) = config.sync_policy {
+                spawn_lsm_syncer(engine.clone(), interval);
+            }
+            Ok(engine)
         }
-    } else {
-        let file = fs::File::create(path)?;
-        Ok(Arc::new(RwLock::new(KVStorage::new(file))))
     }
 }
 
+/// Periodically syncs the log, so that a write is never left unsynced for much longer than
+/// `interval` even if no other write follows it
+fn spawn_log_syncer(log_writer: DiskLogWriter, interval: Duration) {
+    thread::spawn(move || {
+        loop {
+            thread::sleep(interval);
+            if let Err(e) = log_writer.sync_if_due() {
+                warn!("periodic log sync failed");
+                info!("detailed error info: {}", e);
+            }
+        }
+    });
+}
+
+/// Same as `spawn_log_syncer`, for the write-ahead log of an `LsmEngine`, which changes every time
+/// its memtable gets flushed
+fn spawn_lsm_syncer(engine: Arc<LsmEngine>, interval: Duration) {
+    thread::spawn(move || {
+        loop {
+            thread::sleep(interval);
+            if let Err(e) = engine.sync_if_due() {
+                warn!("periodic log sync failed");
+                info!("detailed error info: {}", e);
+            }
+        }
+    });
+}
+
+/// Periodically takes a snapshot. The write lock is only held for copying the content and for
+/// cutting the log short, not while the snapshot file is being written
+fn spawn_snapshotter(storage_engine: Arc<RwLock<KVStorage>>, interval: Duration) {
+    thread::spawn(move || {
+        loop {
+            thread::sleep(interval);
+            let pending = storage_engine.write().unwrap().begin_snapshot();
+            let written = pending.and_then(|pending| pending.write().map(|_| pending));
+            let result = written.and_then(|pending| storage_engine.write().unwrap().finish_snapshot(pending));
+            if let Err(e) = result {
+                warn!("periodic snapshot failed");
+                info!("detailed error info: {}", e);
+            }
+        }
+    });
+}
+
+/// Periodically deletes the keys that have expired, so that their room is given back (on disk, by
+/// the next log compaction)
+fn spawn_reaper(storage_engine: Arc<dyn StorageEngine>, interval: Duration) {
+    thread::spawn(move || {
+        loop {
+            thread::sleep(interval);
+            match storage_engine.reap_expired() {
+                Ok(0) => (),
+                Ok(reaped) => info!("reaped {} expired keys", reaped),
+                Err(e) => {
+                    warn!("reaping expired keys failed");
+                    info!("detailed error info: {}", e);
+                }
+            }
+        }
+    });
+}
+
 fn bind_tcp_listener(config: &KVServerConfig) -> Result<TcpListener, Box<dyn Error>> {
     let addr = SocketAddr::from(([127, 0, 0, 1], config.listen_port));
-    Ok(TcpListener::bind(&addr)?)
+    Ok(TcpListener::bind(addr)?)
+}
+
+#[cfg(unix)]
+fn bind_unix_listener(path: &str) -> Result<UnixListener, Box<dyn Error>> {
+    // the socket file of a previous server is left behind, and would fail binding
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(UnixListener::bind(path)?)
 }
 
 #[cfg(test)]
 mod test_server_handle_connection {
-    use crate::kvstorage::KVStorage;
+    use crate::kvstorage::{KVStorage, Key, Value, DataLayout, WriteBatch, Ttl};
+    use crate::kvstorage::engine::{StorageEngine, LogEngine, MemoryEngine};
+    use crate::kvstorage::scan::ScanOptions;
+    use crate::kvclient::{KVClient, ServerError};
     use crate::util::{gen_key, gen_value, gen_key_n};
     use crate::chunktps::ChunktpConnection;
-    use crate::kvserver::handle_connection;
-    use crate::kvserver::protocol::{Request, ReplyChunk};
+    use crate::chunktps::transport::{duplex, duplex_listener, Listener};
+    use crate::chunktps::tls::{TlsConnector, TlsListener};
+    use crate::chunktps::tls::test::gen_pki;
+    use crate::kvserver::{handle_connection, serve, bind_unix_listener, KVServerConfig, SCAN_BATCH_SIZE};
+    use crate::kvserver::protocol::{Request, ReplyChunk, AUTH_MESSAGE_MAX_SIZE};
+    use crate::kvserver::auth::Credentials;
 
-    use std::sync::{Arc, RwLock};
-    use std::net::{TcpStream, TcpListener};
-    use std::{fs, thread};
+    use std::fs;
+    use std::sync::Arc;
+    use std::error::Error;
+    use std::net::TcpListener;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
     use std::time::Duration;
     use std::ops::Deref;
 
     #[test]
     fn test_handle_put() {
-        let _ = fs::remove_file("test_put.kv");
-        let log_file = fs::File::create("test_put.kv").unwrap();
-        let storage_engine = Arc::new(RwLock::new(KVStorage::new(log_file)));
+        let storage_engine = Arc::new(MemoryEngine::new(DataLayout::Fixed));
         let storage_engine_clone = storage_engine.clone();
+        let (stream, server_stream) = duplex();
         let t = thread::spawn(move || {
-            let tcp_listener = TcpListener::bind("127.0.0.1:1972").unwrap();
-            let (tcp_stream, _) = tcp_listener.accept().unwrap();
-            handle_connection(tcp_stream, storage_engine_clone).unwrap();
+            handle_connection(server_stream, storage_engine_clone, None).unwrap();
         });
 
         let key = gen_key();
         let value = gen_value();
 
-        thread::sleep(Duration::from_secs(1));
-        let tcp_stream = TcpStream::connect("127.0.0.1:1972").unwrap();
-        let mut chunktps = ChunktpConnection::new(tcp_stream);
-        chunktps.write_chunk(Request::Put(key, value).serialize()).unwrap();
+        let mut chunktps = ChunktpConnection::new(stream);
+        chunktps.write_chunk(Request::Put(key.clone(), value.clone()).serialize()).unwrap();
         let _ = chunktps.read_chunk();
         chunktps.write_chunk(Request::Close.serialize()).unwrap();
 
         t.join().unwrap();
-        assert_eq!(storage_engine.read().unwrap().get(&key).unwrap().data.to_vec(), value.data.to_vec());
+        assert_eq!(storage_engine.get(&key).unwrap().unwrap().data.to_vec(), value.data.to_vec());
     }
 
     #[test]
     fn test_handle_get() {
-        let _ = fs::remove_file("test_get.kv");
-        let log_file = fs::File::create("test_get.kv").unwrap();
-        let storage_engine = Arc::new(RwLock::new(KVStorage::new(log_file)));
+        let storage_engine = Arc::new(MemoryEngine::new(DataLayout::Fixed));
         let key = gen_key();
         let value = gen_value();
-        storage_engine.write().unwrap().put(&key, &value).unwrap();
+        storage_engine.put(&key, &value).unwrap();
         let storage_engine_clone = storage_engine.clone();
+        let (stream, server_stream) = duplex();
         let t = thread::spawn(move || {
-            let tcp_listener = TcpListener::bind("127.0.0.1:2333").unwrap();
-            let (tcp_stream, _) = tcp_listener.accept().unwrap();
-            handle_connection(tcp_stream, storage_engine_clone).unwrap();
+            handle_connection(server_stream, storage_engine_clone, None).unwrap();
         });
 
-        thread::sleep(Duration::from_secs(1));
-        let tcp_stream = TcpStream::connect("127.0.0.1:2333").unwrap();
-        let mut chunktps = ChunktpConnection::new(tcp_stream);
-        chunktps.write_chunk(Request::Get(key).serialize()).unwrap();
+        let mut chunktps = ChunktpConnection::new(stream);
+        chunktps.write_chunk(Request::Get(key.clone()).serialize()).unwrap();
         let reply = ReplyChunk::deserialize(chunktps.read_chunk().unwrap()).unwrap();
         match reply {
             ReplyChunk::SingleValue(v) => {
@@ -201,30 +886,25 @@ mod test_server_handle_connection {
 
     #[test]
     fn test_handle_scan() {
-        let _ = fs::remove_file("test_scan.kv");
-        let log_file = fs::File::create("test_scan.kv").unwrap();
-        let storage_engine = Arc::new(RwLock::new(KVStorage::new(log_file)));
+        let storage_engine = Arc::new(LogEngine::new(KVStorage::new(tempfile::tempfile().unwrap())));
         for i in 0..2048 {
             let key = gen_key_n(i);
             let value = gen_value();
-            storage_engine.write().unwrap().put(&key, &value).unwrap();
+            storage_engine.put(&key, &value).unwrap();
         }
 
         let storage_engine_clone = storage_engine.clone();
+        let (stream, server_stream) = duplex();
         let t = thread::spawn(move || {
-            let tcp_listener = TcpListener::bind("127.0.0.1:4396").unwrap();
-            let (tcp_stream, _) = tcp_listener.accept().unwrap();
-            handle_connection(tcp_stream, storage_engine_clone).unwrap();
+            handle_connection(server_stream, storage_engine_clone, None).unwrap();
         });
-        thread::sleep(Duration::from_secs(1));
-        let tcp_stream = TcpStream::connect("127.0.0.1:4396").unwrap();
-        let mut chunktps = ChunktpConnection::new(tcp_stream);
-        chunktps.write_chunk(Request::Scan(gen_key_n(0), gen_key_n(2048)).serialize()).unwrap();
+        let mut chunktps = ChunktpConnection::new(stream);
+        chunktps.write_chunk(Request::Scan(gen_key_n(0), gen_key_n(2048), ScanOptions::from_default()).serialize()).unwrap();
 
         let mut total_data = 0;
         loop {
             let data = chunktps.read_chunk().unwrap();
-            if data.len() == 0 {
+            if data.is_empty() {
                 break;
             }
             let chunk = ReplyChunk::deserialize(data).unwrap();
@@ -232,7 +912,7 @@ mod test_server_handle_connection {
                 ReplyChunk::KVPairs(kv_pairs) => {
                     total_data += kv_pairs.len();
                     for (k, v) in kv_pairs.iter() {
-                        let value = storage_engine.read().unwrap().get(k).unwrap();
+                        let value = storage_engine.get(k).unwrap().unwrap();
                         assert_eq!(value.deref(), v);
                     }
                 },
@@ -244,4 +924,532 @@ mod test_server_handle_connection {
         chunktps.write_chunk(Request::Close.serialize()).unwrap();
         t.join().unwrap();
     }
+
+    #[test]
+    fn test_handle_scan_pages() {
+        let storage_engine = Arc::new(MemoryEngine::new(DataLayout::Fixed));
+        let keys = (0..100).map(gen_key_n).collect::<Vec<_>>();
+        for key in keys.iter() {
+            storage_engine.put(key, &gen_value()).unwrap();
+        }
+        let (stream, server_stream) = duplex();
+        let t = thread::spawn(move || {
+            handle_connection(server_stream, storage_engine, None).unwrap();
+        });
+
+        let mut client = KVClient::new(stream);
+        let mut options = ScanOptions::from_default();
+        options.descending = true;
+        options.include_start = false;
+        options.include_end = true;
+        options.limit = Some(30);
+        let mut scanned = Vec::new();
+        let mut pages = 0;
+        loop {
+            let (chunks, continuation) = client.do_scan_with(&keys[0], &keys[99], &options, |pairs| pairs).unwrap();
+            scanned.extend(chunks.into_iter().flatten().map(|(key, _)| key));
+            pages += 1;
+            match continuation {
+                Some(token) => options.continuation = Some(token),
+                None => break
+            }
+        }
+        assert_eq!(pages, 4);
+        assert_eq!(scanned, keys[1..].iter().rev().cloned().collect::<Vec<_>>());
+
+        // plain scans are unchanged
+        let scanned = client.do_scan(&keys[0], &keys[99], |pairs| pairs.len()).unwrap();
+        assert_eq!(scanned.into_iter().sum::<usize>(), 99);
+
+        // keys may be scanned or counted without their values
+        let scanned = client.do_scan_keys(&keys[10], &keys[20], |keys| keys).unwrap().concat();
+        assert_eq!(scanned, keys[10..20].to_vec());
+        assert_eq!(client.do_count(&keys[10], &keys[20]).unwrap(), 10);
+        assert_eq!(client.do_count(&keys[20], &keys[10]).unwrap(), 0);
+
+        // and by prefix
+        let scanned = client.do_scan_prefix(&keys[42].data, |pairs| pairs).unwrap().concat();
+        assert_eq!(scanned.into_iter().map(|(key, _)| key).collect::<Vec<_>>(), vec![keys[42].clone()]);
+        let scanned = client.do_scan_prefix(&[], |pairs| pairs.len()).unwrap();
+        assert_eq!(scanned.into_iter().sum::<usize>(), 100);
+
+        client.do_close();
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn test_handle_streamed_scan() {
+        let storage_engine = Arc::new(MemoryEngine::new(DataLayout::Fixed));
+        let count = SCAN_BATCH_SIZE as u64 * 3 + 10;
+        for i in 0..count {
+            storage_engine.put(&gen_key_n(i * 2), &gen_value()).unwrap();
+        }
+        let engine = storage_engine.clone();
+        let (stream, server_stream) = duplex();
+        let t = thread::spawn(move || {
+            handle_connection(server_stream, engine, None).unwrap();
+        });
+
+        let mut client = KVClient::new(stream);
+        // writes go on while the range is streamed, keys left alone are all sent, once and in order
+        let engine = storage_engine.clone();
+        let writer = thread::spawn(move || {
+            for i in 0..count {
+                engine.put(&gen_key_n(i * 2 + 1), &gen_value()).unwrap();
+            }
+        });
+        let scanned = client.do_scan(&gen_key_n(0), &gen_key_n(count * 2), |pairs| pairs).unwrap().concat();
+        writer.join().unwrap();
+        let keys = scanned.into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(keys.iter().filter(|key| key.encode() % 2 == 0).count() as u64, count);
+
+        // limits spanning several batches still cut the scan where told
+        let mut options = ScanOptions::from_default();
+        options.descending = true;
+        options.limit = Some(SCAN_BATCH_SIZE * 2 + 1);
+        let (scanned, continuation) = client.do_scan_with(&gen_key_n(0), &gen_key_n(count * 2), &options, |pairs| pairs).unwrap();
+        let keys = scanned.concat().into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+        assert_eq!(keys, (count * 2 - SCAN_BATCH_SIZE as u64 * 2 - 1..count * 2).rev().map(gen_key_n).collect::<Vec<_>>());
+        assert_eq!(continuation.as_ref(), keys.last());
+
+        // scans at a snapshot are streamed too, without seeing later writes
+        let snapshot = client.do_open_snapshot().unwrap();
+        for i in 0..count {
+            storage_engine.delete(&gen_key_n(i)).unwrap();
+        }
+        let scanned = client.do_scan_at(snapshot, &gen_key_n(0), &gen_key_n(count * 2), |pairs| pairs.len()).unwrap();
+        assert_eq!(scanned.into_iter().sum::<usize>() as u64, count * 2);
+        assert!(client.do_scan(&gen_key_n(0), &gen_key_n(count), |pairs| pairs).unwrap().concat().is_empty());
+
+        client.do_close();
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn test_handle_pipeline() {
+        let storage_engine = Arc::new(MemoryEngine::new(DataLayout::Fixed));
+        let engine = storage_engine.clone();
+        let (stream, server_stream) = duplex();
+        let t = thread::spawn(move || {
+            handle_connection(server_stream, engine, None).unwrap();
+        });
+
+        let mut client = KVClient::new(stream);
+        let pairs = (0..2000).map(|i| (gen_key_n(i), gen_value())).collect::<Vec<_>>();
+        let requests = pairs.iter().map(|(key, value)| Request::Put(key.clone(), value.clone())).collect();
+        let replies = client.do_pipeline(requests).unwrap();
+        assert_eq!(replies.len(), 2000);
+        assert!(replies.iter().all(|reply| matches!(reply[..], [ReplyChunk::Success])));
+
+        // each reply goes with its request, however many chunks it takes
+        let mut requests = pairs.iter().map(|(key, _)| Request::Get(key.clone())).collect::<Vec<_>>();
+        requests.push(Request::Scan(gen_key_n(0), gen_key_n(2000), ScanOptions::from_default()));
+        requests.push(Request::Begin);
+        requests.push(Request::Count(gen_key_n(0), gen_key_n(1000)));
+        let replies = client.do_pipeline(requests).unwrap();
+        for ((_, value), reply) in pairs.iter().zip(replies.iter()) {
+            match &reply[..] {
+                [ReplyChunk::SingleValue(Some(v))] => assert_eq!(v, value),
+                _ => panic!()
+            }
+        }
+        let scanned = replies[2000].iter().map(|chunk| match chunk {
+            ReplyChunk::KVPairs(pairs) => pairs.len(),
+            _ => panic!()
+        }).sum::<usize>();
+        assert_eq!(scanned, 2000);
+        assert!(matches!(replies[2001][..], [ReplyChunk::Error]));
+        assert!(matches!(replies[2002][..], [ReplyChunk::Number(1000)]));
+
+        // the connection is back to plain requests afterwards
+        assert_eq!(client.do_get(&gen_key_n(42), |value| value).unwrap(), Some(pairs[42].1.clone()));
+        assert!(client.do_pipeline(vec![]).unwrap().is_empty());
+
+        client.do_close();
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn test_handle_large_values() {
+        let storage_engine = Arc::new(MemoryEngine::new(DataLayout::variable()));
+        let (stream, server_stream) = duplex();
+        let t = thread::spawn(move || {
+            handle_connection(server_stream, storage_engine, None).unwrap();
+        });
+
+        let mut client = KVClient::new(stream);
+        let mut pairs = Vec::new();
+        for i in 0..4u8 {
+            let key = Key::from_bytes(&vec![b'k'; i as usize + 1]);
+            let value = Value::from_bytes(&vec![i; 100000 * i as usize]);
+            client.do_put(&key, &value).unwrap();
+            pairs.push((key, value));
+        }
+
+        for (key, value) in pairs.iter() {
+            assert_eq!(client.do_get(key, |v| v).unwrap().as_ref(), Some(value));
+        }
+        let scanned = client.do_scan(&Key::from_bytes(b""), &Key::from_bytes(b"z"), |ps| ps).unwrap()
+            .into_iter().flatten().collect::<Vec<_>>();
+        assert_eq!(scanned, pairs);
+
+        client.do_close();
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn test_handle_chunktp_v2() {
+        let storage_engine = Arc::new(MemoryEngine::new(DataLayout::variable()));
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp_listener.local_addr().unwrap();
+        let t = thread::spawn(move || {
+            let (tcp_stream, _) = tcp_listener.accept().unwrap();
+            handle_connection(tcp_stream, storage_engine, None).unwrap();
+        });
+
+        let mut client = KVClient::connect(addr).unwrap();
+        assert_eq!(client.chunktp_version(), 2);
+        // values far larger than a v1 chunk, and scans of many batches
+        let large = Value::from_bytes(&vec![7; 300000]);
+        client.do_put(&Key::from_bytes(b"large"), &large).unwrap();
+        assert_eq!(client.do_get(&Key::from_bytes(b"large"), |v| v).unwrap(), Some(large));
+        let requests = (0..5000).map(|i| Request::Put(gen_key_n(i), gen_value())).collect();
+        assert_eq!(client.do_pipeline(requests).unwrap().len(), 5000);
+        let scanned = client.do_scan(&gen_key_n(0), &gen_key_n(5000), |ps| ps.len()).unwrap();
+        assert_eq!(scanned.iter().sum::<usize>(), 5000);
+
+        client.do_close();
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn test_serve_transports() {
+        let storage_engine: Arc<dyn StorageEngine> = Arc::new(MemoryEngine::new(DataLayout::Fixed));
+        let value = gen_value();
+        // in-process clients, served until they are all gone
+        let (listener, connector) = duplex_listener();
+        let engine = storage_engine.clone();
+        let t = thread::spawn(move || serve(listener, engine, None, 2));
+        let mut clients = (0..2).map(|_| KVClient::connect_with(|| connector.connect()).unwrap()).collect::<Vec<_>>();
+        drop(connector);
+        assert_eq!(clients[0].chunktp_version(), 2);
+        clients[0].do_put(&gen_key_n(0), &value).unwrap();
+        assert_eq!(clients[1].do_get(&gen_key_n(0), |v| v).unwrap(), Some(value.clone()));
+        for client in clients.iter_mut() {
+            client.do_close();
+        }
+        t.join().unwrap();
+
+        // the socket file left behind by a previous listener does not get in the way
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kv.sock");
+        drop(UnixListener::bind(&path).unwrap());
+        let unix_listener = bind_unix_listener(path.to_str().unwrap()).unwrap();
+        let t = thread::spawn(move || {
+            let (unix_stream, _) = unix_listener.accept().unwrap();
+            handle_connection(unix_stream, storage_engine, None).unwrap();
+        });
+        let mut client = KVClient::connect_unix(&path).unwrap();
+        assert_eq!(client.do_get(&gen_key_n(0), |v| v).unwrap(), Some(value));
+        client.do_close();
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn test_handle_tls() {
+        let pki = gen_pki();
+        let dir = tempfile::tempdir().unwrap();
+        let file = |name: &str, pem: &str| {
+            let path = dir.path().join(name);
+            fs::write(&path, pem).unwrap();
+            path.to_str().unwrap().to_owned()
+        };
+        let mut config = KVServerConfig::from_default();
+        assert!(config.tls_acceptor().unwrap().is_none());
+        config.tls_cert = Some(file("server.pem", &pki.server.0));
+        assert!(config.tls_acceptor().is_err());
+        config.tls_key = Some(file("server.key", &pki.server.1));
+        config.tls_client_ca = Some(file("ca.pem", &pki.ca));
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp_listener.local_addr().unwrap();
+        let tls_listener = TlsListener::new(tcp_listener, config.tls_acceptor().unwrap().unwrap());
+
+        let storage_engine = Arc::new(MemoryEngine::new(DataLayout::variable()));
+        let t = thread::spawn(move || {
+            handle_connection(tls_listener.accept().unwrap(), storage_engine, None).unwrap();
+        });
+        let identity = Some((pki.client.0.as_bytes(), pki.client.1.as_bytes()));
+        let connector = TlsConnector::from_pem(pki.ca.as_bytes(), "localhost", identity).unwrap();
+        let mut client = KVClient::connect_tls(addr, &connector).unwrap();
+        assert_eq!(client.chunktp_version(), 2);
+        let large = Value::from_bytes(&vec![7; 300000]);
+        client.do_put(&Key::from_bytes(b"large"), &large).unwrap();
+        assert_eq!(client.do_get(&Key::from_bytes(b"large"), |v| v).unwrap(), Some(large));
+        let requests = (0..1000).map(|i| Request::Put(gen_key_n(i), gen_value())).collect();
+        assert_eq!(client.do_pipeline(requests).unwrap().len(), 1000);
+        let scanned = client.do_scan(&gen_key_n(0), &gen_key_n(1000), |ps| ps.len()).unwrap();
+        assert_eq!(scanned.iter().sum::<usize>(), 1000);
+
+        client.do_close();
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn test_handle_auth() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users");
+        // password and token digests of "secret"
+        let password = "pbkdf2-sha256 1000 73616c7473616c74 86047d1ecaad2aea56c699eff32f7d4eb3c36a34d3ffd3dc49394d69fa5d2d74";
+        let token = "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b";
+        fs::write(&path, format!("user admin\npassword {}\nallow read-write all\n\
+                                  user reader\ntoken {}\nallow read-only prefix pub/\n", password, token)).unwrap();
+        let mut config = KVServerConfig::from_default();
+        assert!(config.users().unwrap().is_none());
+        config.users_file = Some(path.to_str().unwrap().to_owned());
+        let users = config.users().unwrap().map(Arc::new);
+        let (listener, connector) = duplex_listener();
+        let engine = Arc::new(MemoryEngine::new(DataLayout::variable()));
+        let t = thread::spawn(move || serve(listener, engine, users, 2));
+        let (public, private) = (Key::from_bytes(b"pub/1"), Key::from_bytes(b"private"));
+        let value = Value::from_bytes(b"value");
+
+        // nothing is allowed before authenticating, and wrong credentials close the connection
+        let mut client = KVClient::connect_with(|| connector.connect()).unwrap();
+        assert!(client.do_get(&public, |v| v).is_err());
+        assert!(client.do_get(&public, |v| v).is_err());
+        let mut client = KVClient::connect_with(|| connector.connect()).unwrap();
+        assert!(client.do_authenticate(&Credentials::Password("admin".to_owned(), "wrong".to_owned())).is_err());
+        assert!(client.do_get(&public, |v| v).is_err());
+        let mut client = KVClient::connect_with(|| connector.connect()).unwrap();
+        let oversized = "x".repeat(AUTH_MESSAGE_MAX_SIZE);
+        assert!(client.do_authenticate(&Credentials::Password("admin".to_owned(), oversized)).is_err());
+        assert!(client.do_get(&public, |v| v).is_err());
+
+        let mut admin = KVClient::connect_with(|| connector.connect()).unwrap();
+        admin.do_authenticate(&Credentials::Password("admin".to_owned(), "secret".to_owned())).unwrap();
+        assert!(admin.do_authenticate(&Credentials::Token("secret".to_owned())).is_err());
+        admin.do_put(&public, &value).unwrap();
+        admin.do_put(&private, &value).unwrap();
+
+        // reads out of its prefix and writes are denied, the connection goes on
+        let mut reader = KVClient::connect_with(|| connector.connect()).unwrap();
+        reader.do_authenticate(&Credentials::Token("secret".to_owned())).unwrap();
+        assert_eq!(reader.do_get(&public, |v| v).unwrap(), Some(value.clone()));
+        let error = reader.do_get(&private, |v| v).unwrap_err();
+        assert!(error.is::<ServerError>() && error.to_string().contains("access denied"));
+        assert!(reader.do_put(&public, &value).is_err());
+        assert!(reader.do_delete(&public, |rows| rows).is_err());
+        assert!(reader.do_scan(&Key::from_bytes(b"a"), &Key::from_bytes(b"z"), |ps| ps.len()).is_err());
+        assert_eq!(reader.do_scan_prefix(b"pub/", |ps| ps.len()).unwrap(), vec![1]);
+        let replies = reader.do_pipeline(vec![Request::Get(public.clone()), Request::Get(private.clone())]).unwrap();
+        assert!(matches!(replies[0][..], [ReplyChunk::SingleValue(Some(_))]));
+        assert!(matches!(replies[1][..], [ReplyChunk::Denied]));
+        assert_eq!(admin.do_get(&private, |v| v).unwrap(), Some(value));
+
+        admin.do_close();
+        reader.do_close();
+        drop((client, connector));
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn test_handle_batch() {
+        let storage_engine = Arc::new(LogEngine::new(KVStorage::new(tempfile::tempfile().unwrap())));
+        let storage_engine_clone = storage_engine.clone();
+        let (stream, server_stream) = duplex();
+        let t = thread::spawn(move || {
+            handle_connection(server_stream, storage_engine_clone, None).unwrap();
+        });
+
+        let mut client = KVClient::new(stream);
+        let (data, index) = (gen_value(), gen_value());
+        client.do_put(&gen_key_n(1), &gen_value()).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put(gen_key_n(0), data.clone()).put(gen_key_n(2), index.clone()).delete(gen_key_n(1));
+        client.do_batch(&batch).unwrap();
+
+        // a batch with a value that does not fit the layout is refused as a whole
+        let mut batch = WriteBatch::new();
+        batch.delete(gen_key_n(0)).put(gen_key_n(3), Value::from_bytes(b"short"));
+        assert!(client.do_batch(&batch).is_err());
+
+        client.do_close();
+        t.join().unwrap();
+        assert_eq!(storage_engine.get(&gen_key_n(0)).unwrap().unwrap().deref(), &data);
+        assert_eq!(storage_engine.get(&gen_key_n(2)).unwrap().unwrap().deref(), &index);
+        assert!(storage_engine.get(&gen_key_n(1)).unwrap().is_none());
+        assert!(storage_engine.get(&gen_key_n(3)).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_handle_transaction() {
+        let storage_engine: Arc<dyn StorageEngine> = Arc::new(MemoryEngine::new(DataLayout::variable()));
+        let (listener, connector) = duplex_listener();
+        let t = thread::spawn(move || {
+            let mut handles = Vec::new();
+            for _ in 0..2 {
+                let stream = listener.accept().unwrap();
+                let storage_engine = storage_engine.clone();
+                handles.push(thread::spawn(move || handle_connection(stream, storage_engine, None).unwrap()));
+            }
+            for handle in handles {
+                handle.join().unwrap();
+            }
+        });
+
+        let mut client = KVClient::new(connector.connect().unwrap());
+        let mut other = KVClient::new(connector.connect().unwrap());
+        let counter = Key::from_bytes(b"counter");
+        other.do_put(&counter, &Value::from_bytes(&[1])).unwrap();
+
+        // the other client sneaks in a write during the first attempt, which has to be retried
+        let mut attempts = 0;
+        let count = client.do_transaction(4, |txn| {
+            attempts += 1;
+            let count = txn.get(&counter)?.unwrap().data[0];
+            if attempts == 1 {
+                other.do_put(&counter, &Value::from_bytes(&[10]))?;
+            }
+            txn.put(&counter, &Value::from_bytes(&[count + 1]))?;
+            assert_eq!(txn.get(&counter)?.unwrap().data, vec![count + 1]);
+            Ok(count + 1)
+        }).unwrap();
+        assert_eq!((attempts, count), (2, 11));
+        assert_eq!(other.do_get(&counter, |v| v).unwrap().unwrap().data, vec![11]);
+
+        // an error aborts the transaction, nothing gets written
+        let result: Result<(), _> = client.do_transaction(4, |txn| {
+            txn.put(&counter, &Value::from_bytes(&[0]))?;
+            txn.delete(&Key::from_bytes(b"missing"))?;
+            Err(Box::new(ServerError::new("changed my mind")) as Box<dyn Error>)
+        });
+        assert!(result.is_err());
+        assert_eq!(client.do_get(&counter, |v| v).unwrap().unwrap().data, vec![11]);
+
+        // conflicting every time gives up eventually
+        let mut value = 20;
+        let result = client.do_transaction(3, |txn| {
+            txn.get(&counter)?;
+            value += 1;
+            other.do_put(&counter, &Value::from_bytes(&[value]))
+        });
+        assert!(result.is_err());
+        assert_eq!(value, 23);
+
+        client.do_close();
+        other.do_close();
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn test_handle_compare_and_swap() {
+        let storage_engine: Arc<dyn StorageEngine> = Arc::new(MemoryEngine::new(DataLayout::variable()));
+        let (stream, server_stream) = duplex();
+        let t = thread::spawn(move || {
+            handle_connection(server_stream, storage_engine, None).unwrap();
+        });
+
+        let mut client = KVClient::new(stream);
+        let lock = Key::from_bytes(b"lock");
+        let (owner1, owner2) = (Value::from_bytes(b"owner 1"), Value::from_bytes(b"owner 2"));
+
+        // a lock taken by one owner cannot be taken or released by another
+        assert_eq!(client.do_put_if_absent(&lock, &owner1).unwrap(), Ok(()));
+        assert_eq!(client.do_put_if_absent(&lock, &owner2).unwrap(), Err(Some(owner1.clone())));
+        assert_eq!(client.do_delete_if_equals(&lock, &owner2).unwrap(), Err(Some(owner1.clone())));
+        assert_eq!(client.do_compare_and_swap(&lock, Some(&owner1), &owner2).unwrap(), Ok(()));
+        assert_eq!(client.do_compare_and_swap(&lock, None, &owner1).unwrap(), Err(Some(owner2.clone())));
+        assert_eq!(client.do_delete_if_equals(&lock, &owner2).unwrap(), Ok(()));
+        assert_eq!(client.do_delete_if_equals(&lock, &owner2).unwrap(), Err(None));
+        assert_eq!(client.do_compare_and_swap(&lock, None, &owner1).unwrap(), Ok(()));
+        assert_eq!(client.do_get(&lock, |v| v).unwrap().unwrap(), owner1);
+
+        // within a transaction, the swap is only written on commit
+        client.do_transaction(1, |txn| {
+            assert_eq!(txn.compare_and_swap(&lock, Some(&owner1), &owner2)?, Ok(()));
+            assert_eq!(txn.compare_and_swap(&lock, Some(&owner1), &owner2)?, Err(Some(owner2.clone())));
+            Ok(())
+        }).unwrap();
+        assert_eq!(client.do_get(&lock, |v| v).unwrap().unwrap(), owner2);
+
+        client.do_close();
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn test_handle_snapshot() {
+        let storage = KVStorage::new(tempfile::tempfile().unwrap());
+        let engine = LogEngine::new(storage);
+        let storage = engine.storage();
+        let storage_engine: Arc<dyn StorageEngine> = Arc::new(engine);
+        let (listener, connector) = duplex_listener();
+        let t = thread::spawn(move || {
+            for _ in 0..2 {
+                let stream = listener.accept().unwrap();
+                handle_connection(stream, storage_engine.clone(), None).unwrap();
+            }
+        });
+
+        let mut client = KVClient::new(connector.connect().unwrap());
+        let values = (0..16).map(|_| gen_value()).collect::<Vec<_>>();
+        for (i, value) in values.iter().enumerate() {
+            client.do_put(&gen_key_n(i as u64), value).unwrap();
+        }
+        let snapshot = client.do_open_snapshot().unwrap();
+        client.do_put(&gen_key_n(16), &gen_value()).unwrap();
+        let other = client.do_open_snapshot().unwrap();
+        for i in 0..16 {
+            client.do_put(&gen_key_n(i), &gen_value()).unwrap();
+        }
+        client.do_delete(&gen_key_n(0), |_| ()).unwrap();
+
+        assert_eq!(client.do_get_at(snapshot, &gen_key_n(0), |v| v).unwrap().unwrap(), values[0]);
+        assert!(client.do_get(&gen_key_n(0), |v| v).unwrap().is_none());
+        let scanned = client.do_scan_at(snapshot, &gen_key_n(0), &gen_key_n(16), |pairs| pairs).unwrap().concat();
+        assert_eq!(scanned.into_iter().map(|(_, value)| value).collect::<Vec<_>>(), values);
+        client.do_release_snapshot(snapshot).unwrap();
+        assert!(client.do_release_snapshot(snapshot).is_err());
+        assert!(client.do_get_at(u64::MAX, &gen_key_n(0), |v| v).is_err());
+        assert_eq!(storage.read().unwrap().read_snapshots(), 1);
+
+        // snapshots left open are released when the connection ends
+        client.do_close();
+        let mut client = KVClient::new(connector.connect().unwrap());
+        assert!(client.do_get_at(other, &gen_key_n(0), |v| v).is_err());
+        assert_eq!(storage.read().unwrap().read_snapshots(), 0);
+        assert_eq!(storage.read().unwrap().old_versions(), 0);
+        client.do_close();
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn test_handle_expiry() {
+        let storage_engine = Arc::new(MemoryEngine::new(DataLayout::Fixed));
+        let storage_engine_clone = storage_engine.clone();
+        let (stream, server_stream) = duplex();
+        let t = thread::spawn(move || {
+            handle_connection(server_stream, storage_engine_clone, None).unwrap();
+        });
+
+        let mut client = KVClient::new(stream);
+        let (key1, key2, value) = (gen_key_n(1), gen_key_n(2), gen_value());
+        client.do_put_with_ttl(&key1, &value, Duration::from_millis(100)).unwrap();
+        client.do_put(&key2, &value).unwrap();
+        assert_eq!(client.do_ttl(&key2).unwrap(), Ttl::Persistent);
+        assert_eq!(client.do_expire(&key2, Duration::from_secs(60)).unwrap(), 1);
+        assert!(matches!(client.do_ttl(&key2).unwrap(), Ttl::Expires(left) if left.as_secs() >= 59));
+        assert_eq!(client.do_persist(&key2).unwrap(), 1);
+        assert_eq!(client.do_expire(&gen_key_n(3), Duration::from_secs(60)).unwrap(), 0);
+        assert!(client.do_put_with_ttl(&key1, &Value::from_bytes(b"short"), Duration::from_secs(1)).is_err());
+
+        thread::sleep(Duration::from_millis(150));
+        assert!(client.do_get(&key1, |v| v).unwrap().is_none());
+        assert_eq!(client.do_ttl(&key1).unwrap(), Ttl::NoValue);
+        assert_eq!(storage_engine.reap_expired().unwrap(), 1);
+        assert_eq!(storage_engine.ttl(&key2).unwrap(), Ttl::Persistent);
+
+        client.do_close();
+        t.join().unwrap();
+    }
 }
@@ -5,12 +5,16 @@
 //! `ServerReplyChunk` APIs to serialize its reply chunks. The client can then use `ReplyChunk` APIs
 //! to deserialize a server reply chunk.
 
-use crate::kvstorage::{Key, Value, KEY_SIZE, VALUE_SIZE};
+use crate::kvstorage::{Key, Value, WriteBatch, BatchOp, Ttl, KEY_SIZE, VALUE_SIZE};
+use crate::kvstorage::scan::ScanOptions;
+use crate::kvserver::auth::Credentials;
+use crate::chunktps::{ChunktpConnection, Transport};
 
 use std::sync::Arc;
 use std::fmt;
 use std::fmt::{Display, Formatter};
 use std::error::Error;
+use std::time::Duration;
 
 /// The error type used by protocol module
 #[derive(Debug)]
@@ -39,12 +43,61 @@ impl Error for ProtocolError {
 /// constant can thus be used for "data per chunk" evaluation conveniently.
 pub const KV_PAIR_SERIALIZED_SIZE: usize = KEY_SIZE + VALUE_SIZE;
 
+/// Max size of a message reassembled from fragments by `read_message`
+pub const MESSAGE_MAX_SIZE: usize = 128 * 1024 * 1024;
+
+/// Max size of the authentication request a server reads from a client it does not trust yet
+pub const AUTH_MESSAGE_MAX_SIZE: usize = 4 * 1024;
+
 const SCAN: u8 = b'S';
 const PUT: u8 = b'P';
 const GET: u8 = b'G';
 const DEL: u8 = b'D';
 const CLOSE: u8 = b'C';
 
+const SCAN_VAR: u8 = b's';
+const PUT_VAR: u8 = b'p';
+const GET_VAR: u8 = b'g';
+const DEL_VAR: u8 = b'd';
+
+const BATCH: u8 = b'B';
+
+const BEGIN: u8 = b'T';
+const COMMIT: u8 = b'K';
+const ABORT: u8 = b'X';
+
+const COMPARE_AND_SWAP: u8 = b'W';
+const PUT_IF_ABSENT: u8 = b'I';
+const DELETE_IF_EQUALS: u8 = b'E';
+
+const OPEN_SNAPSHOT: u8 = b'O';
+const GET_AT: u8 = b'Q';
+const SCAN_AT: u8 = b'Z';
+const RELEASE_SNAPSHOT: u8 = b'R';
+
+const PUT_WITH_TTL: u8 = b'L';
+const EXPIRE: u8 = b'Y';
+const PERSIST: u8 = b'V';
+const TTL: u8 = b'J';
+
+const SCAN_WITH_OPTIONS: u8 = b'F';
+const SCAN_KEYS: u8 = b'H';
+const COUNT: u8 = b'N';
+const SCAN_PREFIX: u8 = b'A';
+
+const PIPELINE: u8 = b'U';
+const PIPELINED: u8 = b'M';
+
+const AUTHENTICATE: u8 = b'a';
+
+const CREDENTIALS_PASSWORD: u8 = 0;
+const CREDENTIALS_TOKEN: u8 = 1;
+
+const SCAN_DESCENDING: u8 = 1;
+const SCAN_EXCLUDE_START: u8 = 2;
+const SCAN_INCLUDE_END: u8 = 4;
+const SCAN_CONTINUATION: u8 = 8;
+
 // Request format
 //  -- 1 byte functionality
 //     'S'
@@ -58,13 +111,158 @@ const CLOSE: u8 = b'C';
 //     'D'
 //     -- KEY_SIZE key
 //     'C'
+//
+// Keys and values of other sizes use the lower case functionality ('s', 'p', 'g', 'd') instead,
+// with the same fields, each of them prefixed by its 4 bytes length in big endian. Requests that
+// fit the fixed sizes are always sent in the upper case form, so that servers and clients that only
+// know about fixed sizes keep working.
+//
+// A batch of puts and deletes, applied atomically, is sent as
+//     'B'
+//     -- operations, one after another until the end of the request
+//        -- 'p' followed by the length prefixed key and value, or
+//        -- 'd' followed by the length prefixed key
+//
+// Transactions are begun by 'T', and ended by either 'K' (commit) or 'X' (abort), none of which
+// carries anything else. Requests in between are part of the transaction.
+//
+// Conditional writes always use length prefixed fields
+//     'W' (compare and swap)
+//     -- key
+//     -- 1 byte, 1 if the expected value follows, 0 if the key is expected to hold no value
+//     -- expected value, if any
+//     -- new value
+//     'I' (put if absent)
+//     -- key
+//     -- value
+//     'E' (delete if equals)
+//     -- key
+//     -- expected value
+//
+// Read snapshots are opened by 'O', which carries nothing else. The other snapshot requests start
+// with the 8 bytes snapshot id in big endian, followed by length prefixed fields
+//     'Q' (get at snapshot)
+//     -- key
+//     'Z' (scan at snapshot)
+//     -- key1
+//     -- key2
+//     'R' (release snapshot)
+//
+// Expiry requests always use length prefixed fields, TTLs are 8 bytes numbers of milliseconds in
+// big endian
+//     'L' (put with TTL)
+//     -- TTL
+//     -- key
+//     -- value
+//     'Y' (expire)
+//     -- TTL
+//     -- key
+//     'V' (persist)
+//     -- key
+//     'J' (TTL)
+//     -- key
+//
+// Scans with options other than the default ones (see `ScanOptions`) always use length prefixed
+// fields
+//     'F'
+//     -- 1 byte flags, or-ed together: 1 descending, 2 key1 excluded, 4 key2 included, 8 a
+//        continuation token follows
+//     -- 8 bytes limit in big endian, 0 for no limit
+//     -- key1
+//     -- key2
+//     -- continuation token, if any
+//
+// Scans of keys only ('H'), and counts of keys ('N'), always use length prefixed fields
+//     -- key1
+//     -- key2
+//
+// Scans of the keys starting with a prefix always use length prefixed fields
+//     'A'
+//     -- prefix
+//
+// A pipeline is started by 'U', which carries nothing else. Once replied with 'A' (success),
+// neither end acknowledges chunks any more, and the client sends requests tagged with ids
+//     'M'
+//     -- 4 bytes request id in big endian
+//     -- the request itself
+// The pipeline is ended by an empty message from the client, after which chunks are acknowledged
+// again.
+//
+// Authentication always uses length prefixed fields
+//     'a'
+//     -- 1 byte kind of credentials, 0 for a password, 1 for a token
+//     -- user name, only for a password
+//     -- password or token
 
 /// A request sent by client or received by server, see its enumerators for further information
 pub enum Request {
-    Scan(Key, Key),
+    /// Scans the interval as told by the options, replied with `KVPairs` chunks, followed by a
+    /// `Continuation` chunk if the scan has been cut short by its limit, and an empty chunk. The
+    /// chunks are streamed while the interval is read in batches, writes made meanwhile may show
+    /// up in later batches (scan at a snapshot for a consistent view)
+    Scan(Key, Key, ScanOptions),
     Put(Key, Value),
     Get(Key),
     Del(Key),
+    /// Puts and deletes to be applied atomically, replied with `Success` or `Error`
+    Batch(WriteBatch),
+    /// Begins a transaction, replied with `Success`, or `Error` if one is already going on. Until
+    /// it ends, gets, puts, deletes and batches of the connection are part of the transaction,
+    /// while scans keep reading committed data
+    Begin,
+    /// Commits the transaction going on, replied with `Success`, or with `Conflict` if a key it
+    /// has read has changed since, in which case nothing is written
+    Commit,
+    /// Drops the transaction going on without writing anything, replied with `Success`
+    Abort,
+    /// Puts the new value if the key currently holds the expected one (`None` meaning no value at
+    /// all), atomically. Replied with `Success` if the value is swapped, or with `Mismatch` and the
+    /// current value otherwise
+    CompareAndSwap(Key, Option<Value>, Value),
+    /// Puts the value if the key holds none, replied like `CompareAndSwap`
+    PutIfAbsent(Key, Value),
+    /// Deletes the key if it holds the expected value, replied like `CompareAndSwap`
+    DeleteIfEquals(Key, Value),
+    /// Opens a read snapshot, replied with its id as a `Number`, or `Error`. The snapshot stays
+    /// open until released, or until the connection is closed
+    OpenSnapshot,
+    /// Gets the value of the key as seen by the snapshot of the given id, replied like `Get`
+    GetAt(u64, Key),
+    /// Scans the interval as seen by the snapshot of the given id, replied like `Scan`
+    ScanAt(u64, Key, Key),
+    /// Releases the snapshot of the given id, replied with `Success`, or `Error` if the connection
+    /// has no such snapshot open
+    ReleaseSnapshot(u64),
+    /// Puts the key - value pair, which expires after the given milliseconds, replied like `Put`
+    PutWithTtl(Key, Value, u64),
+    /// Makes the value of the key expire after the given milliseconds, replied with the rows
+    /// affected as a `Number`
+    Expire(Key, u64),
+    /// Makes the value of the key never expire, replied with the rows affected as a `Number`
+    Persist(Key),
+    /// Asks for the time left before the value of the key expires, replied with `Ttl`
+    Ttl(Key),
+    /// Scans the keys holding a value within the interval, replied with `Keys` chunks followed by
+    /// an empty chunk
+    ScanKeys(Key, Key),
+    /// Counts the keys holding a value within the interval, replied with a `Number`
+    Count(Key, Key),
+    /// Scans the keys starting with the prefix, replied like `Scan` with default options
+    ScanPrefix(Vec<u8>),
+    /// Starts a pipeline, replied with `Success` (or `Error` during a transaction). Chunks are no
+    /// longer acknowledged from then on, and the client sends `Pipelined` requests, followed by an
+    /// empty message. The server replies each of them with `Pipelined` chunks, in any order, and
+    /// then an empty chunk once all of them have been replied, after which chunks are acknowledged
+    /// again
+    Pipeline,
+    /// A request of a pipeline tagged with its id, which the chunks replying it are tagged with.
+    /// Requests of a pipeline are handled concurrently, in no particular order. Transactions, nested
+    /// pipelines and `Close` cannot be pipelined and are replied with `Error`
+    Pipelined(u32, Box<Request>),
+    /// Authenticates the connection, replied with `Success`, or with `Denied` after which the
+    /// server closes the connection. Servers with users (see the `auth` module) deny any other
+    /// request until then, servers without users reply `Success` to any credentials
+    Authenticate(Credentials),
     Close
 }
 
@@ -72,26 +270,195 @@ impl Request {
     /// Serialize a `Request` into a byte buffer
     pub fn serialize(&self) -> Vec<u8> {
         match self {
-            Request::Scan(key1, key2) => {
-                let mut ret = vec![SCAN];
-                ret.append(&mut key1.serialize());
-                ret.append(&mut key2.serialize());
+            Request::Scan(key1, key2, options) if !options.is_default() => {
+                let mut flags = 0;
+                if options.descending {
+                    flags |= SCAN_DESCENDING;
+                }
+                if !options.include_start {
+                    flags |= SCAN_EXCLUDE_START;
+                }
+                if options.include_end {
+                    flags |= SCAN_INCLUDE_END;
+                }
+                if options.continuation.is_some() {
+                    flags |= SCAN_CONTINUATION;
+                }
+                let mut ret = vec![SCAN_WITH_OPTIONS, flags];
+                ret.extend_from_slice(&(options.limit.unwrap_or(0) as u64).to_be_bytes());
+                put_field(&mut ret, &key1.data);
+                put_field(&mut ret, &key2.data);
+                if let Some(token) = &options.continuation {
+                    put_field(&mut ret, &token.data);
+                }
                 ret
             },
+            Request::Scan(key1, key2, _) => {
+                if is_fixed_key(key1) && is_fixed_key(key2) {
+                    let mut ret = vec![SCAN];
+                    ret.append(&mut key1.serialize());
+                    ret.append(&mut key2.serialize());
+                    ret
+                } else {
+                    let mut ret = vec![SCAN_VAR];
+                    put_field(&mut ret, &key1.data);
+                    put_field(&mut ret, &key2.data);
+                    ret
+                }
+            },
             Request::Put(key, value) => {
-                let mut ret = vec![PUT];
-                ret.append(&mut key.serialize());
-                ret.append(&mut value.serialize());
-                ret
+                if is_fixed_key(key) && is_fixed_value(value) {
+                    let mut ret = vec![PUT];
+                    ret.append(&mut key.serialize());
+                    ret.append(&mut value.serialize());
+                    ret
+                } else {
+                    let mut ret = vec![PUT_VAR];
+                    put_field(&mut ret, &key.data);
+                    put_field(&mut ret, &value.data);
+                    ret
+                }
             },
             Request::Get(key) => {
-                let mut ret = vec![GET];
-                ret.append(&mut key.serialize());
-                ret
+                Request::serialize_single_key(GET, GET_VAR, key)
             },
             Request::Del(key) => {
-                let mut ret = vec![DEL];
-                ret.append(&mut key.serialize());
+                Request::serialize_single_key(DEL, DEL_VAR, key)
+            },
+            Request::Batch(batch) => {
+                let mut ret = vec![BATCH];
+                for op in batch.ops() {
+                    match op {
+                        BatchOp::Put(key, value) => {
+                            ret.push(PUT_VAR);
+                            put_field(&mut ret, &key.data);
+                            put_field(&mut ret, &value.data);
+                        },
+                        BatchOp::Delete(key) => {
+                            ret.push(DEL_VAR);
+                            put_field(&mut ret, &key.data);
+                        }
+                    }
+                }
+                ret
+            },
+            Request::Begin => {
+                vec![BEGIN]
+            },
+            Request::Commit => {
+                vec![COMMIT]
+            },
+            Request::Abort => {
+                vec![ABORT]
+            },
+            Request::CompareAndSwap(key, expected, new) => {
+                let mut ret = vec![COMPARE_AND_SWAP];
+                put_field(&mut ret, &key.data);
+                match expected {
+                    Some(expected) => {
+                        ret.push(1);
+                        put_field(&mut ret, &expected.data);
+                    },
+                    None => ret.push(0)
+                }
+                put_field(&mut ret, &new.data);
+                ret
+            },
+            Request::PutIfAbsent(key, value) => {
+                let mut ret = vec![PUT_IF_ABSENT];
+                put_field(&mut ret, &key.data);
+                put_field(&mut ret, &value.data);
+                ret
+            },
+            Request::DeleteIfEquals(key, expected) => {
+                let mut ret = vec![DELETE_IF_EQUALS];
+                put_field(&mut ret, &key.data);
+                put_field(&mut ret, &expected.data);
+                ret
+            },
+            Request::OpenSnapshot => {
+                vec![OPEN_SNAPSHOT]
+            },
+            Request::GetAt(snapshot, key) => {
+                let mut ret = vec![GET_AT];
+                ret.extend_from_slice(&snapshot.to_be_bytes());
+                put_field(&mut ret, &key.data);
+                ret
+            },
+            Request::ScanAt(snapshot, key1, key2) => {
+                let mut ret = vec![SCAN_AT];
+                ret.extend_from_slice(&snapshot.to_be_bytes());
+                put_field(&mut ret, &key1.data);
+                put_field(&mut ret, &key2.data);
+                ret
+            },
+            Request::ReleaseSnapshot(snapshot) => {
+                let mut ret = vec![RELEASE_SNAPSHOT];
+                ret.extend_from_slice(&snapshot.to_be_bytes());
+                ret
+            },
+            Request::PutWithTtl(key, value, ttl) => {
+                let mut ret = vec![PUT_WITH_TTL];
+                ret.extend_from_slice(&ttl.to_be_bytes());
+                put_field(&mut ret, &key.data);
+                put_field(&mut ret, &value.data);
+                ret
+            },
+            Request::Expire(key, ttl) => {
+                let mut ret = vec![EXPIRE];
+                ret.extend_from_slice(&ttl.to_be_bytes());
+                put_field(&mut ret, &key.data);
+                ret
+            },
+            Request::Persist(key) => {
+                let mut ret = vec![PERSIST];
+                put_field(&mut ret, &key.data);
+                ret
+            },
+            Request::Ttl(key) => {
+                let mut ret = vec![TTL];
+                put_field(&mut ret, &key.data);
+                ret
+            },
+            Request::ScanKeys(key1, key2) => {
+                let mut ret = vec![SCAN_KEYS];
+                put_field(&mut ret, &key1.data);
+                put_field(&mut ret, &key2.data);
+                ret
+            },
+            Request::Count(key1, key2) => {
+                let mut ret = vec![COUNT];
+                put_field(&mut ret, &key1.data);
+                put_field(&mut ret, &key2.data);
+                ret
+            },
+            Request::ScanPrefix(prefix) => {
+                let mut ret = vec![SCAN_PREFIX];
+                put_field(&mut ret, prefix);
+                ret
+            },
+            Request::Pipeline => {
+                vec![PIPELINE]
+            },
+            Request::Pipelined(id, request) => {
+                let mut ret = vec![PIPELINED];
+                ret.extend_from_slice(&id.to_be_bytes());
+                ret.append(&mut request.serialize());
+                ret
+            },
+            Request::Authenticate(credentials) => {
+                let mut ret = vec![AUTHENTICATE];
+                match credentials {
+                    Credentials::Password(user, password) => {
+                        ret.push(CREDENTIALS_PASSWORD);
+                        put_field(&mut ret, user.as_bytes());
+                        put_field(&mut ret, password.as_bytes());
+                    },
+                    Credentials::Token(token) => {
+                        ret.push(CREDENTIALS_TOKEN);
+                        put_field(&mut ret, token.as_bytes());
+                    }
+                }
                 ret
             },
             Request::Close => {
@@ -100,11 +467,23 @@ impl Request {
         }
     }
 
+    fn serialize_single_key(fixed: u8, variable: u8, key: &Key) -> Vec<u8> {
+        if is_fixed_key(key) {
+            let mut ret = vec![fixed];
+            ret.append(&mut key.serialize());
+            ret
+        } else {
+            let mut ret = vec![variable];
+            put_field(&mut ret, &key.data);
+            ret
+        }
+    }
+
     /// Deserialize a byte buffer and construct a `Request` enum.
     ///
     /// Fails if the buffer does not meet the format of a `Request`, panics if the buffer is empty
     pub fn deserialize_from(raw: Vec<u8>) -> Result<Self, ProtocolError> {
-        assert!(raw.len() > 0);
+        assert!(!raw.is_empty());
         match raw[0] {
             SCAN => {
                 if raw.len() != 1 + KEY_SIZE * 2 {
@@ -112,7 +491,7 @@ impl Request {
                 } else {
                     let key1 = Key::from_slice(&raw[1..1+KEY_SIZE]);
                     let key2 = Key::from_slice(&raw[1+KEY_SIZE..1+KEY_SIZE*2]);
-                    Ok(Request::Scan(key1, key2))
+                    Ok(Request::Scan(key1, key2, ScanOptions::from_default()))
                 }
             },
             PUT => {
@@ -140,9 +519,163 @@ impl Request {
                     Ok(Request::Del(key))
                 }
             },
-            CLOSE=> {
+            CLOSE => {
                 Ok(Request::Close)
-            }
+            },
+            BEGIN | COMMIT | ABORT | OPEN_SNAPSHOT | PIPELINE => {
+                if raw.len() != 1 {
+                    return Err(ProtocolError::new("incorrect content length"));
+                }
+                match raw[0] {
+                    BEGIN => Ok(Request::Begin),
+                    COMMIT => Ok(Request::Commit),
+                    ABORT => Ok(Request::Abort),
+                    OPEN_SNAPSHOT => Ok(Request::OpenSnapshot),
+                    _ => Ok(Request::Pipeline)
+                }
+            },
+            PIPELINED => {
+                if raw.len() < 1 + 4 + 1 || raw[1 + 4] == PIPELINED {
+                    return Err(ProtocolError::new("incorrect pipelined request"));
+                }
+                let mut id = [0u8; 4];
+                id.copy_from_slice(&raw[1..1+4]);
+                let request = Request::deserialize_from(raw[1+4..].to_vec())?;
+                Ok(Request::Pipelined(u32::from_be_bytes(id), Box::new(request)))
+            },
+            GET_AT | SCAN_AT | RELEASE_SNAPSHOT | PUT_WITH_TTL | EXPIRE => {
+                let mut fields = FieldReader { raw: &raw, pos: 1 };
+                let number = fields.next_number()?;
+                let ret = match raw[0] {
+                    GET_AT => Request::GetAt(number, Key::from_bytes(fields.next()?)),
+                    SCAN_AT => {
+                        let key1 = Key::from_bytes(fields.next()?);
+                        Request::ScanAt(number, key1, Key::from_bytes(fields.next()?))
+                    },
+                    RELEASE_SNAPSHOT => Request::ReleaseSnapshot(number),
+                    PUT_WITH_TTL => {
+                        let key = Key::from_bytes(fields.next()?);
+                        Request::PutWithTtl(key, Value::from_bytes(fields.next()?), number)
+                    },
+                    _ => Request::Expire(Key::from_bytes(fields.next()?), number)
+                };
+                fields.finish()?;
+                Ok(ret)
+            },
+            SCAN_KEYS | COUNT => {
+                let mut fields = FieldReader { raw: &raw, pos: 1 };
+                let key1 = Key::from_bytes(fields.next()?);
+                let key2 = Key::from_bytes(fields.next()?);
+                fields.finish()?;
+                match raw[0] {
+                    SCAN_KEYS => Ok(Request::ScanKeys(key1, key2)),
+                    _ => Ok(Request::Count(key1, key2))
+                }
+            },
+            SCAN_PREFIX => {
+                let mut fields = FieldReader { raw: &raw, pos: 1 };
+                let prefix = fields.next()?.to_vec();
+                fields.finish()?;
+                Ok(Request::ScanPrefix(prefix))
+            },
+            PERSIST | TTL => {
+                let mut fields = FieldReader { raw: &raw, pos: 1 };
+                let key = Key::from_bytes(fields.next()?);
+                fields.finish()?;
+                match raw[0] {
+                    PERSIST => Ok(Request::Persist(key)),
+                    _ => Ok(Request::Ttl(key))
+                }
+            },
+            SCAN_VAR | PUT_VAR | GET_VAR | DEL_VAR => {
+                let mut fields = FieldReader { raw: &raw, pos: 1 };
+                let first = fields.next()?;
+                let ret = match raw[0] {
+                    SCAN_VAR => {
+                        Request::Scan(Key::from_bytes(first), Key::from_bytes(fields.next()?), ScanOptions::from_default())
+                    },
+                    PUT_VAR => Request::Put(Key::from_bytes(first), Value::from_bytes(fields.next()?)),
+                    GET_VAR => Request::Get(Key::from_bytes(first)),
+                    _ => Request::Del(Key::from_bytes(first))
+                };
+                fields.finish()?;
+                Ok(ret)
+            },
+            SCAN_WITH_OPTIONS => {
+                let mut fields = FieldReader { raw: &raw, pos: 1 };
+                let flags = fields.next_byte()?;
+                if flags & !(SCAN_DESCENDING | SCAN_EXCLUDE_START | SCAN_INCLUDE_END | SCAN_CONTINUATION) != 0 {
+                    return Err(ProtocolError::new("incorrect scan flags"));
+                }
+                let limit = fields.next_number()?;
+                let key1 = Key::from_bytes(fields.next()?);
+                let key2 = Key::from_bytes(fields.next()?);
+                let continuation = match flags & SCAN_CONTINUATION {
+                    0 => None,
+                    _ => Some(Key::from_bytes(fields.next()?))
+                };
+                fields.finish()?;
+                let options = ScanOptions {
+                    descending: flags & SCAN_DESCENDING != 0,
+                    limit: if limit == 0 { None } else { Some(limit as usize) },
+                    include_start: flags & SCAN_EXCLUDE_START == 0,
+                    include_end: flags & SCAN_INCLUDE_END != 0,
+                    continuation
+                };
+                Ok(Request::Scan(key1, key2, options))
+            },
+            BATCH => {
+                let mut fields = FieldReader { raw: &raw, pos: 1 };
+                let mut batch = WriteBatch::new();
+                while !fields.is_empty() {
+                    match fields.next_byte()? {
+                        PUT_VAR => {
+                            let key = Key::from_bytes(fields.next()?);
+                            batch.put(key, Value::from_bytes(fields.next()?));
+                        },
+                        DEL_VAR => {
+                            batch.delete(Key::from_bytes(fields.next()?));
+                        },
+                        _ => return Err(ProtocolError::new("incorrect batch operation"))
+                    }
+                }
+                Ok(Request::Batch(batch))
+            },
+            COMPARE_AND_SWAP => {
+                let mut fields = FieldReader { raw: &raw, pos: 1 };
+                let key = Key::from_bytes(fields.next()?);
+                let expected = match fields.next_byte()? {
+                    0 => None,
+                    1 => Some(Value::from_bytes(fields.next()?)),
+                    _ => return Err(ProtocolError::new("incorrect expected value flag"))
+                };
+                let new = Value::from_bytes(fields.next()?);
+                fields.finish()?;
+                Ok(Request::CompareAndSwap(key, expected, new))
+            },
+            PUT_IF_ABSENT | DELETE_IF_EQUALS => {
+                let mut fields = FieldReader { raw: &raw, pos: 1 };
+                let key = Key::from_bytes(fields.next()?);
+                let value = Value::from_bytes(fields.next()?);
+                fields.finish()?;
+                match raw[0] {
+                    PUT_IF_ABSENT => Ok(Request::PutIfAbsent(key, value)),
+                    _ => Ok(Request::DeleteIfEquals(key, value))
+                }
+            },
+            AUTHENTICATE => {
+                let mut fields = FieldReader { raw: &raw, pos: 1 };
+                let credentials = match fields.next_byte()? {
+                    CREDENTIALS_PASSWORD => {
+                        let user = fields.next_string()?;
+                        Credentials::Password(user, fields.next_string()?)
+                    },
+                    CREDENTIALS_TOKEN => Credentials::Token(fields.next_string()?),
+                    _ => return Err(ProtocolError::new("incorrect credentials kind"))
+                };
+                fields.finish()?;
+                Ok(Request::Authenticate(credentials))
+            },
             _ => {
                 Err(ProtocolError::new("incorrect response chunk identifier"))
             }
@@ -160,12 +693,39 @@ impl Request {
 //    -- multiple KEY_SIZE + VALUE_SIZE key-value pairs
 //    'E'
 //    'A'
+//    'C' (conflict, the transaction could not be committed)
+//    'M' (mismatch, a conditional write found another value)
+//    -- length prefixed current value, nothing if the key holds no value
+//    'T' (time left before a key expires)
+//    -- 1 byte, 0 if the key holds no value, 1 if it never expires, 2 if it expires
+//    -- 8 bytes milliseconds left in big endian, only if it expires
+//    'U' (continuation token of a scan cut short by its limit)
+//    -- length prefixed token
+//    'K' (keys)
+//    -- multiple length prefixed keys
+//    'D' (denied, the request is not allowed to the user, see `Request::Authenticate`)
+//    'I' (reply chunk of a pipelined request)
+//    -- 4 bytes request id in big endian
+//    -- the reply chunk itself, nothing for the empty chunk
+//
+// Like requests, values and key-value pairs of other sizes use the lower case data kind ('s', 'p')
+// with every key and value prefixed by its 4 bytes length in big endian.
 
 const SINGLE_VALUE: u8 = b'S';
 const NUMBER: u8 = b'N';
 const KV_PAIRS: u8 = b'P';
 const ERROR: u8 = b'E';
 const SUCCESS: u8 = b'A';
+const CONFLICT: u8 = b'C';
+const MISMATCH: u8 = b'M';
+const TIME_TO_LIVE: u8 = b'T';
+const CONTINUATION: u8 = b'U';
+const KEYS: u8 = b'K';
+const DENIED: u8 = b'D';
+const PIPELINED_REPLY: u8 = b'I';
+
+const SINGLE_VALUE_VAR: u8 = b's';
+const KV_PAIRS_VAR: u8 = b'p';
 
 /// A reply chunk sent by server, see its enumerators for further information
 ///
@@ -176,7 +736,15 @@ pub enum ServerReplyChunk<'a> {
     Number(usize),
     KVPairs(&'a [(Key, Arc<Value>)]),
     Error,
-    Success
+    Success,
+    Conflict,
+    Mismatch(Option<Arc<Value>>),
+    Ttl(Ttl),
+    Continuation(&'a Key),
+    Keys(&'a [Key]),
+    Denied,
+    /// A serialized reply chunk to the pipelined request of the given id, empty for the empty chunk
+    Pipelined(u32, &'a [u8])
 }
 
 impl ServerReplyChunk<'_> {
@@ -184,11 +752,19 @@ impl ServerReplyChunk<'_> {
     pub fn serialize(&self) -> Vec<u8> {
         match self {
             ServerReplyChunk::SingleValue(value) => {
-                let mut ret = vec![SINGLE_VALUE];
-                if let Some(value) = value {
-                    ret.append(&mut value.serialize());
+                match value {
+                    Some(value) if !is_fixed_value(value) => {
+                        let mut ret = vec![SINGLE_VALUE_VAR];
+                        put_field(&mut ret, &value.data);
+                        ret
+                    },
+                    Some(value) => {
+                        let mut ret = vec![SINGLE_VALUE];
+                        ret.append(&mut value.serialize());
+                        ret
+                    },
+                    None => vec![SINGLE_VALUE]
                 }
-                ret
             },
             ServerReplyChunk::Number(number) => {
                 let mut ret = vec![NUMBER];
@@ -202,23 +778,84 @@ impl ServerReplyChunk<'_> {
                 ret
             },
             ServerReplyChunk::KVPairs(pairs) => {
-                let mut ret = vec![KV_PAIRS];
-                for (key, value) in pairs.iter() {
-                    ret.append(&mut key.serialize());
-                    ret.append(&mut value.serialize());
+                if pairs.iter().all(|(key, value)| is_fixed_key(key) && is_fixed_value(value)) {
+                    let mut ret = vec![KV_PAIRS];
+                    for (key, value) in pairs.iter() {
+                        ret.append(&mut key.serialize());
+                        ret.append(&mut value.serialize());
+                    }
+                    ret
+                } else {
+                    let mut ret = vec![KV_PAIRS_VAR];
+                    for (key, value) in pairs.iter() {
+                        put_field(&mut ret, &key.data);
+                        put_field(&mut ret, &value.data);
+                    }
+                    ret
                 }
-                ret
             },
             ServerReplyChunk::Success => {
                 vec![SUCCESS]
             },
             ServerReplyChunk::Error => {
                 vec![ERROR]
+            },
+            ServerReplyChunk::Conflict => {
+                vec![CONFLICT]
+            },
+            ServerReplyChunk::Mismatch(value) => {
+                let mut ret = vec![MISMATCH];
+                if let Some(value) = value {
+                    put_field(&mut ret, &value.data);
+                }
+                ret
+            },
+            ServerReplyChunk::Ttl(ttl) => {
+                match ttl {
+                    Ttl::NoValue => vec![TIME_TO_LIVE, 0],
+                    Ttl::Persistent => vec![TIME_TO_LIVE, 1],
+                    Ttl::Expires(left) => {
+                        let mut ret = vec![TIME_TO_LIVE, 2];
+                        ret.extend_from_slice(&(left.as_millis() as u64).to_be_bytes());
+                        ret
+                    }
+                }
+            },
+            ServerReplyChunk::Continuation(token) => {
+                let mut ret = vec![CONTINUATION];
+                put_field(&mut ret, &token.data);
+                ret
+            },
+            ServerReplyChunk::Keys(keys) => {
+                let mut ret = vec![KEYS];
+                for key in keys.iter() {
+                    put_field(&mut ret, &key.data);
+                }
+                ret
+            },
+            ServerReplyChunk::Denied => {
+                vec![DENIED]
+            },
+            ServerReplyChunk::Pipelined(id, chunk) => {
+                let mut ret = vec![PIPELINED_REPLY];
+                ret.extend_from_slice(&id.to_be_bytes());
+                ret.extend_from_slice(chunk);
+                ret
             }
         }
     }
 }
 
+/// Size of `key` - `value` pair in a serialized `ServerReplyChunk::KVPairs`, at most
+pub fn kv_pair_serialized_size(key: &Key, value: &Value) -> usize {
+    8 + key.data.len() + value.data.len()
+}
+
+/// Size of `key` in a serialized `ServerReplyChunk::Keys`
+pub fn key_serialized_size(key: &Key) -> usize {
+    4 + key.data.len()
+}
+
 /// A reply chunk received by client, see its enumerators for further information
 ///
 /// The `ReplyChunk` is specially created by client side program to deserialize and resolve reply
@@ -228,7 +865,15 @@ pub enum ReplyChunk {
     Number(usize),
     KVPairs(Vec<(Key, Value)>),
     Success,
-    Error
+    Error,
+    Conflict,
+    Mismatch(Option<Value>),
+    Ttl(Ttl),
+    Continuation(Key),
+    Keys(Vec<Key>),
+    Denied,
+    /// A reply chunk to the pipelined request of the given id, `None` for the empty chunk
+    Pipelined(u32, Option<Box<ReplyChunk>>)
 }
 
 impl ReplyChunk {
@@ -249,12 +894,18 @@ impl ReplyChunk {
                     Err(ProtocolError::new("incorrect content length"))
                 }
             },
+            SINGLE_VALUE_VAR => {
+                let mut fields = FieldReader { raw: &raw, pos: 1 };
+                let ret = Value::from_bytes(fields.next()?);
+                fields.finish()?;
+                Ok(ReplyChunk::SingleValue(Some(ret)))
+            },
             NUMBER => {
-                if raw.len() != 1 + KEY_SIZE {
+                if raw.len() != 1 + 8 {
                     Err(ProtocolError::new("incorrect content length"))
                 } else {
                     let mut ret = 0;
-                    for &byte in raw[1..1+KEY_SIZE].iter() {
+                    for &byte in raw[1..1+8].iter() {
                         ret *= 256;
                         ret += byte as usize;
                     }
@@ -262,8 +913,8 @@ impl ReplyChunk {
                 }
             },
             KV_PAIRS => {
-                if (raw.len() - 1) % (KEY_SIZE + VALUE_SIZE) != 0 {
-                    return Err(ProtocolError::new("incorrect content length"))
+                if !(raw.len() - 1).is_multiple_of(KEY_SIZE + VALUE_SIZE) {
+                    Err(ProtocolError::new("incorrect content length"))
                 } else {
                     let mut ret = Vec::new();
                     for i in (1..raw.len()).step_by(KEY_SIZE + VALUE_SIZE) {
@@ -274,6 +925,16 @@ impl ReplyChunk {
                     Ok(ReplyChunk::KVPairs(ret))
                 }
             },
+            KV_PAIRS_VAR => {
+                let mut fields = FieldReader { raw: &raw, pos: 1 };
+                let mut ret = Vec::new();
+                while !fields.is_empty() {
+                    let key = Key::from_bytes(fields.next()?);
+                    let value = Value::from_bytes(fields.next()?);
+                    ret.push((key, value));
+                }
+                Ok(ReplyChunk::KVPairs(ret))
+            },
             SUCCESS => {
                 if raw.len() != 1 {
                     Err(ProtocolError::new("incorrect content length"))
@@ -287,7 +948,67 @@ impl ReplyChunk {
                 } else {
                     Ok(ReplyChunk::Error)
                 }
-            }
+            },
+            CONFLICT => {
+                if raw.len() != 1 {
+                    Err(ProtocolError::new("incorrect content length"))
+                } else {
+                    Ok(ReplyChunk::Conflict)
+                }
+            },
+            DENIED => {
+                if raw.len() != 1 {
+                    Err(ProtocolError::new("incorrect content length"))
+                } else {
+                    Ok(ReplyChunk::Denied)
+                }
+            },
+            MISMATCH => {
+                if raw.len() == 1 {
+                    return Ok(ReplyChunk::Mismatch(None));
+                }
+                let mut fields = FieldReader { raw: &raw, pos: 1 };
+                let ret = Value::from_bytes(fields.next()?);
+                fields.finish()?;
+                Ok(ReplyChunk::Mismatch(Some(ret)))
+            },
+            TIME_TO_LIVE => {
+                let mut fields = FieldReader { raw: &raw, pos: 1 };
+                let ret = match fields.next_byte()? {
+                    0 => Ttl::NoValue,
+                    1 => Ttl::Persistent,
+                    2 => Ttl::Expires(Duration::from_millis(fields.next_number()?)),
+                    _ => return Err(ProtocolError::new("incorrect time to live kind"))
+                };
+                fields.finish()?;
+                Ok(ReplyChunk::Ttl(ret))
+            },
+            CONTINUATION => {
+                let mut fields = FieldReader { raw: &raw, pos: 1 };
+                let ret = Key::from_bytes(fields.next()?);
+                fields.finish()?;
+                Ok(ReplyChunk::Continuation(ret))
+            },
+            KEYS => {
+                let mut fields = FieldReader { raw: &raw, pos: 1 };
+                let mut ret = Vec::new();
+                while !fields.is_empty() {
+                    ret.push(Key::from_bytes(fields.next()?));
+                }
+                Ok(ReplyChunk::Keys(ret))
+            },
+            PIPELINED_REPLY => {
+                if raw.len() < 1 + 4 || raw.get(1 + 4) == Some(&PIPELINED_REPLY) {
+                    return Err(ProtocolError::new("incorrect pipelined reply chunk"));
+                }
+                let mut id = [0u8; 4];
+                id.copy_from_slice(&raw[1..1+4]);
+                let chunk = match raw.len() {
+                    5 => None,
+                    _ => Some(Box::new(ReplyChunk::deserialize(raw[1+4..].to_vec())?))
+                };
+                Ok(ReplyChunk::Pipelined(u32::from_be_bytes(id), chunk))
+            },
             _ => {
                 Err(ProtocolError::new("incorrect reply chunk identifier"))
             }
@@ -295,9 +1016,147 @@ impl ReplyChunk {
     }
 }
 
+// Messages (requests and reply chunks) larger than a chunk are sent in fragments
+//  -- (n - 1) chunks, each of them
+//     -- 1 byte '~'
+//     -- up to the max chunk size of the connection - 1 bytes of the message
+//  -- 1 chunk
+//     -- 1 byte '$'
+//     -- the rest of the message
+//
+// Messages fitting in a chunk are sent as is, neither '~' nor '$' is a valid request
+// functionality or reply data kind.
+
+const FRAGMENT: u8 = b'~';
+const LAST_FRAGMENT: u8 = b'$';
+
+/// Writes `message` into `chunktps`, in fragments if it does not fit in one chunk
+pub fn write_message<S: Transport>(chunktps: &mut ChunktpConnection<S>, message: Vec<u8>) -> Result<(), Box<dyn Error>> {
+    let max_chunk_size = chunktps.max_chunk_size();
+    if message.len() <= max_chunk_size {
+        return chunktps.write_chunk(message);
+    }
+    let mut fragments = message.chunks(max_chunk_size - 1).peekable();
+    while let Some(fragment) = fragments.next() {
+        let mut chunk = Vec::with_capacity(fragment.len() + 1);
+        chunk.push(if fragments.peek().is_some() { FRAGMENT } else { LAST_FRAGMENT });
+        chunk.extend_from_slice(fragment);
+        chunktps.write_chunk(chunk)?;
+    }
+    Ok(())
+}
+
+/// Reads a message written by `write_message` out of `chunktps`, reassembling its fragments.
+/// An empty chunk gives an empty message
+pub fn read_message<S: Transport>(chunktps: &mut ChunktpConnection<S>) -> Result<Vec<u8>, Box<dyn Error>> {
+    read_message_limited(chunktps, MESSAGE_MAX_SIZE)
+}
+
+/// Same as `read_message`, but fails with messages larger than `limit`, and with chunks larger than
+/// `limit` before they are read at all
+pub fn read_message_limited<S: Transport>(chunktps: &mut ChunktpConnection<S>, limit: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let max_chunk_size = chunktps.max_chunk_size();
+    // one more byte for the fragment marker
+    chunktps.set_read_limit(limit.saturating_add(1));
+    let ret = read_fragments(chunktps, limit);
+    chunktps.set_read_limit(max_chunk_size);
+    ret
+}
+
+fn read_fragments<S: Transport>(chunktps: &mut ChunktpConnection<S>, limit: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut chunk = chunktps.read_chunk()?;
+    if chunk.first() != Some(&FRAGMENT) {
+        if chunk.len() > limit {
+            return Err(Box::new(ProtocolError::new("message too large")));
+        }
+        return Ok(chunk);
+    }
+    let mut ret = Vec::new();
+    loop {
+        match chunk.first() {
+            Some(&FRAGMENT) | Some(&LAST_FRAGMENT) => ret.extend_from_slice(&chunk[1..]),
+            _ => return Err(Box::new(ProtocolError::new("unterminated fragmented message")))
+        }
+        if ret.len() > limit {
+            return Err(Box::new(ProtocolError::new("message too large")));
+        }
+        if chunk[0] == LAST_FRAGMENT {
+            return Ok(ret);
+        }
+        chunk = chunktps.read_chunk()?;
+    }
+}
+
+fn is_fixed_key(key: &Key) -> bool {
+    key.data.len() == KEY_SIZE
+}
+
+fn is_fixed_value(value: &Value) -> bool {
+    value.data.len() == VALUE_SIZE
+}
+
+fn put_field(buffer: &mut Vec<u8>, field: &[u8]) {
+    buffer.extend_from_slice(&(field.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(field);
+}
+
+/// Walks through length prefixed fields of a serialized message
+struct FieldReader<'a> {
+    raw: &'a [u8],
+    pos: usize
+}
+
+impl<'a> FieldReader<'a> {
+    fn next(&mut self) -> Result<&'a [u8], ProtocolError> {
+        if self.raw.len() < self.pos + 4 {
+            return Err(ProtocolError::new("incorrect content length"));
+        }
+        let mut size = [0u8; 4];
+        size.copy_from_slice(&self.raw[self.pos..self.pos+4]);
+        let size = u32::from_be_bytes(size) as usize;
+        let start = self.pos + 4;
+        if self.raw.len() - start < size {
+            return Err(ProtocolError::new("incorrect content length"));
+        }
+        self.pos = start + size;
+        Ok(&self.raw[start..self.pos])
+    }
+
+    fn next_number(&mut self) -> Result<u64, ProtocolError> {
+        if self.raw.len() < self.pos + 8 {
+            return Err(ProtocolError::new("incorrect content length"));
+        }
+        let mut number = [0u8; 8];
+        number.copy_from_slice(&self.raw[self.pos..self.pos+8]);
+        self.pos += 8;
+        Ok(u64::from_be_bytes(number))
+    }
+
+    fn next_string(&mut self) -> Result<String, ProtocolError> {
+        String::from_utf8(self.next()?.to_vec()).map_err(|_| ProtocolError::new("incorrect utf-8 string"))
+    }
+
+    fn next_byte(&mut self) -> Result<u8, ProtocolError> {
+        let ret = *self.raw.get(self.pos).ok_or_else(|| ProtocolError::new("incorrect content length"))?;
+        self.pos += 1;
+        Ok(ret)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos == self.raw.len()
+    }
+
+    fn finish(&self) -> Result<(), ProtocolError> {
+        if self.is_empty() { Ok(()) } else { Err(ProtocolError::new("incorrect content length")) }
+    }
+}
+
 #[cfg(test)]
 mod test_request {
     use crate::kvserver::protocol::Request;
+    use crate::kvserver::auth::Credentials;
+    use crate::kvstorage::{Key, Value, WriteBatch};
+    use crate::kvstorage::scan::ScanOptions;
     use crate::util::{gen_key, gen_value};
 
     #[test]
@@ -305,24 +1164,49 @@ mod test_request {
         for _ in 1..10 {
             let key1 = gen_key();
             let key2 = gen_key();
-            let req = Request::Scan(key1, key2);
+            let req = Request::Scan(key1.clone(), key2.clone(), ScanOptions::from_default());
             let req1 = Request::deserialize_from(req.serialize()).unwrap();
             match req1 {
-                Request::Scan(k1, k2) => {
+                Request::Scan(k1, k2, options) => {
                     assert_eq!(k1, key1);
                     assert_eq!(k2, key2);
+                    assert!(options.is_default());
                 },
                 _ => panic!()
             }
         }
     }
 
+    #[test]
+    fn request_serialize_scan_options() {
+        let (key1, key2) = (gen_key(), Key::from_bytes(b"key"));
+        let mut options = ScanOptions::from_default();
+        options.descending = true;
+        options.include_end = true;
+        options.limit = Some(100);
+        let raw = Request::Scan(key1.clone(), key2.clone(), options.clone()).serialize();
+        assert_eq!(raw[0], b'F');
+        match Request::deserialize_from(raw).unwrap() {
+            Request::Scan(k1, k2, o) => assert_eq!((k1, k2, o), (key1.clone(), key2.clone(), options.clone())),
+            _ => panic!()
+        }
+        options.include_start = false;
+        options.limit = None;
+        options.continuation = Some(Key::from_bytes(b""));
+        match Request::deserialize_from(Request::Scan(key1.clone(), key2.clone(), options.clone()).serialize()).unwrap() {
+            Request::Scan(k1, k2, o) => assert_eq!((k1, k2, o), (key1, key2, options)),
+            _ => panic!()
+        }
+        assert!(Request::deserialize_from(vec![b'F', 16, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).is_err());
+        assert!(Request::deserialize_from(vec![b'F', 8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).is_err());
+    }
+
     #[test]
     fn request_serialize_put() {
         for _ in 1..10 {
             let key = gen_key();
             let value = gen_value();
-            let req = Request::Put(key, value);
+            let req = Request::Put(key.clone(), value.clone());
             let req1 = Request::deserialize_from(req.serialize()).unwrap();
             match req1 {
                 Request::Put(k, v) => {
@@ -338,7 +1222,7 @@ mod test_request {
     fn request_serialize_get() {
         for _ in 1..10 {
             let key = gen_key();
-            let req = Request::Get(key);
+            let req = Request::Get(key.clone());
             let req1 = Request::deserialize_from(req.serialize()).unwrap();
             match req1 {
                 Request::Get(k) => {
@@ -353,7 +1237,7 @@ mod test_request {
     fn request_serialize_delete() {
         for _ in 1..10 {
             let key = gen_key();
-            let req = Request::Del(key);
+            let req = Request::Del(key.clone());
             let req1 = Request::deserialize_from(req.serialize()).unwrap();
             match req1 {
                 Request::Del(k) => {
@@ -364,6 +1248,196 @@ mod test_request {
         }
     }
 
+    #[test]
+    fn request_serialize_variable_length() {
+        let key = Key::from_bytes(b"variable");
+        assert_eq!(Request::Get(key.clone()).serialize()[0], b'G');
+        let key = Key::from_bytes(b"a variable length key");
+        let value = Value::from_bytes(&[42u8; 100000]);
+        match Request::deserialize_from(Request::Put(key.clone(), value.clone()).serialize()).unwrap() {
+            Request::Put(k, v) => {
+                assert_eq!(k, key);
+                assert_eq!(v, value);
+            },
+            _ => panic!()
+        }
+        match Request::deserialize_from(Request::Scan(Key::from_bytes(b""), key.clone(), ScanOptions::from_default()).serialize()).unwrap() {
+            Request::Scan(k1, k2, _) => {
+                assert_eq!(k1, Key::from_bytes(b""));
+                assert_eq!(k2, key);
+            },
+            _ => panic!()
+        }
+        match Request::deserialize_from(Request::Del(key.clone()).serialize()).unwrap() {
+            Request::Del(k) => assert_eq!(k, key),
+            _ => panic!()
+        }
+
+        let mut truncated = Request::Get(key).serialize();
+        truncated.pop();
+        assert!(Request::deserialize_from(truncated).is_err());
+    }
+
+    #[test]
+    fn request_serialize_batch() {
+        let mut batch = WriteBatch::new();
+        batch.put(gen_key(), gen_value())
+            .delete(Key::from_bytes(b"a variable length key"))
+            .put(Key::from_bytes(b""), Value::from_bytes(b""));
+        match Request::deserialize_from(Request::Batch(batch.clone()).serialize()).unwrap() {
+            Request::Batch(b) => assert_eq!(b, batch),
+            _ => panic!()
+        }
+        match Request::deserialize_from(Request::Batch(WriteBatch::new()).serialize()).unwrap() {
+            Request::Batch(b) => assert!(b.is_empty()),
+            _ => panic!()
+        }
+
+        let mut truncated = Request::Batch(batch).serialize();
+        truncated.pop();
+        assert!(Request::deserialize_from(truncated).is_err());
+        assert!(Request::deserialize_from(vec![b'B', b'x']).is_err());
+    }
+
+    #[test]
+    fn request_serialize_transaction() {
+        for req in [Request::Begin, Request::Commit, Request::Abort].iter() {
+            match (req, Request::deserialize_from(req.serialize()).unwrap()) {
+                (Request::Begin, Request::Begin) | (Request::Commit, Request::Commit) | (Request::Abort, Request::Abort) => (),
+                _ => panic!()
+            }
+        }
+        assert!(Request::deserialize_from(vec![b'K', 0]).is_err());
+    }
+
+    #[test]
+    fn request_serialize_conditional() {
+        let (key, value1, value2) = (gen_key(), gen_value(), Value::from_bytes(b""));
+        for expected in [Some(value1.clone()), None].iter() {
+            let req = Request::CompareAndSwap(key.clone(), expected.clone(), value2.clone());
+            match Request::deserialize_from(req.serialize()).unwrap() {
+                Request::CompareAndSwap(k, e, v) => assert_eq!((&k, &e, &v), (&key, expected, &value2)),
+                _ => panic!()
+            }
+        }
+        match Request::deserialize_from(Request::PutIfAbsent(key.clone(), value1.clone()).serialize()).unwrap() {
+            Request::PutIfAbsent(k, v) => assert_eq!((k, v), (key.clone(), value1.clone())),
+            _ => panic!()
+        }
+        match Request::deserialize_from(Request::DeleteIfEquals(key.clone(), value1.clone()).serialize()).unwrap() {
+            Request::DeleteIfEquals(k, v) => assert_eq!((k, v), (key.clone(), value1.clone())),
+            _ => panic!()
+        }
+
+        let mut raw = Request::CompareAndSwap(key.clone(), None, value1).serialize();
+        raw[5 + key.data.len()] = 2;
+        assert!(Request::deserialize_from(raw).is_err());
+        assert!(Request::deserialize_from(vec![b'I', 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn request_serialize_snapshot() {
+        let (key1, key2, snapshot) = (gen_key(), Key::from_bytes(b"key"), rand::random());
+        match Request::deserialize_from(Request::OpenSnapshot.serialize()).unwrap() {
+            Request::OpenSnapshot => (),
+            _ => panic!()
+        }
+        match Request::deserialize_from(Request::GetAt(snapshot, key1.clone()).serialize()).unwrap() {
+            Request::GetAt(s, k) => assert_eq!((s, k), (snapshot, key1.clone())),
+            _ => panic!()
+        }
+        match Request::deserialize_from(Request::ScanAt(snapshot, key1.clone(), key2.clone()).serialize()).unwrap() {
+            Request::ScanAt(s, k1, k2) => assert_eq!((s, k1, k2), (snapshot, key1, key2)),
+            _ => panic!()
+        }
+        match Request::deserialize_from(Request::ReleaseSnapshot(snapshot).serialize()).unwrap() {
+            Request::ReleaseSnapshot(s) => assert_eq!(s, snapshot),
+            _ => panic!()
+        }
+        assert!(Request::deserialize_from(vec![b'R', 0, 0, 0]).is_err());
+        assert!(Request::deserialize_from(vec![b'O', 0]).is_err());
+    }
+
+    #[test]
+    fn request_serialize_expiry() {
+        let (key, value, ttl) = (gen_key(), gen_value(), rand::random());
+        match Request::deserialize_from(Request::PutWithTtl(key.clone(), value.clone(), ttl).serialize()).unwrap() {
+            Request::PutWithTtl(k, v, t) => assert_eq!((k, v, t), (key.clone(), value, ttl)),
+            _ => panic!()
+        }
+        match Request::deserialize_from(Request::Expire(key.clone(), ttl).serialize()).unwrap() {
+            Request::Expire(k, t) => assert_eq!((k, t), (key.clone(), ttl)),
+            _ => panic!()
+        }
+        match Request::deserialize_from(Request::Persist(key.clone()).serialize()).unwrap() {
+            Request::Persist(k) => assert_eq!(k, key),
+            _ => panic!()
+        }
+        match Request::deserialize_from(Request::Ttl(key.clone()).serialize()).unwrap() {
+            Request::Ttl(k) => assert_eq!(k, key),
+            _ => panic!()
+        }
+        assert!(Request::deserialize_from(vec![b'Y', 0, 0, 0, 0, 0, 0, 0, 0]).is_err());
+        assert!(Request::deserialize_from(vec![b'L', 0, 0]).is_err());
+    }
+
+    #[test]
+    fn request_serialize_scan_keys() {
+        let (key1, key2) = (gen_key(), Key::from_bytes(b""));
+        match Request::deserialize_from(Request::ScanKeys(key1.clone(), key2.clone()).serialize()).unwrap() {
+            Request::ScanKeys(k1, k2) => assert_eq!((k1, k2), (key1.clone(), key2.clone())),
+            _ => panic!()
+        }
+        match Request::deserialize_from(Request::Count(key1.clone(), key2.clone()).serialize()).unwrap() {
+            Request::Count(k1, k2) => assert_eq!((k1, k2), (key1, key2)),
+            _ => panic!()
+        }
+        assert!(Request::deserialize_from(vec![b'N', 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn request_serialize_scan_prefix() {
+        for prefix in [&b"user:"[..], &[0xff, 0xff], b""] {
+            match Request::deserialize_from(Request::ScanPrefix(prefix.to_vec()).serialize()).unwrap() {
+                Request::ScanPrefix(p) => assert_eq!(p, prefix),
+                _ => panic!()
+            }
+        }
+        assert!(Request::deserialize_from(vec![b'A', 0, 0, 0, 1]).is_err());
+    }
+
+    #[test]
+    fn request_serialize_pipelined() {
+        assert!(matches!(Request::deserialize_from(Request::Pipeline.serialize()).unwrap(), Request::Pipeline));
+        let (key, value) = (gen_key(), gen_value());
+        let request = Request::Pipelined(0x01020304, Box::new(Request::Put(key.clone(), value.clone())));
+        match Request::deserialize_from(request.serialize()).unwrap() {
+            Request::Pipelined(id, request) => match *request {
+                Request::Put(k, v) => assert_eq!((id, k, v), (0x01020304, key, value)),
+                _ => panic!()
+            },
+            _ => panic!()
+        }
+        // pipelined requests are neither empty nor nested
+        assert!(Request::deserialize_from(vec![b'M', 0, 0, 0, 1]).is_err());
+        let nested = Request::Pipelined(1, Box::new(Request::Pipelined(2, Box::new(Request::Close))));
+        assert!(Request::deserialize_from(nested.serialize()).is_err());
+    }
+
+    #[test]
+    fn request_serialize_authenticate() {
+        let credentials = [Credentials::Password("alice".to_owned(), "secret".to_owned()),
+                           Credentials::Password(String::new(), String::new()), Credentials::Token("token".to_owned())];
+        for credentials in credentials.iter() {
+            match Request::deserialize_from(Request::Authenticate(credentials.clone()).serialize()).unwrap() {
+                Request::Authenticate(c) => assert!(&c == credentials),
+                _ => panic!()
+            }
+        }
+        assert!(Request::deserialize_from(vec![b'a', 2, 0, 0, 0, 0]).is_err());
+        assert!(Request::deserialize_from(vec![b'a', 1, 0, 0, 0, 1, 0xff]).is_err());
+    }
+
     #[test]
     fn request_serialize_close() {
         for _ in 1..10 {
@@ -380,9 +1454,11 @@ mod test_request {
 #[cfg(test)]
 mod test_reply_chunk {
     use crate::kvserver::protocol::{ReplyChunk, ServerReplyChunk};
+    use crate::kvstorage::{Key, Value, Ttl};
     use crate::util::{gen_key, gen_value};
     use std::sync::Arc;
     use std::ops::Deref;
+    use std::time::Duration;
 
     #[test]
     fn reply_serialize_single_value() {
@@ -437,4 +1513,97 @@ mod test_reply_chunk {
             }
         }
     }
+
+    #[test]
+    fn reply_serialize_mismatch() {
+        for value in [Some(Arc::new(gen_value())), Some(Arc::new(Value::from_bytes(b""))), None].iter() {
+            match ReplyChunk::deserialize(ServerReplyChunk::Mismatch(value.clone()).serialize()).unwrap() {
+                ReplyChunk::Mismatch(v) => assert_eq!(v.as_ref(), value.as_deref()),
+                _ => panic!()
+            }
+        }
+    }
+
+    #[test]
+    fn reply_serialize_ttl() {
+        for ttl in [Ttl::NoValue, Ttl::Persistent, Ttl::Expires(Duration::from_millis(rand::random::<u32>() as u64))].iter() {
+            match ReplyChunk::deserialize(ServerReplyChunk::Ttl(*ttl).serialize()).unwrap() {
+                ReplyChunk::Ttl(t) => assert_eq!(&t, ttl),
+                _ => panic!()
+            }
+        }
+        assert!(ReplyChunk::deserialize(vec![b'T', 3]).is_err());
+        assert!(ReplyChunk::deserialize(vec![b'T', 2, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn reply_serialize_keys() {
+        let keys = vec![gen_key(), Key::from_bytes(b""), Key::from_bytes(b"key")];
+        match ReplyChunk::deserialize(ServerReplyChunk::Keys(&keys).serialize()).unwrap() {
+            ReplyChunk::Keys(ks) => assert_eq!(ks, keys),
+            _ => panic!()
+        }
+        match ReplyChunk::deserialize(ServerReplyChunk::Keys(&[]).serialize()).unwrap() {
+            ReplyChunk::Keys(ks) => assert!(ks.is_empty()),
+            _ => panic!()
+        }
+        assert!(ReplyChunk::deserialize(vec![b'K', 0, 0, 0, 1]).is_err());
+    }
+
+    #[test]
+    fn reply_serialize_continuation() {
+        for token in [gen_key(), Key::from_bytes(b"")].iter() {
+            match ReplyChunk::deserialize(ServerReplyChunk::Continuation(token).serialize()).unwrap() {
+                ReplyChunk::Continuation(t) => assert_eq!(&t, token),
+                _ => panic!()
+            }
+        }
+        assert!(ReplyChunk::deserialize(vec![b'U', 0, 0]).is_err());
+    }
+
+    #[test]
+    fn reply_serialize_denied() {
+        assert!(matches!(ReplyChunk::deserialize(ServerReplyChunk::Denied.serialize()).unwrap(), ReplyChunk::Denied));
+        assert!(ReplyChunk::deserialize(vec![b'D', 0]).is_err());
+    }
+
+    #[test]
+    fn reply_serialize_pipelined() {
+        let chunk = ServerReplyChunk::Number(42).serialize();
+        match ReplyChunk::deserialize(ServerReplyChunk::Pipelined(7, &chunk).serialize()).unwrap() {
+            ReplyChunk::Pipelined(7, Some(chunk)) => assert!(matches!(*chunk, ReplyChunk::Number(42))),
+            _ => panic!()
+        }
+        match ReplyChunk::deserialize(ServerReplyChunk::Pipelined(u32::MAX, &[]).serialize()).unwrap() {
+            ReplyChunk::Pipelined(id, None) => assert_eq!(id, u32::MAX),
+            _ => panic!()
+        }
+        assert!(ReplyChunk::deserialize(vec![b'I', 0, 0]).is_err());
+        assert!(ReplyChunk::deserialize(vec![b'I', 0, 0, 0, 1, b'E', 0]).is_err());
+    }
+
+    #[test]
+    fn reply_serialize_variable_length() {
+        let value = Arc::new(Value::from_bytes(b"short"));
+        match ReplyChunk::deserialize(ServerReplyChunk::SingleValue(Some(value.clone())).serialize()).unwrap() {
+            ReplyChunk::SingleValue(v) => assert_eq!(v.unwrap(), *value),
+            _ => panic!()
+        }
+
+        let pairs = vec![
+            (Key::from_bytes(b""), Arc::new(Value::from_bytes(b""))),
+            (gen_key(), Arc::new(gen_value())),
+            (Key::from_bytes(b"key"), value)
+        ];
+        match ReplyChunk::deserialize(ServerReplyChunk::KVPairs(&pairs).serialize()).unwrap() {
+            ReplyChunk::KVPairs(ps) => {
+                assert_eq!(ps.len(), pairs.len());
+                for ((k1, v1), (k2, v2)) in ps.iter().zip(pairs.iter()) {
+                    assert_eq!(k1, k2);
+                    assert_eq!(v1, v2.deref());
+                }
+            },
+            _ => panic!()
+        }
+    }
 }
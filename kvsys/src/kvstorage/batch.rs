@@ -0,0 +1,83 @@
+//! Groups of writes applied atomically
+//!
+//! A `WriteBatch` collects puts and deletes, which are then applied all together by
+//! `KVStorage::write_batch` (or `StorageEngine::write_batch`): readers never see some of them
+//! without the others, and the batch is logged as a single record, so that after a crash either
+//! all of it or none of it is replayed.
+//!
+//! ```no_run
+//!     use kvsys::kvstorage::{KVStorage, Key, Value, WriteBatch};
+//!     // ...
+//!     let mut kv = KVStorage::open("data.kv").unwrap();
+//!     let mut batch = WriteBatch::new();
+//!     batch.put(Key::from_bytes(b"data:42"), Value::from_bytes(b"forty-two"));
+//!     batch.put(Key::from_bytes(b"index:answer"), Value::from_bytes(b"data:42"));
+//!     batch.delete(Key::from_bytes(b"index:question"));
+//!     kv.write_batch(&batch).unwrap();
+//!     // ...
+//! ```
+
+use crate::kvstorage::{Key, Value};
+
+/// A write within a `WriteBatch`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BatchOp {
+    Put(Key, Value),
+    Delete(Key)
+}
+
+/// Puts and deletes to be applied atomically, in the order they are added
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>
+}
+
+impl BatchOp {
+    /// The key written by this op
+    pub fn key(&self) -> &Key {
+        match self {
+            BatchOp::Put(key, _) => key,
+            BatchOp::Delete(key) => key
+        }
+    }
+}
+
+impl WriteBatch {
+    /// Creates an empty `WriteBatch`
+    pub fn new() -> Self {
+        WriteBatch { ops: Vec::new() }
+    }
+
+    /// Adds a put of the `key` - `value` pair
+    pub fn put(&mut self, key: Key, value: Value) -> &mut Self {
+        self.ops.push(BatchOp::Put(key, value));
+        self
+    }
+
+    /// Adds a delete of `key`, deleting a key that holds no value is not an error
+    pub fn delete(&mut self, key: Key) -> &mut Self {
+        self.ops.push(BatchOp::Delete(key));
+        self
+    }
+
+    /// Adds `op`
+    pub fn push(&mut self, op: BatchOp) -> &mut Self {
+        self.ops.push(op);
+        self
+    }
+
+    /// The ops of this batch, in the order they are applied
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    /// Count of ops in this batch
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Whether this batch has no op at all
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
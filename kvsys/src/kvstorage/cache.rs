@@ -0,0 +1,196 @@
+//! Values kept on disk instead of in memory
+//!
+//! With a memory budget (see `StorageOptions::memory_budget`), a `KVStorage` only keeps recently
+//! written values in memory. Older values are evicted, leaving a `ValueLocation` which tells where
+//! the value can be read back: a record of the disk log, or an entry of a snapshot file. Values
+//! read back go through a `ValueCache`, so that frequently read values stay in memory.
+
+use crate::kvstorage::Value;
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static NEXT_VALUE_FILE_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A file values can be read back from, which stays readable even after the file gets replaced
+/// by a rename, as long as it is kept open
+pub struct ValueFile {
+    id: u64,
+    file: Mutex<File>
+}
+
+/// Where an evicted value lives on disk
+#[derive(Clone)]
+pub struct ValueLocation {
+    pub file: Arc<ValueFile>,
+    pub offset: u64,
+    pub size: u32
+}
+
+/// An LRU cache of values read back from disk, bounded by the total size of the values
+pub struct ValueCache {
+    lru: Mutex<Lru>,
+    hits: AtomicU64,
+    misses: AtomicU64
+}
+
+struct Lru {
+    capacity: usize,
+    size: usize,
+    next_tick: u64,
+    entries: HashMap<(u64, u64), (Arc<Value>, u64)>,
+    order: BTreeMap<u64, (u64, u64)>
+}
+
+impl ValueFile {
+    /// Opens the file at `path` for reading values back
+    pub fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let file = File::open(path)?;
+        Ok(ValueFile { id: NEXT_VALUE_FILE_ID.fetch_add(1, Ordering::SeqCst), file: Mutex::new(file) })
+    }
+
+    /// Reads `size` bytes at byte `offset`
+    pub fn read(&self, offset: u64, size: u32) -> Result<Value, Box<dyn Error>> {
+        let mut data = vec![0u8; size as usize];
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut data)?;
+        Ok(Value { data })
+    }
+}
+
+impl ValueLocation {
+    /// Reads the value, without going through any cache
+    pub fn read(&self) -> Result<Value, Box<dyn Error>> {
+        self.file.read(self.offset, self.size)
+    }
+
+    /// Whether the value lives in `file`
+    pub fn is_in(&self, file: &Arc<ValueFile>) -> bool {
+        Arc::ptr_eq(&self.file, file)
+    }
+
+    fn cache_key(&self) -> (u64, u64) {
+        (self.file.id, self.offset)
+    }
+}
+
+impl PartialEq for ValueLocation {
+    fn eq(&self, other: &Self) -> bool {
+        self.is_in(&other.file) && self.offset == other.offset
+    }
+}
+
+impl ValueCache {
+    /// Creates an empty `ValueCache` holding at most `capacity` bytes of values
+    pub fn new(capacity: usize) -> Self {
+        let lru = Lru { capacity, size: 0, next_tick: 0, entries: HashMap::new(), order: BTreeMap::new() };
+        ValueCache { lru: Mutex::new(lru), hits: AtomicU64::new(0), misses: AtomicU64::new(0) }
+    }
+
+    /// Gets the value at `location` from the cache, or reads it from disk and caches it
+    pub fn get(&self, location: &ValueLocation) -> Result<Arc<Value>, Box<dyn Error>> {
+        let cache_key = location.cache_key();
+        if let Some(value) = self.lru.lock().unwrap().touch(cache_key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(value);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        // the lock is not held while reading, a value read twice concurrently is cached once
+        let value = Arc::new(location.read()?);
+        self.lru.lock().unwrap().insert(cache_key, value.clone());
+        Ok(value)
+    }
+
+    /// Count of values found in the cache
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Count of values read from disk
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Total size of the values in the cache
+    pub fn size(&self) -> usize {
+        self.lru.lock().unwrap().size
+    }
+
+    /// Drops every value in the cache
+    pub fn clear(&self) {
+        let mut lru = self.lru.lock().unwrap();
+        lru.entries.clear();
+        lru.order.clear();
+        lru.size = 0;
+    }
+}
+
+impl Lru {
+    fn touch(&mut self, cache_key: (u64, u64)) -> Option<Arc<Value>> {
+        let tick = self.next_tick;
+        let (value, last_tick) = self.entries.get_mut(&cache_key)?;
+        self.order.remove(last_tick);
+        *last_tick = tick;
+        let value = value.clone();
+        self.order.insert(tick, cache_key);
+        self.next_tick += 1;
+        Some(value)
+    }
+
+    fn insert(&mut self, cache_key: (u64, u64), value: Arc<Value>) {
+        if value.data.len() > self.capacity || self.entries.contains_key(&cache_key) {
+            return;
+        }
+        self.size += value.data.len();
+        self.entries.insert(cache_key, (value, self.next_tick));
+        self.order.insert(self.next_tick, cache_key);
+        self.next_tick += 1;
+
+        while self.size > self.capacity {
+            let (&tick, &oldest) = self.order.iter().next().unwrap();
+            self.order.remove(&tick);
+            let (value, _) = self.entries.remove(&oldest).unwrap();
+            self.size -= value.data.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::kvstorage::cache::{ValueCache, ValueFile, ValueLocation};
+    use std::io::Write;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_value_cache() {
+        let mut f = tempfile::NamedTempFile::new().unwrap();
+        f.write_all(&(0..=255u8).collect::<Vec<_>>()).unwrap();
+        f.flush().unwrap();
+        let file = Arc::new(ValueFile::open(f.path()).unwrap());
+        let location = |offset| ValueLocation { file: file.clone(), offset, size: 16 };
+
+        let cache = ValueCache::new(32);
+        assert_eq!(cache.get(&location(0)).unwrap().data, (0..16u8).collect::<Vec<_>>());
+        assert_eq!(cache.get(&location(16)).unwrap().data, (16..32u8).collect::<Vec<_>>());
+        assert_eq!((cache.hits(), cache.misses()), (0, 2));
+
+        // touching the value at 0 makes the value at 16 the least recently used
+        cache.get(&location(0)).unwrap();
+        cache.get(&location(32)).unwrap();
+        assert_eq!((cache.hits(), cache.misses()), (1, 3));
+        assert_eq!(cache.size(), 32);
+        cache.get(&location(0)).unwrap();
+        cache.get(&location(16)).unwrap();
+        assert_eq!((cache.hits(), cache.misses()), (2, 4));
+
+        cache.clear();
+        assert_eq!(cache.size(), 0);
+        assert!(cache.get(&ValueLocation { file: file.clone(), offset: 250, size: 16 }).is_err());
+    }
+}
@@ -1,65 +1,223 @@
 //! The Disk Log file API
 
 use crate::kvstorage::{Key, Value, KEY_SIZE, VALUE_SIZE};
-use std::sync::Arc;
+use crate::kvstorage::crc32::crc32;
+use crate::kvstorage::options::SyncPolicy;
+use std::sync::{Arc, Mutex, Condvar};
+use std::time::Instant;
+use std::mem;
+use std::ops::RangeInclusive;
 use std::error::Error;
 use std::fs;
-use std::io::{Read, Write};
+use std::io::{BufReader, Read, Write, Seek, SeekFrom};
 use std::fmt;
 use std::fmt::{Display, Formatter};
 
 // Disk log format
-//  -- 1 byte functionality
-//     'P': put
-//      -- KEY_SIZE bytes key
-//      -- VALUE_SIZE bytes value
-//     'D': delete
-//      -- KEY_SIZE bytes key
+//  -- file header
+//     -- 8 bytes magic (0x89 'PKVLOG' 0x0a)
+//     -- 2 bytes format version, in big endian
+//  -- records, one after another
+//     -- 1 byte functionality
+//        'P': put
+//         -- 4 bytes key length, in big endian
+//         -- key
+//         -- 4 bytes value length, in big endian
+//         -- value
+//        'D': delete
+//         -- 4 bytes key length, in big endian
+//         -- key
+//        'E': expire, sets when the value put last expires
+//         -- 4 bytes key length, in big endian
+//         -- key
+//         -- 8 bytes expiry time in milliseconds since the UNIX epoch, in big endian, 0 for never
+//        'B': batch, applied all together or not at all
+//         -- 4 bytes count of operations, in big endian
+//         -- (count) put, delete or expire records as above, without their checksums
+//     -- 4 bytes CRC-32 of all the bytes above, in big endian
+//
+// Version 3 logs are the same as version 4 logs, but never contain expire records.
+// Version 2 logs are the same as version 3 logs, but never contain batches.
+// Version 1 logs have no length fields, keys are always KEY_SIZE bytes and values are always
+// VALUE_SIZE bytes. Legacy disk logs (written before the header was introduced) are the same as
+// version 1 logs, but have no header and no CRC. Both can still be read but are never written.
+// The magic begins with a byte that is neither 'P' nor 'D', so the formats can never be confused.
+
+const DISK_LOG_MAGIC: [u8; 8] = [0x89, b'P', b'K', b'V', b'L', b'O', b'G', 0x0a];
+pub(crate) const DISK_LOG_HEADER_SIZE: u64 = 10;
+const DISK_LOG_VERSION: u16 = 4;
+const DISK_LOG_VERSION_UNEXPIRING: u16 = 3;
+const DISK_LOG_VERSION_UNBATCHED: u16 = 2;
+const DISK_LOG_VERSION_FIXED: u16 = 1;
 
 const DISK_PUT: u8 = b'P';
 const DISK_DELETE: u8 = b'D';
+const DISK_BATCH: u8 = b'B';
+const DISK_EXPIRE: u8 = b'E';
 
 /// The error type used by disklog module
 #[derive(Debug)]
-pub struct DiskLogError {
-    description: String
+pub enum DiskLogError {
+    /// The disk log content starting at byte `offset` is ill-formed or fails its checksum
+    Corrupted { offset: u64, description: String },
+    /// The disk log ends in the middle of the record (or header) starting at byte `offset`,
+    /// typically because the writer died in the middle of writing it
+    TornTail { offset: u64 },
+    /// Any other error with the disk log
+    Other { description: String }
 }
 
 impl DiskLogError {
     pub fn new(description: &str) -> Self {
-        DiskLogError { description: description.to_owned() }
+        DiskLogError::Other { description: description.to_owned() }
+    }
+
+    pub fn corrupted(offset: u64, description: &str) -> Self {
+        DiskLogError::Corrupted { offset, description: description.to_owned() }
     }
 }
 
 impl Display for DiskLogError {
     fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
-        write!(f, "disk log error: {}", self.description)
+        match self {
+            DiskLogError::Corrupted { offset, description } =>
+                write!(f, "disk log error: corrupted at byte offset {}: {}", offset, description),
+            DiskLogError::TornTail { offset } =>
+                write!(f, "disk log error: incomplete record at byte offset {}", offset),
+            DiskLogError::Other { description } =>
+                write!(f, "disk log error: {}", description)
+        }
     }
 }
 
 impl Error for DiskLogError {
 }
 
+/// Format of a disk log file, detected by `DiskLogReader`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DiskLogFormat {
+    /// Headerless log without checksums, read only
+    Legacy,
+    /// Log with a header and a checksum per record, of the given version. Only the current
+    /// version can be appended to
+    Versioned(u16)
+}
+
+impl DiskLogFormat {
+    /// Whether this is the format written by `DiskLogWriter`
+    pub fn is_current(&self) -> bool {
+        *self == DiskLogFormat::Versioned(DISK_LOG_VERSION)
+    }
+
+    /// Whether keys and values are stored with their lengths
+    fn has_lengths(&self) -> bool {
+        match self {
+            DiskLogFormat::Legacy => false,
+            DiskLogFormat::Versioned(version) => *version >= DISK_LOG_VERSION_UNBATCHED
+        }
+    }
+
+    /// Whether batch records may appear
+    fn has_batches(&self) -> bool {
+        matches!(self, DiskLogFormat::Versioned(version) if *version >= DISK_LOG_VERSION_UNEXPIRING)
+    }
+
+    /// Whether expire records may appear
+    fn has_expiries(&self) -> bool {
+        self.is_current()
+    }
+}
+
+/// Byte offset of the value within a put record of the current format, whose key is `key_size`
+/// bytes long
+pub(crate) fn put_value_offset(key_size: usize) -> u64 {
+    1 + 4 + key_size as u64 + 4
+}
+
 /// A disk log message read out from a file, or going to be write into a file
 pub enum DiskLogMessage {
     Put(Key, Arc<Value>),
-    Delete(Key)
+    Delete(Key),
+    /// Sets the expiry time of the value of the key, in milliseconds since the UNIX epoch, `None`
+    /// for never, see `expiry`
+    Expire(Key, Option<u64>),
+    /// Puts, deletes and expires logged as one record, never contains another batch
+    Batch(Vec<DiskLogMessage>)
 }
 
 impl DiskLogMessage {
-    /// Serialize a `DiskLogMessage` into a byte buffer
+    /// Serialize a `DiskLogMessage` into a byte buffer, the trailing checksum included
     pub fn serialize(&self) -> Vec<u8> {
+        let mut ret = self.serialize_body();
+        let crc = crc32(&ret);
+        ret.extend_from_slice(&crc.to_be_bytes());
+        ret
+    }
+
+    fn serialize_body(&self) -> Vec<u8> {
         match self {
             DiskLogMessage::Put(key, value) => {
                 let mut ret = vec![DISK_PUT];
+                ret.extend_from_slice(&(key.data.len() as u32).to_be_bytes());
                 ret.append(&mut key.serialize());
+                ret.extend_from_slice(&(value.data.len() as u32).to_be_bytes());
                 ret.append(&mut value.serialize());
                 ret
             },
             DiskLogMessage::Delete(key) => {
                 let mut ret = vec![DISK_DELETE];
+                ret.extend_from_slice(&(key.data.len() as u32).to_be_bytes());
                 ret.append(&mut key.serialize());
                 ret
+            },
+            DiskLogMessage::Expire(key, expiry) => {
+                let mut ret = vec![DISK_EXPIRE];
+                ret.extend_from_slice(&(key.data.len() as u32).to_be_bytes());
+                ret.append(&mut key.serialize());
+                ret.extend_from_slice(&expiry.unwrap_or(0).to_be_bytes());
+                ret
+            },
+            DiskLogMessage::Batch(messages) => {
+                let mut ret = vec![DISK_BATCH];
+                ret.extend_from_slice(&(messages.len() as u32).to_be_bytes());
+                for msg in messages.iter() {
+                    ret.append(&mut msg.serialize_body());
+                }
+                ret
+            }
+        }
+    }
+
+    /// Size of the record of this message in the current format, the trailing checksum included
+    pub(crate) fn serialized_size(&self) -> u64 {
+        self.body_size() + 4
+    }
+
+    fn body_size(&self) -> u64 {
+        match self {
+            DiskLogMessage::Put(key, value) => put_value_offset(key.data.len()) + value.data.len() as u64,
+            DiskLogMessage::Delete(key) => 1 + 4 + key.data.len() as u64,
+            DiskLogMessage::Expire(key, _) => 1 + 4 + key.data.len() as u64 + 8,
+            DiskLogMessage::Batch(messages) => 1 + 4 + messages.iter().map(|msg| msg.body_size()).sum::<u64>()
+        }
+    }
+
+    /// Byte offsets of the values put by this message within its record of the current format, in
+    /// the order of the puts
+    pub(crate) fn value_offsets(&self) -> Vec<u64> {
+        match self {
+            DiskLogMessage::Put(key, _) => vec![put_value_offset(key.data.len())],
+            DiskLogMessage::Delete(_) | DiskLogMessage::Expire(..) => Vec::new(),
+            DiskLogMessage::Batch(messages) => {
+                let mut ret = Vec::new();
+                let mut offset = 1 + 4;
+                for msg in messages.iter() {
+                    if let DiskLogMessage::Put(key, _) = msg {
+                        ret.push(offset + put_value_offset(key.data.len()));
+                    }
+                    offset += msg.body_size();
+                }
+                ret
             }
         }
     }
@@ -67,12 +225,56 @@ impl DiskLogMessage {
 
 /// Reader for `DiskLogMessage`
 pub struct DiskLogReader {
-    disk_log_file: fs::File
+    disk_log_file: BufReader<fs::File>,
+    format: Option<DiskLogFormat>,
+    offset: u64,
+    record: Vec<u8>
 }
 
 /// Writer for `DiskLogMessage`
+///
+/// Logs are group committed: `append` only queues a log in memory, and whoever waits on a
+/// `CommitTicket` first writes (and syncs, according to the sync policy) everything queued so far
+/// in one go, on behalf of all the other waiters. Cloning a `DiskLogWriter` gives another handle to
+/// the same log.
+#[derive(Clone)]
 pub struct DiskLogWriter {
-    disk_log_file: fs::File
+    shared: Arc<SharedDiskLog>
+}
+
+/// A log queued by `DiskLogWriter::append`, see `wait`
+#[must_use]
+pub struct CommitTicket {
+    seq: u64,
+    shared: Option<Arc<SharedDiskLog>>
+}
+
+struct SharedDiskLog {
+    queue: Mutex<CommitQueue>,
+    committed: Condvar,
+    file: Mutex<DiskLogFile>
+}
+
+struct CommitQueue {
+    pending: Vec<u8>,
+    // where the next record appended will start in the file, if the file size is known
+    appended_end: Option<u64>,
+    last_appended: u64,
+    last_committed: u64,
+    committing: bool,
+    committed_batches: u64,
+    poisoned: Option<String>,
+    // the logs lost by the latest failed commit, which keep failing after `replace_file`
+    failed: Option<(RangeInclusive<u64>, String)>
+}
+
+struct DiskLogFile {
+    file: fs::File,
+    header_pending: bool,
+    file_size: Option<u64>,
+    sync_policy: SyncPolicy,
+    last_sync: Instant,
+    dirty: bool
 }
 
 impl DiskLogReader {
@@ -81,30 +283,52 @@ impl DiskLogReader {
     /// This function requires the given `File` to be opened with `read`, and the file pointer must
     /// be at the beginning of the file. If not, further operations may return Error
     pub fn new(disk_log_file: fs::File) -> Self {
-        DiskLogReader { disk_log_file }
+        DiskLogReader { disk_log_file: BufReader::new(disk_log_file), format: None, offset: 0, record: Vec::new() }
+    }
+
+    /// Format of the file being read. `None` if nothing has been read yet, or the file is empty
+    pub fn format(&self) -> Option<DiskLogFormat> {
+        self.format
+    }
+
+    /// Byte offset right after the last successfully read log
+    pub fn offset(&self) -> u64 {
+        self.offset
     }
 
     /// Try reading a log out of the file
     ///
     /// returns `None` if there is no more data (reaches EOF), `Err` if there's an error with file
-    /// or disk log format
+    /// or disk log format. A `DiskLogError::Corrupted` error carries the offset of the offending
+    /// record, while a `DiskLogError::TornTail` error means the file ends in the middle of a record
     pub fn next_log(&mut self) -> Result<Option<DiskLogMessage>, Box<dyn Error>> {
         let mut operate: [u8; 1] = [0];
         match self.disk_log_file.read_exact(&mut operate) {
             Ok(_) => {
-                let mut key = [0u8; KEY_SIZE];
-                self.disk_log_file.read_exact(&mut key)?;
-                let key = Key::from_slice(&key);
-                if operate[0] == DISK_PUT {
-                    let mut value = [0u8; VALUE_SIZE];
-                    self.disk_log_file.read_exact(&mut value)?;
-                    let value = Value::from_slice(&value);
-                    Ok(Some(DiskLogMessage::Put(key, Arc::new(value))))
-                } else if operate[0] == DISK_DELETE {
-                    Ok(Some(DiskLogMessage::Delete(key)))
-                } else {
-                    Err(Box::new(DiskLogError::new("incorrect disk log format")))
+                if self.format.is_none() {
+                    if operate[0] != DISK_LOG_MAGIC[0] {
+                        self.format = Some(DiskLogFormat::Legacy);
+                    } else {
+                        self.read_header()?;
+                        return self.next_log();
+                    }
+                }
+                let format = self.format.unwrap();
+                self.record.clear();
+                self.record.push(operate[0]);
+                let msg = self.read_body(format, operate[0], true)?;
+
+                let mut record_size = self.record.len() as u64;
+                if let DiskLogFormat::Versioned(_) = format {
+                    let mut crc = [0u8; 4];
+                    self.read_part(&mut crc)?;
+                    if u32::from_be_bytes(crc) != crc32(&self.record) {
+                        return Err(Box::new(DiskLogError::corrupted(self.offset, "checksum mismatch")));
+                    }
+                    record_size += 4;
                 }
+                self.offset += record_size;
+                Ok(Some(msg))
             },
             Err(e) => {
                 if e.kind() == std::io::ErrorKind::UnexpectedEof  {
@@ -115,22 +339,519 @@ impl DiskLogReader {
             },
         }
     }
+
+    /// Reads the rest of a record (or of an operation within a batch) starting with `operate`, but
+    /// not its checksum. Batches are only accepted if `allow_batch`
+    fn read_body(&mut self, format: DiskLogFormat, operate: u8, allow_batch: bool) -> Result<DiskLogMessage, Box<dyn Error>> {
+        match operate {
+            DISK_PUT => {
+                let key = Key::from_bytes(&self.read_field(format, KEY_SIZE)?);
+                let value = Value::from_bytes(&self.read_field(format, VALUE_SIZE)?);
+                Ok(DiskLogMessage::Put(key, Arc::new(value)))
+            },
+            DISK_DELETE => Ok(DiskLogMessage::Delete(Key::from_bytes(&self.read_field(format, KEY_SIZE)?))),
+            DISK_EXPIRE if format.has_expiries() => {
+                let key = Key::from_bytes(&self.read_field(format, KEY_SIZE)?);
+                let mut expiry = [0u8; 8];
+                self.read_part(&mut expiry)?;
+                self.record.extend_from_slice(&expiry);
+                let expiry = u64::from_be_bytes(expiry);
+                Ok(DiskLogMessage::Expire(key, if expiry == 0 { None } else { Some(expiry) }))
+            },
+            DISK_BATCH if allow_batch && format.has_batches() => {
+                let mut count = [0u8; 4];
+                self.read_part(&mut count)?;
+                self.record.extend_from_slice(&count);
+                let mut messages = Vec::new();
+                for _ in 0..u32::from_be_bytes(count) {
+                    let mut operate = [0u8; 1];
+                    self.read_part(&mut operate)?;
+                    self.record.push(operate[0]);
+                    messages.push(self.read_body(format, operate[0], false)?);
+                }
+                Ok(DiskLogMessage::Batch(messages))
+            },
+            _ => Err(Box::new(DiskLogError::corrupted(self.offset, "incorrect disk log format")))
+        }
+    }
+
+    /// Reads a key or value of the record being read, which is `fixed_size` bytes long if `format`
+    /// has no length fields. The bytes read are kept in `record` for checksum
+    fn read_field(&mut self, format: DiskLogFormat, fixed_size: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        let size = if format.has_lengths() {
+            let mut size = [0u8; 4];
+            self.read_part(&mut size)?;
+            self.record.extend_from_slice(&size);
+            u32::from_be_bytes(size) as usize
+        } else {
+            fixed_size
+        };
+
+        // a corrupted length must not make us allocate gigabytes up front
+        let mut field = Vec::new();
+        (&mut self.disk_log_file).take(size as u64).read_to_end(&mut field)?;
+        if field.len() < size {
+            return Err(Box::new(DiskLogError::TornTail { offset: self.offset }));
+        }
+        self.record.extend_from_slice(&field);
+        Ok(field)
+    }
+
+    /// Reads the remaining part of a record (or header) that has already been started, running
+    /// out of data here means the record is torn
+    fn read_part(&mut self, buf: &mut [u8]) -> Result<(), Box<dyn Error>> {
+        match self.disk_log_file.read_exact(buf) {
+            Ok(_) => Ok(()),
+            Err(ref e) if e.kind() == std::io::ErrorKind::UnexpectedEof =>
+                Err(Box::new(DiskLogError::TornTail { offset: self.offset })),
+            Err(e) => Err(Box::new(e))
+        }
+    }
+
+    fn read_header(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut magic = [0u8; DISK_LOG_MAGIC.len() - 1];
+        self.read_part(&mut magic)?;
+        if magic != DISK_LOG_MAGIC[1..] {
+            return Err(Box::new(DiskLogError::corrupted(0, "incorrect disk log magic")));
+        }
+        let mut version = [0u8; 2];
+        self.read_part(&mut version)?;
+        let version = u16::from_be_bytes(version);
+        if !(DISK_LOG_VERSION_FIXED..=DISK_LOG_VERSION).contains(&version) {
+            return Err(Box::new(DiskLogError::new(&format!("unsupported disk log version {}", version))));
+        }
+        self.format = Some(DiskLogFormat::Versioned(version));
+        self.offset = DISK_LOG_HEADER_SIZE;
+        Ok(())
+    }
+}
+
+/// Whether `disk_log_file` can be appended to by a `DiskLogWriter`: it is empty, or a disk log of
+/// the current format. Unless it is empty, the `File` must be opened with `read`, and the file
+/// pointer is left at the end of the file
+pub fn is_appendable(disk_log_file: &fs::File) -> Result<bool, Box<dyn Error>> {
+    let size = disk_log_file.metadata()?.len();
+    if size == 0 {
+        return Ok(true);
+    }
+    if size < DISK_LOG_HEADER_SIZE {
+        return Ok(false);
+    }
+    let mut file = disk_log_file;
+    let mut header = [0u8; DISK_LOG_HEADER_SIZE as usize];
+    file.seek(SeekFrom::Start(0))?;
+    let read = file.read_exact(&mut header);
+    file.seek(SeekFrom::End(0))?;
+    read.map_err(|e| DiskLogError::new(&format!("cannot read the disk log header: {}", e)))?;
+    Ok(header[..DISK_LOG_MAGIC.len()] == DISK_LOG_MAGIC && header[DISK_LOG_MAGIC.len()..] == DISK_LOG_VERSION.to_be_bytes())
 }
 
 impl DiskLogWriter {
-    /// Create a `DiskLogReader` with given `File`
+    /// Create a `DiskLogWriter` with given `File`, which never syncs the file by itself
     ///
     /// This function requires the given `File` to be opened with `write` + `append`, and the file
-    /// pointer must be at the end of the file. If not, further operations may return Error
+    /// pointer must be at the end of the file. If not, further operations may return Error. If the
+    /// file is empty, the disk log header gets written together with the first log; otherwise the
+    /// file must already be a disk log of the current format (legacy logs cannot be appended to)
     pub fn new(disk_log_file: fs::File) -> Self {
-        DiskLogWriter { disk_log_file }
+        DiskLogWriter::with_sync_policy(disk_log_file, SyncPolicy::Never)
+    }
+
+    /// Create a `DiskLogWriter` with given `File`, syncing it according to `sync_policy`. See
+    /// `new` for requirements on the `File`
+    pub fn with_sync_policy(disk_log_file: fs::File, sync_policy: SyncPolicy) -> Self {
+        let file = DiskLogFile::new(disk_log_file, sync_policy);
+        let queue = CommitQueue {
+            pending: Vec::new(),
+            appended_end: file.records_end(),
+            last_appended: 0,
+            last_committed: 0,
+            committing: false,
+            committed_batches: 0,
+            poisoned: None,
+            failed: None
+        };
+        let shared = SharedDiskLog {
+            queue: Mutex::new(queue),
+            committed: Condvar::new(),
+            file: Mutex::new(file)
+        };
+        DiskLogWriter { shared: Arc::new(shared) }
+    }
+
+    /// Sets the sync policy of this writer
+    pub fn set_sync_policy(&self, sync_policy: SyncPolicy) {
+        self.shared.file.lock().unwrap().sync_policy = sync_policy;
+    }
+
+    /// Queues a log, returns a `CommitTicket` to wait until the log gets committed. Logs are
+    /// committed in the order they are appended
+    ///
+    /// returns `Err` if an earlier commit has failed, after which the writer refuses any new log
+    pub fn append(&self, msg: DiskLogMessage) -> Result<CommitTicket, Box<dyn Error>> {
+        self.append_serialized(msg.serialize())
+    }
+
+    /// Same as `append`, but also returns the byte offset in the file where the log will be
+    /// written, `None` if it is unknown
+    pub(crate) fn append_located(&self, msg: DiskLogMessage) -> Result<(CommitTicket, Option<u64>), Box<dyn Error>> {
+        self.append_serialized_located(msg.serialize())
+    }
+
+    /// Try write a log into the file, and sync the file if the sync policy says so. The log is
+    /// durable once this function returns if the sync policy is `SyncPolicy::Always`
+    ///
+    /// returns `Err` if there's an error with file, in which case the file is cut back to where it
+    /// was before the write (if possible), so that no partial log is left behind
+    pub fn write(&self, msg: DiskLogMessage) -> Result<(), Box<dyn Error>> {
+        self.append(msg)?.wait()
+    }
+
+    /// Queues records that are already serialized (e.g. copied from another disk log of the current
+    /// format), see `append`
+    pub(crate) fn append_serialized(&self, records: Vec<u8>) -> Result<CommitTicket, Box<dyn Error>> {
+        Ok(self.append_serialized_located(records)?.0)
+    }
+
+    fn append_serialized_located(&self, mut records: Vec<u8>) -> Result<(CommitTicket, Option<u64>), Box<dyn Error>> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        if let Some(e) = &queue.poisoned {
+            return Err(Box::new(DiskLogError::new(&format!("disk log is broken by an earlier error: {}", e))));
+        }
+        let offset = queue.appended_end;
+        queue.appended_end = offset.map(|end| end + records.len() as u64);
+        queue.pending.append(&mut records);
+        queue.last_appended += 1;
+        Ok((CommitTicket { seq: queue.last_appended, shared: Some(self.shared.clone()) }, offset))
+    }
+
+    /// Size of the file with all the logs committed so far, `None` if it is unknown
+    pub fn file_size(&self) -> Option<u64> {
+        self.shared.file.lock().unwrap().file_size
+    }
+
+    /// Waits until all logs appended so far are committed
+    pub fn flush(&self) -> Result<(), Box<dyn Error>> {
+        let seq = self.shared.queue.lock().unwrap().last_appended;
+        self.shared.wait_committed(seq)
+    }
+
+    /// Count of write (and sync) rounds done so far, each of which commits one or more logs
+    pub fn committed_batches(&self) -> u64 {
+        self.shared.queue.lock().unwrap().committed_batches
+    }
+
+    /// Sequence number of the last log committed, see `CommitTicket::seq`
+    pub(crate) fn last_committed(&self) -> u64 {
+        self.shared.queue.lock().unwrap().last_committed
     }
 
-    /// Try write a log into the file
+    /// Sequence numbers of the logs lost by the latest failed commit, if any. These are never
+    /// written, not even after `replace_file`
+    pub(crate) fn failed_logs(&self) -> Option<RangeInclusive<u64>> {
+        self.shared.queue.lock().unwrap().failed.as_ref().map(|(failed, _)| failed.clone())
+    }
+
+    /// Commits all the logs appended so far into the current file, then switches to
+    /// `disk_log_file`, which has the same requirements as the one given to `new`. Clears the
+    /// error state of this writer, if any, but the logs lost by it are not written into the new
+    /// file, and their tickets keep failing
+    pub fn replace_file(&self, disk_log_file: fs::File) {
+        let _ = self.flush();
+        let mut file = self.shared.file.lock().unwrap();
+        *file = DiskLogFile::new(disk_log_file, file.sync_policy);
+        let mut queue = self.shared.queue.lock().unwrap();
+        queue.appended_end = file.records_end();
+        queue.poisoned = None;
+    }
+
+    /// Syncs the file if the sync policy is `SyncPolicy::Every` and the interval has passed since
+    /// the last sync. Should be called periodically, so that logs do not stay unsynced for long
+    /// when there are no further writes
+    pub fn sync_if_due(&self) -> Result<(), Box<dyn Error>> {
+        self.shared.file.lock().unwrap().sync_if_due()
+    }
+
+    /// Commits all the logs appended so far, and flushes them to the disk
     ///
     /// returns `Err` if there's an error with file
-    pub fn write(&mut self, msg: DiskLogMessage) -> Result<(), Box<dyn Error>> {
-        self.disk_log_file.write(&msg.serialize())?;
+    pub fn sync(&self) -> Result<(), Box<dyn Error>> {
+        self.flush()?;
+        self.shared.file.lock().unwrap().sync()
+    }
+}
+
+impl CommitTicket {
+    /// A ticket which has nothing to wait for
+    pub fn committed() -> Self {
+        CommitTicket { seq: 0, shared: None }
+    }
+
+    /// Sequence number of the log, which grows with every log appended to a `DiskLogWriter`
+    pub(crate) fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Waits until the log is written into the file, and synced if the sync policy says so.
+    /// If no other thread is committing, the calling thread commits all the logs queued so far,
+    /// otherwise it waits for the committing thread and maybe takes over the next round
+    ///
+    /// returns `Err` if writing the log fails
+    pub fn wait(self) -> Result<(), Box<dyn Error>> {
+        match self.shared {
+            Some(shared) => shared.wait_committed(self.seq),
+            None => Ok(())
+        }
+    }
+}
+
+impl SharedDiskLog {
+    fn wait_committed(&self, seq: u64) -> Result<(), Box<dyn Error>> {
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            if let Some((failed, e)) = &queue.failed {
+                if failed.contains(&seq) {
+                    return Err(Box::new(DiskLogError::new(&format!("failed committing disk log: {}", e))));
+                }
+            }
+            if queue.last_committed >= seq {
+                return Ok(());
+            }
+            if queue.committing {
+                queue = self.committed.wait(queue).unwrap();
+                continue;
+            }
+
+            // becomes the leader of this round, committing everything queued so far
+            let batch = mem::take(&mut queue.pending);
+            let batch_last = queue.last_appended;
+            queue.committing = true;
+            drop(queue);
+
+            let result = self.file.lock().unwrap().write_batch(&batch);
+
+            queue = self.queue.lock().unwrap();
+            queue.committing = false;
+            match result {
+                Ok(_) => {
+                    queue.last_committed = batch_last;
+                    queue.committed_batches += 1;
+                },
+                Err(e) => {
+                    // logs queued after the batch are lost as well, they would follow a gap
+                    queue.pending.clear();
+                    queue.failed = Some((queue.last_committed + 1..=queue.last_appended, e.to_string()));
+                    queue.poisoned = Some(e.to_string());
+                }
+            }
+            self.committed.notify_all();
+        }
+    }
+}
+
+impl DiskLogFile {
+    fn new(file: fs::File, sync_policy: SyncPolicy) -> Self {
+        let file_size = match file.metadata() {
+            Ok(metadata) => Some(metadata.len()),
+            Err(_) => None
+        };
+        DiskLogFile {
+            file,
+            header_pending: file_size == Some(0),
+            file_size,
+            sync_policy,
+            last_sync: Instant::now(),
+            dirty: false
+        }
+    }
+
+    /// Where the next record will start, the header of an empty file is not written yet
+    fn records_end(&self) -> Option<u64> {
+        match self.file_size {
+            Some(0) => Some(DISK_LOG_HEADER_SIZE),
+            file_size => file_size
+        }
+    }
+
+    fn write_batch(&mut self, batch: &[u8]) -> Result<(), Box<dyn Error>> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let mut buffer = Vec::with_capacity(DISK_LOG_HEADER_SIZE as usize + batch.len());
+        if self.header_pending {
+            buffer.extend_from_slice(&DISK_LOG_MAGIC);
+            buffer.extend_from_slice(&DISK_LOG_VERSION.to_be_bytes());
+        }
+        buffer.extend_from_slice(batch);
+
+        if let Err(e) = self.file.write_all(&buffer) {
+            if let Some(file_size) = self.file_size {
+                let _ = self.file.set_len(file_size);
+            }
+            return Err(Box::new(e));
+        }
+        self.file_size = self.file_size.map(|file_size| file_size + buffer.len() as u64);
+        self.header_pending = false;
+        self.dirty = true;
+
+        match self.sync_policy {
+            SyncPolicy::Always => self.sync_data(),
+            SyncPolicy::Every(_) => self.sync_if_due(),
+            SyncPolicy::Never => Ok(())
+        }
+    }
+
+    fn sync_if_due(&mut self) -> Result<(), Box<dyn Error>> {
+        match self.sync_policy {
+            SyncPolicy::Every(interval) if self.dirty && self.last_sync.elapsed() >= interval => {
+                self.sync_data()
+            },
+            _ => Ok(())
+        }
+    }
+
+    fn sync(&mut self) -> Result<(), Box<dyn Error>> {
+        self.file.sync_all()?;
+        self.last_sync = Instant::now();
+        self.dirty = false;
+        Ok(())
+    }
+
+    fn sync_data(&mut self) -> Result<(), Box<dyn Error>> {
+        self.file.sync_data()?;
+        self.last_sync = Instant::now();
+        self.dirty = false;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::kvstorage::{Key, Value};
+    use crate::kvstorage::crc32::crc32;
+    use crate::kvstorage::disklog::{DiskLogWriter, DiskLogReader, DiskLogMessage, DiskLogFormat, DISK_LOG_MAGIC};
+    use crate::util::{gen_key_n, gen_value};
+    use std::io::{Seek, SeekFrom, Write};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_group_commit() {
+        let mut f = tempfile::tempfile().unwrap();
+        let writer = DiskLogWriter::new(f.try_clone().unwrap());
+
+        let mut tickets = Vec::new();
+        for i in 0..3 {
+            tickets.push(writer.append(DiskLogMessage::Put(gen_key_n(i), Arc::new(gen_value()))).unwrap());
+        }
+        assert_eq!(writer.committed_batches(), 0);
+
+        // waiting on the last ticket commits the earlier ones, too
+        tickets.pop().unwrap().wait().unwrap();
+        assert_eq!(writer.committed_batches(), 1);
+        for ticket in tickets {
+            ticket.wait().unwrap();
+        }
+        assert_eq!(writer.committed_batches(), 1);
+
+        writer.write(DiskLogMessage::Delete(gen_key_n(0))).unwrap();
+        assert_eq!(writer.committed_batches(), 2);
+
+        f.seek(SeekFrom::Start(0)).unwrap();
+        let mut reader = DiskLogReader::new(f);
+        let mut count = 0;
+        while reader.next_log().unwrap().is_some() {
+            count += 1;
+        }
+        assert_eq!(count, 4);
+    }
+
+    #[test]
+    fn test_read_version_1() {
+        let (key, value) = (gen_key_n(7), gen_value());
+        let mut content = DISK_LOG_MAGIC.to_vec();
+        content.extend_from_slice(&1u16.to_be_bytes());
+        let mut record = vec![b'P'];
+        record.extend_from_slice(&key.data);
+        record.extend_from_slice(&value.data);
+        record.extend_from_slice(&crc32(&record).to_be_bytes());
+        content.append(&mut record);
+
+        let mut f = tempfile::tempfile().unwrap();
+        f.write_all(&content).unwrap();
+        f.seek(SeekFrom::Start(0)).unwrap();
+        let mut reader = DiskLogReader::new(f);
+        match reader.next_log().unwrap() {
+            Some(DiskLogMessage::Put(k, v)) => {
+                assert_eq!(k, key);
+                assert_eq!(*v, value);
+            },
+            _ => panic!()
+        }
+        assert!(reader.next_log().unwrap().is_none());
+        assert_eq!(reader.format(), Some(DiskLogFormat::Versioned(1)));
+        assert!(!reader.format().unwrap().is_current());
+    }
+
+    #[test]
+    fn test_variable_length_records() {
+        let mut f = tempfile::tempfile().unwrap();
+        let writer = DiskLogWriter::new(f.try_clone().unwrap());
+        let messages = [
+            (Key::from_bytes(b""), Some(Value::from_bytes(b"value of the empty key"))),
+            (Key::from_bytes(b"a key that is longer than eight bytes"), Some(Value::from_bytes(&[1u8; 70000]))),
+            (Key::from_bytes(b"k"), None)
+        ];
+        for (key, value) in messages.iter() {
+            let msg = match value {
+                Some(value) => DiskLogMessage::Put(key.clone(), Arc::new(value.clone())),
+                None => DiskLogMessage::Delete(key.clone())
+            };
+            writer.write(msg).unwrap();
+        }
+
+        f.seek(SeekFrom::Start(0)).unwrap();
+        let mut reader = DiskLogReader::new(f);
+        for (key, value) in messages.iter() {
+            match (reader.next_log().unwrap().unwrap(), value) {
+                (DiskLogMessage::Put(k, v), Some(value)) => {
+                    assert_eq!(&k, key);
+                    assert_eq!(&*v, value);
+                },
+                (DiskLogMessage::Delete(k), None) => assert_eq!(&k, key),
+                _ => panic!()
+            }
+        }
+        assert!(reader.next_log().unwrap().is_none());
+        assert!(reader.format().unwrap().is_current());
+    }
+
+    #[test]
+    fn test_expire_records() {
+        let mut f = tempfile::tempfile().unwrap();
+        let writer = DiskLogWriter::new(f.try_clone().unwrap());
+        let value = Arc::new(gen_value());
+        writer.write(DiskLogMessage::Expire(gen_key_n(0), Some(1234))).unwrap();
+        writer.write(DiskLogMessage::Batch(vec![
+            DiskLogMessage::Put(gen_key_n(1), value.clone()),
+            DiskLogMessage::Expire(gen_key_n(1), None)
+        ])).unwrap();
+
+        f.seek(SeekFrom::Start(0)).unwrap();
+        let mut reader = DiskLogReader::new(f);
+        match reader.next_log().unwrap().unwrap() {
+            DiskLogMessage::Expire(k, expiry) => assert_eq!((k, expiry), (gen_key_n(0), Some(1234))),
+            _ => panic!()
+        }
+        match reader.next_log().unwrap().unwrap() {
+            DiskLogMessage::Batch(messages) => match &messages[..] {
+                [DiskLogMessage::Put(k1, v), DiskLogMessage::Expire(k2, None)] => {
+                    assert_eq!((k1, k2), (&gen_key_n(1), &gen_key_n(1)));
+                    assert_eq!(v, &value);
+                },
+                _ => panic!()
+            },
+            _ => panic!()
+        }
+        assert!(reader.next_log().unwrap().is_none());
+    }
+}
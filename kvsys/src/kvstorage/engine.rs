@@ -0,0 +1,820 @@
+//! Storage engines the server can run on
+//!
+//! `StorageEngine` is what the server needs from a storage: get, put, delete and scan, callable
+//! from many threads at once. Each backend takes care of its own locking, so that e.g. the log
+//! engine can release its lock before waiting for the disk.
+//!
+//! ```no_run
+//!     use std::sync::Arc;
+//!     use kvsys::kvstorage::{KVStorage, DataLayout};
+//!     use kvsys::kvstorage::engine::{StorageEngine, LogEngine, MemoryEngine};
+//!     // ...
+//!     let engine: Arc<dyn StorageEngine> = Arc::new(LogEngine::new(KVStorage::open("data.kv").unwrap()));
+//!     // or, for tests, without touching the disk
+//!     let engine: Arc<dyn StorageEngine> = Arc::new(MemoryEngine::new(DataLayout::Fixed));
+//!     // ...
+//! ```
+
+use crate::kvstorage::{Key, Value, KVStorage, DataLayout, WriteBatch, BatchOp, VersionedValue, SwapResult, ReadSnapshot,
+                       Ttl, NO_VALUE_VERSION};
+use crate::kvstorage::disklog::CommitTicket;
+use crate::kvstorage::expiry;
+use crate::kvstorage::expiry::{ExpiryIndex, expiry_after, has_expired, ttl_of};
+use crate::kvstorage::mvcc::VersionHistory;
+use crate::kvstorage::scan::{ScanOptions, ScanPage, prefix_range};
+use crate::kvstorage::transaction::ConflictError;
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::ops::Bound::{Included, Excluded};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Key-value pairs returned by `StorageEngine::scan`, values are shared with the engine
+pub type KVPairs = Vec<(Key, Arc<Value>)>;
+
+/// A storage backend shared by all the connections of a server
+pub trait StorageEngine: Send + Sync {
+    /// Trying get the value corresponding to the given `key`, returns `None` if not found
+    fn get(&self, key: &Key) -> Result<Option<Arc<Value>>, Box<dyn Error>>;
+
+    /// Trying put the `key` - `value` pair into storage, the put is durable (as far as the engine
+    /// promises) once this function returns
+    fn put(&self, key: &Key, value: &Value) -> Result<(), Box<dyn Error>>;
+
+    /// Trying delete the `key` from storage, returns the rows affected (1 if `key` held a value,
+    /// 0 otherwise)
+    fn delete(&self, key: &Key) -> Result<usize, Box<dyn Error>>;
+
+    /// Trying scan all kv pairs within interval [`key1`, `key2`), according to dictionary order
+    fn scan(&self, key1: &Key, key2: &Key) -> Result<KVPairs, Box<dyn Error>>;
+
+    /// Trying scan all kv pairs whose keys start with `prefix`, according to dictionary order
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<KVPairs, Box<dyn Error>>;
+
+    /// Same as `scan`, but only the keys, without going through their values where possible
+    fn scan_keys(&self, key1: &Key, key2: &Key) -> Result<Vec<Key>, Box<dyn Error>>;
+
+    /// Count of the keys `scan_keys` would return
+    fn count(&self, key1: &Key, key2: &Key) -> Result<usize, Box<dyn Error>>;
+
+    /// Trying apply all the puts and deletes of `batch` atomically: readers see either none or all
+    /// of them, and so does the engine after a crash. Nothing is applied if any of them is refused
+    fn write_batch(&self, batch: &WriteBatch) -> Result<(), Box<dyn Error>>;
+
+    /// Replaces the value of `key` by `new` if it is `expected` (`None` meaning no value), or
+    /// deletes the key if `new` is `None`, atomically. Returns `Ok(Err(current))` with the current
+    /// value if it is not `expected`, see `KVStorage::compare_and_swap`
+    fn compare_and_swap(&self, key: &Key, expected: Option<&Value>, new: Option<&Value>) -> Result<SwapResult, Box<dyn Error>>;
+
+    /// Same as `get`, but also returns the version of the value, which changes every time the key
+    /// is written, see `KVStorage::version`
+    fn get_versioned(&self, key: &Key) -> Result<VersionedValue, Box<dyn Error>>;
+
+    /// Applies `batch` like `write_batch`, but only if every key of `read_set` still has the
+    /// version it was read with, checked atomically with the writes. Returns a `ConflictError`
+    /// otherwise, in which case nothing is applied
+    fn commit(&self, read_set: &[(Key, u64)], batch: &WriteBatch) -> Result<(), Box<dyn Error>>;
+
+    /// Opens a read snapshot of the current content, which `get_at` and `scan_at` keep seeing as
+    /// it is now until it is released by `release_read_snapshot`, see `mvcc`
+    fn create_read_snapshot(&self) -> Result<ReadSnapshot, Box<dyn Error>>;
+
+    /// Releases `snapshot`, returns `Err` if it is not open
+    fn release_read_snapshot(&self, snapshot: ReadSnapshot) -> Result<(), Box<dyn Error>>;
+
+    /// Same as `get`, but reads through `snapshot` if given
+    fn get_at(&self, key: &Key, snapshot: Option<ReadSnapshot>) -> Result<Option<Arc<Value>>, Box<dyn Error>>;
+
+    /// Same as `scan`, but reads through `snapshot` if given
+    fn scan_at(&self, key1: &Key, key2: &Key, snapshot: Option<ReadSnapshot>) -> Result<KVPairs, Box<dyn Error>>;
+
+    /// Trying scan the kv pairs within the interval from `key1` to `key2` as told by `options`,
+    /// through `snapshot` if given, see `scan`
+    fn scan_with(&self, key1: &Key, key2: &Key, options: &ScanOptions, snapshot: Option<ReadSnapshot>)
+        -> Result<ScanPage, Box<dyn Error>>;
+
+    /// Same as `put`, but the key expires after `ttl`, see `expiry`. Engines that do not support
+    /// expiry return a `StorageError`
+    fn put_with_ttl(&self, key: &Key, value: &Value, ttl: Duration) -> Result<(), Box<dyn Error>>;
+
+    /// Makes the value of `key` expire after `ttl`, returns the rows affected (1 if `key` holds a
+    /// value, 0 otherwise)
+    fn expire(&self, key: &Key, ttl: Duration) -> Result<usize, Box<dyn Error>>;
+
+    /// Makes the value of `key` never expire, returns the rows affected (1 if `key` holds a value
+    /// that had an expiry time, 0 otherwise)
+    fn persist(&self, key: &Key) -> Result<usize, Box<dyn Error>>;
+
+    /// Time left before the value of `key` expires
+    fn ttl(&self, key: &Key) -> Result<Ttl, Box<dyn Error>>;
+
+    /// Deletes all the keys that have expired, returns how many. Expired keys are invisible
+    /// anyway, reaping gives their room back
+    fn reap_expired(&self) -> Result<usize, Box<dyn Error>>;
+}
+
+/// Kinds of `StorageEngine` a server can be configured with
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EngineKind {
+    /// `LogEngine`, the persistent `KVStorage`
+    Log,
+    /// `MemoryEngine`, which loses everything on exit
+    Memory,
+    /// `LsmEngine`, the persistent log-structured merge-tree
+    Lsm
+}
+
+impl Display for EngineKind {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match self {
+            EngineKind::Log => write!(f, "log"),
+            EngineKind::Memory => write!(f, "memory"),
+            EngineKind::Lsm => write!(f, "lsm")
+        }
+    }
+}
+
+impl FromStr for EngineKind {
+    type Err = String;
+
+    /// Parses `log`, `memory` or `lsm`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "log" => Ok(EngineKind::Log),
+            "memory" => Ok(EngineKind::Memory),
+            "lsm" => Ok(EngineKind::Lsm),
+            _ => Err(format!("'{}' is not a valid storage engine", s))
+        }
+    }
+}
+
+/// The `StorageEngine` backed by a `KVStorage`, an in-memory map persisted by a disk log
+pub struct LogEngine {
+    storage: Arc<RwLock<KVStorage>>
+}
+
+impl LogEngine {
+    /// Creates a `LogEngine` serving `storage`
+    pub fn new(storage: KVStorage) -> Self {
+        LogEngine { storage: Arc::new(RwLock::new(storage)) }
+    }
+
+    /// The underlying `KVStorage`, for maintenance such as syncing and taking snapshots
+    pub fn storage(&self) -> Arc<RwLock<KVStorage>> {
+        self.storage.clone()
+    }
+
+    /// Waits for `ticket` without holding the lock, see `KVStorage::rollback_failed`
+    fn wait_logged(&self, ticket: CommitTicket) -> Result<(), Box<dyn Error>> {
+        let result = ticket.wait();
+        if result.is_err() {
+            self.storage.write().unwrap().rollback_failed();
+        }
+        result
+    }
+}
+
+impl StorageEngine for LogEngine {
+    fn get(&self, key: &Key) -> Result<Option<Arc<Value>>, Box<dyn Error>> {
+        self.storage.read().unwrap().try_get(key)
+    }
+
+    fn put(&self, key: &Key, value: &Value) -> Result<(), Box<dyn Error>> {
+        // the write lock is released before waiting for the log to be committed, so that puts and
+        // deletes from other connections can be committed together
+        let ticket = self.storage.write().unwrap().put_deferred(key, value)?;
+        self.wait_logged(ticket)
+    }
+
+    fn delete(&self, key: &Key) -> Result<usize, Box<dyn Error>> {
+        let (rows_affected, ticket) = self.storage.write().unwrap().delete_deferred(key)?;
+        self.wait_logged(ticket)?;
+        Ok(rows_affected)
+    }
+
+    fn scan(&self, key1: &Key, key2: &Key) -> Result<KVPairs, Box<dyn Error>> {
+        self.storage.read().unwrap().try_scan(key1, key2)
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<KVPairs, Box<dyn Error>> {
+        self.storage.read().unwrap().try_scan_prefix(prefix)
+    }
+
+    fn scan_keys(&self, key1: &Key, key2: &Key) -> Result<Vec<Key>, Box<dyn Error>> {
+        Ok(self.storage.read().unwrap().scan_keys(key1, key2))
+    }
+
+    fn count(&self, key1: &Key, key2: &Key) -> Result<usize, Box<dyn Error>> {
+        Ok(self.storage.read().unwrap().count(key1, key2))
+    }
+
+    fn write_batch(&self, batch: &WriteBatch) -> Result<(), Box<dyn Error>> {
+        let ticket = self.storage.write().unwrap().write_batch_deferred(batch)?;
+        self.wait_logged(ticket)
+    }
+
+    fn compare_and_swap(&self, key: &Key, expected: Option<&Value>, new: Option<&Value>) -> Result<SwapResult, Box<dyn Error>> {
+        let (result, ticket) = self.storage.write().unwrap().compare_and_swap_deferred(key, expected, new)?;
+        self.wait_logged(ticket)?;
+        Ok(result)
+    }
+
+    fn get_versioned(&self, key: &Key) -> Result<VersionedValue, Box<dyn Error>> {
+        self.storage.read().unwrap().try_get_versioned(key)
+    }
+
+    fn commit(&self, read_set: &[(Key, u64)], batch: &WriteBatch) -> Result<(), Box<dyn Error>> {
+        let ticket = self.storage.write().unwrap().commit_deferred(read_set, batch)?;
+        self.wait_logged(ticket)
+    }
+
+    fn create_read_snapshot(&self) -> Result<ReadSnapshot, Box<dyn Error>> {
+        Ok(self.storage.write().unwrap().create_read_snapshot())
+    }
+
+    fn release_read_snapshot(&self, snapshot: ReadSnapshot) -> Result<(), Box<dyn Error>> {
+        self.storage.write().unwrap().release_read_snapshot(snapshot)
+    }
+
+    fn get_at(&self, key: &Key, snapshot: Option<ReadSnapshot>) -> Result<Option<Arc<Value>>, Box<dyn Error>> {
+        self.storage.read().unwrap().try_get_at(key, snapshot)
+    }
+
+    fn scan_at(&self, key1: &Key, key2: &Key, snapshot: Option<ReadSnapshot>) -> Result<KVPairs, Box<dyn Error>> {
+        self.storage.read().unwrap().try_scan_at(key1, key2, snapshot)
+    }
+
+    fn scan_with(&self, key1: &Key, key2: &Key, options: &ScanOptions, snapshot: Option<ReadSnapshot>)
+        -> Result<ScanPage, Box<dyn Error>> {
+        self.storage.read().unwrap().try_scan_with(key1, key2, options, snapshot)
+    }
+
+    fn put_with_ttl(&self, key: &Key, value: &Value, ttl: Duration) -> Result<(), Box<dyn Error>> {
+        let ticket = self.storage.write().unwrap().put_with_ttl_deferred(key, value, ttl)?;
+        self.wait_logged(ticket)
+    }
+
+    fn expire(&self, key: &Key, ttl: Duration) -> Result<usize, Box<dyn Error>> {
+        let (rows_affected, ticket) = self.storage.write().unwrap().expire_deferred(key, ttl)?;
+        self.wait_logged(ticket)?;
+        Ok(rows_affected)
+    }
+
+    fn persist(&self, key: &Key) -> Result<usize, Box<dyn Error>> {
+        let (rows_affected, ticket) = self.storage.write().unwrap().persist_deferred(key)?;
+        self.wait_logged(ticket)?;
+        Ok(rows_affected)
+    }
+
+    fn ttl(&self, key: &Key) -> Result<Ttl, Box<dyn Error>> {
+        Ok(self.storage.read().unwrap().ttl(key))
+    }
+
+    fn reap_expired(&self) -> Result<usize, Box<dyn Error>> {
+        let (reaped, ticket) = self.storage.write().unwrap().reap_expired_deferred()?;
+        self.wait_logged(ticket)?;
+        Ok(reaped)
+    }
+}
+
+/// A `StorageEngine` that keeps everything in memory and nothing on disk, mainly for tests
+pub struct MemoryEngine {
+    content: RwLock<MemoryContent>,
+    layout: DataLayout
+}
+
+struct MemoryContent {
+    values: BTreeMap<Key, MemoryValue>,
+    last_version: u64,
+    // what keys held before being written (values together with their expiry times), for open
+    // read snapshots
+    history: VersionHistory<Option<(Arc<Value>, Option<u64>)>>,
+    expiries: ExpiryIndex
+}
+
+/// A value of a `MemoryEngine`, together with its version and expiry time
+#[derive(Clone)]
+struct MemoryValue {
+    value: Arc<Value>,
+    version: u64,
+    expiry: Option<u64>
+}
+
+impl MemoryEngine {
+    /// Creates an empty `MemoryEngine` accepting keys and values that fit `layout`
+    pub fn new(layout: DataLayout) -> Self {
+        let content = MemoryContent {
+            values: BTreeMap::new(),
+            last_version: NO_VALUE_VERSION,
+            history: VersionHistory::new(),
+            expiries: ExpiryIndex::new()
+        };
+        MemoryEngine { content: RwLock::new(content), layout }
+    }
+}
+
+impl MemoryContent {
+    /// The value of `key`, unless it has expired
+    fn live(&self, key: &Key) -> Option<&MemoryValue> {
+        self.values.get(key).filter(|value| !has_expired(value.expiry))
+    }
+
+    fn put(&mut self, key: &Key, value: &Value, expiry: Option<u64>) {
+        self.last_version += 1;
+        let new = MemoryValue { value: Arc::new(value.clone()), version: self.last_version, expiry };
+        let old = self.values.insert(key.clone(), new);
+        self.record(key, old, expiry);
+    }
+
+    /// Deletes `key`, returns the rows affected. Expired values are deleted too, but do not count
+    fn delete(&mut self, key: &Key) -> usize {
+        match self.values.remove(key) {
+            Some(old) => {
+                self.last_version += 1;
+                let rows_affected = if has_expired(old.expiry) { 0 } else { 1 };
+                self.record(key, Some(old), None);
+                rows_affected
+            },
+            None => 0
+        }
+    }
+
+    /// Sets the expiry time of the value of `key`, returns the rows affected
+    fn set_expiry(&mut self, key: &Key, expiry: Option<u64>) -> usize {
+        let value = match self.live(key) {
+            Some(value) if expiry.is_some() || value.expiry.is_some() => value.value.clone(),
+            _ => return 0
+        };
+        self.last_version += 1;
+        let new = MemoryValue { value, version: self.last_version, expiry };
+        let old = self.values.insert(key.clone(), new);
+        self.record(key, old, expiry);
+        1
+    }
+
+    /// Keeps what `key` held before the last write for read snapshots, and keeps track of its
+    /// `expiry` time now
+    fn record(&mut self, key: &Key, old: Option<MemoryValue>, expiry: Option<u64>) {
+        self.expiries.update(key, old.as_ref().and_then(|old| old.expiry), expiry);
+        let written = old.as_ref().map(|old| old.version);
+        self.history.record(key, old.map(|old| (old.value, old.expiry)), written, self.last_version);
+    }
+
+    /// What `key` held at `version`
+    fn get_at(&self, key: &Key, version: u64) -> Option<Arc<Value>> {
+        match self.history.find(key, version) {
+            Some(old) => old.as_ref().filter(|(_, expiry)| !has_expired(*expiry)).map(|(value, _)| value.clone()),
+            None => self.live(key).map(|value| value.value.clone())
+        }
+    }
+
+    fn version(&self, key: &Key) -> u64 {
+        self.live(key).map_or(NO_VALUE_VERSION, |value| value.version)
+    }
+
+    fn write_batch(&mut self, batch: &WriteBatch) {
+        for op in batch.ops() {
+            match op {
+                BatchOp::Put(key, value) => self.put(key, value, None),
+                BatchOp::Delete(key) => {
+                    self.delete(key);
+                }
+            }
+        }
+    }
+}
+
+impl StorageEngine for MemoryEngine {
+    fn get(&self, key: &Key) -> Result<Option<Arc<Value>>, Box<dyn Error>> {
+        Ok(self.content.read().unwrap().live(key).map(|value| value.value.clone()))
+    }
+
+    fn put(&self, key: &Key, value: &Value) -> Result<(), Box<dyn Error>> {
+        self.layout.check_key(key)?;
+        self.layout.check_value(value)?;
+        self.content.write().unwrap().put(key, value, None);
+        Ok(())
+    }
+
+    fn delete(&self, key: &Key) -> Result<usize, Box<dyn Error>> {
+        Ok(self.content.write().unwrap().delete(key))
+    }
+
+    fn scan(&self, key1: &Key, key2: &Key) -> Result<KVPairs, Box<dyn Error>> {
+        if key1 >= key2 {
+            return Ok(Vec::new());
+        }
+        Ok(self.content.read().unwrap().values.range::<Key, _>((Included(key1), Excluded(key2)))
+            .filter(|(_, v)| !has_expired(v.expiry))
+            .map(|(k, v)| (k.clone(), v.value.clone()))
+            .collect())
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<KVPairs, Box<dyn Error>> {
+        Ok(self.content.read().unwrap().values.range(prefix_range(prefix))
+            .filter(|(_, v)| !has_expired(v.expiry))
+            .map(|(k, v)| (k.clone(), v.value.clone()))
+            .collect())
+    }
+
+    fn scan_keys(&self, key1: &Key, key2: &Key) -> Result<Vec<Key>, Box<dyn Error>> {
+        if key1 >= key2 {
+            return Ok(Vec::new());
+        }
+        Ok(self.content.read().unwrap().values.range::<Key, _>((Included(key1), Excluded(key2)))
+            .filter(|(_, v)| !has_expired(v.expiry))
+            .map(|(k, _)| k.clone())
+            .collect())
+    }
+
+    fn count(&self, key1: &Key, key2: &Key) -> Result<usize, Box<dyn Error>> {
+        if key1 >= key2 {
+            return Ok(0);
+        }
+        Ok(self.content.read().unwrap().values.range::<Key, _>((Included(key1), Excluded(key2)))
+            .filter(|(_, v)| !has_expired(v.expiry))
+            .count())
+    }
+
+    fn write_batch(&self, batch: &WriteBatch) -> Result<(), Box<dyn Error>> {
+        self.layout.check_batch(batch)?;
+        self.content.write().unwrap().write_batch(batch);
+        Ok(())
+    }
+
+    fn compare_and_swap(&self, key: &Key, expected: Option<&Value>, new: Option<&Value>) -> Result<SwapResult, Box<dyn Error>> {
+        if let Some(new) = new {
+            self.layout.check_key(key)?;
+            self.layout.check_value(new)?;
+        }
+        let mut content = self.content.write().unwrap();
+        let current = content.live(key).map(|value| value.value.clone());
+        if current.as_deref() != expected {
+            return Ok(Err(current));
+        }
+        match new {
+            Some(new) => content.put(key, new, None),
+            None => {
+                content.delete(key);
+            }
+        }
+        Ok(Ok(()))
+    }
+
+    fn get_versioned(&self, key: &Key) -> Result<VersionedValue, Box<dyn Error>> {
+        match self.content.read().unwrap().live(key) {
+            Some(value) => Ok((Some(value.value.clone()), value.version)),
+            None => Ok((None, NO_VALUE_VERSION))
+        }
+    }
+
+    fn commit(&self, read_set: &[(Key, u64)], batch: &WriteBatch) -> Result<(), Box<dyn Error>> {
+        self.layout.check_batch(batch)?;
+        let mut content = self.content.write().unwrap();
+        if let Some((key, _)) = read_set.iter().find(|(key, version)| content.version(key) != *version) {
+            return Err(Box::new(ConflictError::new(key)));
+        }
+        content.write_batch(batch);
+        Ok(())
+    }
+
+    fn create_read_snapshot(&self) -> Result<ReadSnapshot, Box<dyn Error>> {
+        let mut content = self.content.write().unwrap();
+        let version = content.last_version;
+        Ok(content.history.open(version))
+    }
+
+    fn release_read_snapshot(&self, snapshot: ReadSnapshot) -> Result<(), Box<dyn Error>> {
+        self.content.write().unwrap().history.release(snapshot)
+    }
+
+    fn get_at(&self, key: &Key, snapshot: Option<ReadSnapshot>) -> Result<Option<Arc<Value>>, Box<dyn Error>> {
+        let content = self.content.read().unwrap();
+        match snapshot {
+            Some(snapshot) => Ok(content.get_at(key, content.history.version_of(snapshot)?)),
+            None => Ok(content.live(key).map(|value| value.value.clone()))
+        }
+    }
+
+    fn scan_at(&self, key1: &Key, key2: &Key, snapshot: Option<ReadSnapshot>) -> Result<KVPairs, Box<dyn Error>> {
+        let snapshot = match snapshot {
+            Some(snapshot) => snapshot,
+            None => return self.scan(key1, key2)
+        };
+        if key1 >= key2 {
+            return Ok(Vec::new());
+        }
+        let content = self.content.read().unwrap();
+        let version = content.history.version_of(snapshot)?;
+        let range = (Included(key1), Excluded(key2));
+        let current = content.values.range::<Key, _>(range).map(|(key, v)| (key, Some((v.value.clone(), v.expiry))));
+        Ok(content.history.view(range, version, current).into_iter()
+            .filter_map(|(key, held)| held.filter(|(_, expiry)| !has_expired(*expiry)).map(|(value, _)| (key.clone(), value)))
+            .collect())
+    }
+
+    fn scan_with(&self, key1: &Key, key2: &Key, options: &ScanOptions, snapshot: Option<ReadSnapshot>)
+        -> Result<ScanPage, Box<dyn Error>> {
+        let content = self.content.read().unwrap();
+        let version = snapshot.map(|snapshot| content.history.version_of(snapshot)).transpose()?;
+        let range = match options.range(key1, key2) {
+            Some(range) => range,
+            None => return Ok(ScanPage { pairs: Vec::new(), continuation: None })
+        };
+        let current = content.values.range::<Key, _>(range);
+        let live = |(key, v): (&Key, &MemoryValue)| {
+            if has_expired(v.expiry) { None } else { Some(Ok((key.clone(), v.value.clone()))) }
+        };
+        let version = match version {
+            Some(version) => version,
+            None if options.descending => return options.collect(current.rev().filter_map(live)),
+            None => return options.collect(current.filter_map(live))
+        };
+        let current = current.map(|(key, v)| (key, Some((v.value.clone(), v.expiry))));
+        let mut view = content.history.view(range, version, current);
+        if options.descending {
+            view.reverse();
+        }
+        options.collect(view.into_iter()
+            .filter_map(|(key, held)| held.filter(|(_, expiry)| !has_expired(*expiry)).map(|(value, _)| Ok((key.clone(), value)))))
+    }
+
+    fn put_with_ttl(&self, key: &Key, value: &Value, ttl: Duration) -> Result<(), Box<dyn Error>> {
+        self.layout.check_key(key)?;
+        self.layout.check_value(value)?;
+        self.content.write().unwrap().put(key, value, Some(expiry_after(ttl)));
+        Ok(())
+    }
+
+    fn expire(&self, key: &Key, ttl: Duration) -> Result<usize, Box<dyn Error>> {
+        Ok(self.content.write().unwrap().set_expiry(key, Some(expiry_after(ttl))))
+    }
+
+    fn persist(&self, key: &Key) -> Result<usize, Box<dyn Error>> {
+        Ok(self.content.write().unwrap().set_expiry(key, None))
+    }
+
+    fn ttl(&self, key: &Key) -> Result<Ttl, Box<dyn Error>> {
+        Ok(match self.content.read().unwrap().values.get(key) {
+            Some(value) => ttl_of(value.expiry),
+            None => Ttl::NoValue
+        })
+    }
+
+    fn reap_expired(&self) -> Result<usize, Box<dyn Error>> {
+        let mut content = self.content.write().unwrap();
+        let expired = content.expiries.expired(expiry::now());
+        for key in expired.iter() {
+            content.delete(key);
+        }
+        Ok(expired.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::kvstorage::{KVStorage, Key, Value, DataLayout, WriteBatch, Ttl};
+    use crate::kvstorage::engine::{StorageEngine, LogEngine, MemoryEngine};
+    use crate::kvstorage::lsm::{LsmEngine, LsmOptions};
+    use crate::kvstorage::scan::ScanOptions;
+    use crate::util::{gen_key_n, gen_value};
+    use std::ops::Deref;
+    use std::thread;
+    use std::time::Duration;
+
+    fn check_engine(engine: &dyn StorageEngine) {
+        let mut values = Vec::new();
+        for i in 0..64 {
+            let value = gen_value();
+            engine.put(&gen_key_n(i), &value).unwrap();
+            values.push(value);
+        }
+        assert_eq!(engine.delete(&gen_key_n(3)).unwrap(), 1);
+        assert_eq!(engine.delete(&gen_key_n(3)).unwrap(), 0);
+        assert!(engine.get(&gen_key_n(3)).unwrap().is_none());
+        assert_eq!(engine.get(&gen_key_n(4)).unwrap().unwrap().deref(), &values[4]);
+
+        let scanned = engine.scan(&gen_key_n(2), &gen_key_n(10)).unwrap();
+        assert_eq!(scanned.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>(),
+                   [2, 4, 5, 6, 7, 8, 9].iter().map(|&i| gen_key_n(i)).collect::<Vec<_>>());
+        assert!(engine.scan(&gen_key_n(10), &gen_key_n(2)).unwrap().is_empty());
+        let keys = engine.scan_keys(&gen_key_n(2), &gen_key_n(10)).unwrap();
+        assert_eq!(keys, scanned.into_iter().map(|(k, _)| k).collect::<Vec<_>>());
+        assert_eq!(engine.count(&gen_key_n(2), &gen_key_n(10)).unwrap(), 7);
+        assert_eq!(engine.count(&gen_key_n(0), &gen_key_n(100)).unwrap(), 63);
+        assert!(engine.scan_keys(&gen_key_n(10), &gen_key_n(2)).unwrap().is_empty());
+        assert_eq!(engine.count(&gen_key_n(10), &gen_key_n(10)).unwrap(), 0);
+
+        let mut batch = WriteBatch::new();
+        batch.delete(gen_key_n(4)).put(gen_key_n(3), values[3].clone()).delete(gen_key_n(100));
+        engine.write_batch(&batch).unwrap();
+        assert!(engine.get(&gen_key_n(4)).unwrap().is_none());
+        assert_eq!(engine.get(&gen_key_n(3)).unwrap().unwrap().deref(), &values[3]);
+
+        // a batch with a single bad value is refused as a whole
+        let mut batch = WriteBatch::new();
+        batch.delete(gen_key_n(5)).put(gen_key_n(6), Value::from_bytes(b"too short"));
+        assert!(engine.write_batch(&batch).is_err());
+        assert_eq!(engine.get(&gen_key_n(5)).unwrap().unwrap().deref(), &values[5]);
+
+        // compare-and-swap only writes if the current value is the expected one
+        let (key, value) = (gen_key_n(200), gen_value());
+        assert!(engine.compare_and_swap(&key, None, Some(&values[0])).unwrap().is_ok());
+        assert_eq!(engine.compare_and_swap(&key, None, Some(&value)).unwrap().unwrap_err().unwrap().deref(), &values[0]);
+        assert_eq!(engine.compare_and_swap(&key, Some(&value), None).unwrap().unwrap_err().unwrap().deref(), &values[0]);
+        assert!(engine.compare_and_swap(&key, Some(&values[0]), Some(&value)).unwrap().is_ok());
+        assert_eq!(engine.get(&key).unwrap().unwrap().deref(), &value);
+        assert!(engine.compare_and_swap(&key, Some(&value), Some(&Value::from_bytes(b"too short"))).is_err());
+        assert!(engine.compare_and_swap(&key, Some(&value), None).unwrap().is_ok());
+        assert!(engine.get(&key).unwrap().is_none());
+        assert_eq!(engine.compare_and_swap(&key, Some(&value), None).unwrap(), Err(None));
+        assert!(engine.compare_and_swap(&key, None, None).unwrap().is_ok());
+
+        // a read snapshot keeps seeing the content of the moment it has been created
+        let snapshot = engine.create_read_snapshot().unwrap();
+        let before = engine.scan(&gen_key_n(0), &gen_key_n(300)).unwrap();
+        engine.put(&gen_key_n(10), &value).unwrap();
+        engine.delete(&gen_key_n(11)).unwrap();
+        engine.put(&gen_key_n(201), &value).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put(gen_key_n(12), value.clone()).delete(gen_key_n(12)).put(gen_key_n(11), value.clone());
+        engine.write_batch(&batch).unwrap();
+        assert_eq!(engine.get_at(&gen_key_n(10), Some(snapshot)).unwrap().unwrap().deref(), &values[10]);
+        assert_eq!(engine.get_at(&gen_key_n(11), Some(snapshot)).unwrap().unwrap().deref(), &values[11]);
+        assert_eq!(engine.get_at(&gen_key_n(12), Some(snapshot)).unwrap().unwrap().deref(), &values[12]);
+        assert!(engine.get_at(&gen_key_n(201), Some(snapshot)).unwrap().is_none());
+        assert_eq!(engine.get_at(&gen_key_n(10), None).unwrap().unwrap().deref(), &value);
+        assert_eq!(engine.scan_at(&gen_key_n(0), &gen_key_n(300), Some(snapshot)).unwrap(), before);
+        assert_ne!(engine.scan_at(&gen_key_n(0), &gen_key_n(300), None).unwrap(), before);
+        engine.release_read_snapshot(snapshot).unwrap();
+        assert!(engine.get_at(&gen_key_n(10), Some(snapshot)).is_err());
+        assert!(engine.release_read_snapshot(snapshot).is_err());
+    }
+
+    fn check_scan_options(engine: &dyn StorageEngine) {
+        let keys = (0..20).map(gen_key_n).collect::<Vec<_>>();
+        for key in keys.iter() {
+            engine.put(key, &gen_value()).unwrap();
+        }
+        engine.delete(&keys[5]).unwrap();
+        let scan = |options: &ScanOptions, snapshot| {
+            let page = engine.scan_with(&keys[2], &keys[12], options, snapshot).unwrap();
+            (page.pairs.into_iter().map(|(key, _)| key).collect::<Vec<_>>(), page.continuation)
+        };
+        let expected = [2, 3, 4, 6, 7, 8, 9, 10, 11].iter().map(|&i| keys[i].clone()).collect::<Vec<_>>();
+
+        let mut options = ScanOptions::from_default();
+        assert_eq!(scan(&options, None), (expected.clone(), None));
+        options.include_start = false;
+        options.include_end = true;
+        assert_eq!(scan(&options, None).0, [&expected[1..], &keys[12..13]].concat());
+        options.descending = true;
+        let reversed = expected.iter().rev().cloned().collect::<Vec<_>>();
+        assert_eq!(scan(&options, None).0, [&keys[12..13], &reversed[..8]].concat());
+
+        // pages resume right after the continuation token, writes in between included
+        let snapshot = engine.create_read_snapshot().unwrap();
+        options = ScanOptions::from_default();
+        options.limit = Some(4);
+        let (page, continuation) = scan(&options, None);
+        assert_eq!((page, continuation.clone()), (expected[..4].to_vec(), Some(keys[6].clone())));
+        engine.put(&keys[5], &gen_value()).unwrap();
+        engine.delete(&keys[9]).unwrap();
+        options.continuation = continuation;
+        assert_eq!(scan(&options, None).0, [&expected[4..6], &expected[7..9]].concat());
+        options.continuation = Some(keys[8].clone());
+        assert_eq!(scan(&options, None), (keys[10..12].to_vec(), None));
+        options.continuation = Some(keys[8].clone());
+        assert_eq!(scan(&options, Some(snapshot)), (expected[6..].to_vec(), None));
+        options.descending = true;
+        options.limit = Some(3);
+        options.continuation = None;
+        assert_eq!(scan(&options, Some(snapshot)), (reversed[..3].to_vec(), Some(expected[6].clone())));
+        assert_eq!(scan(&options, None).0, [&keys[11..12], &keys[10..11], &keys[8..9]].concat());
+        engine.release_read_snapshot(snapshot).unwrap();
+        assert!(engine.scan_with(&keys[2], &keys[12], &options, Some(snapshot)).is_err());
+        assert_eq!(scan(&ScanOptions::from_default(), None).0.len(), 9);
+        assert_eq!(engine.scan_with(&keys[12], &keys[2], &ScanOptions::from_default(), None).unwrap().pairs.len(), 0);
+    }
+
+    fn check_expiry(engine: &dyn StorageEngine) {
+        let value = gen_value();
+        engine.put_with_ttl(&gen_key_n(0), &value, Duration::from_secs(60)).unwrap();
+        engine.put_with_ttl(&gen_key_n(1), &value, Duration::from_millis(100)).unwrap();
+        engine.put(&gen_key_n(2), &value).unwrap();
+        assert!(matches!(engine.ttl(&gen_key_n(0)).unwrap(), Ttl::Expires(left) if left.as_secs() >= 59));
+        assert_eq!(engine.ttl(&gen_key_n(2)).unwrap(), Ttl::Persistent);
+        assert_eq!(engine.expire(&gen_key_n(2), Duration::from_millis(100)).unwrap(), 1);
+        assert_eq!(engine.persist(&gen_key_n(0)).unwrap(), 1);
+        assert_eq!(engine.persist(&gen_key_n(0)).unwrap(), 0);
+        assert_eq!(engine.expire(&gen_key_n(3), Duration::from_secs(60)).unwrap(), 0);
+
+        // expired keys are invisible before being reaped, read snapshots included
+        let snapshot = engine.create_read_snapshot().unwrap();
+        thread::sleep(Duration::from_millis(150));
+        assert!(engine.get(&gen_key_n(1)).unwrap().is_none());
+        assert!(engine.get_at(&gen_key_n(2), Some(snapshot)).unwrap().is_none());
+        assert_eq!(engine.ttl(&gen_key_n(1)).unwrap(), Ttl::NoValue);
+        assert_eq!(engine.scan(&gen_key_n(0), &gen_key_n(4)).unwrap().len(), 1);
+        assert_eq!(engine.scan_keys(&gen_key_n(0), &gen_key_n(4)).unwrap(), vec![gen_key_n(0)]);
+        assert_eq!(engine.count(&gen_key_n(0), &gen_key_n(4)).unwrap(), 1);
+        assert_eq!(engine.delete(&gen_key_n(1)).unwrap(), 0);
+        assert_eq!(engine.reap_expired().unwrap(), 1);
+        assert_eq!(engine.reap_expired().unwrap(), 0);
+        engine.release_read_snapshot(snapshot).unwrap();
+
+        // a plain put removes the expiry
+        engine.put_with_ttl(&gen_key_n(1), &value, Duration::from_millis(1)).unwrap();
+        engine.put(&gen_key_n(1), &value).unwrap();
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(engine.get(&gen_key_n(1)).unwrap().unwrap().deref(), &value);
+    }
+
+    #[test]
+    fn test_engines() {
+        check_engine(&MemoryEngine::new(DataLayout::Fixed));
+        check_engine(&LogEngine::new(KVStorage::new(tempfile::tempfile().unwrap())));
+        let dir = tempfile::tempdir().unwrap();
+        check_engine(&LsmEngine::open(dir.path(), LsmOptions::from_default()).unwrap());
+    }
+
+    fn check_scan_prefix(engine: &dyn StorageEngine) {
+        let keys = [&b"a"[..], b"ab", b"ab\xff", b"abc", b"ac", b"\xff", b"\xff\xff", b"\xff\xff\x01"]
+            .iter().map(|&bytes| Key::from_bytes(bytes)).collect::<Vec<_>>();
+        for key in keys.iter() {
+            engine.put(key, &Value::from_bytes(&key.data)).unwrap();
+        }
+        engine.delete(&keys[3]).unwrap();
+        let scan = |prefix: &[u8]| {
+            engine.scan_prefix(prefix).unwrap().into_iter().map(|(key, value)| {
+                assert_eq!(key.data, value.data);
+                key
+            }).collect::<Vec<_>>()
+        };
+        assert_eq!(scan(b"ab"), keys[1..3].to_vec());
+        assert_eq!(scan(b"ab\xff"), keys[2..3].to_vec());
+        assert!(scan(b"b").is_empty());
+        // prefixes without a successor go on up to the greatest key
+        assert_eq!(scan(b"\xff"), keys[5..].to_vec());
+        assert_eq!(scan(b"\xff\xff"), keys[6..].to_vec());
+        assert_eq!(scan(b""), [&keys[..3], &keys[4..]].concat());
+    }
+
+    #[test]
+    fn test_engines_scan_prefix() {
+        check_scan_prefix(&MemoryEngine::new(DataLayout::variable()));
+        let mut storage = KVStorage::new(tempfile::tempfile().unwrap());
+        storage.set_layout(DataLayout::variable());
+        check_scan_prefix(&LogEngine::new(storage));
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = LsmOptions::from_default();
+        options.layout = DataLayout::variable();
+        let engine = LsmEngine::open(dir.path(), options).unwrap();
+        check_scan_prefix(&engine);
+        // and with everything in tables
+        engine.flush().unwrap();
+        let prefixed = engine.scan_prefix(b"\xff\xff").unwrap();
+        assert_eq!(prefixed.into_iter().map(|(key, _)| key.data).collect::<Vec<_>>(), vec![b"\xff\xff".to_vec(), b"\xff\xff\x01".to_vec()]);
+    }
+
+    #[test]
+    fn test_engines_scan_options() {
+        check_scan_options(&MemoryEngine::new(DataLayout::Fixed));
+        check_scan_options(&LogEngine::new(KVStorage::new(tempfile::tempfile().unwrap())));
+        let dir = tempfile::tempdir().unwrap();
+        let engine = LsmEngine::open(dir.path(), LsmOptions::from_default()).unwrap();
+        check_scan_options(&engine);
+        // and with everything in tables
+        engine.flush().unwrap();
+        let mut options = ScanOptions::from_default();
+        options.descending = true;
+        options.limit = Some(2);
+        let page = engine.scan_with(&gen_key_n(0), &gen_key_n(20), &options, None).unwrap();
+        assert_eq!(page.pairs.iter().map(|(key, _)| key.clone()).collect::<Vec<_>>(), vec![gen_key_n(19), gen_key_n(18)]);
+        options.include_start = false;
+        options.descending = false;
+        options.continuation = Some(gen_key_n(10));
+        let page = engine.scan_with(&gen_key_n(0), &gen_key_n(20), &options, None).unwrap();
+        assert_eq!(page.pairs.iter().map(|(key, _)| key.clone()).collect::<Vec<_>>(), vec![gen_key_n(11), gen_key_n(12)]);
+        assert_eq!(page.continuation, Some(gen_key_n(12)));
+    }
+
+    #[test]
+    fn test_engines_expiry() {
+        check_expiry(&MemoryEngine::new(DataLayout::Fixed));
+        check_expiry(&LogEngine::new(KVStorage::new(tempfile::tempfile().unwrap())));
+
+        // the lsm engine refuses expiry, keys being persistent there
+        let dir = tempfile::tempdir().unwrap();
+        let engine = LsmEngine::open(dir.path(), LsmOptions::from_default()).unwrap();
+        assert!(engine.put_with_ttl(&gen_key_n(0), &gen_value(), Duration::from_secs(1)).is_err());
+        engine.put(&gen_key_n(0), &gen_value()).unwrap();
+        assert!(engine.expire(&gen_key_n(0), Duration::from_secs(1)).is_err());
+        assert_eq!(engine.ttl(&gen_key_n(0)).unwrap(), Ttl::Persistent);
+        assert_eq!(engine.reap_expired().unwrap(), 0);
+    }
+}
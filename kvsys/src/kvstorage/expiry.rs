@@ -0,0 +1,125 @@
+//! Keys that expire, for data only meant to live for a while (e.g. sessions)
+//!
+//! A key can be given an expiry time (see `KVStorage::put_with_ttl` and `KVStorage::expire`), kept
+//! in milliseconds since the UNIX epoch. Once it has passed, the key holds no value as far as reads
+//! are concerned, read snapshots included. The expired value stays in memory and in the log until
+//! reaped: `KVStorage::reap_expired` logs deletes of expired keys, which the next compaction then
+//! drops together with their puts. Servers reap periodically, see `KVServerConfig::reap_interval`.
+//!
+//! Writing a key without a TTL (a plain put, a batch, a compare-and-swap) removes its expiry.
+//!
+//! ```no_run
+//!     use std::time::Duration;
+//!     use kvsys::kvstorage::{KVStorage, Key, Value};
+//!     // ...
+//!     let mut kv = KVStorage::open("data.kv").unwrap();
+//!     let session = Key::from_bytes(b"session:42");
+//!     kv.put_with_ttl(&session, &Value::from_bytes(b"user=icey"), Duration::from_secs(1800)).unwrap();
+//!     // keep the session alive for another half an hour
+//!     kv.expire(&session, Duration::from_secs(1800)).unwrap();
+//!     // ...
+//!     kv.reap_expired().unwrap();
+//!     // ...
+//! ```
+
+use crate::kvstorage::Key;
+
+use std::collections::BTreeSet;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Time left to a key, see `KVStorage::ttl`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Ttl {
+    /// The key holds no value (or has already expired)
+    NoValue,
+    /// The key holds a value that never expires
+    Persistent,
+    /// The key holds a value that expires after the given time
+    Expires(Duration)
+}
+
+/// Current time in milliseconds since the UNIX epoch, which expiry times are compared against
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64)
+}
+
+/// Expiry time of a key given `ttl` from now
+pub fn expiry_after(ttl: Duration) -> u64 {
+    now().saturating_add(ttl.as_millis().min(u64::MAX as u128) as u64)
+}
+
+/// Whether a key with the given `expiry` time has expired
+pub(crate) fn has_expired(expiry: Option<u64>) -> bool {
+    matches!(expiry, Some(expiry) if expiry <= now())
+}
+
+/// `Ttl` of a key holding a value with the given `expiry` time
+pub(crate) fn ttl_of(expiry: Option<u64>) -> Ttl {
+    match expiry {
+        Some(expiry) => match expiry.checked_sub(now()) {
+            Some(left) if left > 0 => Ttl::Expires(Duration::from_millis(left)),
+            _ => Ttl::NoValue
+        },
+        None => Ttl::Persistent
+    }
+}
+
+/// Keys having an expiry time, ordered by it
+pub(crate) struct ExpiryIndex {
+    keys: BTreeSet<(u64, Key)>
+}
+
+impl ExpiryIndex {
+    pub fn new() -> Self {
+        ExpiryIndex { keys: BTreeSet::new() }
+    }
+
+    /// Updates the expiry time of `key` from `old` to `new`
+    pub fn update(&mut self, key: &Key, old: Option<u64>, new: Option<u64>) {
+        if old == new {
+            return;
+        }
+        if let Some(old) = old {
+            self.keys.remove(&(old, key.clone()));
+        }
+        if let Some(new) = new {
+            self.keys.insert((new, key.clone()));
+        }
+    }
+
+    /// Keys that have expired by `now`, the earliest expired first
+    pub fn expired(&self, now: u64) -> Vec<Key> {
+        self.keys.iter().take_while(|(expiry, _)| *expiry <= now).map(|(_, key)| key.clone()).collect()
+    }
+
+    /// Count of keys having an expiry time
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::kvstorage::expiry::{ExpiryIndex, Ttl, ttl_of, has_expired, now};
+    use crate::util::gen_key_n;
+
+    #[test]
+    fn test_expiry_index() {
+        let mut index = ExpiryIndex::new();
+        index.update(&gen_key_n(0), None, Some(30));
+        index.update(&gen_key_n(1), None, Some(10));
+        index.update(&gen_key_n(2), None, Some(20));
+        index.update(&gen_key_n(2), Some(20), Some(40));
+        index.update(&gen_key_n(1), Some(10), None);
+        assert_eq!(index.len(), 2);
+        assert!(index.expired(29).is_empty());
+        assert_eq!(index.expired(30), vec![gen_key_n(0)]);
+        assert_eq!(index.expired(50), vec![gen_key_n(0), gen_key_n(2)]);
+
+        assert!(has_expired(Some(now())));
+        assert!(!has_expired(Some(now() + 60000)) && !has_expired(None));
+        assert_eq!(ttl_of(None), Ttl::Persistent);
+        assert_eq!(ttl_of(Some(1)), Ttl::NoValue);
+        assert!(matches!(ttl_of(Some(now() + 60000)), Ttl::Expires(left) if left.as_secs() >= 59));
+    }
+}
@@ -0,0 +1,124 @@
+//! The manifest file API
+//!
+//! The manifest records which table files make up an `LsmEngine`, and which write-ahead logs have
+//! already been flushed into them. It is rewritten (to a temporary file, then renamed) every time
+//! the set of tables changes, so table files and logs not mentioned in it can be safely removed.
+
+use crate::kvstorage::sync_parent_dir;
+use crate::kvstorage::crc32::crc32;
+use crate::kvstorage::disklog::DiskLogError;
+
+use std::error::Error;
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// Manifest file format
+//  -- 8 bytes magic (0x89 'PKVMAN' 0x0a)
+//  -- 2 bytes format version, in big endian
+//  -- 8 bytes id of the last write-ahead log flushed into tables, in big endian
+//  -- 4 bytes count of tables, in big endian
+//  -- (count) 8 bytes table ids, newest table first, in big endian
+//  -- 4 bytes CRC-32 of all the bytes above, in big endian
+
+const MANIFEST_MAGIC: [u8; 8] = [0x89, b'P', b'K', b'V', b'M', b'A', b'N', 0x0a];
+const MANIFEST_VERSION: u16 = 1;
+const MANIFEST_FILE_NAME: &str = "MANIFEST";
+
+/// Content of the manifest of an `LsmEngine`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Manifest {
+    /// id of the last write-ahead log whose content is in the tables, 0 for none
+    pub flushed_wal: u64,
+    /// ids of the table files, newest first
+    pub tables: Vec<u64>
+}
+
+/// Path of the manifest of the engine living in `dir`
+pub fn manifest_path(dir: &Path) -> PathBuf {
+    dir.join(MANIFEST_FILE_NAME)
+}
+
+impl Manifest {
+    /// Loads the manifest of the engine living in `dir`, returns an empty `Manifest` if there is
+    /// none yet
+    pub fn load(dir: &Path) -> Result<Self, Box<dyn Error>> {
+        let path = manifest_path(dir);
+        if !path.exists() {
+            return Ok(Manifest::default());
+        }
+        let raw = fs::read(&path)?;
+        if raw.len() < 26 || raw[0..8] != MANIFEST_MAGIC {
+            return Err(Box::new(DiskLogError::corrupted(0, "incorrect manifest header")));
+        }
+        let (body, crc) = raw.split_at(raw.len() - 4);
+        if crc32(body) != u32::from_be_bytes([crc[0], crc[1], crc[2], crc[3]]) {
+            return Err(Box::new(DiskLogError::corrupted(0, "manifest checksum mismatch")));
+        }
+        if u16::from_be_bytes([body[8], body[9]]) != MANIFEST_VERSION {
+            return Err(Box::new(DiskLogError::new("unsupported manifest version")));
+        }
+
+        let flushed_wal = read_u64(&body[10..18]);
+        let count = u32::from_be_bytes([body[18], body[19], body[20], body[21]]) as usize;
+        if body.len() != 22 + count * 8 {
+            return Err(Box::new(DiskLogError::corrupted(18, "incorrect count of tables in manifest")));
+        }
+        let tables = body[22..].chunks(8).map(read_u64).collect();
+        Ok(Manifest { flushed_wal, tables })
+    }
+
+    /// Writes this manifest as the manifest of the engine living in `dir`, replacing the old one
+    /// atomically
+    pub fn write(&self, dir: &Path) -> Result<(), Box<dyn Error>> {
+        let mut raw = Vec::new();
+        raw.extend_from_slice(&MANIFEST_MAGIC);
+        raw.extend_from_slice(&MANIFEST_VERSION.to_be_bytes());
+        raw.extend_from_slice(&self.flushed_wal.to_be_bytes());
+        raw.extend_from_slice(&(self.tables.len() as u32).to_be_bytes());
+        for table in self.tables.iter() {
+            raw.extend_from_slice(&table.to_be_bytes());
+        }
+        let crc = crc32(&raw);
+        raw.extend_from_slice(&crc.to_be_bytes());
+
+        let path = manifest_path(dir);
+        let tmp_path = dir.join(format!("{}.tmp", MANIFEST_FILE_NAME));
+        {
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(&raw)?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, &path)?;
+        sync_parent_dir(&path);
+        Ok(())
+    }
+}
+
+fn read_u64(raw: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(raw);
+    u64::from_be_bytes(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::kvstorage::lsm::manifest::{Manifest, manifest_path};
+    use std::fs;
+
+    #[test]
+    fn test_manifest_rw() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(Manifest::load(dir.path()).unwrap(), Manifest::default());
+
+        let manifest = Manifest { flushed_wal: 7, tables: vec![9, 5, 2] };
+        manifest.write(dir.path()).unwrap();
+        assert_eq!(Manifest::load(dir.path()).unwrap(), manifest);
+
+        let mut raw = fs::read(manifest_path(dir.path())).unwrap();
+        raw[12] ^= 1;
+        fs::write(manifest_path(dir.path()), &raw).unwrap();
+        assert!(Manifest::load(dir.path()).is_err());
+    }
+}
@@ -0,0 +1,881 @@
+//! A log-structured merge-tree storage engine
+//!
+//! `LsmEngine` keeps recent writes in a memtable, which is a `KVStorage` whose disk log serves as
+//! the write-ahead log. Once the memtable grows past `LsmOptions::memtable_size`, it is frozen and
+//! flushed in the background into an immutable table file sorted by key, and a fresh memtable
+//! takes its place. Reads look into the memtable first, then into tables from the newest to the
+//! oldest; scans merge all of them. When there are more than `LsmOptions::max_tables` tables, they
+//! are compacted into one, dropping overwritten values and tombstones.
+//!
+//! A read snapshot opens a read snapshot of the memtable, and holds on to the immutable memtable
+//! and the tables of the moment, which never change. The memtable itself is held on to once it gets
+//! frozen, and table files removed by a compaction stay readable as long as they are held.
+//!
+//! Values of a table all get the same version (see `KVStorage::version`): the one right after the
+//! last version handed out by the memtable it has been flushed from, or the greatest version of the
+//! tables it has been merged from. Memtables hand out greater versions than all the tables, so a
+//! key never gets back a version it had before being overwritten, and optimistic transactions see
+//! the conflict.
+//!
+//! Keys never expire, table files have no room for expiry times: `put_with_ttl` and `expire` are
+//! refused with a `StorageError`.
+//!
+//! All the files of an engine live in one directory
+//!  -- `MANIFEST`, the table files in use and the last write-ahead log flushed into them
+//!  -- `<id>.sst`, table files
+//!  -- `<id>.wal`, write-ahead logs, the one with the greatest id being the current memtable
+//!
+//! ```no_run
+//!     use kvsys::kvstorage::lsm::{LsmEngine, LsmOptions};
+//!     use kvsys::kvstorage::engine::StorageEngine;
+//!     // ...
+//!     let engine = LsmEngine::open("data.lsm", LsmOptions::from_default()).unwrap();
+//!     // ...
+//!     // writes everything in the memtable into a table, and merges all tables into one
+//!     engine.compact().unwrap();
+//!     // ...
+//! ```
+
+pub mod manifest;
+pub mod table;
+
+use crate::kvstorage::{Key, Value, KVStorage, StorageOptions, RecoveryMode, SyncPolicy, DataLayout, WriteBatch, BatchOp,
+                       VersionedValue, SwapResult, ReadSnapshot, StorageError, Ttl, NO_VALUE_VERSION, LOADED_VERSION};
+use crate::kvstorage::disklog::CommitTicket;
+use crate::kvstorage::transaction::ConflictError;
+use crate::kvstorage::engine::{StorageEngine, KVPairs};
+use crate::kvstorage::scan::{ScanOptions, ScanPage, live_pair, prefix_range};
+use crate::kvstorage::options::DEFAULT_SYNC_INTERVAL;
+use crate::kvstorage::lsm::manifest::Manifest;
+use crate::kvstorage::lsm::table::{Table, TableEntry, write_table};
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::iter::Peekable;
+use std::ops::Bound::{Included, Excluded, Unbounded};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use log::{info, warn};
+
+/// Default size of the memtable that triggers a flush
+pub const DEFAULT_MEMTABLE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Default count of tables that triggers a compaction
+pub const DEFAULT_MAX_TABLES: usize = 4;
+
+/// Bytes accounted for every memtable entry besides its key and value
+const MEMTABLE_ENTRY_OVERHEAD: usize = 32;
+
+const TABLE_SUFFIX: &str = ".sst";
+const WAL_SUFFIX: &str = ".wal";
+const TMP_SUFFIX: &str = ".tmp";
+
+/// Options used when opening an `LsmEngine`, see its fields for further information
+#[derive(Clone, Debug)]
+pub struct LsmOptions {
+    /// approximate size in bytes of keys and values in the memtable that triggers a flush
+    pub memtable_size: usize,
+    /// count of tables that triggers a compaction when exceeded
+    pub max_tables: usize,
+    /// when the write-ahead log gets synced to the disk
+    pub sync_policy: SyncPolicy,
+    /// how to handle an incomplete trailing record in the write-ahead log
+    pub recovery: RecoveryMode,
+    /// sizes of keys and values accepted
+    pub layout: DataLayout
+}
+
+impl LsmOptions {
+    /// Creates a `LsmOptions` using default value
+    pub fn from_default() -> Self {
+        LsmOptions {
+            memtable_size: DEFAULT_MEMTABLE_SIZE,
+            max_tables: DEFAULT_MAX_TABLES,
+            sync_policy: SyncPolicy::Every(DEFAULT_SYNC_INTERVAL),
+            recovery: RecoveryMode::TruncateTornTail,
+            layout: DataLayout::Fixed
+        }
+    }
+
+    fn wal_options(&self) -> StorageOptions {
+        StorageOptions {
+            recovery: self.recovery,
+            compaction_ratio: None,
+            sync_policy: self.sync_policy,
+            layout: self.layout,
+            keep_tombstones: true,
+            memory_budget: None
+        }
+    }
+}
+
+/// The `StorageEngine` built as a log-structured merge-tree, see the module documentation
+pub struct LsmEngine {
+    shared: Arc<LsmShared>
+}
+
+struct LsmShared {
+    dir: PathBuf,
+    options: LsmOptions,
+    state: RwLock<LsmState>,
+    manifest: Mutex<Manifest>,
+    // held while flushing or compacting, so that the set of tables only changes in one place
+    maintenance: Mutex<()>,
+    maintenance_scheduled: AtomicBool,
+    next_file_id: AtomicU64
+}
+
+struct LsmState {
+    memtable: KVStorage,
+    memtable_id: u64,
+    memtable_size: usize,
+    immutable: Option<(u64, Arc<KVStorage>)>,
+    tables: Vec<Arc<Table>>,
+    snapshots: HashMap<u64, LsmSnapshot>,
+    next_snapshot_id: u64
+}
+
+/// What a read snapshot reads from
+struct LsmSnapshot {
+    // the memtable of the moment, `None` as long as it is still the memtable
+    frozen: Option<Arc<KVStorage>>,
+    memtable_id: u64,
+    memtable_snapshot: ReadSnapshot,
+    version: u64,
+    immutable: Option<Arc<KVStorage>>,
+    tables: Vec<Arc<Table>>
+}
+
+type EntryIter = Box<dyn Iterator<Item=Result<TableEntry, Box<dyn Error>>>>;
+
+/// Merges iterators over sorted entries into one, taking the entry from the first source when
+/// several of them hold the same key
+struct MergeIter {
+    sources: Vec<Peekable<EntryIter>>
+}
+
+impl LsmEngine {
+    /// Opens the engine living in directory `dir` (creating it if it does not exist) with the given
+    /// `options`. Write-ahead logs left by a previous run are replayed, and all but the newest are
+    /// flushed into tables before this function returns
+    pub fn open<P: AsRef<Path>>(dir: P, options: LsmOptions) -> Result<Self, Box<dyn Error>> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let manifest = Manifest::load(dir)?;
+
+        let mut max_id = manifest.flushed_wal;
+        let mut wals = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let file_name = path.file_name().unwrap().to_string_lossy().into_owned();
+            if file_name.ends_with(TMP_SUFFIX) {
+                fs::remove_file(&path)?;
+            } else if let Some(id) = parse_file_id(&file_name, TABLE_SUFFIX) {
+                max_id = max_id.max(id);
+                if !manifest.tables.contains(&id) {
+                    info!("removing table file '{}' left by an unfinished flush or compaction", path.display());
+                    fs::remove_file(&path)?;
+                }
+            } else if let Some(id) = parse_file_id(&file_name, WAL_SUFFIX) {
+                max_id = max_id.max(id);
+                if id <= manifest.flushed_wal {
+                    fs::remove_file(&path)?;
+                } else {
+                    wals.push(id);
+                }
+            }
+        }
+        wals.sort_unstable();
+
+        let tables = manifest.tables.iter()
+            .map(|&id| Table::open(&table_path(dir, id)).map(Arc::new))
+            .collect::<Result<Vec<_>, _>>()?;
+        let memtable_id = match wals.pop() {
+            Some(id) => id,
+            None => {
+                max_id += 1;
+                max_id
+            }
+        };
+        let memtable = KVStorage::open_with_options(wal_path(dir, memtable_id), &options.wal_options())?;
+        let mut memtable_size = 0;
+        for entry in memtable.entries(..) {
+            let (key, maybe_value) = entry?;
+            memtable_size += entry_size(&key, maybe_value.as_deref());
+        }
+        info!("loaded {} tables and {} bytes of memtable from '{}'", tables.len(), memtable_size, dir.display());
+
+        let engine = LsmEngine {
+            shared: Arc::new(LsmShared {
+                dir: dir.to_owned(),
+                state: RwLock::new(LsmState {
+                    memtable, memtable_id, memtable_size, immutable: None, tables,
+                    snapshots: HashMap::new(), next_snapshot_id: 1
+                }),
+                manifest: Mutex::new(manifest),
+                maintenance: Mutex::new(()),
+                maintenance_scheduled: AtomicBool::new(false),
+                next_file_id: AtomicU64::new(max_id + 1),
+                options
+            })
+        };
+        {
+            let _maintenance = engine.shared.maintenance.lock().unwrap();
+            for id in wals {
+                info!("flushing write-ahead log '{}' left by a previous run", wal_path(dir, id).display());
+                let wal = KVStorage::open_with_options(wal_path(dir, id), &engine.shared.options.wal_options())?;
+                engine.shared.state.write().unwrap().immutable = Some((id, Arc::new(wal)));
+                engine.shared.flush_immutable()?;
+            }
+            let mut state = engine.shared.state.write().unwrap();
+            let last_version = state.tables.iter().map(|table| table.version()).max().unwrap_or(LOADED_VERSION);
+            state.memtable.continue_versions(last_version);
+        }
+        Ok(engine)
+    }
+
+    /// Writes everything in the memtable into a new table right now
+    pub fn flush(&self) -> Result<(), Box<dyn Error>> {
+        let _maintenance = self.shared.maintenance.lock().unwrap();
+        self.shared.freeze_memtable()?;
+        self.shared.flush_immutable()
+    }
+
+    /// Flushes the memtable and merges all the tables into one
+    pub fn compact(&self) -> Result<(), Box<dyn Error>> {
+        let _maintenance = self.shared.maintenance.lock().unwrap();
+        self.shared.freeze_memtable()?;
+        self.shared.flush_immutable()?;
+        self.shared.compact_tables()
+    }
+
+    /// Count of table files in use
+    pub fn table_count(&self) -> usize {
+        self.shared.state.read().unwrap().tables.len()
+    }
+
+    /// Syncs the write-ahead log if the sync policy is `SyncPolicy::Every` and its interval has
+    /// passed. Should be called periodically to bound the time a write stays unsynced
+    pub fn sync_if_due(&self) -> Result<(), Box<dyn Error>> {
+        let log_writer = self.shared.state.read().unwrap().memtable.log_writer();
+        log_writer.sync_if_due()
+    }
+
+    /// Syncs the write-ahead log to the disk right now, regardless of the sync policy
+    pub fn sync(&self) -> Result<(), Box<dyn Error>> {
+        let log_writer = self.shared.state.read().unwrap().memtable.log_writer();
+        log_writer.sync()
+    }
+
+    /// Flushes and compacts in a background thread, unless one is already on its way
+    fn schedule_maintenance(&self) {
+        if self.shared.maintenance_scheduled.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let shared = self.shared.clone();
+        thread::spawn(move || {
+            let _maintenance = shared.maintenance.lock().unwrap();
+            shared.maintenance_scheduled.store(false, Ordering::SeqCst);
+            if let Err(e) = shared.maintain() {
+                warn!("background flush or compaction failed");
+                info!("detailed error info: {}", e);
+            }
+        });
+    }
+
+    /// Waits for `ticket` of a write to the memtable without holding the lock, see
+    /// `KVStorage::rollback_failed`. If the memtable has been frozen meanwhile, the failed write
+    /// is already undone, see `freeze_memtable`
+    fn wait_logged(&self, ticket: CommitTicket) -> Result<(), Box<dyn Error>> {
+        let result = ticket.wait();
+        if result.is_err() {
+            self.shared.state.write().unwrap().memtable.rollback_failed();
+        }
+        result
+    }
+}
+
+impl LsmShared {
+    /// Flushes the memtable if it is full and compacts tables if there are too many of them.
+    /// Requires the maintenance lock
+    fn maintain(&self) -> Result<(), Box<dyn Error>> {
+        let memtable_full = self.state.read().unwrap().memtable_size >= self.options.memtable_size;
+        if memtable_full {
+            self.freeze_memtable()?;
+        }
+        self.flush_immutable()?;
+        if self.state.read().unwrap().tables.len() > self.options.max_tables {
+            self.compact_tables()?;
+        }
+        Ok(())
+    }
+
+    /// Turns the memtable into the immutable memtable and starts a new one, unless the memtable is
+    /// empty or there is already an immutable memtable waiting to be flushed. Requires the
+    /// maintenance lock
+    fn freeze_memtable(&self) -> Result<(), Box<dyn Error>> {
+        let mut state = self.state.write().unwrap();
+        if state.immutable.is_some() || state.memtable.is_empty() {
+            return Ok(());
+        }
+        // writes whose logs fail must not make it into a table
+        if state.memtable.log_writer().flush().is_err() {
+            state.memtable.rollback_failed();
+        }
+        let id = self.next_file_id.fetch_add(1, Ordering::SeqCst);
+        let mut memtable = KVStorage::open_with_options(wal_path(&self.dir, id), &self.options.wal_options())?;
+        // the version after the last one of the old memtable goes to its table, see `flush_immutable`
+        memtable.continue_versions(state.memtable.last_version() + 1);
+        let old_memtable = Arc::new(std::mem::replace(&mut state.memtable, memtable));
+        let old_id = std::mem::replace(&mut state.memtable_id, id);
+        state.memtable_size = 0;
+        for snapshot in state.snapshots.values_mut().filter(|snapshot| snapshot.memtable_id == old_id) {
+            snapshot.frozen = Some(old_memtable.clone());
+        }
+        state.immutable = Some((old_id, old_memtable));
+        Ok(())
+    }
+
+    /// Writes the immutable memtable into a new table, if there is one. Requires the maintenance
+    /// lock
+    fn flush_immutable(&self) -> Result<(), Box<dyn Error>> {
+        let (wal_id, immutable, no_tables) = {
+            let state = self.state.read().unwrap();
+            match &state.immutable {
+                Some((wal_id, immutable)) => (*wal_id, immutable.clone(), state.tables.is_empty()),
+                None => return Ok(())
+            }
+        };
+
+        // tombstones only matter when there are older tables for them to shadow
+        let table_id = self.next_file_id.fetch_add(1, Ordering::SeqCst);
+        let path = table_path(&self.dir, table_id);
+        let entries = write_table(&path, immutable.entries(..)
+            .filter(|entry| !no_tables || !matches!(entry, Ok((_, None)))))?;
+        let table = Arc::new(Table::open(&path)?.with_version(immutable.last_version() + 1));
+
+        {
+            let mut manifest = self.manifest.lock().unwrap();
+            let mut new_manifest = manifest.clone();
+            new_manifest.flushed_wal = wal_id;
+            new_manifest.tables.insert(0, table_id);
+            new_manifest.write(&self.dir)?;
+            *manifest = new_manifest;
+        }
+        {
+            let mut state = self.state.write().unwrap();
+            state.tables.insert(0, table);
+            state.immutable = None;
+        }
+        info!("flushed {} entries into table '{}'", entries, path.display());
+        remove_file_or_warn(&wal_path(&self.dir, wal_id));
+        Ok(())
+    }
+
+    /// Merges all the tables into one, without tombstones. Requires the maintenance lock
+    fn compact_tables(&self) -> Result<(), Box<dyn Error>> {
+        let tables = self.state.read().unwrap().tables.clone();
+        if tables.is_empty() {
+            return Ok(());
+        }
+
+        let table_id = self.next_file_id.fetch_add(1, Ordering::SeqCst);
+        let path = table_path(&self.dir, table_id);
+        let sources = tables.iter().map(|table| Box::new(table.iter_from(&Key::from_bytes(&[]))) as EntryIter);
+        let merged = MergeIter::new(sources.collect())
+            .filter(|entry| !matches!(entry, Ok((_, None))));
+        let entries = write_table(&path, merged)?;
+        let version = tables.iter().map(|table| table.version()).max().unwrap_or(LOADED_VERSION);
+        let table = Arc::new(Table::open(&path)?.with_version(version));
+
+        {
+            let mut manifest = self.manifest.lock().unwrap();
+            let mut new_manifest = manifest.clone();
+            new_manifest.tables = vec![table_id];
+            new_manifest.write(&self.dir)?;
+            *manifest = new_manifest;
+        }
+        self.state.write().unwrap().tables = vec![table];
+        info!("compacted {} tables into table '{}' with {} entries", tables.len(), path.display(), entries);
+        for table in tables.iter() {
+            remove_file_or_warn(table.path());
+        }
+        Ok(())
+    }
+}
+
+impl LsmState {
+    /// Looks `key` up in the memtables only, returns `None` if they know nothing about it, the
+    /// value (`None` for a tombstone) together with its version otherwise
+    fn get_in_memory(&self, key: &Key) -> Result<Option<VersionedValue>, Box<dyn Error>> {
+        if let Some(entry) = self.memtable.lookup_versioned(key)? {
+            return Ok(Some(entry));
+        }
+        match &self.immutable {
+            Some((_, immutable)) => immutable.lookup_versioned(key),
+            None => Ok(None)
+        }
+    }
+
+    /// Looks `key` up everywhere, returns its value together with its version
+    fn get(&self, key: &Key) -> Result<VersionedValue, Box<dyn Error>> {
+        match self.get_in_memory(key)? {
+            Some(entry) => Ok(entry),
+            None => get_from_tables(&self.tables, key)
+        }
+    }
+
+    fn snapshot(&self, snapshot: ReadSnapshot) -> Result<&LsmSnapshot, Box<dyn Error>> {
+        match self.snapshots.get(&snapshot.id()) {
+            Some(snapshot) => Ok(snapshot),
+            None => Err(Box::new(StorageError::new("unknown read snapshot")))
+        }
+    }
+
+    /// The memtables `snapshot` reads from, newest first
+    fn snapshot_memtables<'a>(&'a self, snapshot: &'a LsmSnapshot) -> impl Iterator<Item=&'a KVStorage> {
+        let memtable = snapshot.frozen.as_deref().unwrap_or(&self.memtable);
+        Some(memtable).into_iter().chain(snapshot.immutable.as_deref())
+    }
+}
+
+/// Looks `key` up in `tables`, newest first. Values in tables have the version of their table,
+/// see the module documentation
+fn get_from_tables(tables: &[Arc<Table>], key: &Key) -> Result<VersionedValue, Box<dyn Error>> {
+    for table in tables.iter() {
+        match table.get(key)? {
+            Some(Some(value)) => return Ok((Some(value), table.version())),
+            Some(None) => break,
+            None => ()
+        }
+    }
+    Ok((None, NO_VALUE_VERSION))
+}
+
+impl StorageEngine for LsmEngine {
+    fn get(&self, key: &Key) -> Result<Option<Arc<Value>>, Box<dyn Error>> {
+        Ok(self.get_versioned(key)?.0)
+    }
+
+    fn put(&self, key: &Key, value: &Value) -> Result<(), Box<dyn Error>> {
+        let (ticket, memtable_full) = {
+            let mut state = self.shared.state.write().unwrap();
+            let ticket = state.memtable.put_deferred(key, value)?;
+            state.memtable_size += entry_size(key, Some(value));
+            (ticket, state.memtable_size >= self.shared.options.memtable_size)
+        };
+        self.wait_logged(ticket)?;
+        if memtable_full {
+            self.schedule_maintenance();
+        }
+        Ok(())
+    }
+
+    fn delete(&self, key: &Key) -> Result<usize, Box<dyn Error>> {
+        // the key must be looked up under the write lock, otherwise a concurrent put may be lost
+        // or two concurrent deletes may both report success
+        let (ticket, memtable_full) = {
+            let mut state = self.shared.state.write().unwrap();
+            if state.get(key)?.0.is_none() {
+                return Ok(0);
+            }
+            let ticket = state.memtable.tombstone_deferred(key)?;
+            state.memtable_size += entry_size(key, None);
+            (ticket, state.memtable_size >= self.shared.options.memtable_size)
+        };
+        self.wait_logged(ticket)?;
+        if memtable_full {
+            self.schedule_maintenance();
+        }
+        Ok(1)
+    }
+
+    fn write_batch(&self, batch: &WriteBatch) -> Result<(), Box<dyn Error>> {
+        // deletes are logged as tombstones whether or not the key is visible, which shadows
+        // nothing if it is not
+        let (ticket, memtable_full) = {
+            let mut state = self.shared.state.write().unwrap();
+            let ticket = state.memtable.write_batch_deferred(batch)?;
+            state.memtable_size += batch_size(batch);
+            (ticket, state.memtable_size >= self.shared.options.memtable_size)
+        };
+        self.wait_logged(ticket)?;
+        if memtable_full {
+            self.schedule_maintenance();
+        }
+        Ok(())
+    }
+
+    fn compare_and_swap(&self, key: &Key, expected: Option<&Value>, new: Option<&Value>) -> Result<SwapResult, Box<dyn Error>> {
+        // like delete, the key must be looked up under the write lock
+        let (ticket, memtable_full) = {
+            let mut state = self.shared.state.write().unwrap();
+            let current = state.get(key)?.0;
+            if current.as_deref() != expected {
+                return Ok(Err(current));
+            }
+            let ticket = match new {
+                Some(new) => state.memtable.put_deferred(key, new)?,
+                None if current.is_some() => state.memtable.tombstone_deferred(key)?,
+                None => return Ok(Ok(()))
+            };
+            state.memtable_size += entry_size(key, new);
+            (ticket, state.memtable_size >= self.shared.options.memtable_size)
+        };
+        self.wait_logged(ticket)?;
+        if memtable_full {
+            self.schedule_maintenance();
+        }
+        Ok(Ok(()))
+    }
+
+    fn get_versioned(&self, key: &Key) -> Result<VersionedValue, Box<dyn Error>> {
+        // tables are read without the lock, they never change once written
+        let tables = {
+            let state = self.shared.state.read().unwrap();
+            if let Some(entry) = state.get_in_memory(key)? {
+                return Ok(entry);
+            }
+            state.tables.clone()
+        };
+        get_from_tables(&tables, key)
+    }
+
+    fn commit(&self, read_set: &[(Key, u64)], batch: &WriteBatch) -> Result<(), Box<dyn Error>> {
+        // versions are checked under the write lock, so that no write can sneak in before the batch
+        let (ticket, memtable_full) = {
+            let mut state = self.shared.state.write().unwrap();
+            for (key, version) in read_set.iter() {
+                if state.get(key)?.1 != *version {
+                    return Err(Box::new(ConflictError::new(key)));
+                }
+            }
+            let ticket = state.memtable.write_batch_deferred(batch)?;
+            state.memtable_size += batch_size(batch);
+            (ticket, state.memtable_size >= self.shared.options.memtable_size)
+        };
+        self.wait_logged(ticket)?;
+        if memtable_full {
+            self.schedule_maintenance();
+        }
+        Ok(())
+    }
+
+    fn scan(&self, key1: &Key, key2: &Key) -> Result<KVPairs, Box<dyn Error>> {
+        if key1 >= key2 {
+            return Ok(Vec::new());
+        }
+        let mut sources = Vec::new();
+        {
+            let state = self.shared.state.read().unwrap();
+            let memtables = Some(&state.memtable).into_iter()
+                .chain(state.immutable.as_ref().map(|(_, immutable)| immutable.as_ref()));
+            for memtable in memtables {
+                let entries = memtable.entries((Included(key1), Excluded(key2))).collect::<Vec<_>>();
+                sources.push(Box::new(entries.into_iter()) as EntryIter);
+            }
+            for table in state.tables.iter() {
+                sources.push(Box::new(table.iter_from(key1)) as EntryIter);
+            }
+        }
+
+        collect_values(sources, Some(key2))
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<KVPairs, Box<dyn Error>> {
+        let (start, end) = prefix_range(prefix);
+        let first = Key::from_bytes(prefix);
+        let mut sources = Vec::new();
+        {
+            let state = self.shared.state.read().unwrap();
+            let memtables = Some(&state.memtable).into_iter()
+                .chain(state.immutable.as_ref().map(|(_, immutable)| immutable.as_ref()));
+            for memtable in memtables {
+                let entries = memtable.entries((start.as_ref(), end.as_ref())).collect::<Vec<_>>();
+                sources.push(Box::new(entries.into_iter()) as EntryIter);
+            }
+            for table in state.tables.iter() {
+                sources.push(Box::new(table.iter_from(&first)) as EntryIter);
+            }
+        }
+
+        match &end {
+            Excluded(end) => collect_values(sources, Some(end)),
+            _ => collect_values(sources, None)
+        }
+    }
+
+    fn scan_keys(&self, key1: &Key, key2: &Key) -> Result<Vec<Key>, Box<dyn Error>> {
+        // values in tables are read anyway, they are shared rather than cloned
+        Ok(self.scan(key1, key2)?.into_iter().map(|(key, _)| key).collect())
+    }
+
+    fn count(&self, key1: &Key, key2: &Key) -> Result<usize, Box<dyn Error>> {
+        Ok(self.scan(key1, key2)?.len())
+    }
+
+    fn create_read_snapshot(&self) -> Result<ReadSnapshot, Box<dyn Error>> {
+        let mut state = self.shared.state.write().unwrap();
+        let id = state.next_snapshot_id;
+        state.next_snapshot_id += 1;
+        let snapshot = LsmSnapshot {
+            frozen: None,
+            memtable_id: state.memtable_id,
+            memtable_snapshot: state.memtable.create_read_snapshot(),
+            version: state.memtable.last_version(),
+            immutable: state.immutable.as_ref().map(|(_, immutable)| immutable.clone()),
+            tables: state.tables.clone()
+        };
+        state.snapshots.insert(id, snapshot);
+        Ok(ReadSnapshot::new(id))
+    }
+
+    fn release_read_snapshot(&self, snapshot: ReadSnapshot) -> Result<(), Box<dyn Error>> {
+        let mut state = self.shared.state.write().unwrap();
+        let snapshot = match state.snapshots.remove(&snapshot.id()) {
+            Some(snapshot) => snapshot,
+            None => return Err(Box::new(StorageError::new("unknown read snapshot")))
+        };
+        // a frozen memtable keeps its read snapshots until it is dropped
+        if snapshot.frozen.is_none() {
+            state.memtable.release_read_snapshot(snapshot.memtable_snapshot)?;
+        }
+        Ok(())
+    }
+
+    fn get_at(&self, key: &Key, snapshot: Option<ReadSnapshot>) -> Result<Option<Arc<Value>>, Box<dyn Error>> {
+        let snapshot = match snapshot {
+            Some(snapshot) => snapshot,
+            None => return self.get(key)
+        };
+        let tables = {
+            let state = self.shared.state.read().unwrap();
+            let snapshot = state.snapshot(snapshot)?;
+            for memtable in state.snapshot_memtables(snapshot) {
+                if let Some(maybe_value) = memtable.lookup_at(key, snapshot.version)? {
+                    return Ok(maybe_value);
+                }
+            }
+            snapshot.tables.clone()
+        };
+        Ok(get_from_tables(&tables, key)?.0)
+    }
+
+    fn scan_at(&self, key1: &Key, key2: &Key, snapshot: Option<ReadSnapshot>) -> Result<KVPairs, Box<dyn Error>> {
+        let snapshot = match snapshot {
+            Some(snapshot) => snapshot,
+            None => return self.scan(key1, key2)
+        };
+        if key1 >= key2 {
+            return Ok(Vec::new());
+        }
+        let mut sources = Vec::new();
+        {
+            let state = self.shared.state.read().unwrap();
+            let snapshot = state.snapshot(snapshot)?;
+            for memtable in state.snapshot_memtables(snapshot) {
+                let entries = memtable.entries_at((Included(key1), Excluded(key2)), snapshot.version).collect::<Vec<_>>();
+                sources.push(Box::new(entries.into_iter()) as EntryIter);
+            }
+            for table in snapshot.tables.iter() {
+                sources.push(Box::new(table.iter_from(key1)) as EntryIter);
+            }
+        }
+        collect_values(sources, Some(key2))
+    }
+
+    fn scan_with(&self, key1: &Key, key2: &Key, options: &ScanOptions, snapshot: Option<ReadSnapshot>)
+        -> Result<ScanPage, Box<dyn Error>> {
+        let (start, end) = match options.range(key1, key2) {
+            Some(range) => range,
+            None => return Ok(ScanPage { pairs: Vec::new(), continuation: None })
+        };
+        let first = match start {
+            Included(key) | Excluded(key) => key,
+            Unbounded => key1
+        };
+        let mut sources = Vec::new();
+        {
+            let state = self.shared.state.read().unwrap();
+            let (memtables, tables) = match snapshot {
+                Some(snapshot) => {
+                    let snapshot = state.snapshot(snapshot)?;
+                    let memtables = state.snapshot_memtables(snapshot)
+                        .map(|memtable| memtable.entries_at((start, end), snapshot.version).collect::<Vec<_>>())
+                        .collect::<Vec<_>>();
+                    (memtables, snapshot.tables.clone())
+                },
+                None => {
+                    let memtables = Some(&state.memtable).into_iter()
+                        .chain(state.immutable.as_ref().map(|(_, immutable)| immutable.as_ref()))
+                        .map(|memtable| memtable.entries((start, end)).collect::<Vec<_>>())
+                        .collect::<Vec<_>>();
+                    (memtables, state.tables.clone())
+                }
+            };
+            for entries in memtables {
+                sources.push(Box::new(entries.into_iter()) as EntryIter);
+            }
+            for table in tables.iter() {
+                sources.push(Box::new(table.iter_from(first)) as EntryIter);
+            }
+        }
+
+        let pairs = MergeIter::new(sources)
+            .skip_while(|entry| matches!((entry, start), (Ok((key, _)), Excluded(first)) if key == first))
+            .take_while(|entry| match (entry, end) {
+                (Ok((key, _)), Included(last)) => key <= last,
+                (Ok((key, _)), Excluded(last)) => key < last,
+                _ => true
+            })
+            .filter_map(live_pair);
+        if !options.descending {
+            return options.collect(pairs);
+        }
+        // tables can only be read forward, the whole range is read before going backward
+        let mut pairs = pairs.collect::<Result<Vec<_>, _>>()?;
+        pairs.reverse();
+        options.collect(pairs.into_iter().map(Ok))
+    }
+
+    fn put_with_ttl(&self, _key: &Key, _value: &Value, _ttl: Duration) -> Result<(), Box<dyn Error>> {
+        Err(Box::new(StorageError::new("the lsm engine does not support expiry")))
+    }
+
+    fn expire(&self, _key: &Key, _ttl: Duration) -> Result<usize, Box<dyn Error>> {
+        Err(Box::new(StorageError::new("the lsm engine does not support expiry")))
+    }
+
+    fn persist(&self, _key: &Key) -> Result<usize, Box<dyn Error>> {
+        Ok(0)
+    }
+
+    fn ttl(&self, key: &Key) -> Result<Ttl, Box<dyn Error>> {
+        Ok(if self.get(key)?.is_some() { Ttl::Persistent } else { Ttl::NoValue })
+    }
+
+    fn reap_expired(&self) -> Result<usize, Box<dyn Error>> {
+        Ok(0)
+    }
+}
+
+/// Merges `sources` (newest first) and collects the values of keys before `end`, if any
+fn collect_values(sources: Vec<EntryIter>, end: Option<&Key>) -> Result<KVPairs, Box<dyn Error>> {
+    let mut ret = Vec::new();
+    for entry in MergeIter::new(sources) {
+        let (key, maybe_value) = entry?;
+        if matches!(end, Some(end) if key >= *end) {
+            break;
+        }
+        if let Some(value) = maybe_value {
+            ret.push((key, value));
+        }
+    }
+    Ok(ret)
+}
+
+impl MergeIter {
+    /// Creates a `MergeIter` over `sources`, from the newest to the oldest
+    fn new(sources: Vec<EntryIter>) -> Self {
+        MergeIter { sources: sources.into_iter().map(|source| source.peekable()).collect() }
+    }
+}
+
+impl Iterator for MergeIter {
+    type Item = Result<TableEntry, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut smallest: Option<(usize, Key)> = None;
+        for (i, source) in self.sources.iter_mut().enumerate() {
+            match source.peek() {
+                Some(Err(_)) => return source.next(),
+                Some(Ok((key, _))) if !matches!(&smallest, Some((_, smallest_key)) if smallest_key <= key) => {
+                    smallest = Some((i, key.clone()));
+                },
+                _ => ()
+            }
+        }
+
+        let ret = self.sources[smallest?.0].next()?;
+        // older entries of the same key are shadowed
+        if let Ok((key, _)) = &ret {
+            for source in self.sources.iter_mut() {
+                while matches!(source.peek(), Some(Ok((other, _))) if other == key) {
+                    source.next();
+                }
+            }
+        }
+        Some(ret)
+    }
+}
+
+fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:06}{}", id, TABLE_SUFFIX))
+}
+
+fn wal_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:06}{}", id, WAL_SUFFIX))
+}
+
+/// Parses the id out of a file name like `000042.sst`
+fn parse_file_id(file_name: &str, suffix: &str) -> Option<u64> {
+    file_name.strip_suffix(suffix).and_then(|id| id.parse::<u64>().ok())
+}
+
+/// Approximate bytes taken by a memtable entry
+fn entry_size(key: &Key, value: Option<&Value>) -> usize {
+    key.data.len() + value.map_or(0, |value| value.data.len()) + MEMTABLE_ENTRY_OVERHEAD
+}
+
+/// Approximate bytes taken by the memtable entries written by `batch`
+fn batch_size(batch: &WriteBatch) -> usize {
+    batch.ops().iter()
+        .map(|op| match op {
+            BatchOp::Put(key, value) => entry_size(key, Some(value)),
+            BatchOp::Delete(key) => entry_size(key, None)
+        })
+        .sum()
+}
+
+fn remove_file_or_warn(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        warn!("failed to remove '{}' which is no longer used", path.display());
+        info!("detailed error info: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::kvstorage::{Key, Value};
+    use crate::kvstorage::lsm::{MergeIter, EntryIter};
+    use std::sync::Arc;
+
+    fn source(entries: &[(&str, Option<u8>)]) -> EntryIter {
+        let entries = entries.iter()
+            .map(|&(k, v)| Ok((Key::from_bytes(k.as_bytes()), v.map(|v| Arc::new(Value::from_bytes(&[v]))))))
+            .collect::<Vec<_>>();
+        Box::new(entries.into_iter())
+    }
+
+    #[test]
+    fn test_merge_iter() {
+        let merged = MergeIter::new(vec![
+            source(&[("b", Some(1)), ("d", None)]),
+            source(&[("a", Some(2)), ("b", Some(2)), ("d", Some(2)), ("e", Some(2))]),
+            source(&[("a", Some(3)), ("c", Some(3)), ("e", None)])
+        ]);
+        let merged = merged
+            .map(|entry| entry.unwrap())
+            .map(|(k, v)| (String::from_utf8(k.data).unwrap(), v.map(|v| v.data[0])))
+            .collect::<Vec<_>>();
+        assert_eq!(merged, vec![
+            ("a".to_owned(), Some(2)), ("b".to_owned(), Some(1)), ("c".to_owned(), Some(3)),
+            ("d".to_owned(), None), ("e".to_owned(), Some(2))
+        ]);
+    }
+}
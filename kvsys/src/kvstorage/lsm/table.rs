@@ -0,0 +1,408 @@
+//! The table file API
+//!
+//! A table file is an immutable, sorted run of entries written by `LsmEngine` when it flushes a
+//! memtable or compacts older tables. Entries are grouped into blocks of about `TABLE_BLOCK_SIZE`
+//! bytes, and an index of the first key of every block is kept in memory once the table is opened,
+//! so that looking up a key reads a single block.
+
+use crate::kvstorage::{Key, Value, sync_parent_dir, LOADED_VERSION};
+use crate::kvstorage::crc32::crc32;
+use crate::kvstorage::disklog::DiskLogError;
+
+use std::error::Error;
+use std::fs;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+// Table file format
+//  -- 8 bytes magic (0x89 'PKVSST' 0x0a)
+//  -- 2 bytes format version, in big endian
+//  -- blocks, one after another, each of them
+//     -- entries sorted by key, each of them
+//        -- 4 bytes key length, in big endian
+//        -- key
+//        -- 1 byte kind
+//           'V': value
+//            -- 4 bytes value length, in big endian
+//            -- value
+//           'T': tombstone
+//     -- 4 bytes CRC-32 of the entries, in big endian
+//  -- index, one entry per block
+//     -- 4 bytes length of the first key of the block, in big endian
+//     -- first key of the block
+//     -- 8 bytes offset of the block, in big endian
+//     -- 4 bytes size of the block (CRC included), in big endian
+//  -- footer
+//     -- 8 bytes offset of the index, in big endian
+//     -- 4 bytes size of the index, in big endian
+//     -- 4 bytes CRC-32 of the index, in big endian
+//     -- 8 bytes count of entries, in big endian
+//     -- 8 bytes magic (0x89 'PKVSST' 0x0a)
+
+const TABLE_MAGIC: [u8; 8] = [0x89, b'P', b'K', b'V', b'S', b'S', b'T', 0x0a];
+const TABLE_VERSION: u16 = 1;
+const TABLE_HEADER_SIZE: u64 = 10;
+const TABLE_FOOTER_SIZE: u64 = 32;
+
+const ENTRY_VALUE: u8 = b'V';
+const ENTRY_TOMBSTONE: u8 = b'T';
+
+/// Blocks are cut once they reach this many bytes
+pub const TABLE_BLOCK_SIZE: usize = 4096;
+
+/// An entry of a table, `None` being a tombstone which shadows older values of the key
+pub type TableEntry = (Key, Option<Arc<Value>>);
+
+/// An opened table file
+pub struct Table {
+    path: PathBuf,
+    file: Mutex<File>,
+    index: Vec<BlockHandle>,
+    entries: u64,
+    version: u64
+}
+
+struct BlockHandle {
+    first_key: Key,
+    offset: u64,
+    size: u32
+}
+
+/// Iterator over entries of a `Table` in key order, see `Table::iter_from`
+pub struct TableIter {
+    table: Arc<Table>,
+    next_block: usize,
+    block: std::vec::IntoIter<TableEntry>,
+    start: Option<Key>
+}
+
+/// Writes `entries` (which must be sorted by key, without duplicates) into a table file at `path`,
+/// returns the count of entries written.
+///
+/// The table is written to a temporary file and synced before being renamed to `path`, so a table
+/// file either does not exist or is complete
+pub fn write_table<I>(path: &Path, entries: I) -> Result<u64, Box<dyn Error>>
+    where I: Iterator<Item=Result<TableEntry, Box<dyn Error>>> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let count;
+    {
+        let file = File::create(&tmp_path)?;
+        let mut writer = BufWriter::new(&file);
+        writer.write_all(&TABLE_MAGIC)?;
+        writer.write_all(&TABLE_VERSION.to_be_bytes())?;
+
+        let mut offset = TABLE_HEADER_SIZE;
+        let mut index = Vec::new();
+        let mut block = Vec::new();
+        let mut first_key = None;
+        let mut entries_written = 0u64;
+        for entry in entries {
+            let (key, maybe_value) = entry?;
+            if first_key.is_none() {
+                first_key = Some(key.clone());
+            }
+            put_field(&mut block, &key.data);
+            match maybe_value {
+                Some(value) => {
+                    block.push(ENTRY_VALUE);
+                    put_field(&mut block, &value.data);
+                },
+                None => block.push(ENTRY_TOMBSTONE)
+            }
+            entries_written += 1;
+            if block.len() >= TABLE_BLOCK_SIZE {
+                offset += write_block(&mut writer, &mut index, first_key.take().unwrap(), &mut block, offset)?;
+            }
+        }
+        if let Some(first_key) = first_key {
+            offset += write_block(&mut writer, &mut index, first_key, &mut block, offset)?;
+        }
+
+        writer.write_all(&index)?;
+        writer.write_all(&offset.to_be_bytes())?;
+        writer.write_all(&(index.len() as u32).to_be_bytes())?;
+        writer.write_all(&crc32(&index).to_be_bytes())?;
+        writer.write_all(&entries_written.to_be_bytes())?;
+        writer.write_all(&TABLE_MAGIC)?;
+        writer.flush()?;
+        drop(writer);
+        file.sync_all()?;
+        count = entries_written;
+    }
+    fs::rename(&tmp_path, path)?;
+    sync_parent_dir(path);
+    Ok(count)
+}
+
+/// Writes `block` followed by its checksum, and adds it into `index`. Returns the bytes written
+fn write_block<W: Write>(writer: &mut W, index: &mut Vec<u8>, first_key: Key, block: &mut Vec<u8>, offset: u64)
+    -> Result<u64, Box<dyn Error>> {
+    let crc = crc32(block);
+    block.extend_from_slice(&crc.to_be_bytes());
+    writer.write_all(block)?;
+
+    put_field(index, &first_key.data);
+    index.extend_from_slice(&offset.to_be_bytes());
+    index.extend_from_slice(&(block.len() as u32).to_be_bytes());
+    let size = block.len() as u64;
+    block.clear();
+    Ok(size)
+}
+
+impl Table {
+    /// Opens the table file at `path` and loads its index, returns `Err` if it cannot be read or
+    /// fails validation
+    pub fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut file = File::open(path)?;
+        let file_size = file.metadata()?.len();
+        if file_size < TABLE_HEADER_SIZE + TABLE_FOOTER_SIZE {
+            return Err(Box::new(DiskLogError::corrupted(0, "table file too short")));
+        }
+
+        let mut header = [0u8; TABLE_HEADER_SIZE as usize];
+        file.read_exact(&mut header)?;
+        if header[0..8] != TABLE_MAGIC {
+            return Err(Box::new(DiskLogError::corrupted(0, "incorrect table magic")));
+        }
+        if u16::from_be_bytes([header[8], header[9]]) != TABLE_VERSION {
+            return Err(Box::new(DiskLogError::new("unsupported table version")));
+        }
+
+        let footer_offset = file_size - TABLE_FOOTER_SIZE;
+        let mut footer = [0u8; TABLE_FOOTER_SIZE as usize];
+        file.seek(SeekFrom::Start(footer_offset))?;
+        file.read_exact(&mut footer)?;
+        if footer[24..32] != TABLE_MAGIC {
+            return Err(Box::new(DiskLogError::corrupted(footer_offset, "incorrect table footer")));
+        }
+        let index_offset = read_u64(&footer[0..8]);
+        let index_size = read_u32(&footer[8..12]) as u64;
+        let index_crc = read_u32(&footer[12..16]);
+        let entries = read_u64(&footer[16..24]);
+        if index_offset < TABLE_HEADER_SIZE || index_offset + index_size != footer_offset {
+            return Err(Box::new(DiskLogError::corrupted(footer_offset, "incorrect table footer")));
+        }
+
+        let mut raw_index = vec![0u8; index_size as usize];
+        file.seek(SeekFrom::Start(index_offset))?;
+        file.read_exact(&mut raw_index)?;
+        if crc32(&raw_index) != index_crc {
+            return Err(Box::new(DiskLogError::corrupted(index_offset, "table index checksum mismatch")));
+        }
+        let mut index = Vec::new();
+        let mut pos = 0;
+        while pos < raw_index.len() {
+            let first_key = Key::from_bytes(take_field(&raw_index, &mut pos, index_offset)?);
+            let handle = take_bytes(&raw_index, &mut pos, 12, index_offset)?;
+            let (offset, size) = (read_u64(&handle[0..8]), read_u32(&handle[8..12]));
+            if offset + size as u64 > index_offset {
+                return Err(Box::new(DiskLogError::corrupted(index_offset, "table index out of range")));
+            }
+            index.push(BlockHandle { first_key, offset, size });
+        }
+
+        Ok(Table { path: path.to_owned(), file: Mutex::new(file), index, entries, version: LOADED_VERSION })
+    }
+
+    /// Gives all the values of this table `version`, see `version`
+    pub fn with_version(mut self, version: u64) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Version of all the values of this table, `LOADED_VERSION` unless given by `with_version`.
+    /// Versions are not stored in the file, see `LsmEngine`
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Path of the table file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Count of entries in this table, tombstones included
+    pub fn entries(&self) -> u64 {
+        self.entries
+    }
+
+    /// Looks `key` up, returns `None` if this table knows nothing about it, `Some(None)` if it
+    /// holds a tombstone for it
+    pub fn get(&self, key: &Key) -> Result<Option<Option<Arc<Value>>>, Box<dyn Error>> {
+        let block = match self.block_of(key) {
+            Some(block) => block,
+            None => return Ok(None)
+        };
+        let entries = self.read_block(block)?;
+        match entries.binary_search_by(|(k, _)| k.cmp(key)) {
+            Ok(i) => Ok(Some(entries[i].1.clone())),
+            Err(_) => Ok(None)
+        }
+    }
+
+    /// Iterates over entries of the table, starting from the first key not less than `start`
+    pub fn iter_from(self: &Arc<Self>, start: &Key) -> TableIter {
+        TableIter {
+            table: self.clone(),
+            next_block: self.block_of(start).unwrap_or(0),
+            block: Vec::new().into_iter(),
+            start: Some(start.clone())
+        }
+    }
+
+    /// Index of the only block that may contain `key`, `None` if `key` comes before the first key
+    fn block_of(&self, key: &Key) -> Option<usize> {
+        match self.index.partition_point(|handle| handle.first_key <= *key) {
+            0 => None,
+            n => Some(n - 1)
+        }
+    }
+
+    /// Reads and decodes the block at position `block` of the index
+    fn read_block(&self, block: usize) -> Result<Vec<TableEntry>, Box<dyn Error>> {
+        let handle = &self.index[block];
+        let mut raw = vec![0u8; handle.size as usize];
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(handle.offset))?;
+            file.read_exact(&mut raw)?;
+        }
+        if raw.len() < 4 {
+            return Err(Box::new(DiskLogError::corrupted(handle.offset, "table block too short")));
+        }
+        let (body, crc) = raw.split_at(raw.len() - 4);
+        if crc32(body) != read_u32(crc) {
+            return Err(Box::new(DiskLogError::corrupted(handle.offset, "table block checksum mismatch")));
+        }
+
+        let mut ret = Vec::new();
+        let mut pos = 0;
+        while pos < body.len() {
+            let key = Key::from_bytes(take_field(body, &mut pos, handle.offset)?);
+            let entry = match take_bytes(body, &mut pos, 1, handle.offset)?[0] {
+                ENTRY_VALUE => Some(Arc::new(Value::from_bytes(take_field(body, &mut pos, handle.offset)?))),
+                ENTRY_TOMBSTONE => None,
+                _ => return Err(Box::new(DiskLogError::corrupted(handle.offset, "incorrect table entry kind")))
+            };
+            ret.push((key, entry));
+        }
+        Ok(ret)
+    }
+}
+
+impl Iterator for TableIter {
+    type Item = Result<TableEntry, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.block.next() {
+                match &self.start {
+                    Some(start) if entry.0 < *start => continue,
+                    _ => {
+                        self.start = None;
+                        return Some(Ok(entry));
+                    }
+                }
+            }
+            if self.next_block >= self.table.index.len() {
+                return None;
+            }
+            match self.table.read_block(self.next_block) {
+                Ok(entries) => {
+                    self.block = entries.into_iter();
+                    self.next_block += 1;
+                },
+                Err(e) => {
+                    self.next_block = self.table.index.len();
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+fn put_field(buffer: &mut Vec<u8>, field: &[u8]) {
+    buffer.extend_from_slice(&(field.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(field);
+}
+
+fn take_bytes<'a>(raw: &'a [u8], pos: &mut usize, size: usize, offset: u64) -> Result<&'a [u8], Box<dyn Error>> {
+    if raw.len() - *pos < size {
+        return Err(Box::new(DiskLogError::corrupted(offset, "table content ends unexpectedly")));
+    }
+    *pos += size;
+    Ok(&raw[*pos - size..*pos])
+}
+
+fn take_field<'a>(raw: &'a [u8], pos: &mut usize, offset: u64) -> Result<&'a [u8], Box<dyn Error>> {
+    let size = read_u32(take_bytes(raw, pos, 4, offset)?) as usize;
+    take_bytes(raw, pos, size, offset)
+}
+
+fn read_u32(raw: &[u8]) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(raw);
+    u32::from_be_bytes(bytes)
+}
+
+fn read_u64(raw: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(raw);
+    u64::from_be_bytes(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::kvstorage::{Key, Value};
+    use crate::kvstorage::lsm::table::{Table, write_table};
+    use std::fs;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_table_rw() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("000001.sst");
+        let entries = (0..2000u32)
+            .map(|i| {
+                let key = Key::from_bytes(format!("key{:05}", i * 2).as_bytes());
+                let value = if i % 7 == 0 { None } else { Some(Arc::new(Value::from_bytes(&[i as u8; 100]))) };
+                (key, value)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(write_table(&path, entries.iter().cloned().map(Ok)).unwrap(), 2000);
+
+        let table = Arc::new(Table::open(&path).unwrap());
+        assert_eq!(table.entries(), 2000);
+        for (key, value) in entries.iter() {
+            assert_eq!(&table.get(key).unwrap().unwrap(), value);
+        }
+        assert!(table.get(&Key::from_bytes(b"key00001")).unwrap().is_none());
+        assert!(table.get(&Key::from_bytes(b"a")).unwrap().is_none());
+        assert!(table.get(&Key::from_bytes(b"z")).unwrap().is_none());
+
+        let scanned = table.iter_from(&Key::from_bytes(b"key01001")).map(|e| e.unwrap()).collect::<Vec<_>>();
+        assert_eq!(scanned, entries[501..].to_vec());
+        assert_eq!(table.iter_from(&Key::from_bytes(b"")).count(), 2000);
+
+        let mut raw = fs::read(&path).unwrap();
+        raw[100] ^= 1;
+        fs::write(&path, &raw).unwrap();
+        let table = Table::open(&path).unwrap();
+        assert!(table.get(&entries[0].0).is_err());
+    }
+
+    #[test]
+    fn test_empty_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("000002.sst");
+        assert_eq!(write_table(&path, Vec::new().into_iter()).unwrap(), 0);
+        let table = Arc::new(Table::open(&path).unwrap());
+        assert!(table.get(&Key::from_bytes(b"key")).unwrap().is_none());
+        assert_eq!(table.iter_from(&Key::from_bytes(b"")).count(), 0);
+    }
+}
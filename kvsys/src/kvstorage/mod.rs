@@ -1,8 +1,9 @@
 //! Major storage engine of Project-KV, with data persistence support
 //!
-//! The key of this storage system fixed 8 bytes, while the value is fixed 256 bytes.
-//! (it is possible to change the length of value, but impossible to change the length of key due
-//! to the internal encoding mechanism)
+//! By default, the key of this storage system fixed 8 bytes, while the value is fixed 256 bytes.
+//! With `DataLayout::Variable`, keys and values may have any length (up to configurable maximums).
+//! Either way, keys are ordered byte by byte, so a shorter key comes before all longer keys it is
+//! a prefix of.
 //!
 //! Setting up a `KVStorage` requires an existing `std::File`
 //! ```no_run
@@ -15,52 +16,114 @@
 //! ```
 //!
 //! While setting up a `KVStorage` engine from existing file even requires opening the same file
-//! twice, once for loading existing data, once for appending
+//! twice, once for loading existing data, once for appending (readable, so that its format can be
+//! checked). An incomplete trailing record, left by a writer that died in the middle of it, must
+//! be cut off in between
 //! ```no_run
 //!     use std::fs::File;
 //!     use std::fs::OpenOptions;
-//!     use kvsys::kvstorage::KVStorage;
+//!     use kvsys::kvstorage::{KVStorage, RecoveryMode};
 //!     // ...
 //!     let content;
 //!     let kv;
 //!     {
 //!         let f = File::open("data.kv").unwrap();
-//!         content = KVStorage::read_log_file(f).unwrap();
+//!         let (loaded, torn_tail) = KVStorage::read_log_file_with_recovery(f, RecoveryMode::TruncateTornTail).unwrap();
+//!         if let Some(offset) = torn_tail {
+//!             OpenOptions::new().write(true).open("data.kv").unwrap().set_len(offset).unwrap();
+//!         }
+//!         content = loaded;
 //!     }
 //!     {
-//!         let f = OpenOptions::new().write(true).append(true).open("data.kv").unwrap();
-//!         kv = KVStorage::with_content(content, f);
+//!         let f = OpenOptions::new().read(true).append(true).open("data.kv").unwrap();
+//!         kv = KVStorage::with_content(content, f).unwrap();
 //!     }
 //!     // ...
 //! ```
 //!
-//! This API looks ugly, but let us keep it for sometime.
+//! This API looks ugly, but let us keep it for sometime. If the log file has a path, the whole
+//! dance can be replaced by `KVStorage::open`, which also enables log compaction
+//! ```no_run
+//!     use kvsys::kvstorage::KVStorage;
+//!     // ...
+//!     let mut kv = KVStorage::open("data.kv").unwrap();
+//!     // rewrite the log so that it only contains live key-value pairs
+//!     kv.compact().unwrap();
+//!     // ...
+//! ```
 
+pub mod batch;
 pub mod disklog;
+pub mod engine;
+pub mod expiry;
+pub mod lsm;
+pub mod mvcc;
+pub mod options;
+pub mod scan;
+pub mod snapshot;
+pub mod transaction;
+mod cache;
+mod crc32;
+
+pub use options::{StorageOptions, RecoveryMode, SyncPolicy, DataLayout};
+pub use batch::{WriteBatch, BatchOp};
+pub use mvcc::ReadSnapshot;
+pub use expiry::Ttl;
 
 use std::collections::BTreeMap;
+use std::fs;
 use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::ops::Bound;
 use std::ops::Bound::{Included, Excluded};
+use std::ops::RangeBounds;
 use std::error::Error;
 use std::fmt;
 use std::fmt::{Debug, Display, Formatter};
 use std::sync::Arc;
-use std::u64;
-use crate::kvstorage::disklog::{DiskLogWriter, DiskLogReader, DiskLogMessage};
+use std::time::Duration;
+use crate::kvstorage::disklog::{DiskLogWriter, DiskLogReader, DiskLogMessage, DiskLogError, DiskLogFormat, CommitTicket,
+                                DISK_LOG_HEADER_SIZE, put_value_offset};
+use crate::kvstorage::cache::{ValueCache, ValueFile, ValueLocation};
+use crate::kvstorage::engine::KVPairs;
+use crate::kvstorage::expiry::{ExpiryIndex, expiry_after, has_expired, ttl_of};
+use crate::kvstorage::mvcc::VersionHistory;
+use crate::kvstorage::scan::{ScanOptions, ScanPage, live_pair, prefix_range};
+use crate::kvstorage::transaction::ConflictError;
+
+use log::{info, warn};
 
 pub const KEY_SIZE: usize = 8;
 pub const VALUE_SIZE: usize = 256;
 
-/// `Key` of storage engine
-#[derive(Copy, Clone)]
+/// Default dead records / live keys ratio that triggers an automatic compaction
+pub const DEFAULT_COMPACTION_RATIO: f64 = 1.0;
+
+/// Automatic compaction never happens before the log contains at least this many dead records,
+/// so that small databases are not rewritten over and over again
+pub const COMPACTION_MIN_DEAD_RECORDS: usize = 4096;
+
+const COMPACTION_BATCH_SIZE: usize = 4096;
+
+/// Tombstones of deleted keys are purged from memory automatically once there are at least this
+/// many of them, and more of them than live keys
+pub const PURGE_MIN_TOMBSTONES: usize = 4096;
+
+/// With a memory budget, this share of it (one in `CACHE_BUDGET_SHARE`) caches values read back
+/// from disk, the rest holds recently written values
+const CACHE_BUDGET_SHARE: usize = 4;
+
+/// `Key` of storage engine, ordered byte by byte (dictionary order)
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Key {
-    pub data: [u8; KEY_SIZE]
+    pub data: Vec<u8>
 }
 
 /// `Value` of storage engine
-#[derive(Copy, Clone)]
+#[derive(Clone, PartialEq, Eq)]
 pub struct Value {
-    pub data: [u8; VALUE_SIZE]
+    pub data: Vec<u8>
 }
 
 impl Debug for Key {
@@ -93,45 +156,16 @@ impl Display for Key {
 
 impl Display for Value {
     fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
-        write!(f, "{:?} ('{}...')", self, String::from_utf8_lossy(&self.data[0..8]))
-    }
-}
-
-impl PartialEq for Key {
-    fn eq(&self, other: &Self) -> bool {
-        for (byte1, byte2) in self.data.iter().zip(other.data.iter()) {
-            if byte1 != byte2 {
-                return false
-            }
-        }
-        true
+        let shown = self.data.len().min(8);
+        write!(f, "{:?} ('{}...')", self, String::from_utf8_lossy(&self.data[0..shown]))
     }
 }
 
-impl PartialEq for Value {
-    fn eq(&self, other: &Self) -> bool {
-        for (byte1, byte2) in self.data.iter().zip(other.data.iter()) {
-            if byte1 != byte2 {
-                return false
-            }
-        }
-        true
-    }
-}
-
-impl Eq for Key {
-}
-
-impl Eq for Value {
-}
-
 impl Key {
     /// Construct a `Key` from a slice. Panics if length of the given slice is not `KEY_SIZE`
     pub fn from_slice(slice: &[u8]) -> Self {
         assert_eq!(slice.len(), KEY_SIZE);
-        let mut ret = [0; KEY_SIZE];
-        ret.copy_from_slice(slice);
-        Key { data: ret }
+        Key { data: slice.to_vec() }
     }
 
     /// Construct a `Key` from a slice. Returns `None` if length of the given slice is not
@@ -140,18 +174,22 @@ impl Key {
         if slice.len() != KEY_SIZE {
             None
         } else {
-            let mut ret = [0; KEY_SIZE];
-            ret.copy_from_slice(slice);
-            Some(Key { data: ret })
+            Some(Key { data: slice.to_vec() })
         }
     }
 
+    /// Construct a `Key` of any length from a slice, for use with `DataLayout::Variable`
+    pub fn from_bytes(slice: &[u8]) -> Self {
+        Key { data: slice.to_vec() }
+    }
+
     /// Serialize a `Key` into a byte buffer
     pub fn serialize(&self) -> Vec<u8> {
-        self.data.to_vec()
+        self.data.clone()
     }
 
-    /// Encode a `Key` into a single `u64` for comparing and sorting use.
+    /// Encode a `Key` of `KEY_SIZE` bytes into a single `u64`, which compares the same way as
+    /// the `Key` does. Panics if the length of the `Key` is not `KEY_SIZE`
     /// ```no_run
     ///     use kvsys::kvstorage::Key;
     ///     // ...
@@ -160,27 +198,20 @@ impl Key {
     ///     let encoded = Key::encode_raw(&flat);
     ///     assert_eq!(encoded, expected);
     /// ```
-    pub fn encode(&self) -> InternKey {
-        unsafe {
-            let flat = &self.data as *const u8 as *const u64;
-            u64::from_be(*flat)
-        }
+    pub fn encode(&self) -> u64 {
+        let mut raw = [0u8; KEY_SIZE];
+        raw.copy_from_slice(&self.data);
+        Key::encode_raw(&raw)
     }
 
     /// Encode an array of `KEY_SIZE` bytes into a single `u64`
-    pub fn encode_raw(raw: &[u8; KEY_SIZE]) -> InternKey {
-        unsafe {
-            let flat = raw as *const u8 as *const u64;
-            u64::from_be(*flat)
-        }
+    pub fn encode_raw(raw: &[u8; KEY_SIZE]) -> u64 {
+        u64::from_be_bytes(*raw)
     }
 
     /// Decode a `u64` and get the original `Key`
-    pub fn decode(encoded: InternKey) -> Self {
-        unsafe {
-            let bytes = &(u64::to_be(encoded)) as *const u64 as *const [u8; 8];
-            Key::from_slice(&(*bytes))
-        }
+    pub fn decode(encoded: u64) -> Self {
+        Key::from_slice(&encoded.to_be_bytes())
     }
 }
 
@@ -188,9 +219,7 @@ impl Value {
     /// Construct a `Value` from a slice. Panics if length of the given slice is not `VALUE_SIZE`
     pub fn from_slice(slice: &[u8]) -> Self {
         assert_eq!(slice.len(), VALUE_SIZE);
-        let mut ret = [0; VALUE_SIZE];
-        ret.copy_from_slice(slice);
-        Value { data: ret }
+        Value { data: slice.to_vec() }
     }
 
     /// Construct a `Value` from a slice. Returns `None` if length of the given slice is not `VALUE_SIZE`
@@ -198,32 +227,131 @@ impl Value {
         if slice.len() != VALUE_SIZE {
             None
         } else {
-            let mut ret = [0; VALUE_SIZE];
-            ret.copy_from_slice(slice);
-            Some(Value { data: ret })
+            Some(Value { data: slice.to_vec() })
         }
     }
 
+    /// Construct a `Value` of any length from a slice, for use with `DataLayout::Variable`
+    pub fn from_bytes(slice: &[u8]) -> Self {
+        Value { data: slice.to_vec() }
+    }
+
     /// Serialize a `Value` into a byte buffer
     pub fn serialize(&self) -> Vec<u8> {
-        self.data.to_vec()
+        self.data.clone()
+    }
+}
+
+/// The error type used by kvstorage module for rejected operations
+#[derive(Debug)]
+pub struct StorageError {
+    description: String
+}
+
+impl StorageError {
+    pub fn new(description: &str) -> Self {
+        StorageError { description: description.to_owned() }
+    }
+}
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(f, "storage error: {}", self.description)
     }
 }
 
-type InternKey = u64;
+impl Error for StorageError {
+}
+
+/// The in-memory content of a `KVStorage`, deleted keys may be kept as `None` (tombstones) until
+/// they get purged
+pub type MemStorage = BTreeMap<Key, Option<Arc<Value>>>;
+
+/// Version of keys holding no value, see `KVStorage::version`
+pub const NO_VALUE_VERSION: u64 = 0;
+
+/// Version of values loaded when a `KVStorage` is created, writes afterwards get greater versions.
+/// See `KVStorage::version`
+pub const LOADED_VERSION: u64 = 1;
+
+/// An entry of the in-memory index of a `KVStorage`. Values may have an expiry time, see `expiry`
+#[derive(Clone)]
+pub(crate) enum Slot {
+    /// A deleted key
+    Tombstone,
+    /// A value held in memory, together with where it lives on disk if it may be evicted. `seq`
+    /// orders evictable values from the oldest written
+    Hot { value: Arc<Value>, location: Option<ValueLocation>, seq: u64, version: u64, expiry: Option<u64> },
+    /// A value evicted from memory, which is read back (through the cache) when needed
+    Cold { location: ValueLocation, version: u64, expiry: Option<u64> }
+}
+
+type SlotMap = BTreeMap<Key, Slot>;
+
+/// Content, count of dead records, format and torn tail offset of a log, see
+/// `KVStorage::replay_log_file`
+type ReplayedLog = (SlotMap, usize, Option<DiskLogFormat>, Option<u64>);
+
+/// A value (`None` if there is none) together with its version, see `KVStorage::version`
+pub type VersionedValue = (Option<Arc<Value>>, u64);
+
+/// Result of a compare-and-swap, `Err` with the current value (`None` if there is none) if it did
+/// not match the expected one, see `KVStorage::compare_and_swap`
+pub type SwapResult = Result<(), Option<Arc<Value>>>;
+
+/// A key together with its value, `None` being a tombstone
+pub(crate) type Entry = (Key, Option<Arc<Value>>);
 
 /// A Key-Value storage engine
 pub struct KVStorage {
-    mem_storage: BTreeMap<InternKey, Option<Arc<Value>>>,
-    log_writer: disklog::DiskLogWriter
+    mem_storage: SlotMap,
+    log_writer: disklog::DiskLogWriter,
+    log_path: Option<PathBuf>,
+    live_keys: usize,
+    tombstones: usize,
+    dead_records: usize,
+    compaction_ratio: Option<f64>,
+    snapshot_generation: u64,
+    snapshot_log_offset: Option<u64>,
+    log_epoch: u64,
+    layout: DataLayout,
+    keep_tombstones: bool,
+    memory_budget: Option<usize>,
+    // the log file opened for reading values back, only with a memory budget
+    log_values: Option<Arc<ValueFile>>,
+    cache: ValueCache,
+    hot_size: usize,
+    // keys of evictable values held in memory, by `Slot::Hot::seq`
+    resident: BTreeMap<u64, Key>,
+    next_seq: u64,
+    last_version: u64,
+    // what keys held before being written, for open read snapshots. `None` if they were not there
+    history: VersionHistory<Option<Slot>>,
+    // keys whose values expire, by their expiry times
+    expiries: ExpiryIndex,
+    // what keys held before writes whose logs may not be committed yet, by log sequence number,
+    // see `rollback_failed`
+    uncommitted: Vec<(u64, Key, Option<Slot>)>
+}
+
+/// A point-in-time copy of the content of a `KVStorage`, on its way to become a snapshot file.
+/// See `KVStorage::begin_snapshot`
+pub struct PendingSnapshot {
+    generation: u64,
+    path: PathBuf,
+    content: SlotMap,
+    log_offset: Option<u64>,
+    log_epoch: u64
 }
 
 impl Debug for KVStorage {
     fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
         write!(f, "KV [")?;
-        for (key, maybe_value) in self.mem_storage.iter() {
-            if let Some(value) = maybe_value {
-                write!(f, "{:?} => {:?},", key, value)?;
+        for (key, slot) in self.mem_storage.iter() {
+            match slot {
+                Slot::Hot { value, .. } => write!(f, "{:?} => {:?},", key, value)?,
+                Slot::Cold { .. } => write!(f, "{:?} => (on disk),", key)?,
+                Slot::Tombstone => ()
             }
         }
         write!(f, "]")
@@ -233,77 +361,1399 @@ impl Debug for KVStorage {
 impl KVStorage {
     /// Create a `KVStorage` using given `log_file` as its log output
     pub fn new(log_file: File) -> Self {
-        KVStorage{ mem_storage: BTreeMap::new(), log_writer: DiskLogWriter::new(log_file) }
+        KVStorage::with_slots(BTreeMap::new(), log_file)
+    }
+
+    /// Opens the log file at `path` (creating it if it does not exist), loads its content and
+    /// uses it as log output, with default `StorageOptions`.
+    ///
+    /// Unlike `new` and `with_content`, a `KVStorage` created this way knows where its log lives,
+    /// thus supports `compact`. If the loaded log already contains too many dead records, it gets
+    /// compacted before this function returns. Legacy (headerless) logs are rewritten into the
+    /// current format the same way, since they can be read but not appended to.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        KVStorage::open_with_options(path, &StorageOptions::from_default())
+    }
+
+    /// Same as `open`, but with the given `options`
+    pub fn open_with_options<P: AsRef<Path>>(path: P, options: &StorageOptions) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        // with a memory budget, values are left on disk while loading
+        let log_values = match options.memory_budget {
+            Some(_) => {
+                fs::OpenOptions::new().create(true).append(true).open(path)?;
+                Some(Arc::new(ValueFile::open(path)?))
+            },
+            None => None
+        };
+        let (snapshot_generation, slots) = KVStorage::load_latest_snapshot(path, log_values.is_some())?;
+        let (slots, dead_records, format) = if path.exists() {
+            let log_file = File::open(path)?;
+            let (slots, dead_records, format, torn_tail) =
+                KVStorage::replay_log_file(slots, log_file, options.recovery, options.keep_tombstones, log_values.as_ref())?;
+            if let Some(offset) = torn_tail {
+                warn!("disk log ends with an incomplete record at byte offset {}, truncating", offset);
+                let log_file = fs::OpenOptions::new().write(true).open(path)?;
+                log_file.set_len(offset)?;
+                log_file.sync_all()?;
+            }
+            (slots, dead_records, format)
+        } else {
+            (slots, 0, None)
+        };
+        let log_file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+
+        let mut ret = KVStorage::with_slots(slots, log_file);
+        ret.memory_budget = options.memory_budget;
+        ret.log_values = log_values;
+        ret.cache = ValueCache::new(options.memory_budget.map_or(0, |budget| budget / CACHE_BUDGET_SHARE));
+        ret.log_path = Some(path.to_owned());
+        ret.snapshot_generation = snapshot_generation;
+        ret.dead_records = dead_records;
+        ret.compaction_ratio = options.compaction_ratio;
+        ret.set_sync_policy(options.sync_policy);
+        ret.set_layout(options.layout);
+        ret.keep_tombstones = options.keep_tombstones;
+        info!("loaded {} live keys and {} dead records from '{}'",
+              ret.live_keys, ret.dead_records, path.display());
+        if matches!(format, Some(format) if !format.is_current()) {
+            info!("'{}' is an old format disk log, upgrading it to the current format", path.display());
+            ret.compact()?;
+        } else {
+            ret.maybe_compact();
+        }
+        ret.maybe_evict();
+        Ok(ret)
     }
 
     /// Reads `log_file` and constructs a memory storage. This API looks bogus, but let us keep it for a while
-    pub fn read_log_file(log_file: File) -> Result<BTreeMap<InternKey, Option<Arc<Value>>>, Box<dyn Error>> {
-        let mut ret = BTreeMap::new();
-        let mut log_reader = DiskLogReader::new(log_file);
-        while let Some(log_msg) = log_reader.next_log()? {
-            match log_msg {
-                DiskLogMessage::Put(key, value) => {
-                    ret.insert(key.encode(), Some(value));
+    ///
+    /// The file is only read. If the log ends with an incomplete record, the record is ignored with
+    /// a warning, but left in the file: use `read_log_file_with_recovery` to know where it starts,
+    /// the file must be cut off there before appending to it (`open` does so by itself)
+    pub fn read_log_file(log_file: File) -> Result<MemStorage, Box<dyn Error>> {
+        let (content, torn_tail) = KVStorage::read_log_file_with_recovery(log_file, RecoveryMode::TruncateTornTail)?;
+        if let Some(offset) = torn_tail {
+            warn!("disk log ends with an incomplete record at byte offset {}, ignoring it", offset);
+        }
+        Ok(content)
+    }
+
+    /// Same as `read_log_file`, but handles an incomplete trailing record according to `recovery`.
+    /// Under `RecoveryMode::TruncateTornTail`, also returns the byte offset the log should be
+    /// truncated to, if it ends with an incomplete record
+    pub fn read_log_file_with_recovery(log_file: File, recovery: RecoveryMode) -> Result<(MemStorage, Option<u64>), Box<dyn Error>> {
+        let (slots, _, _, torn_tail) = KVStorage::replay_log_file(BTreeMap::new(), log_file, recovery, false, None)?;
+        let content = slots.into_iter()
+            .map(|(key, slot)| match slot {
+                Slot::Hot { value, expiry, .. } if !has_expired(expiry) => (key, Some(value)),
+                Slot::Hot { .. } | Slot::Tombstone => (key, None),
+                Slot::Cold { .. } => unreachable!("values are only left on disk with a memory budget")
+            })
+            .collect();
+        Ok((content, torn_tail))
+    }
+
+    /// Loads the latest valid snapshot of the log file at `log_path`, returns its generation and
+    /// content. Returns generation 0 and empty content if there is no snapshot. Values are left in
+    /// the snapshot file if `cold`
+    fn load_latest_snapshot(log_path: &Path, cold: bool) -> Result<(u64, SlotMap), Box<dyn Error>> {
+        for generation in snapshot::list_snapshots(log_path)? {
+            let path = snapshot::snapshot_path(log_path, generation);
+            let snapshot_values = if cold { Some(Arc::new(ValueFile::open(&path)?)) } else { None };
+            let mut content = BTreeMap::new();
+            let loaded = snapshot::read_snapshot_with(&path, |key, value, offset, expiry| {
+                let slot = match &snapshot_values {
+                    Some(file) => Slot::cold(ValueLocation { file: file.clone(), offset, size: value.data.len() as u32 }),
+                    None => Slot::hot(Arc::new(value))
+                };
+                content.insert(key, slot.with_expiry(expiry));
+            });
+            match loaded {
+                Ok(_) => {
+                    info!("loaded snapshot '{}' with {} keys", path.display(), content.len());
+                    return Ok((generation, content));
                 },
-                DiskLogMessage::Delete(key) => {
-                    ret.remove(&key.encode());
+                Err(e) => {
+                    warn!("snapshot '{}' is invalid, trying an older one", path.display());
+                    info!("detailed error info: {}", e);
                 }
             }
         }
-        Ok(ret)
+        Ok((0, BTreeMap::new()))
+    }
+
+    /// Replays `log_file` on top of `ret`, returns the memory storage together with the count of
+    /// dead records (records that no longer contribute to the memory storage), the format of the
+    /// log and the offset of its incomplete trailing record if any, which is left to the caller
+    /// (under `RecoveryMode::Strict`, such a record fails the replay instead). Deleted keys are left as tombstones if `keep_tombstones`, expired keys are loaded as
+    /// they are until reaped (see `reap_expired`). Values are left in the log
+    /// if it is of the current format and `log_values` (the same file opened for reading) is given
+    fn replay_log_file(mut ret: SlotMap, log_file: File, recovery: RecoveryMode, keep_tombstones: bool,
+                       log_values: Option<&Arc<ValueFile>>) -> Result<ReplayedLog, Box<dyn Error>> {
+        let mut dead_records = 0;
+        let mut torn_tail = None;
+        let mut log_reader = DiskLogReader::new(log_file);
+        loop {
+            let log_msg = match log_reader.next_log() {
+                Ok(Some(log_msg)) => log_msg,
+                Ok(None) => break,
+                Err(e) => {
+                    torn_tail = Some(KVStorage::torn_tail_offset(e, recovery)?);
+                    break;
+                }
+            };
+            let cold = log_values
+                .filter(|_| matches!(log_reader.format(), Some(format) if format.is_current()))
+                .map(|file| (file, log_reader.offset() - log_msg.serialized_size()));
+            let mut value_offsets = log_msg.value_offsets().into_iter();
+            let messages = match log_msg {
+                DiskLogMessage::Batch(messages) => messages,
+                log_msg => vec![log_msg]
+            };
+            for log_msg in messages {
+                match log_msg {
+                    DiskLogMessage::Put(key, value) => {
+                        let slot = match cold {
+                            Some((file, record_start)) => {
+                                let offset = record_start + value_offsets.next().unwrap();
+                                Slot::cold(ValueLocation { file: file.clone(), offset, size: value.data.len() as u32 })
+                            },
+                            None => Slot::hot(value)
+                        };
+                        if ret.insert(key, slot).is_some() {
+                            dead_records += 1;
+                        }
+                    },
+                    DiskLogMessage::Delete(key) => {
+                        // the delete record itself is always dead, and so is the put it removes
+                        dead_records += 1;
+                        let removed = if keep_tombstones { ret.insert(key, Slot::Tombstone) } else { ret.remove(&key) };
+                        if matches!(removed, Some(slot) if slot.is_value()) {
+                            dead_records += 1;
+                        }
+                    },
+                    DiskLogMessage::Expire(key, expiry) => {
+                        // like deletes, expire records are dead right away, compaction rewrites the
+                        // expiry times still needed
+                        dead_records += 1;
+                        if let Some(slot) = ret.get_mut(&key) {
+                            slot.set_expiry(expiry);
+                        }
+                    },
+                    DiskLogMessage::Batch(_) => unreachable!("batches are never nested")
+                }
+            }
+        }
+        Ok((ret, dead_records, log_reader.format(), torn_tail))
+    }
+
+    /// The offset of the torn trailing record `e` tells about, which is treated as the end of the
+    /// log under `RecoveryMode::TruncateTornTail`. Gives `e` back otherwise
+    fn torn_tail_offset(e: Box<dyn Error>, recovery: RecoveryMode) -> Result<u64, Box<dyn Error>> {
+        match e.downcast_ref::<DiskLogError>() {
+            Some(DiskLogError::TornTail { offset }) if recovery == RecoveryMode::TruncateTornTail => Ok(*offset),
+            _ => Err(e)
+        }
     }
 
     /// Create a `KVStorage` using given `log_file` as its log output, and with existing data `mem_storage`
-    pub fn with_content(mem_storage: BTreeMap<InternKey, Option<Arc<Value>>>, log_file: File) -> Self {
-        KVStorage{ mem_storage, log_writer: DiskLogWriter::new(log_file) }
+    ///
+    /// `log_file` must be opened with `read` + `append`, and be either empty or a disk log of the
+    /// current format, use `open` to upgrade older disk logs
+    ///
+    /// Returns `Err` if `log_file` cannot be appended to, rather than appending records it could not
+    /// be read back with
+    pub fn with_content(mem_storage: MemStorage, log_file: File) -> Result<Self, Box<dyn Error>> {
+        if !disklog::is_appendable(&log_file)? {
+            return Err(Box::new(StorageError::new("the log file is not a disk log of the current format, use `open` to upgrade it")));
+        }
+        let slots = mem_storage.into_iter()
+            .map(|(key, maybe_value)| (key, maybe_value.map_or(Slot::Tombstone, Slot::hot)))
+            .collect();
+        Ok(KVStorage::with_slots(slots, log_file))
+    }
+
+    fn with_slots(mem_storage: SlotMap, log_file: File) -> Self {
+        let live_keys = mem_storage.values().filter(|slot| slot.is_value()).count();
+        let tombstones = mem_storage.len() - live_keys;
+        let hot_size = mem_storage.values()
+            .map(|slot| match slot { Slot::Hot { value, .. } => value.data.len(), _ => 0 })
+            .sum();
+        let mut expiries = ExpiryIndex::new();
+        for (key, slot) in mem_storage.iter() {
+            expiries.update(key, None, slot.expiry());
+        }
+        KVStorage {
+            mem_storage,
+            log_writer: DiskLogWriter::new(log_file),
+            log_path: None,
+            live_keys,
+            tombstones,
+            dead_records: 0,
+            compaction_ratio: Some(DEFAULT_COMPACTION_RATIO),
+            snapshot_generation: 0,
+            snapshot_log_offset: None,
+            log_epoch: 0,
+            layout: DataLayout::Fixed,
+            keep_tombstones: false,
+            memory_budget: None,
+            log_values: None,
+            cache: ValueCache::new(0),
+            hot_size,
+            resident: BTreeMap::new(),
+            next_seq: 0,
+            last_version: LOADED_VERSION,
+            history: VersionHistory::new(),
+            expiries,
+            uncommitted: Vec::new()
+        }
+    }
+
+    /// Sets the dead records / live keys ratio above which the log gets compacted automatically.
+    /// `None` disables automatic compaction. Has no effect if the `KVStorage` is not created by
+    /// `open`
+    pub fn set_compaction_ratio(&mut self, ratio: Option<f64>) {
+        self.compaction_ratio = ratio;
+    }
+
+    /// Sets the sizes of keys and values accepted by `put`, a `KVStorage` created by `new` or
+    /// `with_content` only accepts `KEY_SIZE` bytes keys and `VALUE_SIZE` bytes values by default.
+    ///
+    /// Content already stored is not checked against the new layout
+    pub fn set_layout(&mut self, layout: DataLayout) {
+        self.layout = layout;
+    }
+
+    /// Sizes of keys and values accepted by this `KVStorage`
+    pub fn layout(&self) -> DataLayout {
+        self.layout
     }
 
-    /// Trying get the value corresponding to the given `key`, returns `None` if not found
+    /// Sets when the log file gets synced to the disk. A `KVStorage` created by `new` or
+    /// `with_content` never syncs by default
+    pub fn set_sync_policy(&mut self, sync_policy: SyncPolicy) {
+        self.log_writer.set_sync_policy(sync_policy);
+    }
+
+    /// Syncs the log file if the sync policy is `SyncPolicy::Every` and its interval has passed.
+    /// Should be called periodically to bound the time a write stays unsynced
+    pub fn sync_if_due(&mut self) -> Result<(), Box<dyn Error>> {
+        self.log_writer.sync_if_due()
+    }
+
+    /// Syncs the log file to the disk right now, regardless of the sync policy
+    pub fn sync(&mut self) -> Result<(), Box<dyn Error>> {
+        self.log_writer.sync()
+    }
+
+    /// Returns a handle to the log writer, which can be used to sync the log (see `sync_if_due`)
+    /// without locking the `KVStorage`
+    pub fn log_writer(&self) -> DiskLogWriter {
+        self.log_writer.clone()
+    }
+
+    /// Count of records in the log file that no longer contribute to the storage content
+    pub fn dead_records(&self) -> usize {
+        self.dead_records
+    }
+
+    /// Count of keys currently holding a value, expired keys included until they are reaped
+    pub fn live_keys(&self) -> usize {
+        self.live_keys
+    }
+
+    /// Count of keys whose values have an expiry time, expired keys included until they are reaped
+    pub fn expiring_keys(&self) -> usize {
+        self.expiries.len()
+    }
+
+    /// Count of deleted keys still remembered in memory, see `purge_tombstones`
+    pub fn tombstones(&self) -> usize {
+        self.tombstones
+    }
+
+    /// Bytes of values held in memory, recently written ones and cached ones together. Stays
+    /// around the memory budget (if any) once there are enough values, see
+    /// `StorageOptions::memory_budget`
+    pub fn memory_usage(&self) -> usize {
+        self.hot_size + self.cache.size()
+    }
+
+    /// Count of values evicted from memory that were found in the cache when needed
+    pub fn cache_hits(&self) -> u64 {
+        self.cache.hits()
+    }
+
+    /// Count of values evicted from memory that had to be read back from disk
+    pub fn cache_misses(&self) -> u64 {
+        self.cache.misses()
+    }
+
+    /// Forgets all deleted keys kept in memory. Deleted keys are invisible either way, purging only
+    /// gives their memory back.
+    ///
+    /// This happens automatically once tombstones outnumber live keys (and there are at least
+    /// `PURGE_MIN_TOMBSTONES` of them), as well as on compaction, so there is seldom a need to call
+    /// this function
+    pub fn purge_tombstones(&mut self) {
+        if self.tombstones > 0 {
+            self.mem_storage.retain(|_, slot| slot.is_value());
+            self.tombstones = 0;
+        }
+    }
+
+    /// Trying get the value corresponding to the given `key`, returns `None` if not found.
+    ///
+    /// Panics if the value has been evicted from memory and cannot be read back from disk, use
+    /// `try_get` to handle that instead
     pub fn get(&self, key: &Key) -> Option<Arc<Value>> {
-        let encoded_key = key.encode();
-        if let Some(maybe_value) = self.mem_storage.get(&encoded_key) {
-            (*maybe_value).clone()
-        } else {
-            None
+        self.try_get(key).expect("failed reading an evicted value back from disk")
+    }
+
+    /// Same as `get`, but returns `Err` if the value cannot be read back from disk
+    pub fn try_get(&self, key: &Key) -> Result<Option<Arc<Value>>, Box<dyn Error>> {
+        match self.mem_storage.get(key) {
+            Some(slot) => self.load(slot),
+            None => Ok(None)
+        }
+    }
+
+    /// Version of the value of `key`, which changes every time the key is written: every write
+    /// gets a version greater than any other so far (its sequence number), and a value has the
+    /// version of the write that put it (or changed its expiry time), while a key holding no value
+    /// (an expired one included) has version
+    /// `NO_VALUE_VERSION`. Values loaded when the `KVStorage` is created all have version
+    /// `LOADED_VERSION`, so versions are only comparable within the lifetime of a `KVStorage`
+    pub fn version(&self, key: &Key) -> u64 {
+        self.mem_storage.get(key).map_or(NO_VALUE_VERSION, Slot::version)
+    }
+
+    /// Same as `try_get`, but also returns the version of the value, see `version`
+    pub fn try_get_versioned(&self, key: &Key) -> Result<VersionedValue, Box<dyn Error>> {
+        match self.mem_storage.get(key) {
+            Some(slot) => Ok((self.load(slot)?, slot.version())),
+            None => Ok((None, NO_VALUE_VERSION))
+        }
+    }
+
+    /// Opens a read snapshot of the current content: `try_get_at` and `try_scan_at` through it keep
+    /// seeing the content as it is now, until it is released by `release_read_snapshot`. Values
+    /// overwritten or deleted meanwhile are kept in memory for it, see `mvcc`
+    pub fn create_read_snapshot(&mut self) -> ReadSnapshot {
+        self.history.open(self.last_version)
+    }
+
+    /// Releases `snapshot`, dropping the old values only it could see. Returns a `StorageError`
+    /// if it is not open
+    pub fn release_read_snapshot(&mut self, snapshot: ReadSnapshot) -> Result<(), Box<dyn Error>> {
+        self.history.release(snapshot)
+    }
+
+    /// Count of read snapshots open
+    pub fn read_snapshots(&self) -> usize {
+        self.history.snapshots()
+    }
+
+    /// Count of old values kept for read snapshots
+    pub fn old_versions(&self) -> usize {
+        self.history.len()
+    }
+
+    /// Same as `try_get`, but reads through `snapshot` if given. Returns a `StorageError` if it
+    /// is not open
+    pub fn try_get_at(&self, key: &Key, snapshot: Option<ReadSnapshot>) -> Result<Option<Arc<Value>>, Box<dyn Error>> {
+        match snapshot {
+            Some(snapshot) => Ok(self.lookup_at(key, self.history.version_of(snapshot)?)?.flatten()),
+            None => self.try_get(key)
         }
     }
 
+    /// Same as `try_scan`, but reads through `snapshot` if given. Returns a `StorageError` if it
+    /// is not open
+    pub fn try_scan_at(&self, key1: &Key, key2: &Key, snapshot: Option<ReadSnapshot>) -> Result<KVPairs, Box<dyn Error>> {
+        let version = match snapshot {
+            Some(snapshot) => self.history.version_of(snapshot)?,
+            None => return self.try_scan(key1, key2)
+        };
+        if key1 >= key2 {
+            return Ok(Vec::new());
+        }
+        let mut ret = Vec::new();
+        for entry in self.entries_at((Included(key1), Excluded(key2)), version) {
+            if let (key, Some(value)) = entry? {
+                ret.push((key, value));
+            }
+        }
+        Ok(ret)
+    }
+
     /// Trying put the `key` - `value` pair into storage, returns `Err` if the logging file
-    /// unexpectedly goes wrong
+    /// unexpectedly goes wrong, or a `StorageError` if the sizes of `key` and `value` do not fit
+    /// the `DataLayout`. The put has been logged according to the sync policy once this
+    /// function returns
     pub fn put(&mut self, key: &Key, value: &Value) -> Result<(), Box<dyn Error>>{
-        let encoded_key = key.encode();
-        let value = Arc::new(*value);
-        self.log_writer.write(DiskLogMessage::Put(*key, value.clone()))?;
-        self.mem_storage.insert(encoded_key, Some(value));
-        Ok(())
+        let ticket = self.put_deferred(key, value)?;
+        self.wait_logged(ticket)
+    }
+
+    /// Same as `put`, but returns as soon as the put is queued for logging (and visible to readers),
+    /// the returned `CommitTicket` must be waited before acknowledging the put, and
+    /// `rollback_failed` called if that fails.
+    ///
+    /// This allows logs from concurrent writers to be committed together (see `DiskLogWriter`),
+    /// by waiting on the ticket after releasing any lock held on the `KVStorage`.
+    ///
+    /// Until the ticket is through, readers see a put that is not logged (nor synced) yet: it is
+    /// read uncommitted, and is gone again if the log fails and `rollback_failed` undoes it, or if
+    /// the process dies before the log gets written. A reader acting upon such a value cannot count
+    /// on it surviving a crash
+    pub fn put_deferred(&mut self, key: &Key, value: &Value) -> Result<CommitTicket, Box<dyn Error>> {
+        self.put_expiring_deferred(key, value, None)
+    }
+
+    /// Same as `put`, but the key expires after `ttl`, see `expiry`
+    pub fn put_with_ttl(&mut self, key: &Key, value: &Value, ttl: Duration) -> Result<(), Box<dyn Error>> {
+        let ticket = self.put_with_ttl_deferred(key, value, ttl)?;
+        self.wait_logged(ticket)
+    }
+
+    /// Same as `put_with_ttl`, but returns as soon as the put is queued for logging, see
+    /// `put_deferred`
+    pub fn put_with_ttl_deferred(&mut self, key: &Key, value: &Value, ttl: Duration) -> Result<CommitTicket, Box<dyn Error>> {
+        self.put_expiring_deferred(key, value, Some(expiry_after(ttl)))
+    }
+
+    fn put_expiring_deferred(&mut self, key: &Key, value: &Value, expiry: Option<u64>) -> Result<CommitTicket, Box<dyn Error>> {
+        self.layout.check_key(key)?;
+        self.layout.check_value(value)?;
+        let value = Arc::new(value.clone());
+        let put = DiskLogMessage::Put(key.clone(), value.clone());
+        // a put with an expiry time is logged as a batch, so that it is never replayed without it
+        let log_msg = match expiry {
+            Some(_) => DiskLogMessage::Batch(vec![put, DiskLogMessage::Expire(key.clone(), expiry)]),
+            None => put
+        };
+        let value_offset = log_msg.value_offsets()[0];
+        let (ticket, offset) = self.log_writer.append_located(log_msg)?;
+        let location = self.log_location(offset.map(|offset| offset + value_offset), &value);
+        self.record_uncommitted(ticket.seq(), key);
+        self.apply_put(key, value, location, expiry);
+        if expiry.is_some() {
+            // the expire operation is dead right away, see `replay_log_file`
+            self.dead_records += 1;
+        }
+        self.maybe_evict();
+        self.maybe_compact();
+        Ok(ticket)
+    }
+
+    /// Where a value logged at byte `offset` of the log can be read back, if values are ever
+    /// evicted
+    fn log_location(&self, offset: Option<u64>, value: &Value) -> Option<ValueLocation> {
+        match (&self.log_values, offset) {
+            (Some(file), Some(offset)) => Some(ValueLocation { file: file.clone(), offset, size: value.data.len() as u32 }),
+            _ => None
+        }
+    }
+
+    /// Puts a value that has been logged into memory
+    fn apply_put(&mut self, key: &Key, value: Arc<Value>, location: Option<ValueLocation>, expiry: Option<u64>) {
+        match self.set_slot(key, Slot::Hot { value, location, seq: 0, version: 0, expiry }) {
+            Some(Slot::Tombstone) => {
+                self.tombstones -= 1;
+                self.live_keys += 1;
+            },
+            Some(_) => self.dead_records += 1,
+            None => self.live_keys += 1
+        }
+    }
+
+    /// Leaves a tombstone for a key whose delete has been logged
+    fn apply_tombstone(&mut self, key: &Key) {
+        // the delete record itself is always dead, and so is the put it removes
+        self.dead_records += 1;
+        match self.set_slot(key, Slot::Tombstone) {
+            Some(Slot::Tombstone) => (),
+            Some(_) => {
+                self.live_keys -= 1;
+                self.dead_records += 1;
+                self.tombstones += 1;
+            },
+            None => self.tombstones += 1
+        }
+    }
+
+    /// Keeps what `key` holds before a write logged as `seq`, until the log is committed
+    fn record_uncommitted(&mut self, seq: u64, key: &Key) {
+        let committed = self.log_writer.last_committed();
+        let done = self.uncommitted.iter().take_while(|(seq, _, _)| *seq <= committed).count();
+        self.uncommitted.drain(..done);
+        self.uncommitted.push((seq, key.clone(), self.mem_storage.get(key).cloned()));
+    }
+
+    /// Waits for `ticket` of a write to this storage, see `rollback_failed`
+    fn wait_logged(&mut self, ticket: CommitTicket) -> Result<(), Box<dyn Error>> {
+        let result = ticket.wait();
+        if result.is_err() {
+            self.rollback_failed();
+        }
+        result
+    }
+
+    /// Undoes the writes whose logs failed to be committed, so that readers do not see (and
+    /// compaction does not write) content that is not in the log. Writes are applied to memory as
+    /// soon as they are queued for logging, so this must be called whenever waiting on a
+    /// `CommitTicket` of this storage fails, which the writes that wait by themselves do
+    pub fn rollback_failed(&mut self) {
+        let failed = match self.log_writer.failed_logs() {
+            Some(failed) => failed,
+            None => return
+        };
+        while self.uncommitted.last().is_some_and(|(seq, _, _)| failed.contains(seq)) {
+            let (_, key, previous) = self.uncommitted.pop().unwrap();
+            self.restore_slot(&key, previous);
+        }
+    }
+
+    /// Puts back what `key` held before a write, `None` if it was not there. Dead records are
+    /// left as they are, they only make the next compaction come a bit early
+    fn restore_slot(&mut self, key: &Key, previous: Option<Slot>) {
+        match &previous {
+            Some(slot) if slot.is_value() => self.live_keys += 1,
+            Some(_) => self.tombstones += 1,
+            None => ()
+        }
+        let absent = previous.is_none();
+        match self.set_slot(key, previous.unwrap_or(Slot::Tombstone)) {
+            Some(slot) if slot.is_value() => self.live_keys -= 1,
+            Some(_) => self.tombstones -= 1,
+            None => ()
+        }
+        if absent {
+            // a tombstone goes away without any bookkeeping, see `purge_tombstones`
+            self.mem_storage.remove(key);
+        }
+    }
+
+    /// Applies all the puts and deletes of `batch` atomically, returns `Err` if the logging file
+    /// unexpectedly goes wrong, or a `StorageError` if any key or value does not fit the
+    /// `DataLayout`, in which case nothing is applied. The batch is logged as a single record, so
+    /// it is either replayed entirely or not at all after a crash
+    pub fn write_batch(&mut self, batch: &WriteBatch) -> Result<(), Box<dyn Error>> {
+        let ticket = self.write_batch_deferred(batch)?;
+        self.wait_logged(ticket)
+    }
+
+    /// Same as `write_batch`, but returns as soon as the batch is queued for logging (and visible
+    /// to readers, uncommitted), see `put_deferred`
+    pub fn write_batch_deferred(&mut self, batch: &WriteBatch) -> Result<CommitTicket, Box<dyn Error>> {
+        self.layout.check_batch(batch)?;
+        if batch.is_empty() {
+            return Ok(CommitTicket::committed());
+        }
+        let mut values = Vec::new();
+        let messages = batch.ops().iter()
+            .map(|op| match op {
+                BatchOp::Put(key, value) => {
+                    let value = Arc::new(value.clone());
+                    values.push(value.clone());
+                    DiskLogMessage::Put(key.clone(), value)
+                },
+                BatchOp::Delete(key) => DiskLogMessage::Delete(key.clone())
+            })
+            .collect();
+        let log_msg = DiskLogMessage::Batch(messages);
+        let value_offsets = log_msg.value_offsets();
+        let (ticket, offset) = self.log_writer.append_located(log_msg)?;
+
+        let mut puts = values.into_iter().zip(value_offsets);
+        for op in batch.ops() {
+            match op {
+                BatchOp::Put(key, _) => {
+                    let (value, value_offset) = puts.next().unwrap();
+                    let location = self.log_location(offset.map(|offset| offset + value_offset), &value);
+                    self.record_uncommitted(ticket.seq(), key);
+                    self.apply_put(key, value, location, None);
+                },
+                BatchOp::Delete(key) => {
+                    // the delete of a key that holds no value is logged anyway, it is dead
+                    // right away unless tombstones are kept
+                    if self.keep_tombstones || matches!(self.mem_storage.get(key), Some(slot) if slot.is_value()) {
+                        self.record_uncommitted(ticket.seq(), key);
+                        self.apply_tombstone(key);
+                    } else {
+                        self.dead_records += 1;
+                    }
+                }
+            }
+        }
+        self.maybe_purge_tombstones();
+        self.maybe_evict();
+        self.maybe_compact();
+        Ok(ticket)
+    }
+
+    /// Replaces the value of `key` by `new` if it is `expected`, atomically. `None` as `expected`
+    /// means the key holds no value, and `None` as `new` deletes the key. Returns `Ok(Err(current))`
+    /// with the current value if it is not `expected`, in which case nothing is written, a
+    /// `StorageError` if `key` and `new` do not fit the `DataLayout`, or `Err` if the logging file
+    /// unexpectedly goes wrong
+    pub fn compare_and_swap(&mut self, key: &Key, expected: Option<&Value>, new: Option<&Value>)
+        -> Result<SwapResult, Box<dyn Error>> {
+        let (result, ticket) = self.compare_and_swap_deferred(key, expected, new)?;
+        self.wait_logged(ticket)?;
+        Ok(result)
+    }
+
+    /// Same as `compare_and_swap`, but returns as soon as the write is queued for logging, see
+    /// `put_deferred`
+    pub fn compare_and_swap_deferred(&mut self, key: &Key, expected: Option<&Value>, new: Option<&Value>)
+        -> Result<(SwapResult, CommitTicket), Box<dyn Error>> {
+        if let Some(new) = new {
+            self.layout.check_key(key)?;
+            self.layout.check_value(new)?;
+        }
+        let current = self.try_get(key)?;
+        if current.as_deref() != expected {
+            return Ok((Err(current), CommitTicket::committed()));
+        }
+        let ticket = match new {
+            Some(new) => self.put_deferred(key, new)?,
+            None => self.delete_deferred(key)?.1
+        };
+        Ok((Ok(()), ticket))
+    }
+
+    /// Applies `batch` like `write_batch`, but only if every key of `read_set` still has the
+    /// version it was read with (see `version`). Returns a `ConflictError` otherwise, in which
+    /// case nothing is applied. This is the commit of an optimistic transaction, see `transaction`
+    pub fn commit(&mut self, read_set: &[(Key, u64)], batch: &WriteBatch) -> Result<(), Box<dyn Error>> {
+        let ticket = self.commit_deferred(read_set, batch)?;
+        self.wait_logged(ticket)
+    }
+
+    /// Same as `commit`, but returns as soon as the batch is queued for logging, see `put_deferred`
+    pub fn commit_deferred(&mut self, read_set: &[(Key, u64)], batch: &WriteBatch) -> Result<CommitTicket, Box<dyn Error>> {
+        if let Some((key, _)) = read_set.iter().find(|(key, version)| self.version(key) != *version) {
+            return Err(Box::new(ConflictError::new(key)));
+        }
+        self.write_batch_deferred(batch)
     }
 
-    /// Trying delete the `key` from storage, returns the rows affected (deleted or not, exactly)
-    /// if succeeded, `Err` if the internal logging system goes wrong
+    /// Trying delete the `key` from storage, returns the rows affected (1 if `key` held a value,
+    /// 0 otherwise) if succeeded, `Err` if the internal logging system goes wrong. Deleting a key
+    /// that holds no value writes nothing into the log, unless it has expired but not been reaped
     pub fn delete(&mut self, key: &Key) -> Result<usize, Box<dyn Error>> {
-        let encoded_key = key.encode();
-        if let Some(maybe_value) = self.mem_storage.get_mut(&encoded_key) {
-            self.log_writer.write(DiskLogMessage::Delete(*key))?;
-            *maybe_value = None;
-            Ok(1)
-        } else {
-            Ok(0)
+        let (rows_affected, ticket) = self.delete_deferred(key)?;
+        self.wait_logged(ticket)?;
+        Ok(rows_affected)
+    }
+
+    /// Same as `delete`, but returns as soon as the delete is queued for logging (and visible to
+    /// readers, uncommitted), see `put_deferred`
+    pub fn delete_deferred(&mut self, key: &Key) -> Result<(usize, CommitTicket), Box<dyn Error>> {
+        match self.mem_storage.get(key) {
+            Some(slot) if slot.is_value() => {
+                let rows_affected = if slot.is_live() { 1 } else { 0 };
+                let ticket = self.log_writer.append(DiskLogMessage::Delete(key.clone()))?;
+                self.record_uncommitted(ticket.seq(), key);
+                self.apply_tombstone(key);
+                self.maybe_purge_tombstones();
+                self.maybe_compact();
+                Ok((rows_affected, ticket))
+            },
+            _ => Ok((0, CommitTicket::committed()))
+        }
+    }
+
+    /// Makes the value of `key` expire after `ttl`, replacing any expiry time it had. Returns the
+    /// rows affected (1 if `key` holds a value, 0 otherwise), or `Err` if the logging file
+    /// unexpectedly goes wrong. See `expiry`
+    pub fn expire(&mut self, key: &Key, ttl: Duration) -> Result<usize, Box<dyn Error>> {
+        let (rows_affected, ticket) = self.expire_deferred(key, ttl)?;
+        self.wait_logged(ticket)?;
+        Ok(rows_affected)
+    }
+
+    /// Same as `expire`, but returns as soon as the change is queued for logging, see
+    /// `put_deferred`
+    pub fn expire_deferred(&mut self, key: &Key, ttl: Duration) -> Result<(usize, CommitTicket), Box<dyn Error>> {
+        self.set_expiry_deferred(key, Some(expiry_after(ttl)))
+    }
+
+    /// Removes the expiry time of the value of `key`, so that it never expires. Returns the rows
+    /// affected (1 if `key` holds a value that had an expiry time, 0 otherwise), or `Err` if the
+    /// logging file unexpectedly goes wrong
+    pub fn persist(&mut self, key: &Key) -> Result<usize, Box<dyn Error>> {
+        let (rows_affected, ticket) = self.persist_deferred(key)?;
+        self.wait_logged(ticket)?;
+        Ok(rows_affected)
+    }
+
+    /// Same as `persist`, but returns as soon as the change is queued for logging, see
+    /// `put_deferred`
+    pub fn persist_deferred(&mut self, key: &Key) -> Result<(usize, CommitTicket), Box<dyn Error>> {
+        self.set_expiry_deferred(key, None)
+    }
+
+    fn set_expiry_deferred(&mut self, key: &Key, expiry: Option<u64>) -> Result<(usize, CommitTicket), Box<dyn Error>> {
+        let slot = match self.mem_storage.get(key) {
+            Some(slot) if slot.is_live() && (expiry.is_some() || slot.expiry().is_some()) => slot.clone(),
+            _ => return Ok((0, CommitTicket::committed()))
+        };
+        let ticket = self.log_writer.append(DiskLogMessage::Expire(key.clone(), expiry))?;
+        // the expire record is dead right away, see `replay_log_file`
+        self.dead_records += 1;
+        self.record_uncommitted(ticket.seq(), key);
+        self.set_slot(key, slot.with_expiry(expiry));
+        self.maybe_compact();
+        Ok((1, ticket))
+    }
+
+    /// Time left before the value of `key` expires
+    pub fn ttl(&self, key: &Key) -> Ttl {
+        match self.mem_storage.get(key) {
+            Some(slot) if slot.is_value() => ttl_of(slot.expiry()),
+            _ => Ttl::NoValue
+        }
+    }
+
+    /// Deletes all the keys that have expired, logging their deletes so that the log shrinks on
+    /// the next compaction. Returns the count of keys deleted, or `Err` if the logging file
+    /// unexpectedly goes wrong. Expired keys are invisible either way, see `expiry`
+    pub fn reap_expired(&mut self) -> Result<usize, Box<dyn Error>> {
+        let (reaped, ticket) = self.reap_expired_deferred()?;
+        self.wait_logged(ticket)?;
+        Ok(reaped)
+    }
+
+    /// Same as `reap_expired`, but returns as soon as the deletes are queued for logging, see
+    /// `put_deferred`
+    pub fn reap_expired_deferred(&mut self) -> Result<(usize, CommitTicket), Box<dyn Error>> {
+        let expired = self.expiries.expired(expiry::now());
+        if expired.is_empty() {
+            return Ok((0, CommitTicket::committed()));
+        }
+        let messages = expired.iter().map(|key| DiskLogMessage::Delete(key.clone())).collect();
+        let ticket = self.log_writer.append(DiskLogMessage::Batch(messages))?;
+        for key in expired.iter() {
+            self.record_uncommitted(ticket.seq(), key);
+            self.apply_tombstone(key);
+        }
+        self.maybe_purge_tombstones();
+        self.maybe_compact();
+        Ok((expired.len(), ticket))
+    }
+
+    /// Logs a delete of `key` and leaves a tombstone for it, whether or not it holds a value here.
+    /// Used by storages whose content shadows older data kept elsewhere, see `lsm`
+    pub(crate) fn tombstone_deferred(&mut self, key: &Key) -> Result<CommitTicket, Box<dyn Error>> {
+        let ticket = self.log_writer.append(DiskLogMessage::Delete(key.clone()))?;
+        self.record_uncommitted(ticket.seq(), key);
+        self.apply_tombstone(key);
+        Ok(ticket)
+    }
+
+    /// Looks `key` up, returns `None` if this storage knows nothing about it, the value (`None`
+    /// for a tombstone) together with its version (see `version`) otherwise
+    pub(crate) fn lookup_versioned(&self, key: &Key) -> Result<Option<VersionedValue>, Box<dyn Error>> {
+        match self.mem_storage.get(key) {
+            Some(slot) => Ok(Some((self.load(slot)?, slot.version()))),
+            None => Ok(None)
+        }
+    }
+
+    /// Same as `lookup_versioned`, but looks into the content as it was at `version`, which must
+    /// not be older than the oldest read snapshot open. Versions are left out
+    pub(crate) fn lookup_at(&self, key: &Key, version: u64) -> Result<Option<Option<Arc<Value>>>, Box<dyn Error>> {
+        let slot = match self.history.find(key, version) {
+            Some(old) => old.as_ref(),
+            None => self.mem_storage.get(key)
+        };
+        match slot {
+            Some(slot) => Ok(Some(self.load(slot)?)),
+            None => Ok(None)
         }
     }
 
-    /// Trying scan all kv pairs within interval [`key1`, `key2`), according to dictionary order
+    /// The version of the last write so far
+    pub(crate) fn last_version(&self) -> u64 {
+        self.last_version
+    }
+
+    /// Makes values written from now on get versions greater than `last_version`, so that a
+    /// storage taking over from another one (see `lsm`) never reuses its versions
+    pub(crate) fn continue_versions(&mut self, last_version: u64) {
+        self.last_version = self.last_version.max(last_version);
+    }
+
+    /// Entries with keys within `range`, tombstones included as `None`
+    pub(crate) fn entries<R: RangeBounds<Key>>(&self, range: R)
+        -> impl DoubleEndedIterator<Item=Result<Entry, Box<dyn Error>>> + '_ {
+        self.mem_storage.range(range).map(move |(key, slot)| Ok((key.clone(), self.load(slot)?)))
+    }
+
+    /// Same as `entries`, but with the content as it was at `version`, see `lookup_at`. Values are
+    /// only loaded as the entries are iterated through
+    pub(crate) fn entries_at(&self, range: (Bound<&Key>, Bound<&Key>), version: u64)
+        -> impl DoubleEndedIterator<Item=Result<Entry, Box<dyn Error>>> + '_ {
+        let current = self.mem_storage.range::<Key, _>(range).map(|(key, slot)| (key, Some(slot.clone())));
+        self.history.view(range, version, current).into_iter()
+            .filter_map(move |(key, slot)| slot.map(|slot| Ok((key.clone(), self.load(&slot)?))))
+    }
+
+    /// Whether there is nothing in this storage, not even tombstones
+    pub(crate) fn is_empty(&self) -> bool {
+        self.mem_storage.is_empty()
+    }
+
+    /// Trying scan all kv pairs within interval [`key1`, `key2`), according to dictionary order.
+    ///
+    /// Panics if some value has been evicted from memory and cannot be read back from disk, use
+    /// `try_scan` to handle that instead
     pub fn scan(&self, key1: &Key, key2: &Key) -> Vec<(Key, Arc<Value>)> {
-        let (encoded_key1, encoded_key2) = (key1.encode(), key2.encode());
-        self.mem_storage.range((Included(encoded_key1), Excluded(encoded_key2)))
-            .filter(|x| {
-                let (_, v) = x;
-                if let Some(_) = v { true } else { false }
-            })
-            .map(|x| {
-                let (k, v) = x;
-                (Key::decode(*k), v.as_ref().unwrap().clone())
-            })
-            .collect::<Vec<_>>()
+        self.try_scan(key1, key2).expect("failed reading an evicted value back from disk")
+    }
+
+    /// Same as `scan`, but returns `Err` if some value cannot be read back from disk
+    pub fn try_scan(&self, key1: &Key, key2: &Key) -> Result<KVPairs, Box<dyn Error>> {
+        if key1 >= key2 {
+            return Ok(Vec::new());
+        }
+        let mut ret = Vec::new();
+        for entry in self.entries((Included(key1), Excluded(key2))) {
+            if let (key, Some(value)) = entry? {
+                ret.push((key, value));
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Trying scan all kv pairs whose keys start with `prefix`, according to dictionary order.
+    ///
+    /// Panics if some value has been evicted from memory and cannot be read back from disk, use
+    /// `try_scan_prefix` to handle that instead
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Vec<(Key, Arc<Value>)> {
+        self.try_scan_prefix(prefix).expect("failed reading an evicted value back from disk")
+    }
+
+    /// Same as `scan_prefix`, but returns `Err` if some value cannot be read back from disk
+    pub fn try_scan_prefix(&self, prefix: &[u8]) -> Result<KVPairs, Box<dyn Error>> {
+        self.entries(prefix_range(prefix)).filter_map(live_pair).collect()
+    }
+
+    /// Scans the keys holding a value within interval [`key1`, `key2`), according to dictionary
+    /// order. Values are neither cloned nor read back from disk
+    pub fn scan_keys(&self, key1: &Key, key2: &Key) -> Vec<Key> {
+        if key1 >= key2 {
+            return Vec::new();
+        }
+        self.mem_storage.range::<Key, _>((Included(key1), Excluded(key2)))
+            .filter(|(_, slot)| slot.is_live())
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Count of keys holding a value within interval [`key1`, `key2`), see `scan_keys`
+    pub fn count(&self, key1: &Key, key2: &Key) -> usize {
+        if key1 >= key2 {
+            return 0;
+        }
+        self.mem_storage.range::<Key, _>((Included(key1), Excluded(key2))).filter(|(_, slot)| slot.is_live()).count()
+    }
+
+    /// Trying scan the kv pairs within the interval from `key1` to `key2` as told by `options`
+    /// (see `scan`), through `snapshot` if given. Returns `Err` if some value cannot be read back
+    /// from disk, or a `StorageError` if `snapshot` is not open
+    pub fn try_scan_with(&self, key1: &Key, key2: &Key, options: &ScanOptions, snapshot: Option<ReadSnapshot>)
+        -> Result<ScanPage, Box<dyn Error>> {
+        let version = snapshot.map(|snapshot| self.history.version_of(snapshot)).transpose()?;
+        let range = match options.range(key1, key2) {
+            Some(range) => range,
+            None => return Ok(ScanPage { pairs: Vec::new(), continuation: None })
+        };
+        match version {
+            Some(version) => {
+                let entries = self.entries_at(range, version);
+                if options.descending {
+                    options.collect(entries.rev().filter_map(live_pair))
+                } else {
+                    options.collect(entries.filter_map(live_pair))
+                }
+            },
+            None if options.descending => options.collect(self.entries(range).rev().filter_map(live_pair)),
+            None => options.collect(self.entries(range).filter_map(live_pair))
+        }
+    }
+
+    /// The value held by `slot`, read back through the cache if it has been evicted. An expired
+    /// value is no value
+    fn load(&self, slot: &Slot) -> Result<Option<Arc<Value>>, Box<dyn Error>> {
+        match slot {
+            Slot::Tombstone => Ok(None),
+            slot if !slot.is_live() => Ok(None),
+            Slot::Hot { value, .. } => Ok(Some(value.clone())),
+            Slot::Cold { location, .. } => Ok(Some(self.cache.get(location)?))
+        }
+    }
+
+    /// Replaces the slot of `key` by a newly written one, keeping track of the values held in
+    /// memory, giving the write a new version and keeping the old slot for read snapshots. Returns
+    /// the old slot
+    fn set_slot(&mut self, key: &Key, mut slot: Slot) -> Option<Slot> {
+        self.last_version += 1;
+        match &mut slot {
+            Slot::Hot { value, location, seq, version, .. } => {
+                *version = self.last_version;
+                self.hot_size += value.data.len();
+                if location.is_some() {
+                    *seq = self.next_seq;
+                    self.next_seq += 1;
+                    self.resident.insert(*seq, key.clone());
+                }
+            },
+            Slot::Cold { version, .. } => *version = self.last_version,
+            Slot::Tombstone => ()
+        }
+        let expiry = slot.expiry();
+        let old = self.mem_storage.insert(key.clone(), slot);
+        self.expiries.update(key, old.as_ref().and_then(Slot::expiry), expiry);
+        if let Some(Slot::Hot { value, location, seq, .. }) = &old {
+            self.hot_size -= value.data.len();
+            if location.is_some() {
+                self.resident.remove(seq);
+            }
+        }
+        let written = old.as_ref().filter(|slot| slot.is_value()).map(Slot::version);
+        self.history.record(key, old.clone(), written, self.last_version);
+        old
+    }
+
+    /// Evicts the oldest written values from memory until the values left fit the memory budget.
+    /// Values whose log has not been written yet cannot be evicted
+    fn maybe_evict(&mut self) {
+        let hot_budget = match self.memory_budget {
+            Some(budget) => budget - budget / CACHE_BUDGET_SHARE,
+            None => return
+        };
+        let committed = self.log_writer.file_size().unwrap_or(0);
+        while self.hot_size > hot_budget {
+            let (&seq, key) = match self.resident.iter().next() {
+                Some(oldest) => oldest,
+                None => break
+            };
+            let slot = self.mem_storage.get_mut(key).unwrap();
+            let (location, version, expiry) = match slot {
+                Slot::Hot { location: Some(location), version, expiry, .. } => (location.clone(), *version, *expiry),
+                _ => unreachable!("only evictable values are tracked")
+            };
+            let in_log = self.log_values.as_ref().is_some_and(|file| location.is_in(file));
+            if in_log && location.offset + location.size as u64 > committed {
+                break;
+            }
+            self.hot_size -= location.size as usize;
+            *slot = Slot::Cold { location, version, expiry };
+            self.resident.remove(&seq);
+        }
+    }
+
+    /// Rewrites the log file so that it only contains the live content of the storage, then
+    /// atomically replaces the old log with it.
+    ///
+    /// The new log is first written to a temporary file next to the old one, synced to disk and
+    /// then renamed over the old log, so a crash during compaction leaves either the old or the new
+    /// log in place, never a mixture. If snapshots are taken (see `snapshot`), a new snapshot of
+    /// the content replaces them, since the new log no longer has the deletes that come after them.
+    /// Returns `Err` if the `KVStorage` is not created by `open` or the file system goes wrong, in
+    /// which case the old log keeps being used.
+    pub fn compact(&mut self) -> Result<(), Box<dyn Error>> {
+        let log_path = match &self.log_path {
+            Some(log_path) => log_path.clone(),
+            None => return Err(Box::new(DiskLogError::new("compaction requires a log file opened by path")))
+        };
+        let mut tmp_path = log_path.clone().into_os_string();
+        tmp_path.push(".compact");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        info!("compacting '{}', {} live keys, {} dead records",
+              log_path.display(), self.live_keys, self.dead_records);
+        // logs still queued belong to the old file, their writers are waiting for them. The writes
+        // whose logs fail are undone rather than written into the new log
+        if self.log_writer.flush().is_err() {
+            self.rollback_failed();
+        }
+        // where the values land in the new log, in key order
+        let mut offsets = Vec::new();
+        {
+            let tmp_writer = DiskLogWriter::new(File::create(&tmp_path)?);
+            for (i, (key, slot)) in self.mem_storage.iter().enumerate() {
+                match slot.read_uncached()? {
+                    Some(value) => {
+                        let (_, offset) = tmp_writer.append_located(DiskLogMessage::Put(key.clone(), value))?;
+                        offsets.push(offset.map(|offset| offset + put_value_offset(key.data.len())));
+                        if let Some(expiry) = slot.expiry() {
+                            let _ = tmp_writer.append(DiskLogMessage::Expire(key.clone(), Some(expiry)))?;
+                        }
+                    },
+                    None if self.keep_tombstones => {
+                        let _ = tmp_writer.append(DiskLogMessage::Delete(key.clone()))?;
+                    },
+                    None => ()
+                }
+                if i % COMPACTION_BATCH_SIZE == COMPACTION_BATCH_SIZE - 1 {
+                    tmp_writer.flush()?;
+                }
+            }
+            tmp_writer.sync()?;
+        }
+        let log_values = match self.memory_budget {
+            Some(_) => Some(Arc::new(ValueFile::open(&tmp_path)?)),
+            None => None
+        };
+        // the new log has no record of deleted keys, so older snapshots would bring them back,
+        // including one begun but not written yet. The content is snapshotted before the new log
+        // takes over, which is correct with either log
+        let old_snapshots = snapshot::list_snapshots(&log_path)?;
+        let snapshotted = !old_snapshots.is_empty() || self.snapshot_generation > 0;
+        if snapshotted {
+            self.snapshot_generation += 1;
+            write_slots(&snapshot::snapshot_path(&log_path, self.snapshot_generation), &self.mem_storage)?;
+        }
+        fs::rename(&tmp_path, &log_path)?;
+        sync_parent_dir(&log_path);
+
+        let log_file = fs::OpenOptions::new().append(true).open(&log_path)?;
+        self.log_writer.replace_file(log_file);
+        if let Some(log_values) = log_values {
+            self.relocate_values(log_values, offsets);
+        }
+        for generation in old_snapshots.into_iter().filter(|generation| *generation != self.snapshot_generation) {
+            let _ = fs::remove_file(snapshot::snapshot_path(&log_path, generation));
+        }
+        self.uncommitted.clear();
+        self.log_epoch += 1;
+        // the next snapshot cuts the new log where the one just written ends
+        self.snapshot_log_offset = if snapshotted { self.log_writer.file_size() } else { None };
+        if !self.keep_tombstones {
+            self.purge_tombstones();
+        }
+        self.dead_records = 0;
+        Ok(())
+    }
+
+    /// Writes a snapshot file of the current content, and cuts the log short so that it only
+    /// contains records written after the previous snapshot. Restarting then loads the snapshot
+    /// and replays the (short) log, instead of replaying the whole history.
+    ///
+    /// The log is not cut right at the new snapshot, so that if the new snapshot ever gets
+    /// damaged, the previous snapshot together with the log still gives the complete content.
+    /// Replaying records that are older than the snapshot being loaded does no harm, since
+    /// replaying a log on top of an older state gives the same result. For the same reason, the
+    /// first snapshot after opening does not cut the log at all.
+    ///
+    /// This blocks the `KVStorage` while the whole content is written, use `begin_snapshot`,
+    /// `PendingSnapshot::write` and `finish_snapshot` to write the snapshot file without holding a
+    /// lock. Returns `Err` if the `KVStorage` is not created by `open` or the file system goes wrong
+    pub fn snapshot(&mut self) -> Result<(), Box<dyn Error>> {
+        let pending = self.begin_snapshot()?;
+        pending.write()?;
+        self.finish_snapshot(pending)
+    }
+
+    /// Takes a point-in-time copy of the current content, which can then be written into a
+    /// snapshot file by `PendingSnapshot::write` while the `KVStorage` keeps serving, and finally
+    /// handed back to `finish_snapshot`.
+    ///
+    /// Copying the content is cheap compared to writing it, since values are shared
+    pub fn begin_snapshot(&mut self) -> Result<PendingSnapshot, Box<dyn Error>> {
+        let log_path = match &self.log_path {
+            Some(log_path) => log_path.clone(),
+            None => return Err(Box::new(DiskLogError::new("snapshot requires a log file opened by path")))
+        };
+        self.log_writer.flush()?;
+        self.snapshot_generation += 1;
+        Ok(PendingSnapshot {
+            generation: self.snapshot_generation,
+            path: snapshot::snapshot_path(&log_path, self.snapshot_generation),
+            content: self.mem_storage.clone(),
+            log_offset: self.log_writer.file_size(),
+            log_epoch: self.log_epoch
+        })
+    }
+
+    /// Cuts the log short after `pending` has been written, and removes snapshots older than the
+    /// previous one.
+    ///
+    /// If the log has been rewritten (e.g. compacted) since `begin_snapshot`, the snapshot is
+    /// removed instead: the new log may lack the deletes that come after it, and the rewrite has
+    /// taken a newer snapshot anyway, see `compact`
+    pub fn finish_snapshot(&mut self, pending: PendingSnapshot) -> Result<(), Box<dyn Error>> {
+        if !pending.path.exists() {
+            return Err(Box::new(DiskLogError::new("snapshot has not been written")));
+        }
+        let log_path = match &self.log_path {
+            Some(log_path) => log_path.clone(),
+            None => return Err(Box::new(DiskLogError::new("snapshot requires a log file opened by path")))
+        };
+        if pending.log_epoch != self.log_epoch {
+            fs::remove_file(&pending.path)?;
+            info!("snapshot '{}' dropped, the log has been rewritten meanwhile", pending.path.display());
+            return Ok(());
+        }
+
+        self.snapshot_log_offset = match (self.snapshot_log_offset, pending.log_offset) {
+            (Some(cut), Some(offset)) if cut > DISK_LOG_HEADER_SIZE => {
+                self.cut_log_before(&log_path, cut)?;
+                Some(offset - cut + DISK_LOG_HEADER_SIZE)
+            },
+            (_, offset) => offset
+        };
+        if let Some(log_values) = self.log_values.clone() {
+            self.relocate_into_snapshot(&pending, &log_values)?;
+        }
+
+        for generation in snapshot::list_snapshots(&log_path)? {
+            if generation + 1 < pending.generation {
+                let _ = fs::remove_file(snapshot::snapshot_path(&log_path, generation));
+            }
+        }
+        info!("snapshot '{}' done", pending.path.display());
+        Ok(())
+    }
+
+    /// Rewrites the log so that it only contains the records from byte `cut` on
+    fn cut_log_before(&mut self, log_path: &Path, cut: u64) -> Result<(), Box<dyn Error>> {
+        self.log_writer.flush()?;
+        let mut tail = Vec::new();
+        {
+            let mut log_file = File::open(log_path)?;
+            log_file.seek(SeekFrom::Start(cut))?;
+            log_file.read_to_end(&mut tail)?;
+        }
+
+        let mut tmp_path = log_path.as_os_str().to_owned();
+        tmp_path.push(".truncate");
+        let tmp_path = PathBuf::from(tmp_path);
+        {
+            let tmp_writer = DiskLogWriter::new(File::create(&tmp_path)?);
+            if !tail.is_empty() {
+                tmp_writer.append_serialized(tail)?.wait()?;
+            }
+            tmp_writer.sync()?;
+        }
+        let new_log_values = match self.log_values {
+            Some(_) => Some(Arc::new(ValueFile::open(&tmp_path)?)),
+            None => None
+        };
+        fs::rename(&tmp_path, log_path)?;
+        sync_parent_dir(log_path);
+
+        let log_file = fs::OpenOptions::new().append(true).open(log_path)?;
+        self.log_writer.replace_file(log_file);
+        if let (Some(old), Some(new)) = (self.log_values.take(), new_log_values) {
+            // values in the tail move along, the others are in the snapshot, see `finish_snapshot`
+            for slot in self.mem_storage.values_mut() {
+                let location = match slot.location() {
+                    Some(location) if location.is_in(&old) && location.offset >= cut => location,
+                    _ => continue
+                };
+                let offset = location.offset - cut + DISK_LOG_HEADER_SIZE;
+                let size = location.size;
+                slot.set_location(ValueLocation { file: new.clone(), offset, size });
+            }
+            self.log_values = Some(new);
+        }
+        self.log_epoch += 1;
+        if !self.keep_tombstones {
+            self.purge_tombstones();
+        }
+        self.dead_records = 0;
+        Ok(())
+    }
+
+    /// Points values to where `compact` has just written them in the new log `log_values`, given
+    /// their `offsets` in key order
+    fn relocate_values(&mut self, log_values: Arc<ValueFile>, offsets: Vec<Option<u64>>) {
+        let mut offsets = offsets.into_iter();
+        for (key, slot) in self.mem_storage.iter_mut().filter(|(_, slot)| slot.is_value()) {
+            let offset = match offsets.next() {
+                Some(Some(offset)) => offset,
+                _ => continue
+            };
+            let location = ValueLocation { file: log_values.clone(), offset, size: slot.value_size() as u32 };
+            if let Slot::Hot { location: None, seq, .. } = slot {
+                // values loaded from an old format log become evictable
+                *seq = self.next_seq;
+                self.next_seq += 1;
+                self.resident.insert(*seq, key.clone());
+            }
+            slot.set_location(location);
+        }
+        self.log_values = Some(log_values);
+        self.cache.clear();
+    }
+
+    /// Points values that are neither in the current log (`log_values`) nor changed since
+    /// `pending` was taken to the snapshot file `pending` has been written into, so that the files
+    /// they used to live in can go
+    fn relocate_into_snapshot(&mut self, pending: &PendingSnapshot, log_values: &Arc<ValueFile>) -> Result<(), Box<dyn Error>> {
+        let snapshot_values = Arc::new(ValueFile::open(&pending.path)?);
+        let live = pending.content.iter().filter(|(_, slot)| slot.is_value());
+        let offsets = snapshot::snapshot_value_offsets(live.clone().map(|(key, slot)| (key.data.len(), slot.value_size())));
+        for ((key, pending_slot), offset) in live.zip(offsets) {
+            let pending_location = match pending_slot.location() {
+                Some(location) if !location.is_in(log_values) => location,
+                _ => continue
+            };
+            if let Some(slot) = self.mem_storage.get_mut(key) {
+                if slot.location() == Some(pending_location) {
+                    let size = pending_location.size;
+                    slot.set_location(ValueLocation { file: snapshot_values.clone(), offset, size });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn maybe_purge_tombstones(&mut self) {
+        if !self.keep_tombstones && self.tombstones >= PURGE_MIN_TOMBSTONES && self.tombstones > self.live_keys {
+            self.purge_tombstones();
+        }
+    }
+
+    fn needs_compaction(&self) -> bool {
+        match self.compaction_ratio {
+            Some(ratio) => self.log_path.is_some()
+                && self.dead_records >= COMPACTION_MIN_DEAD_RECORDS
+                && self.dead_records as f64 > self.live_keys as f64 * ratio,
+            None => false
+        }
+    }
+
+    fn maybe_compact(&mut self) {
+        if self.needs_compaction() {
+            if let Err(e) = self.compact() {
+                warn!("automatic log compaction failed, keep using the old log");
+                info!("detailed error info: {}", e);
+            }
+        }
+    }
+}
+
+impl PendingSnapshot {
+    /// Writes the snapshot file, which takes a while but does not need the `KVStorage`
+    pub fn write(&self) -> Result<(), Box<dyn Error>> {
+        write_slots(&self.path, &self.content)
+    }
+}
+
+/// Writes the values of `content` into a snapshot file at `path`
+fn write_slots(path: &Path, content: &SlotMap) -> Result<(), Box<dyn Error>> {
+    let count = content.values().filter(|slot| slot.is_value()).count() as u64;
+    let entries = content.iter()
+        .filter_map(|(key, slot)| slot.read_uncached().map(|v| v.map(|value| (key, value, slot.expiry()))).transpose());
+    snapshot::write_snapshot_entries(path, count, entries)
+}
+
+impl Slot {
+    fn hot(value: Arc<Value>) -> Self {
+        Slot::Hot { value, location: None, seq: 0, version: LOADED_VERSION, expiry: None }
+    }
+
+    fn cold(location: ValueLocation) -> Self {
+        Slot::Cold { location, version: LOADED_VERSION, expiry: None }
+    }
+
+    fn with_expiry(mut self, expiry: Option<u64>) -> Self {
+        self.set_expiry(expiry);
+        self
+    }
+
+    /// Version of the value held, `NO_VALUE_VERSION` if there is none or it has expired
+    fn version(&self) -> u64 {
+        match self {
+            slot if !slot.is_live() => NO_VALUE_VERSION,
+            Slot::Hot { version, .. } => *version,
+            Slot::Cold { version, .. } => *version,
+            Slot::Tombstone => NO_VALUE_VERSION
+        }
+    }
+
+    /// Whether a value is held, expired or not
+    fn is_value(&self) -> bool {
+        !matches!(self, Slot::Tombstone)
+    }
+
+    /// Whether a value that has not expired is held
+    fn is_live(&self) -> bool {
+        self.is_value() && !has_expired(self.expiry())
+    }
+
+    fn expiry(&self) -> Option<u64> {
+        match self {
+            Slot::Tombstone => None,
+            Slot::Hot { expiry, .. } => *expiry,
+            Slot::Cold { expiry, .. } => *expiry
+        }
+    }
+
+    fn set_expiry(&mut self, new_expiry: Option<u64>) {
+        match self {
+            Slot::Tombstone => (),
+            Slot::Hot { expiry, .. } => *expiry = new_expiry,
+            Slot::Cold { expiry, .. } => *expiry = new_expiry
+        }
+    }
+
+    fn value_size(&self) -> usize {
+        match self {
+            Slot::Tombstone => 0,
+            Slot::Hot { value, .. } => value.data.len(),
+            Slot::Cold { location, .. } => location.size as usize
+        }
+    }
+
+    fn location(&self) -> Option<&ValueLocation> {
+        match self {
+            Slot::Tombstone => None,
+            Slot::Hot { location, .. } => location.as_ref(),
+            Slot::Cold { location, .. } => Some(location)
+        }
+    }
+
+    fn set_location(&mut self, new_location: ValueLocation) {
+        match self {
+            Slot::Tombstone => (),
+            Slot::Hot { location, .. } => *location = Some(new_location),
+            Slot::Cold { location, .. } => *location = new_location
+        }
+    }
+
+    /// The value held, read back from disk if it has been evicted, bypassing the cache so that
+    /// going through every value does not wipe it out
+    fn read_uncached(&self) -> Result<Option<Arc<Value>>, Box<dyn Error>> {
+        match self {
+            Slot::Tombstone => Ok(None),
+            Slot::Hot { value, .. } => Ok(Some(value.clone())),
+            Slot::Cold { location, .. } => Ok(Some(Arc::new(location.read()?)))
+        }
+    }
+}
+
+/// Syncs the directory containing `path`, which makes a rename in that directory durable. This is
+/// not supported on every platform, so errors are ignored
+fn sync_parent_dir(path: &Path) {
+    if let Ok(dir) = File::open(parent_dir(path)) {
+        let _ = dir.sync_all();
+    }
+}
+
+/// Directory containing `path`, which is `.` for a bare file name
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new(".")
     }
 }
 
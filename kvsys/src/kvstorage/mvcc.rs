@@ -0,0 +1,214 @@
+//! Multi-version concurrency control, consistent reads while writes go on
+//!
+//! Every write gets a new version, its sequence number (see `KVStorage::version`). A
+//! `ReadSnapshot` taken at some version sees the content as it was right then, whatever is written
+//! afterwards: storages keep what keys held before each write in a `VersionHistory`, for as long as
+//! some open snapshot may need it. Snapshots must be released once done with, otherwise old
+//! versions pile up in memory.
+//!
+//! ```no_run
+//!     use kvsys::kvstorage::{KVStorage, Key, Value};
+//!     // ...
+//!     let mut kv = KVStorage::open("data.kv").unwrap();
+//!     let snapshot = kv.create_read_snapshot();
+//!     kv.put(&Key::from_bytes(b"answer"), &Value::from_bytes(b"42")).unwrap();
+//!     // the snapshot still sees what "answer" held before the put
+//!     let old = kv.try_get_at(&Key::from_bytes(b"answer"), Some(snapshot)).unwrap();
+//!     kv.release_read_snapshot(snapshot).unwrap();
+//!     // ...
+//! ```
+
+use crate::kvstorage::{Key, StorageError};
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::ops::Bound;
+
+/// A consistent view for reads, which stays open until released. Only meaningful to the storage
+/// (or engine) it has been created by
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ReadSnapshot {
+    id: u64
+}
+
+impl ReadSnapshot {
+    pub(crate) fn new(id: u64) -> Self {
+        ReadSnapshot { id }
+    }
+
+    /// Identifies the snapshot among the ones open on the same storage, snapshots created at the
+    /// same time may share their id
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+/// Versions of keys overwritten while read snapshots are open, `T` being what a key may hold
+/// (which must be able to tell a key holds nothing, e.g. a tombstone)
+pub(crate) struct VersionHistory<T> {
+    // versions open snapshots have been taken at, with the count of snapshots at each of them
+    snapshots: BTreeMap<u64, usize>,
+    // what keys held before writes, together with the versions of these writes, oldest first
+    old: BTreeMap<Key, Vec<(u64, T)>>
+}
+
+impl<T: Clone> VersionHistory<T> {
+    pub fn new() -> Self {
+        VersionHistory { snapshots: BTreeMap::new(), old: BTreeMap::new() }
+    }
+
+    /// Opens a snapshot at `version`, which should be the version of the last write
+    pub fn open(&mut self, version: u64) -> ReadSnapshot {
+        *self.snapshots.entry(version).or_insert(0) += 1;
+        ReadSnapshot::new(version)
+    }
+
+    /// Releases `snapshot`, and drops the versions no snapshot can see any more
+    pub fn release(&mut self, snapshot: ReadSnapshot) -> Result<(), Box<dyn Error>> {
+        match self.snapshots.get_mut(&snapshot.id) {
+            Some(count) if *count > 1 => *count -= 1,
+            Some(_) => {
+                self.snapshots.remove(&snapshot.id);
+                self.collect_garbage();
+            },
+            None => return Err(Box::new(StorageError::new("unknown read snapshot")))
+        }
+        Ok(())
+    }
+
+    /// The version `snapshot` has been taken at, or `Err` if it is not open
+    pub fn version_of(&self, snapshot: ReadSnapshot) -> Result<u64, Box<dyn Error>> {
+        if self.snapshots.contains_key(&snapshot.id) {
+            Ok(snapshot.id)
+        } else {
+            Err(Box::new(StorageError::new("unknown read snapshot")))
+        }
+    }
+
+    /// Count of snapshots open
+    pub fn snapshots(&self) -> usize {
+        self.snapshots.values().sum()
+    }
+
+    /// Count of old versions kept
+    pub fn len(&self) -> usize {
+        self.old.values().map(Vec::len).sum()
+    }
+
+    /// Records that `key` held `old` right before the write of `version`. `written` is the
+    /// version `old` was written at, if known, so that it is only kept if some snapshot can see it
+    pub fn record(&mut self, key: &Key, old: T, written: Option<u64>, version: u64) {
+        let newest = match self.snapshots.keys().next_back() {
+            Some(&newest) => newest,
+            None => return
+        };
+        // a key whose history is known was last written by the last write recorded
+        let written = written
+            .or_else(|| self.old.get(key).and_then(|versions| versions.last()).map(|(version, _)| *version))
+            .unwrap_or(0);
+        if written <= newest {
+            self.old.entry(key.clone()).or_default().push((version, old));
+        }
+    }
+
+    /// What `key` held at `version`, or `None` if it still holds the same
+    pub fn find(&self, key: &Key, version: u64) -> Option<&T> {
+        self.old.get(key).and_then(|versions| find_in(versions, version))
+    }
+
+    /// Merges `current`, the keys within `range` together with what they hold now in key order,
+    /// with the history of the keys within `range`, giving what each key held at `version`
+    pub fn view<'a, I>(&'a self, range: (Bound<&Key>, Bound<&Key>), version: u64, current: I) -> Vec<(&'a Key, T)>
+        where I: Iterator<Item=(&'a Key, T)> {
+        let mut current = current.peekable();
+        let mut old = self.old.range::<Key, _>(range).peekable();
+        let mut ret = Vec::new();
+        loop {
+            let next_current = current.peek().map(|(key, _)| *key);
+            let next_old = old.peek().map(|(key, _)| *key);
+            let key = match (next_current, next_old) {
+                (Some(key1), Some(key2)) => key1.min(key2),
+                (Some(key), None) | (None, Some(key)) => key,
+                (None, None) => return ret
+            };
+            let held = if next_current == Some(key) { current.next().map(|(_, held)| held) } else { None };
+            let versions = if next_old == Some(key) { old.next().map(|(_, versions)| versions) } else { None };
+            match versions.and_then(|versions| find_in(versions, version)) {
+                Some(old) => ret.push((key, old.clone())),
+                None => ret.extend(held.map(|held| (key, held)))
+            }
+        }
+    }
+
+    /// Drops versions that no open snapshot can see, the versions of a key between two writes
+    /// being seen by snapshots taken in between
+    fn collect_garbage(&mut self) {
+        if self.snapshots.is_empty() {
+            self.old.clear();
+            return;
+        }
+        let snapshots = &self.snapshots;
+        self.old.retain(|_, versions| {
+            let mut since = 0;
+            versions.retain(|(version, _)| {
+                let seen = snapshots.range(since..*version).next().is_some();
+                since = *version;
+                seen
+            });
+            !versions.is_empty()
+        });
+    }
+}
+
+/// What `versions` (see `VersionHistory::old`) tells a key held at `version`, which is what the
+/// first write after `version` has overwritten
+fn find_in<T>(versions: &[(u64, T)], version: u64) -> Option<&T> {
+    versions.iter().find(|(written, _)| *written > version).map(|(_, old)| old)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::kvstorage::mvcc::VersionHistory;
+    use crate::util::gen_key_n;
+    use std::ops::Bound::{Included, Excluded};
+
+    #[test]
+    fn test_version_history() {
+        let mut history = VersionHistory::new();
+        let (key0, key1, key2) = (gen_key_n(0), gen_key_n(1), gen_key_n(2));
+        // nothing is kept without snapshots
+        history.record(&key0, Some("a"), Some(1), 2);
+        assert_eq!(history.len(), 0);
+
+        let snapshot1 = history.open(2);
+        history.record(&key0, Some("b"), Some(2), 3);
+        history.record(&key0, Some("c"), Some(3), 4);
+        history.record(&key1, None, None, 5);
+        let snapshot2 = history.open(5);
+        let snapshot3 = history.open(5);
+        history.record(&key0, Some("d"), Some(4), 6);
+        assert_eq!(history.find(&key0, 2), Some(&Some("b")));
+        assert_eq!(history.find(&key0, 5), Some(&Some("d")));
+        assert_eq!(history.find(&key1, 2), Some(&None));
+        assert_eq!(history.find(&key1, 5), None);
+        assert_eq!(history.find(&key2, 2), None);
+
+        let current = vec![(&key0, Some("e")), (&key1, Some("f")), (&key2, Some("g"))];
+        let view = history.view((Included(&key0), Excluded(&key2)), 2, current.clone().into_iter().take(2));
+        assert_eq!(view, vec![(&key0, Some("b")), (&key1, None)]);
+        let view = history.view((Included(&key1), Excluded(&key2)), 5, current.into_iter().skip(1).take(1));
+        assert_eq!(view, vec![(&key1, Some("f"))]);
+
+        // "c" is only seen by the first snapshot, "d" by the others
+        assert_eq!(history.version_of(snapshot1).unwrap(), 2);
+        history.release(snapshot1).unwrap();
+        assert!(history.release(snapshot1).is_err());
+        assert!(history.version_of(snapshot1).is_err());
+        assert_eq!(history.len(), 1);
+        assert_eq!(history.find(&key0, 5), Some(&Some("d")));
+        history.release(snapshot2).unwrap();
+        assert_eq!(history.len(), 1);
+        history.release(snapshot3).unwrap();
+        assert_eq!((history.len(), history.snapshots()), (0, 0));
+    }
+}
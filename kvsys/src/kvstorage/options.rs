@@ -0,0 +1,172 @@
+//! Options for opening a `KVStorage` with `KVStorage::open_with_options`
+
+use crate::kvstorage::{Key, Value, StorageError, DEFAULT_COMPACTION_RATIO, KEY_SIZE, VALUE_SIZE};
+use crate::kvstorage::batch::{WriteBatch, BatchOp};
+
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Default maximum key size of `DataLayout::Variable`
+pub const DEFAULT_MAX_KEY_SIZE: usize = 4096;
+/// Default maximum value size of `DataLayout::Variable`
+pub const DEFAULT_MAX_VALUE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Default interval of `SyncPolicy::Every`
+pub const DEFAULT_SYNC_INTERVAL: Duration = Duration::from_millis(100);
+
+/// When the disk log gets synced to the disk, which decides what may be lost on power failure.
+///
+/// Whatever the policy, a write is visible to readers as soon as it is queued for logging, before
+/// its group commit is written, let alone synced (see `KVStorage::put_deferred`): a value read may
+/// still be lost on a crash (or on a failing log) together with the write that put it
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Syncs after every write, nothing acknowledged is ever lost
+    Always,
+    /// Syncs at most once per interval, writes acknowledged within the last interval may be lost
+    Every(Duration),
+    /// Leaves syncing to the operating system
+    Never
+}
+
+impl Display for SyncPolicy {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match self {
+            SyncPolicy::Always => write!(f, "always"),
+            SyncPolicy::Every(interval) => write!(f, "{}ms", interval.as_millis()),
+            SyncPolicy::Never => write!(f, "never")
+        }
+    }
+}
+
+impl FromStr for SyncPolicy {
+    type Err = String;
+
+    /// Parses `always`, `never`, or an interval in milliseconds like `100ms` (or just `100`)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(SyncPolicy::Always),
+            "never" => Ok(SyncPolicy::Never),
+            _ => {
+                let millis = s.trim_end_matches("ms").parse::<u64>()
+                    .map_err(|_| format!("'{}' is not a valid sync policy", s))?;
+                Ok(SyncPolicy::Every(Duration::from_millis(millis)))
+            }
+        }
+    }
+}
+
+/// What to do when the disk log ends with an incomplete record, which happens when the writer dies
+/// in the middle of writing it
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RecoveryMode {
+    /// Truncates the log back to the last complete record, with a warning logged
+    TruncateTornTail,
+    /// Refuses to load the log, reporting a `DiskLogError::TornTail` error
+    Strict
+}
+
+/// Sizes of keys and values accepted by a `KVStorage`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DataLayout {
+    /// Keys are exactly `KEY_SIZE` bytes, values are exactly `VALUE_SIZE` bytes
+    Fixed,
+    /// Keys and values may have any length up to the given maximums
+    Variable { max_key_size: usize, max_value_size: usize }
+}
+
+impl DataLayout {
+    /// `DataLayout::Variable` with default maximums
+    pub fn variable() -> Self {
+        DataLayout::Variable { max_key_size: DEFAULT_MAX_KEY_SIZE, max_value_size: DEFAULT_MAX_VALUE_SIZE }
+    }
+
+    /// Checks whether `key` is acceptable under this layout
+    pub fn check_key(&self, key: &Key) -> Result<(), StorageError> {
+        match *self {
+            DataLayout::Fixed if key.data.len() != KEY_SIZE =>
+                Err(StorageError::new(&format!("key must be {} bytes long", KEY_SIZE))),
+            DataLayout::Variable { max_key_size, .. } if key.data.len() > max_key_size =>
+                Err(StorageError::new(&format!("key must be at most {} bytes long", max_key_size))),
+            _ => Ok(())
+        }
+    }
+
+    /// Checks whether `value` is acceptable under this layout
+    pub fn check_value(&self, value: &Value) -> Result<(), StorageError> {
+        match *self {
+            DataLayout::Fixed if value.data.len() != VALUE_SIZE =>
+                Err(StorageError::new(&format!("value must be {} bytes long", VALUE_SIZE))),
+            DataLayout::Variable { max_value_size, .. } if value.data.len() > max_value_size =>
+                Err(StorageError::new(&format!("value must be at most {} bytes long", max_value_size))),
+            _ => Ok(())
+        }
+    }
+
+    /// Checks whether every key and value written by `batch` is acceptable under this layout
+    pub fn check_batch(&self, batch: &WriteBatch) -> Result<(), StorageError> {
+        for op in batch.ops() {
+            self.check_key(op.key())?;
+            if let BatchOp::Put(_, value) = op {
+                self.check_value(value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Options used when opening a `KVStorage`, see its fields for further information
+#[derive(Clone, Debug)]
+pub struct StorageOptions {
+    /// how to handle an incomplete trailing record in the disk log
+    pub recovery: RecoveryMode,
+    /// dead records / live keys ratio that triggers log compaction, `None` for never
+    pub compaction_ratio: Option<f64>,
+    /// when the disk log gets synced to the disk
+    pub sync_policy: SyncPolicy,
+    /// sizes of keys and values accepted
+    pub layout: DataLayout,
+    /// keeps deleted keys as tombstones (in memory and through compaction) instead of purging them,
+    /// for a storage whose content shadows older data kept elsewhere, see `lsm`
+    pub keep_tombstones: bool,
+    /// bytes of values to hold in memory, `None` for all of them. Older values beyond the budget
+    /// are evicted and read back from the log (or snapshot) when needed, through a cache taking a
+    /// quarter of the budget
+    pub memory_budget: Option<usize>
+}
+
+impl StorageOptions {
+    /// Creates a `StorageOptions` using default value
+    pub fn from_default() -> Self {
+        StorageOptions {
+            recovery: RecoveryMode::TruncateTornTail,
+            compaction_ratio: Some(DEFAULT_COMPACTION_RATIO),
+            sync_policy: SyncPolicy::Every(DEFAULT_SYNC_INTERVAL),
+            layout: DataLayout::Fixed,
+            keep_tombstones: false,
+            memory_budget: None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::kvstorage::options::SyncPolicy;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_sync_policy() {
+        assert_eq!("always".parse::<SyncPolicy>().unwrap(), SyncPolicy::Always);
+        assert_eq!("never".parse::<SyncPolicy>().unwrap(), SyncPolicy::Never);
+        assert_eq!("250ms".parse::<SyncPolicy>().unwrap(), SyncPolicy::Every(Duration::from_millis(250)));
+        assert_eq!("40".parse::<SyncPolicy>().unwrap(), SyncPolicy::Every(Duration::from_millis(40)));
+        assert!("sometimes".parse::<SyncPolicy>().is_err());
+        assert!("-1ms".parse::<SyncPolicy>().is_err());
+
+        for policy in [SyncPolicy::Always, SyncPolicy::Never, SyncPolicy::Every(Duration::from_millis(7))].iter() {
+            assert_eq!(policy.to_string().parse::<SyncPolicy>().unwrap(), *policy);
+        }
+    }
+}
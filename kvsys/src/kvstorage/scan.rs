@@ -0,0 +1,183 @@
+//! Options of range scans: direction, bounds, row limits and pagination
+//!
+//! A plain scan returns every pair within [`key1`, `key2`) in ascending key order. `ScanOptions`
+//! may reverse the order, include or exclude either bound, and limit the rows returned. A scan cut
+//! short by its limit gives a continuation token (the last key returned), which resumes the scan
+//! right after it when given back in the options of the next scan of the same range.
+//!
+//! ```no_run
+//!     use kvsys::kvstorage::{KVStorage, Key};
+//!     use kvsys::kvstorage::scan::ScanOptions;
+//!     // ...
+//!     let kv = KVStorage::open("data.kv").unwrap();
+//!     let (key1, key2) = (Key::from_bytes(b"a"), Key::from_bytes(b"z"));
+//!     let mut options = ScanOptions::from_default();
+//!     options.descending = true;
+//!     options.limit = Some(100);
+//!     loop {
+//!         let page = kv.try_scan_with(&key1, &key2, &options, None).unwrap();
+//!         // ...
+//!         match page.continuation {
+//!             Some(token) => options.continuation = Some(token),
+//!             None => break
+//!         }
+//!     }
+//! ```
+
+use crate::kvstorage::{Key, Value, Entry};
+use crate::kvstorage::engine::KVPairs;
+
+use std::error::Error;
+use std::ops::Bound;
+use std::ops::Bound::{Included, Excluded, Unbounded};
+use std::sync::Arc;
+
+/// A key together with its value, as returned by scans
+pub(crate) type Pair = (Key, Arc<Value>);
+
+/// How a scan walks through its range, see the module documentation
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScanOptions {
+    /// Returns pairs from the greatest key down, instead of from the smallest key up
+    pub descending: bool,
+    /// Maximum count of pairs returned, `None` (or `Some(0)`) for no limit
+    pub limit: Option<usize>,
+    /// Whether `key1` itself is within the range
+    pub include_start: bool,
+    /// Whether `key2` itself is within the range
+    pub include_end: bool,
+    /// The continuation token of the previous page, the scan resumes right after it
+    pub continuation: Option<Key>
+}
+
+/// Pairs returned by a scan with `ScanOptions`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScanPage {
+    /// The pairs, in the order of the scan
+    pub pairs: KVPairs,
+    /// The token to resume the scan with, if it has been cut short by its limit
+    pub continuation: Option<Key>
+}
+
+impl ScanOptions {
+    /// Options of a plain scan: ascending, within [`key1`, `key2`), without limit
+    pub fn from_default() -> Self {
+        ScanOptions { descending: false, limit: None, include_start: true, include_end: false, continuation: None }
+    }
+
+    /// Whether these are the options of a plain scan
+    pub fn is_default(&self) -> bool {
+        *self == ScanOptions::from_default()
+    }
+
+    /// The bounds of the keys a scan from `key1` to `key2` still has to go through, or `None` if
+    /// there are none
+    pub(crate) fn range<'a>(&'a self, key1: &'a Key, key2: &'a Key) -> Option<(Bound<&'a Key>, Bound<&'a Key>)> {
+        let mut start = if self.include_start { Included(key1) } else { Excluded(key1) };
+        let mut end = if self.include_end { Included(key2) } else { Excluded(key2) };
+        match &self.continuation {
+            Some(token) if !self.descending && token >= key1 => start = Excluded(token),
+            Some(token) if self.descending && token <= key2 => end = Excluded(token),
+            _ => ()
+        }
+        let empty = match (start, end) {
+            (Included(first), Included(last)) => first > last,
+            (Included(first), Excluded(last)) | (Excluded(first), Included(last)) | (Excluded(first), Excluded(last)) => {
+                first >= last
+            },
+            _ => unreachable!()
+        };
+        if empty { None } else { Some((start, end)) }
+    }
+
+    /// Collects the page out of `pairs`, the pairs within the range in the order of the scan
+    pub(crate) fn collect<I>(&self, pairs: I) -> Result<ScanPage, Box<dyn Error>>
+        where I: Iterator<Item=Result<Pair, Box<dyn Error>>> {
+        let limit = self.limit.filter(|&limit| limit > 0);
+        let mut ret = Vec::new();
+        for pair in pairs {
+            if Some(ret.len()) == limit {
+                // there is more than a page, the next one starts after the last key returned
+                let continuation = ret.last().map(|(key, _): &Pair| key.clone());
+                return Ok(ScanPage { pairs: ret, continuation });
+            }
+            ret.push(pair?);
+        }
+        Ok(ScanPage { pairs: ret, continuation: None })
+    }
+}
+
+/// The bounds of the keys starting with `prefix`: from `prefix` itself up to its successor, the
+/// smallest key greater than all of them. There is no successor if the prefix is empty or only
+/// made of 0xff bytes, all the keys from `prefix` on start with it then
+pub fn prefix_range(prefix: &[u8]) -> (Bound<Key>, Bound<Key>) {
+    let mut successor = prefix.to_vec();
+    while let Some(&last) = successor.last() {
+        if last < 0xff {
+            *successor.last_mut().unwrap() += 1;
+            return (Included(Key::from_bytes(prefix)), Excluded(Key::from_bytes(&successor)));
+        }
+        successor.pop();
+    }
+    (Included(Key::from_bytes(prefix)), Unbounded)
+}
+
+/// The pair of `entry` (see `KVStorage::entries`), or `None` if it is a tombstone
+pub(crate) fn live_pair(entry: Result<Entry, Box<dyn Error>>) -> Option<Result<Pair, Box<dyn Error>>> {
+    match entry {
+        Ok((key, Some(value))) => Some(Ok((key, value))),
+        Ok((_, None)) => None,
+        Err(e) => Some(Err(e))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::kvstorage::Key;
+    use crate::kvstorage::scan::{ScanOptions, prefix_range};
+    use crate::util::{gen_key_n, gen_value};
+    use std::ops::Bound::{Included, Excluded, Unbounded};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_scan_options() {
+        let (key1, key2, key3) = (gen_key_n(1), gen_key_n(2), gen_key_n(3));
+        let mut options = ScanOptions::from_default();
+        assert!(options.is_default());
+        assert_eq!(options.range(&key1, &key2), Some((Included(&key1), Excluded(&key2))));
+        assert_eq!(options.range(&key1, &key1), None);
+        options.include_end = true;
+        assert_eq!(options.range(&key1, &key1), Some((Included(&key1), Included(&key1))));
+        options.include_start = false;
+        assert_eq!(options.range(&key1, &key1), None);
+        assert_eq!(options.range(&key2, &key1), None);
+
+        // the continuation token moves the bound the scan starts from
+        options.continuation = Some(key2.clone());
+        assert_eq!(options.range(&key1, &key3), Some((Excluded(&key2), Included(&key3))));
+        assert_eq!(options.range(&key1, &key2), None);
+        options.descending = true;
+        assert_eq!(options.range(&key1, &key3), Some((Excluded(&key1), Excluded(&key2))));
+        options.continuation = Some(gen_key_n(0));
+        assert_eq!(options.range(&key1, &key3), None);
+
+        let pairs = (0..5).map(|i| (gen_key_n(i), Arc::new(gen_value()))).collect::<Vec<_>>();
+        options.limit = Some(2);
+        let page = options.collect(pairs.clone().into_iter().map(Ok)).unwrap();
+        assert_eq!((page.pairs, page.continuation), (pairs[..2].to_vec(), Some(gen_key_n(1))));
+        options.limit = Some(5);
+        let page = options.collect(pairs.clone().into_iter().map(Ok)).unwrap();
+        assert_eq!((page.pairs, page.continuation), (pairs.clone(), None));
+        options.limit = Some(0);
+        assert_eq!(options.collect(pairs.clone().into_iter().map(Ok)).unwrap().pairs.len(), 5);
+    }
+
+    #[test]
+    fn test_prefix_range() {
+        let key = |bytes: &[u8]| Key::from_bytes(bytes);
+        assert_eq!(prefix_range(b"ab"), (Included(key(b"ab")), Excluded(key(b"ac"))));
+        assert_eq!(prefix_range(&[1, 0xff, 0xff]), (Included(key(&[1, 0xff, 0xff])), Excluded(key(&[2]))));
+        assert_eq!(prefix_range(&[0xff, 0xff]), (Included(key(&[0xff, 0xff])), Unbounded));
+        assert_eq!(prefix_range(b""), (Included(key(b"")), Unbounded));
+    }
+}
@@ -0,0 +1,288 @@
+//! The snapshot file API
+//!
+//! A snapshot file is a sorted dump of the content of a `KVStorage` at some point in time. Together
+//! with the disk log records written after that point, it reproduces the whole storage, so the log
+//! can be cut short after a snapshot is taken and restarting does not need to replay it all.
+//!
+//! Snapshots of the log file `data.kv` are named `data.kv.snap.<generation>`, the one with the
+//! greatest generation being the latest.
+
+use crate::kvstorage::{Key, Value, KEY_SIZE, VALUE_SIZE, MemStorage, parent_dir, sync_parent_dir};
+use crate::kvstorage::crc32::Crc32;
+use crate::kvstorage::disklog::DiskLogError;
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// Snapshot file format
+//  -- 8 bytes magic (0x89 'PKVSNP' 0x0a)
+//  -- 2 bytes format version, in big endian
+//  -- 8 bytes count of entries, in big endian
+//  -- (count) entries, sorted by key
+//     -- 4 bytes key length, in big endian
+//     -- key
+//     -- 4 bytes value length, in big endian
+//     -- value
+//     -- 8 bytes expiry time in milliseconds since the UNIX epoch, in big endian, 0 for never
+//  -- 4 bytes CRC-32 of all the bytes above, in big endian
+//
+// Version 2 snapshots are the same as version 3 snapshots, but have no expiry times.
+// Version 1 snapshots have no length fields, keys are always KEY_SIZE bytes and values are always
+// VALUE_SIZE bytes. They can still be read but are never written.
+
+const SNAPSHOT_MAGIC: [u8; 8] = [0x89, b'P', b'K', b'V', b'S', b'N', b'P', 0x0a];
+const SNAPSHOT_VERSION: u16 = 3;
+const SNAPSHOT_VERSION_FIXED: u16 = 1;
+const SNAPSHOT_HEADER_SIZE: u64 = 18;
+const SNAPSHOT_INFIX: &str = ".snap.";
+const TMP_SUFFIX: &str = ".tmp";
+
+/// Path of the snapshot of generation `generation` belonging to the log file at `log_path`
+pub fn snapshot_path(log_path: &Path, generation: u64) -> PathBuf {
+    let mut ret = log_path.as_os_str().to_owned();
+    ret.push(format!("{}{}", SNAPSHOT_INFIX, generation));
+    PathBuf::from(ret)
+}
+
+/// Lists generations of all the snapshots belonging to the log file at `log_path`, in descending
+/// order
+pub fn list_snapshots(log_path: &Path) -> Result<Vec<u64>, Box<dyn Error>> {
+    let log_name = match log_path.file_name() {
+        Some(log_name) => log_name.to_string_lossy().into_owned(),
+        None => return Ok(Vec::new())
+    };
+    let log_dir = parent_dir(log_path);
+    let prefix = format!("{}{}", log_name, SNAPSHOT_INFIX);
+
+    let mut ret = Vec::new();
+    for entry in fs::read_dir(log_dir)? {
+        let file_name = entry?.file_name().to_string_lossy().into_owned();
+        if file_name.starts_with(&prefix) {
+            if let Ok(generation) = file_name[prefix.len()..].parse::<u64>() {
+                ret.push(generation);
+            }
+        }
+    }
+    ret.sort_unstable_by(|a, b| b.cmp(a));
+    Ok(ret)
+}
+
+/// Writes all live pairs of `content` into a snapshot file at `path`.
+///
+/// The snapshot is written to a temporary file and synced before being renamed to `path`, so a
+/// snapshot file either does not exist or is complete
+pub fn write_snapshot(path: &Path, content: &MemStorage) -> Result<(), Box<dyn Error>> {
+    let count = content.values().filter(|v| v.is_some()).count() as u64;
+    let entries = content.iter()
+        .filter_map(|(key, maybe_value)| maybe_value.as_ref().map(|value| Ok((key, value.clone(), None))));
+    write_snapshot_entries(path, count, entries)
+}
+
+/// Same as `write_snapshot`, with the `count` live pairs given by `entries` in key order together
+/// with their expiry times, which may fail to produce some values (e.g. values that have to be
+/// read back from disk)
+pub(crate) fn write_snapshot_entries<'a, I>(path: &Path, count: u64, entries: I) -> Result<(), Box<dyn Error>>
+    where I: Iterator<Item=Result<(&'a Key, Arc<Value>, Option<u64>), Box<dyn Error>>> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(TMP_SUFFIX);
+    let tmp_path = PathBuf::from(tmp_path);
+
+    {
+        let file = fs::File::create(&tmp_path)?;
+        let mut writer = ChecksumWriter { inner: BufWriter::new(&file), crc: Crc32::new() };
+        writer.write_all(&SNAPSHOT_MAGIC)?;
+        writer.write_all(&SNAPSHOT_VERSION.to_be_bytes())?;
+        writer.write_all(&count.to_be_bytes())?;
+        let mut written = 0;
+        for entry in entries {
+            let (key, value, expiry) = entry?;
+            writer.write_all(&(key.data.len() as u32).to_be_bytes())?;
+            writer.write_all(&key.data)?;
+            writer.write_all(&(value.data.len() as u32).to_be_bytes())?;
+            writer.write_all(&value.data)?;
+            writer.write_all(&expiry.unwrap_or(0).to_be_bytes())?;
+            written += 1;
+        }
+        if written != count {
+            return Err(Box::new(DiskLogError::new("snapshot entries do not match their count")));
+        }
+        let crc = writer.crc.finish();
+        writer.inner.write_all(&crc.to_be_bytes())?;
+        writer.inner.flush()?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)?;
+    sync_parent_dir(path);
+    Ok(())
+}
+
+/// Byte offset of the value of each entry of a snapshot file, given the sizes of the keys and
+/// values of its entries in order
+pub(crate) fn snapshot_value_offsets<I: Iterator<Item=(usize, usize)>>(sizes: I) -> impl Iterator<Item=u64> {
+    sizes.scan(SNAPSHOT_HEADER_SIZE, |offset, (key_size, value_size)| {
+        let value_offset = *offset + 4 + key_size as u64 + 4;
+        *offset = value_offset + value_size as u64 + 8;
+        Some(value_offset)
+    })
+}
+
+/// Reads the snapshot file at `path`, returns `Err` if it cannot be read or fails validation
+pub fn read_snapshot(path: &Path) -> Result<MemStorage, Box<dyn Error>> {
+    let mut ret = BTreeMap::new();
+    read_snapshot_with(path, |key, value, _, _| {
+        ret.insert(key, Some(Arc::new(value)));
+    })?;
+    Ok(ret)
+}
+
+/// Reads the snapshot file at `path`, calling `f` with the key, value, byte offset of the value
+/// and expiry time of every entry. `Err` may be returned after `f` has been called on some
+/// entries, if the file fails validation
+pub(crate) fn read_snapshot_with<F: FnMut(Key, Value, u64, Option<u64>)>(path: &Path, mut f: F) -> Result<(), Box<dyn Error>> {
+    let file = fs::File::open(path)?;
+    let mut reader = ChecksumReader { inner: BufReader::new(file), crc: Crc32::new() };
+
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if magic != SNAPSHOT_MAGIC {
+        return Err(Box::new(DiskLogError::corrupted(0, "incorrect snapshot magic")));
+    }
+    let mut version = [0u8; 2];
+    reader.read_exact(&mut version)?;
+    let version = u16::from_be_bytes(version);
+    if !(SNAPSHOT_VERSION_FIXED..=SNAPSHOT_VERSION).contains(&version) {
+        return Err(Box::new(DiskLogError::new("unsupported snapshot version")));
+    }
+    let mut count = [0u8; 8];
+    reader.read_exact(&mut count)?;
+
+    let mut offset = SNAPSHOT_HEADER_SIZE;
+    for _ in 0..u64::from_be_bytes(count) {
+        let (key, value) = if version == SNAPSHOT_VERSION_FIXED {
+            (read_field(&mut reader, KEY_SIZE)?, read_field(&mut reader, VALUE_SIZE)?)
+        } else {
+            let key_size = read_length(&mut reader)?;
+            let key = read_field(&mut reader, key_size)?;
+            let value_size = read_length(&mut reader)?;
+            (key, read_field(&mut reader, value_size)?)
+        };
+        let lengths_size = if version == SNAPSHOT_VERSION_FIXED { 0 } else { 8 };
+        let value_offset = offset + lengths_size + key.len() as u64;
+        offset = value_offset + value.len() as u64;
+        let expiry = if version == SNAPSHOT_VERSION {
+            offset += 8;
+            let mut expiry = [0u8; 8];
+            reader.read_exact(&mut expiry)?;
+            Some(u64::from_be_bytes(expiry)).filter(|&expiry| expiry != 0)
+        } else {
+            None
+        };
+        f(Key::from_bytes(&key), Value::from_bytes(&value), value_offset, expiry);
+    }
+
+    let expected = reader.crc.finish();
+    let mut crc = [0u8; 4];
+    reader.inner.read_exact(&mut crc)?;
+    if u32::from_be_bytes(crc) != expected {
+        return Err(Box::new(DiskLogError::corrupted(0, "snapshot checksum mismatch")));
+    }
+    Ok(())
+}
+
+fn read_length<R: Read>(reader: &mut R) -> Result<usize, Box<dyn Error>> {
+    let mut size = [0u8; 4];
+    reader.read_exact(&mut size)?;
+    Ok(u32::from_be_bytes(size) as usize)
+}
+
+/// Reads `size` bytes, without trusting `size` for allocating up front
+fn read_field<R: Read>(reader: &mut R, size: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut ret = Vec::new();
+    reader.take(size as u64).read_to_end(&mut ret)?;
+    if ret.len() < size {
+        return Err(Box::new(DiskLogError::new("snapshot ends unexpectedly")));
+    }
+    Ok(ret)
+}
+
+struct ChecksumWriter<W: Write> {
+    inner: W,
+    crc: Crc32
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.crc.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct ChecksumReader<R: Read> {
+    inner: R,
+    crc: Crc32
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.crc.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::kvstorage::{Key, Value};
+    use crate::kvstorage::snapshot::{write_snapshot, read_snapshot, list_snapshots, snapshot_path};
+    use crate::util::{gen_key_n, gen_value};
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_snapshot_rw() {
+        let dir = tempfile::tempdir().unwrap();
+        let log_path = dir.path().join("snap.kv");
+        let mut content = BTreeMap::new();
+        for i in 0..100 {
+            content.insert(gen_key_n(i), if i % 10 == 0 { None } else { Some(Arc::new(gen_value())) });
+        }
+
+        write_snapshot(&snapshot_path(&log_path, 3), &content).unwrap();
+        write_snapshot(&snapshot_path(&log_path, 12), &content).unwrap();
+        assert_eq!(list_snapshots(&log_path).unwrap(), vec![12, 3]);
+
+        let loaded = read_snapshot(&snapshot_path(&log_path, 12)).unwrap();
+        assert_eq!(loaded.len(), 90);
+        for (key, value) in loaded.iter() {
+            assert_eq!(value.as_ref().unwrap(), content[key].as_ref().unwrap());
+        }
+
+        let mut raw = fs::read(snapshot_path(&log_path, 3)).unwrap();
+        raw[100] ^= 1;
+        fs::write(snapshot_path(&log_path, 3), &raw).unwrap();
+        assert!(read_snapshot(&snapshot_path(&log_path, 3)).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_variable_length() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = snapshot_path(&dir.path().join("snap.kv"), 1);
+        let mut content = BTreeMap::new();
+        content.insert(Key::from_bytes(b""), Some(Arc::new(Value::from_bytes(b"empty key"))));
+        content.insert(Key::from_bytes(b"a"), Some(Arc::new(Value::from_bytes(b""))));
+        content.insert(Key::from_bytes(b"a longer key"), Some(Arc::new(Value::from_bytes(&[7u8; 100000]))));
+
+        write_snapshot(&path, &content).unwrap();
+        assert_eq!(read_snapshot(&path).unwrap(), content);
+    }
+}
@@ -0,0 +1,244 @@
+//! Optimistic transactions on top of a `StorageEngine`
+//!
+//! A `Transaction` reads through the engine, remembering the version of every key it reads (see
+//! `KVStorage::version`), and buffers its writes. Committing applies the writes atomically, but
+//! only if none of the keys read has changed since; otherwise the commit fails with a
+//! `ConflictError` and the transaction may be retried from the start.
+//!
+//! ```no_run
+//!     use kvsys::kvstorage::{KVStorage, Key, Value};
+//!     use kvsys::kvstorage::engine::LogEngine;
+//!     use kvsys::kvstorage::transaction::{Transaction, ConflictError};
+//!     // ...
+//!     let engine = LogEngine::new(KVStorage::open("data.kv").unwrap());
+//!     let counter = Key::from_bytes(b"counter");
+//!     loop {
+//!         let mut txn = Transaction::new();
+//!         let count = txn.get(&engine, &counter).unwrap().map_or(0, |value| value.data[0]);
+//!         txn.put(&counter, &Value::from_bytes(&[count + 1]));
+//!         match txn.commit(&engine) {
+//!             Err(e) if e.is::<ConflictError>() => continue,
+//!             result => break result.unwrap()
+//!         }
+//!     }
+//!     // ...
+//! ```
+
+use crate::kvstorage::{Key, Value, WriteBatch, BatchOp, SwapResult};
+use crate::kvstorage::engine::StorageEngine;
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+/// The error of a commit refused because a key read by the transaction has changed since
+#[derive(Debug)]
+pub struct ConflictError {
+    key: Key
+}
+
+impl ConflictError {
+    pub fn new(key: &Key) -> Self {
+        ConflictError { key: key.clone() }
+    }
+
+    /// The key found changed
+    pub fn key(&self) -> &Key {
+        &self.key
+    }
+}
+
+impl Display for ConflictError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(f, "transaction conflict: {} has changed since it was read", self.key)
+    }
+}
+
+impl Error for ConflictError {
+}
+
+/// A read-modify-write transaction, see the module documentation
+#[derive(Default)]
+pub struct Transaction {
+    read_set: BTreeMap<Key, u64>,
+    writes: BTreeMap<Key, Option<Arc<Value>>>,
+    // a key read twice has changed in between, the commit cannot succeed
+    conflict: Option<Key>
+}
+
+impl Transaction {
+    /// Begins an empty transaction
+    pub fn new() -> Self {
+        Transaction { read_set: BTreeMap::new(), writes: BTreeMap::new(), conflict: None }
+    }
+
+    /// Gets the value of `key`, as written by this transaction if it has written it, or as read
+    /// from `engine` otherwise
+    pub fn get(&mut self, engine: &dyn StorageEngine, key: &Key) -> Result<Option<Arc<Value>>, Box<dyn Error>> {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        let (value, version) = engine.get_versioned(key)?;
+        match self.read_set.get(key) {
+            Some(&read_version) if read_version != version => {
+                if self.conflict.is_none() {
+                    self.conflict = Some(key.clone());
+                }
+            },
+            Some(_) => (),
+            None => {
+                self.read_set.insert(key.clone(), version);
+            }
+        }
+        Ok(value)
+    }
+
+    /// Puts the `key` - `value` pair, which is only written into the engine on commit
+    pub fn put(&mut self, key: &Key, value: &Value) {
+        self.writes.insert(key.clone(), Some(Arc::new(value.clone())));
+    }
+
+    /// Deletes `key` on commit, returns the rows affected (1 if `key` holds a value as seen by
+    /// this transaction, 0 otherwise). The key is read to tell, so the commit fails if it changes
+    pub fn delete(&mut self, engine: &dyn StorageEngine, key: &Key) -> Result<usize, Box<dyn Error>> {
+        let rows_affected = if self.get(engine, key)?.is_some() { 1 } else { 0 };
+        self.writes.insert(key.clone(), None);
+        Ok(rows_affected)
+    }
+
+    /// Replaces the value of `key` by `new` on commit if it is `expected` as seen by this
+    /// transaction (`None` meaning no value, and deleting the key as `new`). Returns `Err` with the
+    /// current value otherwise, see `StorageEngine::compare_and_swap`. The key is read to tell, so
+    /// the commit fails if it changes
+    pub fn compare_and_swap(&mut self, engine: &dyn StorageEngine, key: &Key, expected: Option<&Value>, new: Option<&Value>)
+        -> Result<SwapResult, Box<dyn Error>> {
+        let current = self.get(engine, key)?;
+        if current.as_deref() != expected {
+            return Ok(Err(current));
+        }
+        self.writes.insert(key.clone(), new.map(|new| Arc::new(new.clone())));
+        Ok(Ok(()))
+    }
+
+    /// Adds all the puts and deletes of `batch`, which are only written into the engine on commit
+    pub fn write_batch(&mut self, batch: &WriteBatch) {
+        for op in batch.ops() {
+            match op {
+                BatchOp::Put(key, value) => self.put(key, value),
+                BatchOp::Delete(key) => {
+                    self.writes.insert(key.clone(), None);
+                }
+            }
+        }
+    }
+
+    /// Applies the writes of this transaction to `engine` atomically, provided that no key it has
+    /// read has changed since. Returns a `ConflictError` otherwise, in which case nothing is
+    /// written
+    pub fn commit(self, engine: &dyn StorageEngine) -> Result<(), Box<dyn Error>> {
+        if let Some(key) = &self.conflict {
+            return Err(Box::new(ConflictError::new(key)));
+        }
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes.into_iter() {
+            match value {
+                Some(value) => batch.put(key, Arc::try_unwrap(value).unwrap_or_else(|value| (*value).clone())),
+                None => batch.delete(key)
+            };
+        }
+        let read_set = self.read_set.into_iter().collect::<Vec<_>>();
+        engine.commit(&read_set, &batch)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::kvstorage::{KVStorage, DataLayout, WriteBatch};
+    use crate::kvstorage::engine::{StorageEngine, LogEngine, MemoryEngine};
+    use crate::kvstorage::lsm::{LsmEngine, LsmOptions};
+    use crate::kvstorage::transaction::{Transaction, ConflictError};
+    use crate::util::{gen_key_n, gen_value};
+    use std::ops::Deref;
+
+    fn check_transactions(engine: &dyn StorageEngine) {
+        let (value1, value2) = (gen_value(), gen_value());
+        engine.put(&gen_key_n(0), &value1).unwrap();
+
+        // writes are only visible to the transaction until it commits
+        let mut txn = Transaction::new();
+        assert_eq!(txn.get(engine, &gen_key_n(0)).unwrap().unwrap().deref(), &value1);
+        txn.put(&gen_key_n(1), &value2);
+        assert_eq!(txn.delete(engine, &gen_key_n(0)).unwrap(), 1);
+        assert!(txn.get(engine, &gen_key_n(0)).unwrap().is_none());
+        assert_eq!(txn.get(engine, &gen_key_n(1)).unwrap().unwrap().deref(), &value2);
+        assert!(engine.get(&gen_key_n(1)).unwrap().is_none());
+        txn.commit(engine).unwrap();
+        assert!(engine.get(&gen_key_n(0)).unwrap().is_none());
+        assert_eq!(engine.get(&gen_key_n(1)).unwrap().unwrap().deref(), &value2);
+
+        // a key read (even a missing one) and then written by someone else fails the commit
+        for key in [gen_key_n(0), gen_key_n(1)].iter() {
+            let mut txn = Transaction::new();
+            txn.get(engine, key).unwrap();
+            txn.put(&gen_key_n(2), &value1);
+            engine.put(key, &value1).unwrap();
+            let e = txn.commit(engine).err().unwrap();
+            assert_eq!(e.downcast_ref::<ConflictError>().unwrap().key(), key);
+            assert!(engine.get(&gen_key_n(2)).unwrap().is_none());
+        }
+
+        // so does a key found changed when read again, even if it changes back before the commit
+        let mut txn = Transaction::new();
+        assert!(txn.get(engine, &gen_key_n(5)).unwrap().is_none());
+        engine.put(&gen_key_n(5), &value1).unwrap();
+        assert!(txn.get(engine, &gen_key_n(5)).unwrap().is_some());
+        let mut batch = WriteBatch::new();
+        batch.delete(gen_key_n(5));
+        engine.write_batch(&batch).unwrap();
+        assert!(txn.commit(engine).unwrap_err().is::<ConflictError>());
+
+        // a compare-and-swap reads the key, and sees the writes of the transaction
+        let mut txn = Transaction::new();
+        assert_eq!(txn.compare_and_swap(engine, &gen_key_n(6), Some(&value1), Some(&value2)).unwrap(), Err(None));
+        assert!(txn.compare_and_swap(engine, &gen_key_n(6), None, Some(&value1)).unwrap().is_ok());
+        assert!(txn.compare_and_swap(engine, &gen_key_n(6), Some(&value1), Some(&value2)).unwrap().is_ok());
+        engine.put(&gen_key_n(6), &value1).unwrap();
+        assert!(txn.commit(engine).unwrap_err().is::<ConflictError>());
+        let mut txn = Transaction::new();
+        assert!(txn.compare_and_swap(engine, &gen_key_n(6), Some(&value1), None).unwrap().is_ok());
+        txn.commit(engine).unwrap();
+        assert!(engine.get(&gen_key_n(6)).unwrap().is_none());
+
+        // writes to keys that have not been read do not matter
+        let mut txn = Transaction::new();
+        txn.get(engine, &gen_key_n(1)).unwrap();
+        txn.put(&gen_key_n(3), &value2);
+        engine.put(&gen_key_n(3), &value1).unwrap();
+        txn.commit(engine).unwrap();
+        assert_eq!(engine.get(&gen_key_n(3)).unwrap().unwrap().deref(), &value2);
+    }
+
+    #[test]
+    fn test_transactions() {
+        check_transactions(&MemoryEngine::new(DataLayout::Fixed));
+        check_transactions(&LogEngine::new(KVStorage::new(tempfile::tempfile().unwrap())));
+        let dir = tempfile::tempdir().unwrap();
+        let engine = LsmEngine::open(dir.path(), LsmOptions::from_default()).unwrap();
+        check_transactions(&engine);
+
+        // versions stay unique when the memtable moves into a table
+        let mut txn = Transaction::new();
+        let value = txn.get(&engine, &gen_key_n(3)).unwrap().unwrap();
+        engine.flush().unwrap();
+        txn.put(&gen_key_n(3), &gen_value());
+        assert!(txn.commit(&engine).unwrap_err().is::<ConflictError>());
+        let mut txn = Transaction::new();
+        assert_eq!(txn.get(&engine, &gen_key_n(3)).unwrap().unwrap(), value);
+        engine.put(&gen_key_n(4), &gen_value()).unwrap();
+        engine.flush().unwrap();
+        engine.put(&gen_key_n(3), &gen_value()).unwrap();
+        assert!(txn.commit(&engine).unwrap_err().is::<ConflictError>());
+    }
+}
@@ -1,10 +1,18 @@
 #[cfg(test)]
 mod test {
-    use kvsys::kvstorage::KVStorage;
+    use kvsys::kvstorage::{KVStorage, Key, Value, StorageOptions, RecoveryMode, SyncPolicy, DataLayout, WriteBatch,
+                           COMPACTION_MIN_DEAD_RECORDS, PURGE_MIN_TOMBSTONES, KEY_SIZE, VALUE_SIZE, NO_VALUE_VERSION,
+                           LOADED_VERSION, Ttl};
+    use kvsys::kvstorage::transaction::ConflictError;
+    use kvsys::kvstorage::disklog::DiskLogError;
+    use kvsys::kvstorage::engine::StorageEngine;
+    use kvsys::kvstorage::lsm::{LsmEngine, LsmOptions};
+    use kvsys::kvstorage::snapshot::{list_snapshots, snapshot_path};
     use std::{fs, thread};
     use kvsys::util::{gen_key, gen_key_n, gen_value};
     use std::ops::Deref;
-    use std::sync::{Arc, RwLock};
+    use std::sync::{Arc, Barrier, RwLock};
+    use std::time::Duration;
 
     fn from_existing_file(path: &str) -> KVStorage {
         let content;
@@ -14,8 +22,8 @@ mod test {
         }
 
         {
-            let f = fs::OpenOptions::new().write(true).append(true).open(path).unwrap();
-            KVStorage::with_content(content, f)
+            let f = fs::OpenOptions::new().read(true).append(true).open(path).unwrap();
+            KVStorage::with_content(content, f).unwrap()
         }
     }
 
@@ -32,65 +40,68 @@ mod test {
 
     #[test]
     fn test_persist_storage() {
-        let _ = fs::remove_file("test1.kv");
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test1.kv");
         let (key, value) = (gen_key(), gen_value());
 
         {
-            let f = fs::File::create("test1.kv").unwrap();
+            let f = fs::File::create(&path).unwrap();
             let mut kv = KVStorage::new(f);
             kv.put(&key, &value).unwrap();
         }
 
         {
-            let kv = from_existing_file("test1.kv");
+            let kv = from_existing_file(path.to_str().unwrap());
             assert_eq!(kv.get(&key).unwrap().deref(), &value);
         }
     }
 
     #[test]
     fn test_rw_some() {
-        let _ = fs::remove_file("test2.kv");
-        let f = fs::File::create("test2.kv").unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test2.kv");
+        let f = fs::File::create(&path).unwrap();
         let mut kv = KVStorage::new(f);
 
         let mut keys = Vec::new();
         let mut values = Vec::new();
         for i in 0..255 {
             let (key, value) = (gen_key_n(i), gen_value());
-            keys.push(key);
-            values.push(value);
+            keys.push(key.clone());
+            values.push(value.clone());
             kv.put(&key, &value).unwrap();
         }
 
         for i in 0..255 {
-            let key = keys[i];
-            let value = values[i];
+            let key = keys[i].clone();
+            let value = values[i].clone();
             assert_eq!(kv.get(&key).unwrap().deref(), &value);
         }
     }
 
     #[test]
     fn test_persist_some() {
-        let _ = fs::remove_file("test3.kv");
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test3.kv");
 
         let mut keys = Vec::new();
         let mut values = Vec::new();
         {
-            let f = fs::File::create("test3.kv").unwrap();
+            let f = fs::File::create(&path).unwrap();
             let mut kv = KVStorage::new(f);
             for i in 0..255 {
                 let (key, value) = (gen_key_n(i), gen_value());
-                keys.push(key);
-                values.push(value);
+                keys.push(key.clone());
+                values.push(value.clone());
                 kv.put(&key, &value).unwrap();
             }
         }
 
         {
-            let kv = from_existing_file("test3.kv");
+            let kv = from_existing_file(path.to_str().unwrap());
             for i in 0..255 {
-                let key = keys[i];
-                let value = values[i];
+                let key = keys[i].clone();
+                let value = values[i].clone();
                 assert_eq!(kv.get(&key).unwrap().deref(), &value);
             }
         }
@@ -98,22 +109,23 @@ mod test {
 
     #[test]
     fn test_persist_with_delete() {
-        let _ = fs::remove_file("test4.kv");
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test4.kv");
 
         let mut keys = Vec::new();
         let mut keys_to_delete = Vec::new();
         let mut values = Vec::new();
         {
-            let f = fs::File::create("test4.kv").unwrap();
+            let f = fs::File::create(&path).unwrap();
             let mut kv = KVStorage::new(f);
             for i in 0..255 {
                 let (key, value) = (gen_key_n(i), gen_value());
-                keys.push(key);
+                keys.push(key.clone());
                 if rand::random() {
-                    values.push(Some(value));
+                    values.push(Some(value.clone()));
                 } else {
                     values.push(None);
-                    keys_to_delete.push(key)
+                    keys_to_delete.push(key.clone())
                 }
                 kv.put(&key, &value).unwrap();
             }
@@ -124,11 +136,11 @@ mod test {
         }
 
         {
-            let kv = from_existing_file("test4.kv");
+            let kv = from_existing_file(path.to_str().unwrap());
             for i in 0..255 {
-                let key = keys[i];
-                if let Some(value) = values[i] {
-                    assert_eq!(kv.get(&key).unwrap().deref(), &value);
+                let key = keys[i].clone();
+                if let Some(value) = &values[i] {
+                    assert_eq!(kv.get(&key).unwrap().deref(), value);
                 } else {
                     assert!(kv.get(&key).is_none());
                 }
@@ -136,10 +148,56 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_delete_consistent_after_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tombstone.kv");
+        let (key, value) = (gen_key(), gen_value());
+        {
+            let mut kv = KVStorage::open(&path).unwrap();
+            assert_eq!(kv.delete(&key).unwrap(), 0);
+            kv.put(&key, &value).unwrap();
+            assert_eq!(kv.delete(&key).unwrap(), 1);
+            assert_eq!(kv.delete(&key).unwrap(), 0);
+            assert_eq!(kv.tombstones(), 1);
+            assert_eq!(kv.dead_records(), 2);
+
+            kv.purge_tombstones();
+            assert_eq!(kv.tombstones(), 0);
+            assert_eq!(kv.delete(&key).unwrap(), 0);
+            assert!(kv.get(&key).is_none());
+        }
+
+        let mut kv = KVStorage::open(&path).unwrap();
+        assert_eq!(kv.dead_records(), 2);
+        assert_eq!(kv.delete(&key).unwrap(), 0);
+        assert!(kv.get(&key).is_none());
+        kv.put(&key, &value).unwrap();
+        assert_eq!(kv.delete(&key).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_auto_purge_tombstones() {
+        let mut kv = KVStorage::new(tempfile::tempfile().unwrap());
+        let count = PURGE_MIN_TOMBSTONES as u64 + 16;
+        for i in 0..count {
+            kv.put(&gen_key_n(i), &gen_value()).unwrap();
+        }
+        for i in 0..count - 16 {
+            assert_eq!(kv.delete(&gen_key_n(i)).unwrap(), 1);
+        }
+        assert_eq!(kv.tombstones(), 0);
+        assert_eq!(kv.live_keys(), 16);
+        for i in 0..count {
+            assert_eq!(kv.get(&gen_key_n(i)).is_some(), i >= count - 16);
+        }
+    }
+
     #[test]
     fn test_multi_thread_rw() {
-        let _ = fs::remove_file("test5.kv");
-        let f = fs::File::create("test5.kv").unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test5.kv");
+        let f = fs::File::create(&path).unwrap();
         let mut kv = KVStorage::new(f);
         let mut values1 = Vec::new();
         let mut values2 = Vec::new();
@@ -192,4 +250,994 @@ mod test {
             assert!(value.deref() == values2.read().unwrap().get(i as usize).unwrap());
         }
     }
+
+    #[test]
+    fn test_compact() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("compact.kv");
+
+        let mut values = Vec::new();
+        {
+            let mut kv = KVStorage::open(&path).unwrap();
+            kv.set_compaction_ratio(None);
+            for _ in 0..8 {
+                values.clear();
+                for i in 0..255 {
+                    let value = gen_value();
+                    values.push(value.clone());
+                    kv.put(&gen_key_n(i), &value).unwrap();
+                }
+            }
+            for i in 0..64 {
+                kv.delete(&gen_key_n(i)).unwrap();
+            }
+            assert_eq!(kv.live_keys(), 255 - 64);
+            assert_eq!(kv.dead_records(), 255 * 7 + 64 * 2);
+
+            let size_before = fs::metadata(&path).unwrap().len();
+            kv.compact().unwrap();
+            assert_eq!(kv.dead_records(), 0);
+            assert!(fs::metadata(&path).unwrap().len() < size_before);
+
+            // the storage must keep working on the new log
+            kv.put(&gen_key_n(0), &values[0]).unwrap();
+        }
+
+        {
+            let kv = KVStorage::open(&path).unwrap();
+            assert_eq!(kv.dead_records(), 0);
+            assert_eq!(kv.live_keys(), 255 - 63);
+            for i in 0..255 {
+                let value = kv.get(&gen_key_n(i));
+                if i > 0 && i < 64 {
+                    assert!(value.is_none());
+                } else {
+                    assert_eq!(value.unwrap().deref(), &values[i as usize]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_auto_compact() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("auto_compact.kv");
+        let (key, value) = (gen_key(), gen_value());
+
+        let mut kv = KVStorage::open(&path).unwrap();
+        for _ in 0..COMPACTION_MIN_DEAD_RECORDS {
+            kv.put(&key, &gen_value()).unwrap();
+        }
+        kv.put(&key, &value).unwrap();
+        assert!(kv.dead_records() < COMPACTION_MIN_DEAD_RECORDS);
+        drop(kv);
+
+        let kv = KVStorage::open(&path).unwrap();
+        assert_eq!(kv.get(&key).unwrap().deref(), &value);
+        assert!(kv.dead_records() < COMPACTION_MIN_DEAD_RECORDS);
+    }
+
+    #[test]
+    fn test_compact_requires_path() {
+        let f = tempfile::tempfile().unwrap();
+        let mut kv = KVStorage::new(f);
+        kv.put(&gen_key(), &gen_value()).unwrap();
+        assert!(kv.compact().is_err());
+    }
+
+    #[test]
+    fn test_detect_corruption() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("corrupt.kv");
+        {
+            let mut kv = KVStorage::open(&path).unwrap();
+            for i in 0..3 {
+                kv.put(&gen_key_n(i), &gen_value()).unwrap();
+            }
+        }
+
+        // flips a bit inside the value of the second record
+        const HEADER_SIZE: usize = 10;
+        const RECORD_SIZE: usize = 1 + 4 + KEY_SIZE + 4 + VALUE_SIZE + 4;
+        let mut content = fs::read(&path).unwrap();
+        assert_eq!(content.len(), HEADER_SIZE + RECORD_SIZE * 3);
+        content[HEADER_SIZE + RECORD_SIZE + 1 + 4 + KEY_SIZE + 4 + 42] ^= 0x10;
+        fs::write(&path, &content).unwrap();
+
+        let e = KVStorage::open(&path).err().unwrap();
+        match e.downcast_ref::<DiskLogError>() {
+            Some(DiskLogError::Corrupted { offset, .. }) => {
+                assert_eq!(*offset, (HEADER_SIZE + RECORD_SIZE) as u64)
+            },
+            _ => panic!("unexpected error: {}", e)
+        }
+    }
+
+    #[test]
+    fn test_variable_length() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("variable.kv");
+        let mut options = StorageOptions::from_default();
+        options.layout = DataLayout::Variable { max_key_size: 64, max_value_size: 1 << 20 };
+
+        let large_value = Value::from_bytes(&vec![0x5a; 1 << 20]);
+        let pairs = [
+            (Key::from_bytes(b""), Value::from_bytes(b"empty key")),
+            (Key::from_bytes(b"a"), Value::from_bytes(b"")),
+            (Key::from_bytes(b"a\x00"), Value::from_bytes(b"zero")),
+            (Key::from_bytes(b"ab"), large_value.clone()),
+            (Key::from_bytes(b"b"), Value::from_bytes(b"b"))
+        ];
+        {
+            let mut kv = KVStorage::open_with_options(&path, &options).unwrap();
+            // inserted backwards, scanned in byte order
+            for (key, value) in pairs.iter().rev() {
+                kv.put(key, value).unwrap();
+            }
+            assert!(kv.put(&Key::from_bytes(&[0u8; 65]), &Value::from_bytes(b"")).is_err());
+            assert!(kv.put(&Key::from_bytes(b"c"), &Value::from_bytes(&vec![0; (1 << 20) + 1])).is_err());
+            kv.snapshot().unwrap();
+            kv.delete(&Key::from_bytes(b"b")).unwrap();
+        }
+
+        let kv = KVStorage::open_with_options(&path, &options).unwrap();
+        let scanned = kv.scan(&Key::from_bytes(b""), &Key::from_bytes(b"b"));
+        assert_eq!(scanned.len(), 4);
+        for ((key, value), (expected_key, expected_value)) in scanned.iter().zip(pairs.iter()) {
+            assert_eq!(key, expected_key);
+            assert_eq!(value.deref(), expected_value);
+        }
+        assert!(kv.get(&Key::from_bytes(b"b")).is_none());
+        assert_eq!(kv.get(&Key::from_bytes(b"ab")).unwrap().deref(), &large_value);
+        assert!(kv.scan(&Key::from_bytes(b"b"), &Key::from_bytes(b"a")).is_empty());
+    }
+
+    #[test]
+    fn test_fixed_layout_rejects_other_sizes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut kv = KVStorage::open(dir.path().join("fixed.kv")).unwrap();
+        assert!(kv.put(&Key::from_bytes(b"short"), &gen_value()).is_err());
+        assert!(kv.put(&gen_key(), &Value::from_bytes(b"short")).is_err());
+        assert!(kv.put(&gen_key(), &gen_value()).is_ok());
+        assert_eq!(kv.live_keys(), 1);
+    }
+
+    #[test]
+    fn test_read_legacy_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("legacy.kv");
+        let (key1, value1) = (gen_key_n(1), gen_value());
+        let (key2, value2) = (gen_key_n(2), gen_value());
+
+        let mut content = Vec::new();
+        for (key, value) in [(key1.clone(), value1.clone()), (key2.clone(), value2.clone())].iter() {
+            content.push(b'P');
+            content.extend_from_slice(&key.data);
+            content.extend_from_slice(&value.data);
+        }
+        content.push(b'D');
+        content.extend_from_slice(&key2.data);
+        fs::write(&path, &content).unwrap();
+
+        {
+            let kv = KVStorage::read_log_file(fs::File::open(&path).unwrap()).unwrap();
+            assert_eq!(kv.values().filter(|v| v.is_some()).count(), 1);
+            // legacy logs cannot be appended to, they are left untouched
+            let f = fs::OpenOptions::new().read(true).append(true).open(&path).unwrap();
+            assert!(KVStorage::with_content(kv, f).is_err());
+            assert_eq!(fs::read(&path).unwrap(), content);
+        }
+
+        {
+            let mut kv = KVStorage::open(&path).unwrap();
+            assert_eq!(kv.get(&key1).unwrap().deref(), &value1);
+            assert!(kv.get(&key2).is_none());
+            kv.put(&key2, &value2).unwrap();
+        }
+        assert_ne!(fs::read(&path).unwrap()[0], b'P');
+
+        {
+            let kv = from_existing_file(path.to_str().unwrap());
+            assert_eq!(kv.get(&key1).unwrap().deref(), &value1);
+            assert_eq!(kv.get(&key2).unwrap().deref(), &value2);
+        }
+    }
+
+    #[test]
+    fn test_recover_torn_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("torn.kv");
+        let mut values = Vec::new();
+        {
+            let mut kv = KVStorage::open(&path).unwrap();
+            for i in 0..3 {
+                let value = gen_value();
+                values.push(value.clone());
+                kv.put(&gen_key_n(i), &value).unwrap();
+            }
+        }
+
+        // cuts the last record in the middle, as if the server died while writing it
+        let full_size = fs::metadata(&path).unwrap().len();
+        let record_size = (1 + 4 + KEY_SIZE + 4 + VALUE_SIZE + 4) as u64;
+        fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(full_size - 100).unwrap();
+
+        let mut strict = StorageOptions::from_default();
+        strict.recovery = RecoveryMode::Strict;
+        let e = KVStorage::open_with_options(&path, &strict).err().unwrap();
+        match e.downcast_ref::<DiskLogError>() {
+            Some(DiskLogError::TornTail { offset }) => assert_eq!(*offset, full_size - record_size),
+            _ => panic!("unexpected error: {}", e)
+        }
+        assert_eq!(fs::metadata(&path).unwrap().len(), full_size - 100);
+
+        // reading the log alone leaves the file as it is, even through a read only handle
+        let content = KVStorage::read_log_file(fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(content.len(), 2);
+        let (_, torn_tail) = KVStorage::read_log_file_with_recovery(fs::File::open(&path).unwrap(),
+                                                                     RecoveryMode::TruncateTornTail).unwrap();
+        assert_eq!(torn_tail, Some(full_size - record_size));
+        assert_eq!(fs::metadata(&path).unwrap().len(), full_size - 100);
+
+        {
+            let mut kv = KVStorage::open(&path).unwrap();
+            assert_eq!(fs::metadata(&path).unwrap().len(), full_size - record_size);
+            assert_eq!(kv.get(&gen_key_n(0)).unwrap().deref(), &values[0]);
+            assert_eq!(kv.get(&gen_key_n(1)).unwrap().deref(), &values[1]);
+            assert!(kv.get(&gen_key_n(2)).is_none());
+            kv.put(&gen_key_n(2), &values[2]).unwrap();
+        }
+
+        {
+            let kv = from_existing_file(path.to_str().unwrap());
+            for i in 0..3 {
+                assert_eq!(kv.get(&gen_key_n(i)).unwrap().deref(), &values[i as usize]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_recover_torn_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("torn_header.kv");
+        let (key, value) = (gen_key(), gen_value());
+        {
+            let mut kv = KVStorage::open(&path).unwrap();
+            kv.put(&key, &value).unwrap();
+        }
+        fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(4).unwrap();
+
+        {
+            let mut kv = KVStorage::open(&path).unwrap();
+            assert!(kv.get(&key).is_none());
+            kv.put(&key, &value).unwrap();
+        }
+        let kv = KVStorage::open(&path).unwrap();
+        assert_eq!(kv.get(&key).unwrap().deref(), &value);
+    }
+
+    #[test]
+    fn test_sync_policies() {
+        let dir = tempfile::tempdir().unwrap();
+        let policies = [SyncPolicy::Always, SyncPolicy::Every(Duration::from_millis(0)), SyncPolicy::Never];
+        for (i, &policy) in policies.iter().enumerate() {
+            let path = dir.path().join(format!("sync{}.kv", i));
+            let mut options = StorageOptions::from_default();
+            options.sync_policy = policy;
+
+            let mut values = Vec::new();
+            {
+                let mut kv = KVStorage::open_with_options(&path, &options).unwrap();
+                for i in 0..16 {
+                    let value = gen_value();
+                    values.push(value.clone());
+                    kv.put(&gen_key_n(i), &value).unwrap();
+                }
+                kv.delete(&gen_key_n(0)).unwrap();
+                kv.sync_if_due().unwrap();
+                kv.compact().unwrap();
+                kv.put(&gen_key_n(0), &values[0]).unwrap();
+            }
+
+            let kv = KVStorage::open_with_options(&path, &options).unwrap();
+            for i in 0..16 {
+                assert_eq!(kv.get(&gen_key_n(i)).unwrap().deref(), &values[i as usize]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_group_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("group_commit.kv");
+        let mut options = StorageOptions::from_default();
+        options.sync_policy = SyncPolicy::Always;
+
+        let kv = Arc::new(RwLock::new(KVStorage::open_with_options(&path, &options).unwrap()));
+        let batches = kv.read().unwrap().log_writer().committed_batches();
+        // every round, all the writers queue their logs before any of them waits, so that the
+        // first one to wait commits the whole round
+        let barrier = Arc::new(Barrier::new(8));
+        let mut writers = Vec::new();
+        for t in 0..8 {
+            let kv = kv.clone();
+            let barrier = barrier.clone();
+            writers.push(thread::spawn(move || {
+                let mut values = Vec::new();
+                for i in 0..128 {
+                    let value = gen_value();
+                    let ticket = kv.write().unwrap().put_deferred(&gen_key_n(t * 128 + i), &value).unwrap();
+                    barrier.wait();
+                    ticket.wait().unwrap();
+                    values.push(value.clone());
+                }
+                let (rows_affected, ticket) = kv.write().unwrap().delete_deferred(&gen_key_n(t * 128)).unwrap();
+                barrier.wait();
+                ticket.wait().unwrap();
+                assert_eq!(rows_affected, 1);
+                values
+            }));
+        }
+        let values = writers.into_iter().map(|w| w.join().unwrap()).collect::<Vec<_>>();
+        assert_eq!(kv.read().unwrap().log_writer().committed_batches() - batches, 129);
+        drop(kv);
+
+        let kv = KVStorage::open(&path).unwrap();
+        for t in 0..8 {
+            assert!(kv.get(&gen_key_n(t * 128)).is_none());
+            for i in 1..128 {
+                assert_eq!(kv.get(&gen_key_n(t * 128 + i)).unwrap().deref(), &values[t as usize][i as usize]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_rollback_failed_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rollback.kv");
+        let value = gen_value();
+        KVStorage::open(&path).unwrap().put(&gen_key_n(0), &value).unwrap();
+        // the log is opened read only, so that every commit fails
+        let failing = || {
+            let content = KVStorage::read_log_file(fs::File::open(&path).unwrap()).unwrap();
+            KVStorage::with_content(content, fs::File::open(&path).unwrap()).unwrap()
+        };
+
+        let mut kv = failing();
+        assert!(kv.put(&gen_key_n(0), &gen_value()).is_err());
+        assert_eq!(kv.get(&gen_key_n(0)).unwrap().deref(), &value);
+        assert!(kv.put(&gen_key_n(1), &gen_value()).is_err());
+        assert!(kv.get(&gen_key_n(1)).is_none());
+
+        let mut kv = failing();
+        let put = kv.put_deferred(&gen_key_n(0), &gen_value()).unwrap();
+        let (rows_affected, delete) = kv.delete_deferred(&gen_key_n(0)).unwrap();
+        assert_eq!(rows_affected, 1);
+        let mut batch = WriteBatch::new();
+        batch.put(gen_key_n(1), gen_value()).put(gen_key_n(2), gen_value());
+        let batch = kv.write_batch_deferred(&batch).unwrap();
+        assert_eq!(kv.live_keys(), 2);
+        assert!(batch.wait().is_err());
+        kv.rollback_failed();
+        assert!(put.wait().is_err());
+        assert!(delete.wait().is_err());
+        assert_eq!(kv.get(&gen_key_n(0)).unwrap().deref(), &value);
+        assert!(kv.get(&gen_key_n(1)).is_none());
+        assert!(kv.get(&gen_key_n(2)).is_none());
+        assert_eq!(kv.live_keys(), 1);
+        assert_eq!(kv.tombstones(), 0);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snapshot.kv");
+        let mut values = Vec::new();
+        {
+            let mut kv = KVStorage::open(&path).unwrap();
+            for i in 0..255 {
+                let value = gen_value();
+                values.push(value.clone());
+                kv.put(&gen_key_n(i), &value).unwrap();
+            }
+            kv.delete(&gen_key_n(0)).unwrap();
+            // the first snapshot does not cut the log, the second one cuts it back to the first
+            let log_size = fs::metadata(&path).unwrap().len();
+            kv.snapshot().unwrap();
+            assert_eq!(fs::metadata(&path).unwrap().len(), log_size);
+
+            let pending = kv.begin_snapshot().unwrap();
+            kv.put(&gen_key_n(0), &values[0]).unwrap();
+            kv.delete(&gen_key_n(1)).unwrap();
+            pending.write().unwrap();
+            kv.put(&gen_key_n(2), &values[3]).unwrap();
+            values[2] = values[3].clone();
+            kv.finish_snapshot(pending).unwrap();
+            assert!(fs::metadata(&path).unwrap().len() < 1024);
+
+            kv.put(&gen_key_n(3), &values[4]).unwrap();
+            values[3] = values[4].clone();
+        }
+        assert_eq!(list_snapshots(&path).unwrap(), vec![2, 1]);
+
+        {
+            let mut kv = KVStorage::open(&path).unwrap();
+            assert_eq!(kv.live_keys(), 254);
+            for i in 0..255 {
+                if i == 1 {
+                    assert!(kv.get(&gen_key_n(i)).is_none());
+                } else {
+                    assert_eq!(kv.get(&gen_key_n(i)).unwrap().deref(), &values[i as usize]);
+                }
+            }
+            kv.put(&gen_key_n(1), &values[1]).unwrap();
+            kv.snapshot().unwrap();
+            kv.put(&gen_key_n(4), &values[5]).unwrap();
+            values[4] = values[5].clone();
+            kv.snapshot().unwrap();
+            kv.put(&gen_key_n(5), &values[6]).unwrap();
+            values[5] = values[6].clone();
+        }
+        assert_eq!(list_snapshots(&path).unwrap(), vec![4, 3]);
+
+        // a damaged latest snapshot falls back to the previous one, plus the log
+        fs::write(snapshot_path(&path, 4), b"garbage").unwrap();
+        let kv = KVStorage::open(&path).unwrap();
+        for i in 0..255 {
+            assert_eq!(kv.get(&gen_key_n(i)).unwrap().deref(), &values[i as usize]);
+        }
+    }
+
+    #[test]
+    fn test_compact_after_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("compact_snapshot.kv");
+        let value = gen_value();
+        {
+            let mut kv = KVStorage::open(&path).unwrap();
+            kv.put(&gen_key_n(0), &gen_value()).unwrap();
+            kv.put(&gen_key_n(1), &value).unwrap();
+            kv.snapshot().unwrap();
+            assert_eq!(kv.delete(&gen_key_n(0)).unwrap(), 1);
+            kv.compact().unwrap();
+            assert_eq!(list_snapshots(&path).unwrap(), vec![2]);
+            kv.put(&gen_key_n(2), &value).unwrap();
+        }
+
+        let mut kv = KVStorage::open(&path).unwrap();
+        assert!(kv.get(&gen_key_n(0)).is_none());
+        assert_eq!(kv.get(&gen_key_n(1)).unwrap().deref(), &value);
+        assert_eq!(kv.get(&gen_key_n(2)).unwrap().deref(), &value);
+        // the next snapshot cuts the log where the compacted content ends
+        kv.snapshot().unwrap();
+        drop(kv);
+        let kv = KVStorage::open(&path).unwrap();
+        assert!(kv.get(&gen_key_n(0)).is_none());
+        assert_eq!(kv.live_keys(), 2);
+    }
+
+    #[test]
+    fn test_compact_during_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("compact_during_snapshot.kv");
+        let value = gen_value();
+        {
+            let mut kv = KVStorage::open(&path).unwrap();
+            kv.put(&gen_key_n(0), &gen_value()).unwrap();
+            kv.put(&gen_key_n(1), &value).unwrap();
+            let pending = kv.begin_snapshot().unwrap();
+            assert_eq!(kv.delete(&gen_key_n(0)).unwrap(), 1);
+            kv.compact().unwrap();
+            // the snapshot begun before the compaction still holds the deleted key
+            pending.write().unwrap();
+            kv.finish_snapshot(pending).unwrap();
+            assert_eq!(list_snapshots(&path).unwrap(), vec![2]);
+        }
+
+        let kv = KVStorage::open(&path).unwrap();
+        assert!(kv.get(&gen_key_n(0)).is_none());
+        assert_eq!(kv.get(&gen_key_n(1)).unwrap().deref(), &value);
+    }
+
+    #[test]
+    fn test_lsm_commit_after_flush() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = LsmEngine::open(dir.path().join("data.lsm"), LsmOptions::from_default()).unwrap();
+        engine.put(&gen_key_n(0), &gen_value()).unwrap();
+        engine.flush().unwrap();
+
+        // the key is read from a table, then overwritten and flushed into another table
+        let (_, version) = engine.get_versioned(&gen_key_n(0)).unwrap();
+        engine.put(&gen_key_n(0), &gen_value()).unwrap();
+        engine.flush().unwrap();
+        assert_ne!(engine.get_versioned(&gen_key_n(0)).unwrap().1, version);
+
+        let mut batch = WriteBatch::new();
+        batch.put(gen_key_n(0), gen_value());
+        let e = engine.commit(&[(gen_key_n(0), version)], &batch).unwrap_err();
+        assert_eq!(e.downcast_ref::<ConflictError>().unwrap().key(), &gen_key_n(0));
+
+        // merging the tables keeps the version from going back either
+        let (_, version) = engine.get_versioned(&gen_key_n(0)).unwrap();
+        engine.put(&gen_key_n(0), &gen_value()).unwrap();
+        engine.compact().unwrap();
+        assert!(engine.commit(&[(gen_key_n(0), version)], &batch).is_err());
+    }
+
+    #[test]
+    fn test_lsm_engine() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.lsm");
+        let mut options = LsmOptions::from_default();
+        options.memtable_size = 64 * 1024;
+        options.max_tables = 2;
+
+        let mut values = Vec::new();
+        {
+            let engine = LsmEngine::open(&path, options.clone()).unwrap();
+            for i in 0..1024 {
+                let value = gen_value();
+                engine.put(&gen_key_n(i), &value).unwrap();
+                values.push(Some(value));
+            }
+            engine.flush().unwrap();
+            assert!(engine.table_count() >= 1);
+
+            // deletes and overwrites in the memtable shadow the tables
+            for i in (0..1024).step_by(3) {
+                assert_eq!(engine.delete(&gen_key_n(i)).unwrap(), 1);
+                values[i as usize] = None;
+            }
+            assert_eq!(engine.delete(&gen_key_n(0)).unwrap(), 0);
+            assert_eq!(engine.delete(&gen_key_n(4096)).unwrap(), 0);
+            for i in (1..1024).step_by(5) {
+                let value = gen_value();
+                engine.put(&gen_key_n(i), &value).unwrap();
+                values[i as usize] = Some(value);
+            }
+            engine.flush().unwrap();
+            for i in 0..1024 {
+                assert_eq!(engine.get(&gen_key_n(i)).unwrap().as_deref(), values[i as usize].as_ref());
+            }
+
+            let scanned = engine.scan(&gen_key_n(100), &gen_key_n(200)).unwrap();
+            let expected = (100..200)
+                .filter_map(|i| values[i as usize].as_ref().map(|v| (gen_key_n(i), v)))
+                .collect::<Vec<_>>();
+            assert_eq!(scanned.len(), expected.len());
+            for ((key, value), (expected_key, expected_value)) in scanned.iter().zip(expected.iter()) {
+                assert!(key == expected_key && value.deref() == *expected_value);
+            }
+
+            engine.compact().unwrap();
+            assert_eq!(engine.table_count(), 1);
+            assert_eq!(engine.scan(&gen_key_n(0), &gen_key_n(1024)).unwrap().len(),
+                       values.iter().filter(|v| v.is_some()).count());
+
+            // the last writes stay in the write-ahead log only
+            engine.put(&gen_key_n(2048), &values[1].clone().unwrap()).unwrap();
+            assert_eq!(engine.delete(&gen_key_n(1)).unwrap(), 1);
+            values[1] = None;
+        }
+
+        let engine = LsmEngine::open(&path, options).unwrap();
+        assert_eq!(engine.table_count(), 1);
+        for i in 0..1024 {
+            assert_eq!(engine.get(&gen_key_n(i)).unwrap().as_deref(), values[i as usize].as_ref());
+        }
+        assert!(engine.get(&gen_key_n(2048)).unwrap().is_some());
+        assert!(engine.scan(&gen_key_n(1024), &gen_key_n(0)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_lsm_engine_background_maintenance() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = LsmOptions::from_default();
+        options.memtable_size = 16 * 1024;
+        options.max_tables = 2;
+        options.layout = DataLayout::variable();
+
+        let engine = LsmEngine::open(dir.path(), options.clone()).unwrap();
+        for i in 0..4096u32 {
+            engine.put(&Key::from_bytes(&i.to_be_bytes()), &Value::from_bytes(&[i as u8; 64])).unwrap();
+        }
+        // flushes and compactions happen in the background, give them a while
+        for _ in 0..100 {
+            if engine.table_count() > 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert!(engine.table_count() > 0);
+        for i in 0..4096u32 {
+            assert_eq!(engine.get(&Key::from_bytes(&i.to_be_bytes())).unwrap().unwrap().data, vec![i as u8; 64]);
+        }
+        assert_eq!(engine.scan(&Key::from_bytes(&[]), &Key::from_bytes(&[0xff; 5])).unwrap().len(), 4096);
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test_memory_budget.kv");
+        let budget = 64 * VALUE_SIZE;
+        let mut options = StorageOptions::from_default();
+        options.memory_budget = Some(budget);
+        options.compaction_ratio = None;
+
+        let mut values = Vec::new();
+        {
+            let mut kv = KVStorage::open_with_options(&path, &options).unwrap();
+            for i in 0..1024 {
+                let value = gen_value();
+                kv.put(&gen_key_n(i), &value).unwrap();
+                values.push(Some(value));
+                assert!(kv.memory_usage() <= budget + VALUE_SIZE);
+            }
+            for i in (0..1024).step_by(4) {
+                kv.delete(&gen_key_n(i)).unwrap();
+                values[i as usize] = None;
+            }
+
+            for i in 0..1024 {
+                assert_eq!(kv.get(&gen_key_n(i)).as_deref(), values[i as usize].as_ref());
+            }
+            assert!(kv.cache_misses() > 0);
+            let hits = kv.cache_hits();
+            kv.get(&gen_key_n(1)).unwrap();
+            kv.get(&gen_key_n(1)).unwrap();
+            assert!(kv.cache_hits() > hits);
+            assert!(kv.memory_usage() <= budget + VALUE_SIZE);
+
+            // values move to the new log, and into snapshots when the log gets cut
+            kv.compact().unwrap();
+            kv.snapshot().unwrap();
+            for i in (1..1024).step_by(4) {
+                let value = gen_value();
+                kv.put(&gen_key_n(i), &value).unwrap();
+                values[i as usize] = Some(value);
+            }
+            kv.snapshot().unwrap();
+            kv.snapshot().unwrap();
+            let scanned = kv.scan(&gen_key_n(0), &gen_key_n(1024));
+            let expected = values.iter().enumerate()
+                .filter_map(|(i, v)| v.as_ref().map(|v| (gen_key_n(i as u64), v)))
+                .collect::<Vec<_>>();
+            assert_eq!(scanned.len(), expected.len());
+            for ((key, value), (expected_key, expected_value)) in scanned.iter().zip(expected.iter()) {
+                assert!(key == expected_key && value.deref() == *expected_value);
+            }
+        }
+
+        {
+            let kv = KVStorage::open_with_options(&path, &options).unwrap();
+            assert_eq!(kv.memory_usage(), 0);
+            for i in 0..1024 {
+                assert_eq!(kv.get(&gen_key_n(i)).as_deref(), values[i as usize].as_ref());
+            }
+            assert!(kv.memory_usage() <= budget);
+        }
+        let kv = KVStorage::open(&path).unwrap();
+        for i in 0..1024 {
+            assert_eq!(kv.get(&gen_key_n(i)).as_deref(), values[i as usize].as_ref());
+        }
+    }
+
+    #[test]
+    fn test_write_batch() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test_write_batch.kv");
+        let mut options = StorageOptions::from_default();
+        options.layout = DataLayout::Variable { max_key_size: 16, max_value_size: 1024 };
+        // a tight budget, so that values replayed from batches are read back from the log
+        options.memory_budget = Some(256);
+        let data_key = |i: u8| Key::from_bytes(&[b'd', i]);
+        let index_key = |i: u8| Key::from_bytes(&[b'i', i]);
+        let data_value = |i: u8| Value::from_bytes(&vec![i; 100 + i as usize]);
+
+        let mut last_batch_start = 0;
+        {
+            let mut kv = KVStorage::open_with_options(&path, &options).unwrap();
+            kv.put(&Key::from_bytes(b"stale"), &data_value(0)).unwrap();
+            for i in 0..8 {
+                let mut batch = WriteBatch::new();
+                batch.put(data_key(i), data_value(i))
+                    .put(index_key(i), Value::from_bytes(&data_key(i).data))
+                    .delete(Key::from_bytes(b"stale"))
+                    .delete(Key::from_bytes(b"never written"));
+                last_batch_start = fs::metadata(&path).unwrap().len();
+                kv.write_batch(&batch).unwrap();
+            }
+            assert_eq!(kv.live_keys(), 16);
+
+            // a batch is refused as a whole if any of its values is too large
+            let mut batch = WriteBatch::new();
+            batch.delete(data_key(0)).put(data_key(9), Value::from_bytes(&[0; 2048]));
+            assert!(kv.write_batch(&batch).is_err());
+            assert!(kv.get(&data_key(0)).is_some());
+        }
+
+        // cuts the last batch in the middle, none of its writes may survive
+        let full_size = fs::metadata(&path).unwrap().len();
+        fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(full_size - 150).unwrap();
+        let mut strict = options.clone();
+        strict.recovery = RecoveryMode::Strict;
+        let e = KVStorage::open_with_options(&path, &strict).err().unwrap();
+        match e.downcast_ref::<DiskLogError>() {
+            Some(DiskLogError::TornTail { offset }) => assert_eq!(*offset, last_batch_start),
+            _ => panic!("unexpected error: {}", e)
+        }
+
+        let check = |kv: &KVStorage| {
+            for i in 0..7 {
+                assert_eq!(kv.get(&data_key(i)).unwrap().deref(), &data_value(i));
+                assert_eq!(kv.get(&index_key(i)).unwrap().data, data_key(i).data);
+            }
+            assert!(kv.get(&data_key(7)).is_none());
+            assert!(kv.get(&index_key(7)).is_none());
+            assert!(kv.get(&Key::from_bytes(b"stale")).is_none());
+            assert_eq!(kv.live_keys(), 14);
+        };
+        {
+            let kv = KVStorage::open_with_options(&path, &options).unwrap();
+            assert_eq!(fs::metadata(&path).unwrap().len(), last_batch_start);
+            check(&kv);
+            assert!(kv.cache_misses() > 0);
+        }
+        let mut kv = KVStorage::open_with_options(&path, &options).unwrap();
+        kv.compact().unwrap();
+        check(&kv);
+    }
+
+    #[test]
+    fn test_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test_versions.kv");
+        let mut options = StorageOptions::from_default();
+        options.memory_budget = Some(16 * VALUE_SIZE);
+        {
+            let mut kv = KVStorage::open_with_options(&path, &options).unwrap();
+            for i in 0..64 {
+                kv.put(&gen_key_n(i), &gen_value()).unwrap();
+            }
+        }
+
+        let mut kv = KVStorage::open_with_options(&path, &options).unwrap();
+        assert_eq!(kv.version(&gen_key_n(0)), LOADED_VERSION);
+        assert_eq!(kv.version(&gen_key_n(64)), NO_VALUE_VERSION);
+        kv.put(&gen_key_n(0), &gen_value()).unwrap();
+        let (value, version) = kv.try_get_versioned(&gen_key_n(0)).unwrap();
+        assert!(version > LOADED_VERSION);
+        assert_eq!(value, kv.get(&gen_key_n(0)));
+
+        // versions survive eviction and compaction, they only change on writes
+        for i in 1..64 {
+            kv.put(&gen_key_n(i), &gen_value()).unwrap();
+        }
+        kv.compact().unwrap();
+        assert_eq!(kv.version(&gen_key_n(0)), version);
+        kv.put(&gen_key_n(0), &gen_value()).unwrap();
+        assert!(kv.version(&gen_key_n(0)) > kv.version(&gen_key_n(63)));
+        kv.delete(&gen_key_n(0)).unwrap();
+        assert_eq!(kv.version(&gen_key_n(0)), NO_VALUE_VERSION);
+
+        let read_set = [(gen_key_n(1), kv.version(&gen_key_n(1))), (gen_key_n(0), NO_VALUE_VERSION)];
+        let mut batch = WriteBatch::new();
+        batch.put(gen_key_n(0), gen_value());
+        kv.commit(&read_set, &batch).unwrap();
+        let e = kv.commit(&read_set, &batch).unwrap_err();
+        assert_eq!(e.downcast_ref::<ConflictError>().unwrap().key(), &gen_key_n(0));
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test_compare_and_swap.kv");
+        let mut options = StorageOptions::from_default();
+        options.memory_budget = Some(16 * VALUE_SIZE);
+        let values = (0..64).map(|_| gen_value()).collect::<Vec<_>>();
+        {
+            let mut kv = KVStorage::open_with_options(&path, &options).unwrap();
+            for (i, value) in values.iter().enumerate() {
+                kv.put(&gen_key_n(i as u64), value).unwrap();
+            }
+
+            // evicted values are read back to be compared
+            let value = gen_value();
+            assert_eq!(kv.compare_and_swap(&gen_key_n(0), Some(&value), Some(&value)).unwrap().unwrap_err().unwrap().deref(),
+                       &values[0]);
+            assert!(kv.compare_and_swap(&gen_key_n(0), Some(&values[0]), Some(&value)).unwrap().is_ok());
+            assert!(kv.compare_and_swap(&gen_key_n(1), Some(&values[1]), None).unwrap().is_ok());
+            assert_eq!(kv.compare_and_swap(&gen_key_n(1), Some(&values[1]), None).unwrap(), Err(None));
+            assert!(kv.compare_and_swap(&gen_key_n(64), None, Some(&value)).unwrap().is_ok());
+            assert!(kv.compare_and_swap(&gen_key_n(2), Some(&values[2]), Some(&Value::from_bytes(b"short"))).is_err());
+        }
+
+        // swaps are logged like puts and deletes
+        let kv = KVStorage::open_with_options(&path, &options).unwrap();
+        assert_eq!(kv.get(&gen_key_n(0)), kv.get(&gen_key_n(64)));
+        assert!(kv.get(&gen_key_n(1)).is_none());
+        assert_eq!(kv.get(&gen_key_n(2)).unwrap().deref(), &values[2]);
+    }
+
+    #[test]
+    fn test_read_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test_read_snapshots.kv");
+        let mut options = StorageOptions::from_default();
+        options.memory_budget = Some(16 * VALUE_SIZE);
+        let mut kv = KVStorage::open_with_options(&path, &options).unwrap();
+        let values = (0..64).map(|_| gen_value()).collect::<Vec<_>>();
+        for (i, value) in values.iter().enumerate() {
+            kv.put(&gen_key_n(i as u64), value).unwrap();
+        }
+
+        // old values are kept for the snapshot, through eviction, compaction and snapshot files
+        let snapshot = kv.create_read_snapshot();
+        for i in 0..64 {
+            kv.put(&gen_key_n(i), &gen_value()).unwrap();
+        }
+        for i in 0..32 {
+            kv.delete(&gen_key_n(i)).unwrap();
+        }
+        kv.compact().unwrap();
+        kv.snapshot().unwrap();
+        kv.purge_tombstones();
+        assert_eq!(kv.old_versions(), 64);
+        let later = kv.create_read_snapshot();
+        kv.put(&gen_key_n(0), &gen_value()).unwrap();
+        for i in 0..64 {
+            assert_eq!(kv.try_get_at(&gen_key_n(i), Some(snapshot)).unwrap().unwrap().deref(), &values[i as usize]);
+        }
+        let scanned = kv.try_scan_at(&gen_key_n(0), &gen_key_n(64), Some(snapshot)).unwrap();
+        assert_eq!(scanned.iter().map(|(_, value)| value.deref().clone()).collect::<Vec<_>>(), values);
+        assert!(kv.try_get_at(&gen_key_n(0), Some(later)).unwrap().is_none());
+        assert_eq!(kv.try_scan_at(&gen_key_n(0), &gen_key_n(64), Some(later)).unwrap().len(), 32);
+        assert_eq!(kv.try_scan_at(&gen_key_n(0), &gen_key_n(64), None).unwrap().len(), 33);
+
+        // releasing drops what only the released snapshot could see
+        kv.release_read_snapshot(snapshot).unwrap();
+        assert!(kv.try_get_at(&gen_key_n(0), Some(snapshot)).is_err());
+        assert_eq!((kv.read_snapshots(), kv.old_versions()), (1, 1));
+        kv.release_read_snapshot(later).unwrap();
+        assert_eq!(kv.old_versions(), 0);
+        kv.put(&gen_key_n(1), &gen_value()).unwrap();
+        assert_eq!(kv.old_versions(), 0);
+    }
+
+    #[test]
+    fn test_lsm_read_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = LsmOptions::from_default();
+        options.max_tables = 1;
+        let engine = LsmEngine::open(dir.path(), options).unwrap();
+        let values = (0..64).map(|_| gen_value()).collect::<Vec<_>>();
+        for (i, value) in values.iter().enumerate() {
+            engine.put(&gen_key_n(i as u64), value).unwrap();
+        }
+        engine.flush().unwrap();
+        engine.put(&gen_key_n(0), &gen_value()).unwrap();
+
+        // the snapshot sees through flushes and compactions, which go on regardless
+        let snapshot = engine.create_read_snapshot().unwrap();
+        let expected = engine.scan(&gen_key_n(0), &gen_key_n(64)).unwrap();
+        for round in 0..3 {
+            for i in 0..64 {
+                if i % 3 == round {
+                    engine.delete(&gen_key_n(i)).unwrap();
+                } else {
+                    engine.put(&gen_key_n(i), &gen_value()).unwrap();
+                }
+            }
+            engine.compact().unwrap();
+            assert_eq!(engine.scan_at(&gen_key_n(0), &gen_key_n(64), Some(snapshot)).unwrap(), expected);
+        }
+        assert_eq!(engine.table_count(), 1);
+        assert_eq!(engine.get_at(&gen_key_n(1), Some(snapshot)).unwrap().unwrap().deref(), &values[1]);
+        engine.release_read_snapshot(snapshot).unwrap();
+        assert!(engine.scan_at(&gen_key_n(0), &gen_key_n(64), Some(snapshot)).is_err());
+        assert!(engine.get_at(&gen_key_n(2), None).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_expiry() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test_expiry.kv");
+        let value = gen_value();
+        {
+            let mut kv = KVStorage::open(&path).unwrap();
+            kv.set_compaction_ratio(None);
+            for i in 0..64 {
+                kv.put_with_ttl(&gen_key_n(i), &value, Duration::from_millis(200)).unwrap();
+            }
+            kv.put_with_ttl(&gen_key_n(64), &value, Duration::from_secs(0)).unwrap();
+            assert!(kv.get(&gen_key_n(64)).is_none());
+            kv.put_with_ttl(&gen_key_n(65), &value, Duration::from_secs(3600)).unwrap();
+            kv.put(&gen_key_n(66), &value).unwrap();
+            assert_eq!(kv.persist(&gen_key_n(0)).unwrap(), 1);
+            assert_eq!(kv.expire(&gen_key_n(66), Duration::from_secs(3600)).unwrap(), 1);
+            assert_eq!(kv.expiring_keys(), 66);
+        }
+
+        // expiry times survive reopening, compaction and snapshot files
+        let mut kv = KVStorage::open(&path).unwrap();
+        kv.set_compaction_ratio(None);
+        kv.compact().unwrap();
+        kv.snapshot().unwrap();
+        let mut kv = KVStorage::open(&path).unwrap();
+        kv.set_compaction_ratio(None);
+        assert_eq!(kv.ttl(&gen_key_n(0)), Ttl::Persistent);
+        assert!(matches!(kv.ttl(&gen_key_n(66)), Ttl::Expires(left) if left.as_secs() > 3500));
+        assert_eq!(kv.get(&gen_key_n(1)).unwrap().deref(), &value);
+        assert!(kv.get(&gen_key_n(64)).is_none());
+
+        // expired keys are invisible until reaped, and reaping lets compaction shrink the file
+        thread::sleep(Duration::from_millis(250));
+        assert!(kv.get(&gen_key_n(1)).is_none());
+        assert_eq!(kv.try_scan(&gen_key_n(0), &gen_key_n(100)).unwrap().len(), 3);
+        assert_eq!(kv.live_keys(), 67);
+        assert_eq!(kv.reap_expired().unwrap(), 64);
+        assert_eq!((kv.live_keys(), kv.expiring_keys()), (3, 2));
+        let size_before = fs::metadata(&path).unwrap().len();
+        kv.compact().unwrap();
+        assert!(fs::metadata(&path).unwrap().len() < size_before);
+
+        let kv = KVStorage::open(&path).unwrap();
+        assert_eq!(kv.try_scan(&gen_key_n(0), &gen_key_n(100)).unwrap().len(), 3);
+        assert_eq!(kv.ttl(&gen_key_n(1)), Ttl::NoValue);
+        assert_eq!(kv.get(&gen_key_n(65)).unwrap().deref(), &value);
+    }
+
+    #[test]
+    fn test_scan_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test_scan_keys.kv");
+        let mut options = StorageOptions::from_default();
+        options.memory_budget = Some(16 * VALUE_SIZE);
+        let mut kv = KVStorage::open_with_options(&path, &options).unwrap();
+        for i in 0..256 {
+            kv.put(&gen_key_n(i), &gen_value()).unwrap();
+        }
+        for i in 0..64 {
+            kv.delete(&gen_key_n(i * 4)).unwrap();
+        }
+        kv.put_with_ttl(&gen_key_n(1), &gen_value(), Duration::from_secs(0)).unwrap();
+
+        // evicted values are not read back, deleted and expired keys are left out
+        let keys = kv.scan_keys(&gen_key_n(0), &gen_key_n(16));
+        assert_eq!(keys, [2, 3, 5, 6, 7, 9, 10, 11, 13, 14, 15].iter().map(|&i| gen_key_n(i)).collect::<Vec<_>>());
+        assert_eq!(kv.count(&gen_key_n(0), &gen_key_n(256)), 191);
+        assert_eq!(kv.cache_hits() + kv.cache_misses(), 0);
+        assert_eq!(kv.count(&gen_key_n(0), &gen_key_n(256)), kv.scan(&gen_key_n(0), &gen_key_n(256)).len());
+        assert!(kv.scan_keys(&gen_key_n(16), &gen_key_n(0)).is_empty());
+    }
+
+    #[test]
+    fn test_scan_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test_scan_prefix.kv");
+        let mut options = StorageOptions::from_default();
+        options.layout = DataLayout::variable();
+        options.memory_budget = Some(64);
+        {
+            let mut kv = KVStorage::open_with_options(&path, &options).unwrap();
+            for user in 0..20u8 {
+                for field in [&b"name"[..], b"mail"] {
+                    let key = [b"user:", &[user][..], b":", field].concat();
+                    kv.put(&Key::from_bytes(&key), &Value::from_bytes(&[field, &[user][..]].concat())).unwrap();
+                }
+            }
+            kv.put(&Key::from_bytes(&[0xff; 4]), &Value::from_bytes(b"last")).unwrap();
+            kv.delete(&Key::from_bytes(b"user:\x07:mail")).unwrap();
+        }
+
+        // values evicted from memory are read back from disk
+        let kv = KVStorage::open_with_options(&path, &options).unwrap();
+        assert_eq!(kv.scan_prefix(b"user:").len(), 39);
+        let pairs = kv.try_scan_prefix(b"user:\x07:").unwrap();
+        assert_eq!(pairs.len(), 1);
+        assert_eq!((&pairs[0].0.data[..], &pairs[0].1.data[..]), (&b"user:\x07:name"[..], &b"name\x07"[..]));
+        assert_eq!(kv.scan_prefix(&[0xff, 0xff]).len(), 1);
+        assert_eq!(kv.scan_prefix(b"").len(), 40);
+        assert!(kv.scan_prefix(b"users").is_empty());
+    }
 }